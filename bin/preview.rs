@@ -20,6 +20,8 @@ struct Opt {
     generate: Option<PathBuf>,
     #[structopt(long)]
     download: Option<PathBuf>,
+    #[structopt(long)]
+    procedural: bool,
 }
 
 fn compute_projection_matrix(width: f32, height: f32) -> cgmath::Matrix4<f32> {
@@ -203,6 +205,9 @@ fn main() {
                 ))
                 .unwrap()
         }
+        None if opt.procedural => {
+            runtime.block_on(terra::Terrain::new_preview(&device, &queue)).unwrap()
+        }
         None => runtime.block_on(terra::Terrain::new(&device, &queue)).unwrap(),
     };
 
@@ -334,7 +339,8 @@ fn main() {
 
                 // Compute position and camera matrices.
                 let (lat, long) = camera.latitude_longitude();
-                let surface_height = terrain.get_height(lat.to_radians(), long.to_radians()) as f64;
+                let surface_height =
+                    terrain.get_height(lat.to_radians(), long.to_radians()).unwrap_or(0.0) as f64;
                 let (position, view) = camera.anchored_position_view(surface_height + 2.0);
                 let proj = compute_projection_matrix(size.width as f32, size.height as f32);
                 let view: cgmath::Matrix4<f32> = cgmath::Matrix3::from(view).into();
@@ -26,6 +26,8 @@ pub enum ShaderSource {
         name: &'static str,
         path: PathBuf,
         header_paths: HashMap<&'static str, PathBuf>,
+        /// Headers supplied directly rather than loaded from disk, see `with_header`.
+        headers: HashMap<&'static str, String>,
         defines: Vec<(&'static str, &'static str)>,
     },
     FilesWGSL {
@@ -46,7 +48,7 @@ impl ShaderSource {
         for header in header_paths.values_mut() {
             *header = std::fs::canonicalize(directory.join(&header)).unwrap();
         }
-        ShaderSource::Files { name, path, header_paths, defines }
+        ShaderSource::Files { name, path, header_paths, headers: HashMap::new(), defines }
     }
     pub fn new_wgsl(
         directory: PathBuf,
@@ -60,6 +62,27 @@ impl ShaderSource {
         }
         ShaderSource::FilesWGSL { name, path, header_paths }
     }
+    /// Make `contents` available to `#include "name"` directives, replacing any existing header
+    /// with the same name. Only supported for GLSL sources.
+    pub fn with_header(mut self, name: &'static str, contents: String) -> Self {
+        match &mut self {
+            ShaderSource::Inline { headers, .. } | ShaderSource::Files { headers, .. } => {
+                headers.insert(name, contents);
+            }
+            ShaderSource::FilesWGSL { .. } => panic!("WGSL shaders don't support headers"),
+        }
+        self
+    }
+    /// Add a preprocessor definition. Only supported for GLSL sources.
+    pub fn with_define(mut self, name: &'static str, value: &'static str) -> Self {
+        match &mut self {
+            ShaderSource::Inline { defines, .. } | ShaderSource::Files { defines, .. } => {
+                defines.push((name, value));
+            }
+            ShaderSource::FilesWGSL { .. } => panic!("WGSL shaders don't support defines"),
+        }
+        self
+    }
     pub(crate) fn load(
         &self,
         stage: shaderc::ShaderKind,
@@ -68,9 +91,9 @@ impl ShaderSource {
             ShaderSource::Inline { name, contents, headers, defines } => {
                 (name, contents.clone(), headers.clone(), Some(defines))
             }
-            ShaderSource::Files { name, path, header_paths, defines } => {
+            ShaderSource::Files { name, path, header_paths, headers, defines } => {
                 let file = std::fs::read_to_string(path)?;
-                let mut headers = HashMap::new();
+                let mut headers = headers.clone();
                 for (&name, path) in header_paths.iter() {
                     headers.insert(name, std::fs::read_to_string(path)?);
                 }
@@ -66,6 +66,9 @@ impl<'a> AssetLoadContext<'a> {
     }
 
     pub fn reset<N: ToPrimitive>(&mut self, message: impl Into<Cow<'static, str>>, total: N) {
+        let message = message.into();
+        log::info!("{}", message);
+
         let new_bar = ProgressBar::new(total.to_u64().unwrap());
         let new_bar = self.inner.bars.add(new_bar);
 
@@ -93,6 +96,9 @@ impl<'a> AssetLoadContext<'a> {
     where
         'a: 'b,
     {
+        let message = message.into();
+        log::info!("{}", message);
+
         let bar = ProgressBar::new(total.to_u64().unwrap());
         let bar = self.inner.bars.add(bar);
         bar.set_style(PROGRESS_BAR_STYLE.clone());
@@ -144,7 +150,8 @@ pub(crate) enum CompressionType {
 pub(crate) trait WebAsset {
     type Type;
 
-    fn url(&self) -> String;
+    /// Locations the asset can be downloaded from, tried in order until one succeeds.
+    fn urls(&self) -> Vec<String>;
     fn filename(&self) -> String;
     fn parse(&self, context: &mut AssetLoadContext, data: Vec<u8>) -> Result<Self::Type, Error>;
 
@@ -154,6 +161,56 @@ pub(crate) trait WebAsset {
     fn credentials(&self) -> Option<(String, String)> {
         None
     }
+    /// Largest download that will be accepted, in bytes. Defaults to 1 GiB, enough for the
+    /// largest textures. Models are limited to 256 MiB.
+    fn max_size(&self) -> u64 {
+        1 << 30
+    }
+
+    /// Download the asset from a single `url`, reporting progress through `context`.
+    fn download(&self, context: &mut AssetLoadContext, url: &str) -> Result<Vec<u8>, Error> {
+        use curl::easy::Easy;
+        let mut data = Vec::<u8>::new();
+        let mut easy = Easy::new();
+        easy.url(url)?;
+        easy.progress(true)?;
+        easy.follow_location(true)?;
+        easy.fail_on_error(true)?;
+        easy.max_filesize(self.max_size())?;
+        if let Some((username, password)) = self.credentials() {
+            easy.cookie_file("")?;
+            easy.unrestricted_auth(true)?;
+            easy.username(&username)?;
+            easy.password(&password)?;
+        }
+        let max_size = self.max_size();
+        let mut too_large = false;
+        let mut transfer = easy.transfer();
+        transfer.write_function(|d| {
+            // The server may not report a size up front, so also check as data arrives.
+            // Returning a short count makes curl abort the transfer.
+            if (data.len() + d.len()) as u64 > max_size {
+                too_large = true;
+                return Ok(0);
+            }
+            let len = d.len();
+            data.extend(d);
+            Ok(len)
+        })?;
+        transfer.progress_function(|t, c, _, _| {
+            if t > 0.0 {
+                context.set_progress_and_total(c, t);
+            }
+            true
+        })?;
+        let result = transfer.perform();
+        drop(transfer);
+        if too_large || result.as_ref().err().map_or(false, |e| e.is_filesize_exceeded()) {
+            anyhow::bail!("Download of '{}' aborted: response exceeds {} bytes", url, max_size);
+        }
+        result?;
+        Ok(data)
+    }
 
     fn load(&self, context: &mut AssetLoadContext) -> Result<Self::Type, Error> {
         let context =
@@ -185,39 +242,31 @@ pub(crate) trait WebAsset {
             }
         }
 
-        let mut data = Vec::<u8>::new();
-        {
+        let mut data = None;
+        let mut last_error = None;
+        for url in self.urls() {
             context.reset(format!("Downloading {}... ", &self.filename()), 100);
             // Bytes display will be disabled by the reset() below, or in the event of an error,
             // by the decrement_level() call in the outer scope.
             context.bytes_display_enabled(true);
 
-            use curl::easy::Easy;
-            let mut easy = Easy::new();
-            easy.url(&self.url())?;
-            easy.progress(true)?;
-            easy.follow_location(true)?;
-            easy.fail_on_error(true)?;
-            if let Some((username, password)) = self.credentials() {
-                easy.cookie_file("")?;
-                easy.unrestricted_auth(true)?;
-                easy.username(&username)?;
-                easy.password(&password)?;
-            }
-            let mut transfer = easy.transfer();
-            transfer.write_function(|d| {
-                let len = d.len();
-                data.extend(d);
-                Ok(len)
-            })?;
-            transfer.progress_function(|t, c, _, _| {
-                if t > 0.0 {
-                    context.set_progress_and_total(c, t);
+            match self.download(context, &url) {
+                Ok(contents) => {
+                    log::info!("Downloaded {} from '{}'", self.filename(), url);
+                    data = Some(contents);
+                    break;
+                }
+                Err(e) => {
+                    log::warn!("Download of {} from '{}' failed: {}", self.filename(), url, e);
+                    last_error = Some(e);
                 }
-                true
-            })?;
-            transfer.perform()?;
+            }
         }
+        let data = match (data, last_error) {
+            (Some(data), _) => data,
+            (None, Some(e)) => return Err(e),
+            (None, None) => anyhow::bail!("No download sources for {}", self.filename()),
+        };
 
         context.reset(format!("Saving {}... ", &self.filename()), 100);
         if let Some(parent) = filename.parent() {
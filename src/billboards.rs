@@ -3,6 +3,7 @@ use std::{
     fs::File,
     io::{BufReader, Read},
     num::NonZeroU32,
+    ops::Range,
 };
 
 use anyhow::Error;
@@ -13,13 +14,31 @@ use zip::ZipArchive;
 use crate::{
     asset::TERRA_DIRECTORY,
     cache::TextureFormat,
-    gpu_state::GpuState,
+    generate::ComputeShader,
+    gpu_state::{DrawIndexedIndirect, GpuState},
     speedtree_xml::{parse_xml, SpeedTreeModel},
 };
 
 const RESOLUTION: u32 = 256;
 const FRAMES_PER_SIDE: u32 = 6;
 
+/// Maximum number of trees drawn as 3D models in a single frame. Trees beyond this are left as
+/// billboards.
+const MAX_TREE_MODELS: u32 = 8192;
+/// Size in bytes of each entry in the tree model instance buffer.
+const TREE_MODEL_INSTANCE_SIZE: u64 = 32;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct CullTreeModelsUniforms {
+    base_entry: u32,
+    num_entries: u32,
+    max_instances: u32,
+    padding: u32,
+}
+unsafe impl bytemuck::Zeroable for CullTreeModelsUniforms {}
+unsafe impl bytemuck::Pod for CullTreeModelsUniforms {}
+
 // #[derive(Copy, Clone, Debug, Default)]
 // #[repr(C)]
 // struct Vertex {
@@ -35,6 +54,10 @@ pub(crate) struct Models {
     tree: SpeedTreeModel,
     shader: rshader::ShaderSet,
     albedo_texture: Vec<u8>,
+
+    tree_model_shader: rshader::ShaderSet,
+    tree_model_bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
+    cull_tree_models: ComputeShader<CullTreeModelsUniforms>,
 }
 impl Models {
     pub fn new() -> Result<Self, Error> {
@@ -54,7 +77,30 @@ impl Models {
         )
         .unwrap();
 
-        Ok(Self { tree, shader, albedo_texture })
+        let tree_model_shader = rshader::ShaderSet::simple(
+            rshader::shader_source!("shaders", "tree-models.vert", "declarations.glsl"),
+            rshader::shader_source!(
+                "shaders",
+                "tree-models.frag",
+                "declarations.glsl",
+                "pbr.glsl",
+                "hash.glsl"
+            ),
+        )
+        .unwrap();
+        let cull_tree_models = ComputeShader::new(
+            rshader::shader_source!("shaders", "cull-tree-models.comp", "declarations.glsl"),
+            "cull-tree-models".to_owned(),
+        );
+
+        Ok(Self {
+            tree,
+            shader,
+            albedo_texture,
+            tree_model_shader,
+            tree_model_bindgroup_pipeline: None,
+            cull_tree_models,
+        })
     }
 
     pub fn make_buffers(&self, device: &wgpu::Device) -> (wgpu::Buffer, wgpu::Buffer) {
@@ -72,6 +118,44 @@ impl Models {
         (vertex_buffer, index_buffer)
     }
 
+    /// Create the buffers that nearby trees are written to each frame so they can be drawn as 3D
+    /// models: a list of instances, and the indirect draw arguments that count them.
+    pub fn make_tree_model_buffers(&self, device: &wgpu::Device) -> (wgpu::Buffer, wgpu::Buffer) {
+        let instances = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("buffer.tree_models.instances"),
+            size: TREE_MODEL_INSTANCE_SIZE * MAX_TREE_MODELS as u64,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let indirect = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("buffer.tree_models.indirect"),
+            size: std::mem::size_of::<DrawIndexedIndirect>() as u64,
+            usage: wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::INDIRECT
+                | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        (instances, indirect)
+    }
+
+    /// Bytes of GPU memory taken by the buffers and textures created by the `make_*` methods.
+    pub fn gpu_bytes(&self) -> u64 {
+        let buffers = std::mem::size_of_val(&self.tree.vertices[..])
+            + std::mem::size_of_val(&self.tree.indices[..])
+            + std::mem::size_of::<DrawIndexedIndirect>();
+        let tree_model_instances = TREE_MODEL_INSTANCE_SIZE * MAX_TREE_MODELS as u64;
+
+        // The models' albedo is compressed to one byte per texel. Billboards and top-down views
+        // each have albedo, normals, ambient occlusion and depth, for 4 + 4 + 1 + 2 bytes per
+        // texel.
+        let models_albedo = 4096 * 4096;
+        let billboard_side = (RESOLUTION * FRAMES_PER_SIDE) as u64;
+        let billboards = billboard_side * billboard_side * 2 * 11;
+        let topdown = (RESOLUTION * RESOLUTION) as u64 * 11;
+
+        buffers as u64 + tree_model_instances + models_albedo + billboards + topdown
+    }
+
     pub fn make_models_albedo(
         &self,
         device: &wgpu::Device,
@@ -194,6 +278,129 @@ impl Models {
         self.shader.refresh()
     }
 
+    /// (Re)build the pipelines used to draw trees as 3D models near the camera.
+    pub fn update_tree_models(
+        &mut self,
+        device: &wgpu::Device,
+        gpu_state: &GpuState,
+        depth_format: wgpu::TextureFormat,
+        depth_compare: wgpu::CompareFunction,
+    ) {
+        self.cull_tree_models.refresh(device, gpu_state);
+        if self.tree_model_shader.refresh() {
+            self.tree_model_bindgroup_pipeline = None;
+        }
+        if self.tree_model_bindgroup_pipeline.is_some() {
+            return;
+        }
+
+        let (bind_group, bind_group_layout) = gpu_state.bind_group_for_shader(
+            device,
+            &self.tree_model_shader,
+            HashMap::new(),
+            HashMap::new(),
+            "tree-models",
+        );
+        let render_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: [&bind_group_layout][..].into(),
+                push_constant_ranges: &[],
+                label: Some("pipeline.tree-models.layout"),
+            });
+        self.tree_model_bindgroup_pipeline = Some((
+            bind_group,
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                layout: Some(&render_pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                        label: Some("shader.tree-models.vertex"),
+                        source: self.tree_model_shader.vertex(),
+                    }),
+                    entry_point: "main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                        label: Some("shader.tree-models.fragment"),
+                        source: self.tree_model_shader.fragment(),
+                    }),
+                    entry_point: "main",
+                    targets: &[wgpu::ColorTargetState {
+                        format: wgpu::TextureFormat::Bgra8UnormSrgb,
+                        blend: Some(wgpu::BlendState {
+                            color: wgpu::BlendComponent::REPLACE,
+                            alpha: wgpu::BlendComponent::REPLACE,
+                        }),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    }],
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::TriangleList,
+                    cull_mode: None,
+                    ..Default::default()
+                },
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: depth_format,
+                    depth_compare,
+                    depth_write_enabled: true,
+                    bias: Default::default(),
+                    stencil: Default::default(),
+                }),
+                multisample: Default::default(),
+                multiview: None,
+                label: Some("pipeline.tree-models"),
+            }),
+        ));
+    }
+
+    /// Gather the trees close enough to the camera to be drawn as 3D models from the tree
+    /// billboard mesh entries `entries`, which must already have been culled for this frame.
+    pub fn cull_tree_models(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        gpu_state: &GpuState,
+        entries: Range<usize>,
+    ) {
+        let lod = self.tree.lods[0].clone();
+        queue.write_buffer(
+            &gpu_state.tree_model_indirect,
+            0,
+            bytemuck::bytes_of(&DrawIndexedIndirect {
+                vertex_count: lod.end - lod.start,
+                instance_count: 0,
+                base_index: lod.start,
+                vertex_offset: 0,
+                base_instance: 0,
+            }),
+        );
+        self.cull_tree_models.run(
+            device,
+            encoder,
+            gpu_state,
+            (entries.len() as u32, 1, 1),
+            &CullTreeModelsUniforms {
+                base_entry: entries.start as u32,
+                num_entries: entries.len() as u32,
+                max_instances: MAX_TREE_MODELS,
+                padding: 0,
+            },
+        );
+    }
+
+    pub fn render_tree_models<'a>(
+        &'a self,
+        rpass: &mut wgpu::RenderPass<'a>,
+        gpu_state: &'a GpuState,
+    ) {
+        let (bind_group, pipeline) = self.tree_model_bindgroup_pipeline.as_ref().unwrap();
+        rpass.set_pipeline(pipeline);
+        rpass.set_bind_group(0, bind_group, &[]);
+        rpass.set_index_buffer(gpu_state.model_indices.slice(..), wgpu::IndexFormat::Uint32);
+        rpass.draw_indexed_indirect(&gpu_state.tree_model_indirect, 0);
+    }
+
     pub fn render_billboards(
         &self,
         device: &wgpu::Device,
@@ -5,7 +5,7 @@ use std::{
     num::{NonZeroU32, NonZeroU64},
 };
 
-use super::{LayerMask, LayerParams, LayerType, MeshCache};
+use super::{LayerMask, LayerParams, LayerType, MeshCache, VegetationConfig};
 use crate::{
     cache::{mesh::MeshGenerateUniforms, MeshType, TileCache},
     gpu_state::{DrawIndexedIndirect, GpuState},
@@ -52,6 +52,7 @@ struct MeshGen {
     min_level: u8,
     base_entry: u32,
     entries_per_node: u32,
+    seed: u32,
 
     clear_indirect_buffer: wgpu::Buffer,
 }
@@ -94,6 +95,7 @@ impl GenerateTile for MeshGen {
             storage_base_entry: entry,
             mesh_base_entry: self.base_entry + entry,
             entries_per_node: self.entries_per_node,
+            seed: self.seed,
         };
 
         assert!(std::mem::size_of::<MeshGenerateUniforms>() <= 256);
@@ -162,6 +164,8 @@ struct ShaderGen {
     ancestor_inputs: LayerMask,
     outputs: LayerMask,
     name: String,
+    /// Extra uniform data placed after the slot index.
+    params: Vec<u8>,
 }
 impl GenerateTile for ShaderGen {
     fn outputs(&self) -> LayerMask {
@@ -197,6 +201,7 @@ impl GenerateTile for ShaderGen {
     ) {
         let uniform_offset = uniform_data.len();
         uniform_data.extend_from_slice(bytemuck::bytes_of(&(slot as u32)));
+        uniform_data.extend_from_slice(&self.params);
         uniform_data.resize(uniform_offset + 256, 0);
 
         let views_needed = self.outputs() & self.parent_inputs();
@@ -253,7 +258,7 @@ impl GenerateTile for ShaderGen {
                 hashmap!["ubo".into() => (true, wgpu::BindingResource::Buffer(wgpu::BufferBinding {
                     buffer: &state.generate_uniforms,
                     offset: 0,
-                    size: Some(NonZeroU64::new(4).unwrap()),
+                    size: Some(NonZeroU64::new(4 + self.params.len() as u64).unwrap()),
                 }))],
                 image_views.iter().map(|(n, v)| (n.clone(), v)).collect(),
                 &format!("generate.{}", self.name),
@@ -305,6 +310,7 @@ struct ShaderGenBuilder {
     parent_inputs: LayerMask,
     outputs: LayerMask,
     ancestor_dependencies: LayerMask,
+    params: Vec<u8>,
 }
 impl ShaderGenBuilder {
     fn new(name: String, shader: ShaderSource) -> Self {
@@ -316,6 +322,7 @@ impl ShaderGenBuilder {
             peer_inputs: LayerMask::empty(),
             parent_inputs: LayerMask::empty(),
             ancestor_dependencies: LayerMask::empty(),
+            params: Vec::new(),
         }
     }
     fn dimensions(mut self, dimensions: u32) -> Self {
@@ -338,6 +345,11 @@ impl ShaderGenBuilder {
         self.ancestor_dependencies = ancestor_dependencies;
         self
     }
+    fn params(mut self, params: &[u8]) -> Self {
+        assert!(params.len() <= 252);
+        self.params = params.to_vec();
+        self
+    }
     fn build(self) -> Box<dyn GenerateTile> {
         Box::new(ShaderGen {
             name: self.name,
@@ -349,6 +361,7 @@ impl ShaderGenBuilder {
             parent_inputs: self.parent_inputs,
             dimensions: self.dimensions,
             ancestor_inputs: self.ancestor_dependencies,
+            params: self.params,
         })
     }
 }
@@ -358,6 +371,7 @@ pub(crate) fn generators(
     layers: &VecMap<LayerParams>,
     meshes: &VecMap<MeshCache>,
     soft_float64: bool,
+    vegetation: &VegetationConfig,
 ) -> Vec<Box<dyn GenerateTile>> {
     let heightmaps_resolution = layers[LayerType::Heightmaps].texture_resolution;
     let displacements_resolution = layers[LayerType::Displacements].texture_resolution;
@@ -365,7 +379,7 @@ pub(crate) fn generators(
     let grass_canopy_resolution = layers[LayerType::GrassCanopy].texture_resolution;
     let tree_attributes_resolution = layers[LayerType::GrassCanopy].texture_resolution;
 
-    vec![
+    let mut generators = vec![
         ShaderGenBuilder::new(
             "heightmaps".into(),
             rshader::shader_source!("../shaders", "gen-heightmaps.comp", "declarations.glsl", "hash.glsl"),
@@ -399,6 +413,14 @@ pub(crate) fn generators(
         .outputs(LayerType::TreeAttributes.bit_mask())
         .dimensions(tree_attributes_resolution)
         .ancestor_inputs(LayerType::TreeCover.bit_mask())
+        .params(
+            &[
+                bytemuck::bytes_of(&vegetation.tree_density),
+                bytemuck::bytes_of(&vegetation.min_tree_cover),
+                bytemuck::bytes_of(&vegetation.placement_seed),
+            ]
+            .concat(),
+        )
         .build(),
         ShaderGenBuilder::new(
             "materials".into(),
@@ -425,7 +447,10 @@ pub(crate) fn generators(
         .dimensions(513)
         .peer_inputs(LayerType::Heightmaps.bit_mask())
         .build(),
-        Box::new(MeshGen {
+    ];
+
+    if meshes.contains_key(MeshType::Grass as usize) {
+        generators.push(Box::new(MeshGen {
             shaders: vec![
                 // ShaderSet::compute_only(rshader::shader_source!(
                 //     "../shaders",
@@ -437,12 +462,14 @@ pub(crate) fn generators(
                     "../shaders",
                     "gen-grass.wgsl",
                     "declarations.wgsl"
-                )).unwrap(),
+                ))
+                .unwrap(),
                 ShaderSet::compute_only(rshader::shader_source!(
                     "../shaders",
                     "bounding-sphere.comp",
                     "declarations.glsl"
-                )).unwrap(),
+                ))
+                .unwrap(),
             ],
             dimensions: vec![(16, 16, 1), (16, 1, 1)],
             bindgroup_pipeline: vec![None, None],
@@ -455,21 +482,27 @@ pub(crate) fn generators(
             min_level: meshes[MeshType::Grass].desc.min_level,
             base_entry: meshes[MeshType::Grass].base_entry as u32,
             entries_per_node: meshes[MeshType::Grass].desc.entries_per_node as u32,
+            seed: vegetation.placement_seed,
             clear_indirect_buffer: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 usage: wgpu::BufferUsages::COPY_SRC,
                 label: Some("buffer.grass.clear_indirect"),
                 contents: &vec![0; mem::size_of::<DrawIndexedIndirect>() * 16],
-            })
-        }),
-        Box::new(MeshGen {
-            shaders: vec![
-                ShaderSet::compute_only(rshader::shader_source!(
-                    "../shaders",
-                    "gen-terrain-bounding.comp",
-                    "declarations.glsl"
-                )).unwrap()
-            ],
-            dimensions: vec![(4, 1, 1)],
+            }),
+        }));
+    }
+    if meshes.contains_key(MeshType::Terrain as usize) {
+        // Each entry draws one block of the node's 64x64 grid, matching the layout of the index
+        // buffer built by `QuadTree::create_index_buffer`.
+        let terrain_entries = meshes[MeshType::Terrain].desc.entries_per_node as u32;
+        let terrain_block_indices = 64 * 64 * 6 / terrain_entries;
+        generators.push(Box::new(MeshGen {
+            shaders: vec![ShaderSet::compute_only(rshader::shader_source!(
+                "../shaders",
+                "gen-terrain-bounding.comp",
+                "declarations.glsl"
+            ))
+            .unwrap()],
+            dimensions: vec![(meshes[MeshType::Terrain].desc.entries_per_node as u32, 1, 1)],
             bindgroup_pipeline: vec![None],
             peer_inputs: LayerType::Displacements.bit_mask(),
             ancestor_inputs: LayerMask::empty(),
@@ -478,30 +511,39 @@ pub(crate) fn generators(
             min_level: meshes[MeshType::Terrain].desc.min_level,
             base_entry: meshes[MeshType::Terrain].base_entry as u32,
             entries_per_node: meshes[MeshType::Terrain].desc.entries_per_node as u32,
+            seed: vegetation.placement_seed,
             clear_indirect_buffer: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 usage: wgpu::BufferUsages::COPY_SRC,
                 label: Some("buffer.terrain.clear_indirect"),
-                contents: bytemuck::cast_slice(&(0..4).map(|i| DrawIndexedIndirect {
-                    vertex_count: 32 * 32 * 6,
-                    instance_count: 1,
-                    vertex_offset: 0,
-                    base_instance: 0,
-                    base_index: 32 * 32 * 6 * i,
-                }).collect::<Vec<_>>()),
-            })
-        }),
-        Box::new(MeshGen {
+                contents: bytemuck::cast_slice(
+                    &(0..terrain_entries)
+                        .map(|i| DrawIndexedIndirect {
+                            vertex_count: terrain_block_indices,
+                            instance_count: 1,
+                            vertex_offset: 0,
+                            base_instance: 0,
+                            base_index: terrain_block_indices * i,
+                        })
+                        .collect::<Vec<_>>(),
+                ),
+            }),
+        }));
+    }
+    if meshes.contains_key(MeshType::TreeBillboards as usize) {
+        generators.push(Box::new(MeshGen {
             shaders: vec![
                 ShaderSet::compute_only(rshader::wgsl_source!(
                     "../shaders",
                     "gen-tree-billboards.wgsl",
                     "declarations.wgsl"
-                )).unwrap(),
+                ))
+                .unwrap(),
                 ShaderSet::compute_only(rshader::shader_source!(
                     "../shaders",
                     "bounding-tree-billboards.comp",
                     "declarations.glsl"
-                )).unwrap(),
+                ))
+                .unwrap(),
             ],
             dimensions: vec![(16, 16, 1), (16, 1, 1)],
             bindgroup_pipeline: vec![None, None],
@@ -512,13 +554,16 @@ pub(crate) fn generators(
             min_level: meshes[MeshType::TreeBillboards].desc.min_level,
             base_entry: meshes[MeshType::TreeBillboards].base_entry as u32,
             entries_per_node: meshes[MeshType::TreeBillboards].desc.entries_per_node as u32,
+            seed: vegetation.placement_seed,
             clear_indirect_buffer: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 usage: wgpu::BufferUsages::COPY_SRC,
                 label: Some("buffer.tree_billboards.clear_indirect"),
                 contents: &vec![0; mem::size_of::<DrawIndexedIndirect>() * 16],
-            })
-        }),
-    ]
+            }),
+        }));
+    }
+
+    generators
 }
 
 pub(super) struct DynamicGenerator {
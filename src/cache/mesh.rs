@@ -12,6 +12,7 @@ pub(crate) struct MeshGenerateUniforms {
     pub(super) storage_base_entry: u32,
     pub(super) mesh_base_entry: u32,
     pub(super) entries_per_node: u32,
+    pub(super) seed: u32,
 }
 unsafe impl bytemuck::Zeroable for MeshGenerateUniforms {}
 unsafe impl bytemuck::Pod for MeshGenerateUniforms {}
@@ -24,6 +25,8 @@ pub(crate) struct CullMeshUniforms {
     pub(super) entries_per_node: u32,
     pub(super) base_slot: u32,
     pub(super) mesh_index: u32,
+    pub(super) frustum_culling: u32,
+    pub(super) max_distance: f32,
 }
 unsafe impl bytemuck::Zeroable for CullMeshUniforms {}
 unsafe impl bytemuck::Pod for CullMeshUniforms {}
@@ -49,12 +52,28 @@ pub(crate) struct MeshCacheDesc {
     pub render: rshader::ShaderSet,
     pub render_shadow: Option<rshader::ShaderSet>,
     pub cull_mode: Option<wgpu::Face>,
+    pub front_face: wgpu::FrontFace,
+    pub depth_format: wgpu::TextureFormat,
+    pub depth_compare: wgpu::CompareFunction,
+    pub shadow_depth_bias: wgpu::DepthBiasState,
     pub render_overlapping_levels: bool,
     pub entries_per_node: usize,
     pub min_level: u8,
     pub max_level: u8,
     pub ty: MeshType,
 }
+impl MeshCacheDesc {
+    /// Bytes of GPU memory taken by this mesh's storage, indirect draw and bounding sphere
+    /// buffers and its share of the index buffer, if every node had `entries_per_node` entries.
+    pub(crate) fn gpu_bytes(&self, entries_per_node: usize) -> u64 {
+        let nodes = (super::TileCache::base_slot(self.max_level + 1)
+            - super::TileCache::base_slot(self.min_level)) as u64;
+        let entry_bytes = mem::size_of::<DrawIndexedIndirect>() as u64 + 16;
+        self.max_bytes_per_node * nodes
+            + entry_bytes * nodes * entries_per_node as u64
+            + 4 * self.index_buffer.len() as u64
+    }
+}
 
 pub(crate) struct MeshCache {
     pub(super) desc: MeshCacheDesc,
@@ -66,6 +85,7 @@ pub(crate) struct MeshCache {
 
     bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
     shadow_bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
+    depth_bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
 }
 impl MeshCache {
     pub(super) fn new(desc: MeshCacheDesc, base_slot: usize, num_slots: usize, index_buffer_range: Range<u64>) -> Self {
@@ -75,6 +95,7 @@ impl MeshCache {
             num_entries: num_slots,
             bindgroup_pipeline: None,
             shadow_bindgroup_pipeline: None,
+            depth_bindgroup_pipeline: None,
             index_buffer_range,
         }
     }
@@ -126,12 +147,13 @@ impl MeshCache {
                     }),
                     primitive: wgpu::PrimitiveState {
                         cull_mode: self.desc.cull_mode,
+                        front_face: self.desc.front_face,
                         ..Default::default()
                     },
                     depth_stencil: Some(wgpu::DepthStencilState {
-                        format: wgpu::TextureFormat::Depth32Float,
+                        format: self.desc.depth_format,
                         depth_write_enabled: true,
-                        depth_compare: wgpu::CompareFunction::Greater,
+                        depth_compare: self.desc.depth_compare,
                         bias: Default::default(),
                         stencil: Default::default(),
                     }),
@@ -145,71 +167,99 @@ impl MeshCache {
         if let Some(ref mut render_shadow) = self.desc.render_shadow {
             if render_shadow.refresh() {
                 self.shadow_bindgroup_pipeline = None;
+                self.depth_bindgroup_pipeline = None;
             }
+        }
+        if let Some(ref render_shadow) = self.desc.render_shadow {
             if self.shadow_bindgroup_pipeline.is_none() {
-                let (bind_group, bind_group_layout) = gpu_state.bind_group_for_shader(
+                self.shadow_bindgroup_pipeline = Some(Self::make_depth_only_pipeline(
                     device,
-                    &render_shadow,
-                    HashMap::new(),
-                    HashMap::new(),
-                    self.desc.ty.name(),
-                );
-                let render_pipeline_layout =
-                    device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                        bind_group_layouts: &[&bind_group_layout],
-                        push_constant_ranges: &[],
-                        label: Some(&format!("{}_shadow.pipeline_layout", self.desc.ty.name())),
-                    });
-                self.shadow_bindgroup_pipeline = Some((
-                    bind_group,
-                    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-                        layout: Some(&render_pipeline_layout),
-                        vertex: wgpu::VertexState {
-                            module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
-                                label: Some(&format!(
-                                    "shader.{}_shadow.vertex",
-                                    self.desc.ty.name()
-                                )),
-                                source: render_shadow.vertex(),
-                            }),
-                            entry_point: "main",
-                            buffers: &[],
-                        },
-                        fragment: Some(wgpu::FragmentState {
-                            module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
-                                label: Some(&format!(
-                                    "shader.{}_shadow.fragment",
-                                    self.desc.ty.name()
-                                )),
-                                source: render_shadow.fragment(),
-                            }),
-                            entry_point: "main",
-                            targets: &[],
-                        }),
-                        primitive: wgpu::PrimitiveState {
-                            cull_mode: self.desc.cull_mode,
-                            ..Default::default()
-                        },
-                        depth_stencil: Some(wgpu::DepthStencilState {
-                            format: wgpu::TextureFormat::Depth24Plus,
-                            depth_write_enabled: true,
-                            depth_compare: wgpu::CompareFunction::Less,
-                            bias: wgpu::DepthBiasState {
-                                constant: 0,
-                                slope_scale: 0.0,
-                                clamp: 0.0,
-                            },
-                            stencil: Default::default(),
-                        }),
-                        multisample: Default::default(),
-                        multiview: None,
-                        label: Some(&format!("pipeline.render.{}_shadow", self.desc.ty.name())),
-                    }),
+                    gpu_state,
+                    render_shadow,
+                    &self.desc,
+                    wgpu::TextureFormat::Depth24Plus,
+                    wgpu::CompareFunction::Less,
+                    self.desc.shadow_depth_bias,
+                    "shadow",
+                ));
+            }
+            if self.depth_bindgroup_pipeline.is_none() {
+                self.depth_bindgroup_pipeline = Some(Self::make_depth_only_pipeline(
+                    device,
+                    gpu_state,
+                    render_shadow,
+                    &self.desc,
+                    self.desc.depth_format,
+                    self.desc.depth_compare,
+                    Default::default(),
+                    "depth",
                 ));
             }
         }
     }
 
+    /// Build a pipeline that draws the mesh with `shader` into a depth buffer only.
+    fn make_depth_only_pipeline(
+        device: &wgpu::Device,
+        gpu_state: &GpuState,
+        shader: &rshader::ShaderSet,
+        desc: &MeshCacheDesc,
+        depth_format: wgpu::TextureFormat,
+        depth_compare: wgpu::CompareFunction,
+        bias: wgpu::DepthBiasState,
+        pass: &str,
+    ) -> (wgpu::BindGroup, wgpu::RenderPipeline) {
+        let name = format!("{}_{}", desc.ty.name(), pass);
+        let (bind_group, bind_group_layout) = gpu_state.bind_group_for_shader(
+            device,
+            shader,
+            HashMap::new(),
+            HashMap::new(),
+            desc.ty.name(),
+        );
+        let render_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[&bind_group_layout],
+                push_constant_ranges: &[],
+                label: Some(&format!("{}.pipeline_layout", name)),
+            });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            layout: Some(&render_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                    label: Some(&format!("shader.{}.vertex", name)),
+                    source: shader.vertex(),
+                }),
+                entry_point: "main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                    label: Some(&format!("shader.{}.fragment", name)),
+                    source: shader.fragment(),
+                }),
+                entry_point: "main",
+                targets: &[],
+            }),
+            primitive: wgpu::PrimitiveState {
+                cull_mode: desc.cull_mode,
+                front_face: desc.front_face,
+                ..Default::default()
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: depth_format,
+                depth_write_enabled: true,
+                depth_compare,
+                bias,
+                stencil: Default::default(),
+            }),
+            multisample: Default::default(),
+            multiview: None,
+            label: Some(&format!("pipeline.render.{}", name)),
+        });
+        (bind_group, pipeline)
+    }
+
     pub fn render<'a>(
         &'a self,
         device: &wgpu::Device,
@@ -240,11 +290,31 @@ impl MeshCache {
         device: &wgpu::Device,
         rpass: &mut wgpu::RenderPass<'a>,
         gpu_state: &'a GpuState,
+    ) {
+        self.render_depth_only(device, rpass, gpu_state, &self.shadow_bindgroup_pipeline);
+    }
+
+    /// Draw the mesh into the main view's depth buffer, without any color output.
+    pub fn render_depth<'a>(
+        &'a self,
+        device: &wgpu::Device,
+        rpass: &mut wgpu::RenderPass<'a>,
+        gpu_state: &'a GpuState,
+    ) {
+        self.render_depth_only(device, rpass, gpu_state, &self.depth_bindgroup_pipeline);
+    }
+
+    fn render_depth_only<'a>(
+        &'a self,
+        device: &wgpu::Device,
+        rpass: &mut wgpu::RenderPass<'a>,
+        gpu_state: &'a GpuState,
+        bindgroup_pipeline: &'a Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
     ) {
         if self.desc.render_shadow.is_some() {
-            rpass.set_pipeline(&self.shadow_bindgroup_pipeline.as_ref().unwrap().1);
+            rpass.set_pipeline(&bindgroup_pipeline.as_ref().unwrap().1);
             rpass.set_index_buffer(gpu_state.mesh_index.slice(self.index_buffer_range.clone()), wgpu::IndexFormat::Uint32);
-            rpass.set_bind_group(0, &self.shadow_bindgroup_pipeline.as_ref().unwrap().0, &[]);
+            rpass.set_bind_group(0, &bindgroup_pipeline.as_ref().unwrap().0, &[]);
             if device.features().contains(wgpu::Features::MULTI_DRAW_INDIRECT) {
                 rpass.multi_draw_indexed_indirect(
                     &gpu_state.mesh_indirect,
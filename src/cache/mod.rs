@@ -8,15 +8,17 @@ use crate::{
     cache::tile::NodeSlot, generate::ComputeShader, gpu_state::GpuState, mapfile::MapFile,
     terrain::quadtree::QuadTree,
 };
+use anyhow::Error;
 use futures::{future::BoxFuture, FutureExt};
 use maplit::hashmap;
 use serde::{Deserialize, Serialize};
 use std::hash::Hash;
 use std::num::NonZeroU64;
-use std::ops::{Index, IndexMut};
+use std::ops::{Index, IndexMut, RangeInclusive};
+use std::time::Instant;
 use std::{cmp::Eq, sync::Arc};
 use std::{collections::HashMap, num::NonZeroU32};
-pub(crate) use tile::{LayerParams, TextureFormat};
+pub(crate) use tile::{LayerParams, TextureFormat, TileLayout};
 use types::{Priority, VNode, MAX_QUADTREE_LEVEL, NODE_OFFSETS};
 use vec_map::VecMap;
 use wgpu::util::DeviceExt;
@@ -28,7 +30,7 @@ use self::{generators::GenerateTile, tile::CpuHeightmap};
 const SLOTS_PER_LEVEL: usize = 32;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) enum LayerType {
+pub enum LayerType {
     Heightmaps = 0,
     Displacements = 1,
     AlbedoRoughness = 2,
@@ -93,6 +95,22 @@ impl LayerType {
             _ => false,
         }
     }
+    /// Number of tiles this layer stores on disk at `level` on a single cube face. Every face has
+    /// the same number, and levels beyond `streamed_levels` have none.
+    pub fn tiles_per_face(&self, level: u8) -> usize {
+        if level < self.streamed_levels() {
+            1 << (2 * level as usize)
+        } else {
+            0
+        }
+    }
+    /// Number of tiles this layer stores on disk at `levels` on the cube faces enabled in `faces`,
+    /// ordered like `TerrainConfig::faces`. Doesn't depend on any tiles existing, so is suitable
+    /// for sizing storage and progress bars before downloading or generating anything.
+    pub fn tile_count(&self, levels: RangeInclusive<u8>, faces: [bool; 6]) -> usize {
+        let per_face: usize = levels.map(|level| self.tiles_per_face(level)).sum();
+        per_face * faces.iter().filter(|&&enabled| enabled).count()
+    }
     pub fn iter() -> impl Iterator<Item = Self> {
         (0..=10).map(Self::from_index)
     }
@@ -110,7 +128,7 @@ impl<T> IndexMut<LayerType> for VecMap<T> {
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) enum MeshType {
+pub enum MeshType {
     Terrain = 0,
     Grass = 1,
     TreeBillboards = 2,
@@ -151,13 +169,20 @@ impl<T> IndexMut<MeshType> for VecMap<T> {
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
-pub(crate) struct LayerMask(NonZeroU32);
+pub struct LayerMask(NonZeroU32);
 impl LayerMask {
     const VALID: u32 = 0x80000000;
 
     pub fn empty() -> Self {
         Self(NonZeroU32::new(Self::VALID).unwrap())
     }
+    /// Every layer and mesh type.
+    pub fn all() -> Self {
+        LayerType::iter()
+            .map(LayerMask::from)
+            .chain(MeshType::iter().map(LayerMask::from))
+            .fold(Self::empty(), |a, b| a | b)
+    }
     pub fn contains_layer(&self, t: LayerType) -> bool {
         assert!((t as usize) < 16);
         self.0.get() & (1 << (t as usize)) != 0
@@ -208,6 +233,14 @@ impl std::ops::Not for LayerMask {
     }
 }
 
+impl std::fmt::Display for LayerMask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let layers = LayerType::iter().filter(|&t| self.contains_layer(t)).map(|t| t.name());
+        let meshes = MeshType::iter().filter(|&t| self.contains_mesh(t)).map(|t| t.name());
+        write!(f, "{}", layers.chain(meshes).collect::<Vec<_>>().join(", "))
+    }
+}
+
 lazy_static! {
     pub(crate) static ref LAYERS_BY_NAME: HashMap<&'static str, LayerType> =
         LayerType::iter().map(|t| (t.name(), t)).collect();
@@ -259,12 +292,77 @@ impl std::ops::Not for GeneratorMask {
 }
 
 pub trait PriorityCacheEntry {
-    type Key: Hash + Eq;
+    type Key: Hash + Eq + std::fmt::Debug;
 
     fn priority(&self) -> Priority;
     fn key(&self) -> Self::Key;
 }
 
+/// Returns the tile cache layers that are bound by `shader`.
+fn shader_layers(shader: &rshader::ShaderSet) -> LayerMask {
+    let mut mask = LayerMask::empty();
+    for name in shader.desc_names().iter().flatten() {
+        let name = name.rsplit_once(char::is_numeric).map(|(n, _)| n).unwrap_or(name);
+        if let Some(layer) = LAYERS_BY_NAME.get(name) {
+            mask |= layer.bit_mask();
+        }
+    }
+    mask
+}
+
+/// Strategy used to decide which tiles to evict when the cache is full.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evict the tiles that are least important for the current camera position. Responds
+    /// quickly to camera movement, but tiles that fall out of view are discarded immediately and
+    /// must be streamed again if the camera returns.
+    Priority,
+    /// Evict the tiles that have gone the longest without being needed. Works well when orbiting
+    /// a fixed point or replaying a scripted tour, at the cost of holding onto stale tiles while
+    /// the camera travels.
+    LeastRecentlyUsed,
+    /// Evict the tiles whose highest priority since entering the cache is lowest. Keeps detail
+    /// around places the camera has visited up close, but can starve newly visible regions of
+    /// cache space.
+    KeepHighest,
+}
+impl Default for EvictionPolicy {
+    fn default() -> Self {
+        EvictionPolicy::Priority
+    }
+}
+
+/// Controls how tree cover data is turned into rendered trees.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct VegetationConfig {
+    /// Multiplier applied to the tree cover when deciding whether each candidate tree position is
+    /// populated. Values above one make forests denser, and values below one make them sparser.
+    pub tree_density: f32,
+    /// Tree cover percentage below which no trees are placed at all.
+    pub min_tree_cover: f32,
+    /// Distance in meters within which trees are drawn as 3D models instead of billboards. Zero
+    /// disables the models so that every tree is a billboard.
+    pub tree_model_distance: f32,
+    /// Width in meters of the band beyond `tree_model_distance` across which 3D models cross-fade
+    /// into billboards.
+    pub tree_model_fade: f32,
+    /// Seed for scattering grass and trees. Placement is a pure function of the seed and the
+    /// tiles, so clients using the same seed and the same map file scatter identical foliage on
+    /// every machine, while different seeds give different but equally plausible arrangements.
+    pub placement_seed: u32,
+}
+impl Default for VegetationConfig {
+    fn default() -> Self {
+        Self {
+            tree_density: 1.0,
+            min_tree_cover: 0.0,
+            tree_model_distance: 150.0,
+            tree_model_fade: 50.0,
+            placement_seed: 0,
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct PriorityCache<T: PriorityCacheEntry> {
     size: usize,
@@ -309,6 +407,11 @@ impl<T: PriorityCacheEntry> PriorityCache<T> {
                     }
                 }
 
+                log::trace!(
+                    "Evicting {:?} to make room for {:?}",
+                    self.slots[index].key(),
+                    e.key()
+                );
                 self.reverse.remove(&self.slots[index].key());
                 self.reverse.insert(e.key(), index);
                 self.slots[index] = e;
@@ -361,6 +464,31 @@ pub(crate) struct TileCache {
     free_download_buffers: Vec<wgpu::Buffer>,
     total_download_buffers: usize,
 
+    /// Number of streamed tiles that couldn't be fetched.
+    tile_fetch_failures: u64,
+    /// Number of streamed tiles that were fetched but failed to decode.
+    tile_decode_failures: u64,
+    /// Streamed tiles that couldn't be fetched, indexed by layer, along with when they may next be
+    /// requested and how many times in a row fetching them has failed.
+    failed_fetches: VecMap<HashMap<VNode, (Instant, u32)>>,
+
+    eviction_policy: EvictionPolicy,
+    /// Layers and meshes that are streamed, generated and allocated on the GPU.
+    enabled_layers: LayerMask,
+    /// Maximum number of bytes of streamed tiles to upload per frame, or `None` for no limit.
+    upload_budget: Option<usize>,
+    /// Upload budget set by `begin_teleport`, which applies on top of `upload_budget` until no
+    /// streamed tiles are left in flight.
+    teleport_budget: Option<usize>,
+    /// Mesh types that are drawn. Hidden meshes are still streamed, generated and culled.
+    visible_meshes: LayerMask,
+    /// Elevation in streamed heightmaps that corresponds to sea level.
+    sea_level: f32,
+    /// Lowest height relative to sea level that streamed heightmaps are clamped to.
+    height_floor: f32,
+    /// Number of times `update_levels` has been called.
+    frame: u64,
+
     index_buffer_contents: Vec<u32>,
     cull_shader: ComputeShader<mesh::CullMeshUniforms>,
 }
@@ -370,9 +498,25 @@ impl TileCache {
         device: &wgpu::Device,
         mapfile: Arc<MapFile>,
         mesh_layers: Vec<MeshCacheDesc>,
-    ) -> Self {
+        eviction_policy: EvictionPolicy,
+        enabled_layers: LayerMask,
+        vegetation: VegetationConfig,
+        max_concurrent_tile_decodes: usize,
+    ) -> Result<Self, Error> {
         let layers = mapfile.layers().clone();
 
+        if !enabled_layers.contains_layer(LayerType::Heightmaps) {
+            anyhow::bail!("The heightmaps layer cannot be disabled");
+        }
+        let mesh_layers: Vec<_> =
+            mesh_layers.into_iter().filter(|desc| enabled_layers.contains_mesh(desc.ty)).collect();
+        for desc in &mesh_layers {
+            let missing = shader_layers(&desc.render) & !enabled_layers;
+            if missing != LayerMask::empty() {
+                anyhow::bail!("The {} mesh samples disabled layers: {}", desc.ty.name(), missing);
+            }
+        }
+
         let mut index_buffer_contents = Vec::new();
 
         let mut base_slot = 0;
@@ -397,10 +541,33 @@ impl TileCache {
         let meshes = meshes.into_iter().collect();
 
         let soft_float64 = !device.features().contains(wgpu::Features::SHADER_FLOAT64);
-        let generators = generators::generators(device, &layers, &meshes, soft_float64);
+        let mut generators =
+            generators::generators(device, &layers, &meshes, soft_float64, &vegetation);
+        for generator in &generators {
+            let outputs = generator.outputs() & enabled_layers;
+            if outputs == LayerMask::empty() {
+                continue;
+            } else if outputs != generator.outputs() {
+                anyhow::bail!(
+                    "Layers {} are generated together and must all be enabled or all be disabled",
+                    generator.outputs()
+                );
+            }
+
+            let inputs =
+                generator.peer_inputs() | generator.parent_inputs() | generator.ancestor_inputs();
+            if inputs & !enabled_layers != LayerMask::empty() {
+                anyhow::bail!(
+                    "Generating {} requires disabled layers: {}",
+                    outputs,
+                    inputs & !enabled_layers
+                );
+            }
+        }
+        generators.retain(|g| g.outputs() & enabled_layers != LayerMask::empty());
 
         let mut level_masks = vec![LayerMask::empty(); 23];
-        for layer in layers.values() {
+        for layer in layers.values().filter(|l| enabled_layers.contains_layer(l.layer_type)) {
             for i in layer.min_level..=layer.max_level {
                 level_masks[i as usize] |= layer.layer_type.bit_mask();
             }
@@ -431,24 +598,38 @@ impl TileCache {
             )
         });
 
-        Self {
-            streamer: TileStreamerEndpoint::new(mapfile).unwrap(),
+        Ok(Self {
+            sea_level: mapfile.sea_level(),
+            height_floor: mapfile.height_floor(),
+            streamer: TileStreamerEndpoint::new(mapfile, max_concurrent_tile_decodes)?,
             level_masks,
             start_download: start_tx,
             completed_downloads: completed_rx,
             free_download_buffers: Vec::new(),
             total_download_buffers: 0,
+            tile_fetch_failures: 0,
+            tile_decode_failures: 0,
+            failed_fetches: VecMap::new(),
+            eviction_policy,
+            enabled_layers,
+            upload_budget: None,
+            teleport_budget: None,
+            visible_meshes: LayerMask::all(),
+            frame: 0,
             levels,
             layers,
             meshes,
             generators,
-            dynamic_generators: generators::dynamic_generators(),
+            dynamic_generators: generators::dynamic_generators()
+                .into_iter()
+                .filter(|g| shader_layers(&g.shader) & !enabled_layers == LayerMask::empty())
+                .collect(),
             index_buffer_contents,
             cull_shader: ComputeShader::new(
                 rshader::shader_source!("../shaders", "cull-meshes.comp", "declarations.glsl"),
                 "cull-meshes".to_owned(),
             ),
-        }
+        })
     }
 
     pub fn update(
@@ -513,6 +694,9 @@ impl TileCache {
 
         let (command_buffer, mut planned_heightmap_downloads) =
             TileCache::generate_tiles(self, mapfile, device, &queue, gpu_state);
+        if self.streamer.num_inflight() == 0 {
+            self.teleport_budget = None;
+        }
 
         self.write_nodes(queue, gpu_state, camera);
 
@@ -564,8 +748,9 @@ impl TileCache {
                 face: 0,
                 coords: [0; 2],
                 parent: -1,
+                coarser_neighbors: 0,
                 padding0: 0.0,
-                padding: [0; 43],
+                padding: [0; 42],
             };
             TileCache::base_slot(self.levels.len() as u8)
         ];
@@ -590,15 +775,22 @@ impl TileCache {
                     .and_then(|(parent, _)| self.get_slot(parent))
                     .map(|s| s as i32)
                     .unwrap_or(-1);
+                if let Some(terrain_nodes) = frame_nodes.get(MeshType::Terrain as usize) {
+                    for (i, neighbor) in slot.node.neighbors().iter().enumerate() {
+                        if Self::drawn_by_ancestor(terrain_nodes, *neighbor) {
+                            data[index].coarser_neighbors |= 1 << i;
+                        }
+                    }
+                }
 
                 for (mesh_index, m) in &self.meshes {
                     assert!(m.desc.entries_per_node <= 32);
-                    data[index].mesh_valid_mask[mesh_index] = if slot.valid.contains_mesh(m.desc.ty)
-                    {
-                        0xffffffff >> (32 - m.desc.entries_per_node)
-                    } else {
-                        0
-                    };
+                    data[index].mesh_valid_mask[mesh_index] =
+                        if slot.valid.contains_mesh(m.desc.ty) && !slot.mesh_culled(m.desc.ty) {
+                            0xffffffff >> (32 - m.desc.entries_per_node)
+                        } else {
+                            0
+                        };
                     if let Some(ref frame_nodes) = frame_nodes.get(mesh_index) {
                         data[index].mesh_valid_mask[mesh_index] &=
                             *frame_nodes.get(&slot.node).unwrap_or(&0) as u32;
@@ -631,18 +823,10 @@ impl TileCache {
                             };
 
                             let texture_resolution = self.layers[layer].texture_resolution as f32;
-                            let texture_border = self.layers[layer].texture_border_size as f32;
-                            let texture_ratio = if self.layers[layer].grid_registration {
-                                (texture_resolution - 2.0 * texture_border - 1.0)
-                                    / texture_resolution
-                            } else {
-                                (texture_resolution - 2.0 * texture_border) / texture_resolution
-                            };
-                            let texture_origin = if self.layers[layer].grid_registration {
-                                (texture_border + 0.5) / texture_resolution
-                            } else {
-                                texture_border / texture_resolution
-                            };
+                            let texture_ratio =
+                                self.layers[layer].inner_resolution() as f32 / texture_resolution;
+                            let texture_origin =
+                                self.layers[layer].texel_coordinate(0.0) / texture_resolution;
 
                             data[index].layer_origins[layer_slot] = [
                                 texture_origin + texture_ratio * base_offset.x,
@@ -668,6 +852,19 @@ impl TileCache {
         queue.write_buffer(&gpu_state.nodes, 0, bytemuck::cast_slice(&data));
     }
 
+    /// Whether the area covered by `node` is drawn by one of its ancestors in `frame_nodes`, which
+    /// maps each drawn node to the mask of children whose area it covers.
+    fn drawn_by_ancestor(frame_nodes: &HashMap<VNode, u8>, node: VNode) -> bool {
+        let mut node = node;
+        while let Some((parent, child_index)) = node.parent() {
+            if frame_nodes.get(&parent).map_or(false, |mask| mask & (1 << child_index) != 0) {
+                return true;
+            }
+            node = parent;
+        }
+        false
+    }
+
     fn generator_dependencies(&self, node: VNode, mask: LayerMask) -> GeneratorMask {
         let mut generators = GeneratorMask::empty();
 
@@ -723,6 +920,8 @@ impl TileCache {
         device: &wgpu::Device,
         encoder: &mut wgpu::CommandEncoder,
         gpu_state: &'a GpuState,
+        frustum_culling: bool,
+        max_distance: Option<f32>,
     ) {
         for (mesh_index, c) in &self.meshes {
             self.cull_shader.run(
@@ -736,6 +935,8 @@ impl TileCache {
                     num_nodes: (c.num_entries / c.desc.entries_per_node) as u32,
                     base_slot: TileCache::base_slot(c.desc.min_level) as u32,
                     mesh_index: mesh_index as u32,
+                    frustum_culling: frustum_culling as u32,
+                    max_distance: max_distance.unwrap_or(f32::INFINITY),
                 },
             );
         }
@@ -747,6 +948,36 @@ impl TileCache {
         }
     }
 
+    pub fn set_upload_budget(&mut self, budget: Option<usize>) {
+        self.upload_budget = budget;
+    }
+
+    pub fn begin_teleport(&mut self, budget: usize) {
+        self.teleport_budget = Some(budget);
+    }
+
+    /// Maximum number of bytes of streamed tiles to upload this frame, if limited.
+    fn frame_upload_budget(&self) -> Option<usize> {
+        match (self.upload_budget, self.teleport_budget) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    pub fn set_mesh_visible(&mut self, ty: MeshType, visible: bool) {
+        if visible {
+            self.visible_meshes |= ty.bit_mask();
+        } else {
+            self.visible_meshes &= !ty.bit_mask();
+        }
+    }
+
+    /// Returns the range of mesh entries used by `ty`, if it is enabled and visible.
+    pub fn visible_mesh_entries(&self, ty: MeshType) -> Option<std::ops::Range<usize>> {
+        let c = self.meshes.get(ty as usize)?;
+        self.visible_meshes.contains_mesh(ty).then(|| c.base_entry..c.base_entry + c.num_entries)
+    }
+
     pub fn render_meshes<'a>(
         &'a self,
         device: &wgpu::Device,
@@ -754,7 +985,9 @@ impl TileCache {
         gpu_state: &'a GpuState,
     ) {
         for (_, c) in &self.meshes {
-            c.render(device, rpass, gpu_state);
+            if self.visible_meshes.contains_mesh(c.desc.ty) {
+                c.render(device, rpass, gpu_state);
+            }
         }
     }
 
@@ -765,7 +998,24 @@ impl TileCache {
         gpu_state: &'a GpuState,
     ) {
         for (_, c) in &self.meshes {
-            c.render_shadow(device, rpass, gpu_state);
+            if self.visible_meshes.contains_mesh(c.desc.ty) {
+                c.render_shadow(device, rpass, gpu_state);
+            }
+        }
+    }
+
+    /// Draw the terrain mesh into the main view's depth buffer. Other meshes are skipped, since
+    /// their shadow pass shaders orient them towards the sun rather than the camera.
+    pub fn render_terrain_depth<'a>(
+        &'a self,
+        device: &wgpu::Device,
+        rpass: &mut wgpu::RenderPass<'a>,
+        gpu_state: &'a GpuState,
+    ) {
+        for (_, c) in &self.meshes {
+            if c.desc.ty == MeshType::Terrain && self.visible_meshes.contains_mesh(c.desc.ty) {
+                c.render_depth(device, rpass, gpu_state);
+            }
         }
     }
 }
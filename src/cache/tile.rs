@@ -2,23 +2,28 @@ use crate::{
     cache::{self, PriorityCacheEntry},
     terrain::quadtree::QuadTree,
 };
-use crate::{coordinates, stream::TileResult};
+use crate::{
+    coordinates,
+    stream::{TileFailure, TileResult},
+    ElevationStats, SurfaceSample,
+};
 use crate::{
     gpu_state::GpuState,
     mapfile::{MapFile, TileState},
 };
-use cache::LayerType;
-use cgmath::Vector3;
+use cache::{LayerType, MeshType};
+use cgmath::{InnerSpace, Vector3};
 use fnv::FnvHashMap;
 use futures::future::BoxFuture;
 use futures::stream::futures_unordered::FuturesUnordered;
 use futures::StreamExt;
 use serde::{Deserialize, Serialize};
-use std::{num::NonZeroU32, sync::Arc};
+use std::time::{Duration, Instant};
+use std::{collections::HashSet, num::NonZeroU32, sync::Arc};
 use types::{Priority, VNode, MAX_QUADTREE_LEVEL};
 use vec_map::VecMap;
 
-use super::{GeneratorMask, LayerMask, TileCache, SLOTS_PER_LEVEL};
+use super::{EvictionPolicy, GeneratorMask, LayerMask, TileCache, SLOTS_PER_LEVEL};
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum TextureFormat {
@@ -54,6 +59,23 @@ impl TextureFormat {
             TextureFormat::UASTC => 16,
         }
     }
+    /// Stable name of the format, for recording in the tile cache.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            TextureFormat::R8 => "R8",
+            TextureFormat::RG8 => "RG8",
+            TextureFormat::RGBA8 => "RGBA8",
+            TextureFormat::RGBA16F => "RGBA16F",
+            TextureFormat::R32 => "R32",
+            TextureFormat::R32F => "R32F",
+            TextureFormat::RG32F => "RG32F",
+            TextureFormat::RGBA32F => "RGBA32F",
+            TextureFormat::SRGBA => "SRGBA",
+            TextureFormat::BC4 => "BC4",
+            TextureFormat::BC5 => "BC5",
+            TextureFormat::UASTC => "UASTC",
+        }
+    }
     pub fn to_wgpu(&self, wgpu_features: wgpu::Features) -> wgpu::TextureFormat {
         match *self {
             TextureFormat::R8 => wgpu::TextureFormat::R8Unorm,
@@ -131,7 +153,8 @@ pub(crate) struct NodeSlot {
     pub(super) coords: [u32; 2],
 
     pub(super) parent: i32,
-    pub(super) padding: [u32; 43],
+    pub(super) coarser_neighbors: u32,
+    pub(super) padding: [u32; 42],
 }
 unsafe impl bytemuck::Pod for NodeSlot {}
 unsafe impl bytemuck::Zeroable for NodeSlot {}
@@ -153,11 +176,78 @@ pub(crate) struct LayerParams {
     /// Format used by this layer.
     pub texture_format: &'static [TextureFormat],
 
+    /// Whether samples lie on a grid that includes the edges of the node, rather than at the
+    /// centers of cells that tile it.
     pub grid_registration: bool,
 
     pub min_level: u8,
     pub max_level: u8,
 }
+impl LayerParams {
+    /// Placement of this layer's samples relative to the node.
+    pub fn layout(&self) -> TileLayout {
+        let (resolution, border) = (self.texture_resolution, self.texture_border_size);
+        if self.grid_registration {
+            TileLayout {
+                inner_resolution: resolution - 2 * border - 1,
+                origin: border as f64 + 0.5,
+            }
+        } else {
+            TileLayout { inner_resolution: resolution - 2 * border, origin: border as f64 }
+        }
+    }
+
+    /// Description of the parameters that determine how this layer's tiles are encoded on disk,
+    /// so that a tile cache written with different ones can be detected. The levels the layer
+    /// spans are left out since they don't change the contents of any tile.
+    pub fn fingerprint(&self) -> String {
+        let formats: Vec<_> = self.texture_format.iter().map(TextureFormat::name).collect();
+        format!(
+            "texture_resolution={}\ntexture_border_size={}\ntexture_format={}\n\
+             grid_registration={}\n",
+            self.texture_resolution,
+            self.texture_border_size,
+            formats.join(","),
+            self.grid_registration
+        )
+    }
+
+    /// Number of sample spacings that span the node itself, not counting the border.
+    pub fn inner_resolution(&self) -> u32 {
+        self.layout().inner_resolution
+    }
+
+    /// Convert a position across the node, from zero at one edge to one at the other, into a
+    /// coordinate in texels along the same axis, where texel `i` covers `i..i + 1`.
+    ///
+    /// Every layer places the edges of the node at the same positions, so layers with either
+    /// registration can be sampled together. With grid registration the edges fall on the
+    /// centers of the first and last samples inside the border, and otherwise on the boundaries
+    /// between border and inner texels.
+    pub fn texel_coordinate(&self, t: f32) -> f32 {
+        self.layout().texel_coordinate(t as f64) as f32
+    }
+}
+
+/// Where the samples of a tile lie relative to its node, along either axis.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct TileLayout {
+    /// Number of sample spacings that span the node.
+    pub inner_resolution: u32,
+    /// Texel coordinate of the near edge of the node.
+    pub origin: f64,
+}
+impl TileLayout {
+    /// Texel coordinate of position `t` across the node, where texel `i` covers `i..i + 1`.
+    pub fn texel_coordinate(&self, t: f64) -> f64 {
+        self.origin + t * self.inner_resolution as f64
+    }
+
+    /// Position across the node of texel coordinate `texel`. Inverse of `texel_coordinate`.
+    pub fn node_position(&self, texel: f64) -> f64 {
+        (texel - self.origin) / self.inner_resolution as f64
+    }
+}
 
 #[derive(Clone)]
 pub(super) enum CpuHeightmap {
@@ -169,6 +259,15 @@ pub(super) enum CpuHeightmap {
 pub(super) struct Entry {
     /// How imporant this entry is for the current frame.
     priority: Priority,
+    /// How important generating and drawing grass and trees on this entry is for the current
+    /// frame.
+    vegetation_priority: Priority,
+    /// How important this entry is to keep in the cache, as determined by the eviction policy.
+    rank: Priority,
+    /// Highest priority this entry has had since it was added to the cache.
+    peak_priority: Priority,
+    /// The last frame on which this entry's priority was above the cutoff.
+    last_needed: u64,
     /// The node this entry is for.
     pub(super) node: VNode,
     /// bitmask of whether the tile for each layer is valid.
@@ -177,25 +276,47 @@ pub(super) struct Entry {
     streaming: LayerMask,
     /// A CPU copy of the heightmap tile, useful for collision detection and such.
     heightmap: Option<CpuHeightmap>,
+    /// A CPU copy of the base albedo tile as RGBA8 texels, if this node has one.
+    albedo: Option<Arc<Vec<u8>>>,
     /// Map from layer to the generators that were used (perhaps indirectly) to produce it.
     pub(super) generators: VecMap<GeneratorMask>,
 }
 impl Entry {
-    fn new(node: VNode, priority: Priority) -> Self {
+    fn new(node: VNode, priority: Priority, frame: u64) -> Self {
         Self {
             node,
             priority,
+            vegetation_priority: priority,
+            rank: priority,
+            peak_priority: priority,
+            last_needed: frame,
             valid: LayerMask::empty(),
             streaming: LayerMask::empty(),
             heightmap: None,
+            albedo: None,
             generators: VecMap::new(),
         }
     }
 }
+impl Entry {
+    /// Priority for generating meshes of type `ty` for this entry.
+    fn mesh_priority(&self, ty: MeshType) -> Priority {
+        match ty {
+            MeshType::Terrain => self.priority,
+            MeshType::Grass | MeshType::TreeBillboards => self.vegetation_priority,
+        }
+    }
+
+    /// Whether meshes of type `ty` shouldn't be drawn for this entry even though the node itself
+    /// is needed, because the view direction lowered their priority below the cutoff.
+    pub(super) fn mesh_culled(&self, ty: MeshType) -> bool {
+        self.priority >= Priority::cutoff() && self.mesh_priority(ty) < Priority::cutoff()
+    }
+}
 impl PriorityCacheEntry for Entry {
     type Key = VNode;
     fn priority(&self) -> Priority {
-        self.priority
+        self.rank
     }
     fn key(&self) -> VNode {
         self.node
@@ -204,13 +325,32 @@ impl PriorityCacheEntry for Entry {
 
 impl TileCache {
     pub(super) fn update_levels(&mut self, quadtree: &QuadTree) {
+        self.frame += 1;
+
         let mut min_priorities = Vec::new();
         for cache in &mut self.levels {
             for entry in cache.slots_mut() {
                 entry.priority = quadtree.node_priority(entry.node);
+                entry.vegetation_priority = quadtree.vegetation_priority(entry.node);
+                entry.peak_priority = entry.peak_priority.max(entry.priority);
+                if entry.priority >= Priority::cutoff() {
+                    entry.last_needed = self.frame;
+                }
+                entry.rank = match self.eviction_policy {
+                    EvictionPolicy::Priority => entry.priority,
+                    EvictionPolicy::LeastRecentlyUsed if entry.priority >= Priority::cutoff() => {
+                        entry.priority
+                    }
+                    EvictionPolicy::LeastRecentlyUsed => {
+                        // Map age into (0, 1] so that stale entries always rank below needed ones.
+                        let age = (self.frame - entry.last_needed) as f32;
+                        Priority::from_f32(1.0 / (1.0 + age))
+                    }
+                    EvictionPolicy::KeepHighest => entry.peak_priority,
+                };
             }
             min_priorities
-                .push(cache.slots().iter().map(|s| s.priority).min().unwrap_or(Priority::none()));
+                .push(cache.slots().iter().map(|s| s.rank).min().unwrap_or(Priority::none()));
         }
 
         let mut missing = vec![Vec::new(); self.levels.len()];
@@ -223,7 +363,7 @@ impl TileCache {
             if !self.levels[level].contains(&node)
                 && (priority > min_priorities[level] || !self.levels[level].is_full())
             {
-                missing[level].push(Entry::new(node, priority));
+                missing[level].push(Entry::new(node, priority, self.frame));
             }
 
             node.level() < MAX_QUADTREE_LEVEL
@@ -243,16 +383,26 @@ impl TileCache {
     ) -> (wgpu::CommandBuffer, Vec<(VNode, wgpu::Buffer)>) {
         let mut planned_heightmap_downloads = Vec::new();
         let mut pending_generate = Vec::new();
+        let now = Instant::now();
 
-        for layer in self.layers.values().filter(|l| !l.layer_type.dynamic()) {
+        for layer in self
+            .layers
+            .values()
+            .filter(|l| !l.layer_type.dynamic() && self.enabled_layers.contains_layer(l.layer_type))
+        {
             for level in layer.min_level..=layer.max_level {
                 for ref mut entry in self.levels[level as usize].slots_mut() {
-                    if entry.priority() > Priority::cutoff() {
+                    if entry.priority > Priority::cutoff() {
                         let ty = layer.layer_type;
                         if !((entry.valid | entry.streaming).contains_layer(ty)) {
                             match mapfile.tile_state(ty, entry.node).unwrap() {
                                 TileState::MissingBase | TileState::Base => {
-                                    if self.streamer.num_inflight() < 128 {
+                                    let backing_off = self
+                                        .failed_fetches
+                                        .get(ty.index())
+                                        .and_then(|f| f.get(&entry.node))
+                                        .map_or(false, |&(retry_at, _)| retry_at > now);
+                                    if self.streamer.num_inflight() < 128 && !backing_off {
                                         entry.streaming |= ty.bit_mask();
                                         self.streamer.request_tile(entry.node, ty);
                                     }
@@ -269,7 +419,7 @@ impl TileCache {
         for mesh in self.meshes.values() {
             for level in mesh.desc.min_level..=mesh.desc.max_level {
                 for ref mut entry in self.levels[level as usize].slots_mut() {
-                    if entry.priority() > Priority::cutoff()
+                    if entry.mesh_priority(mesh.desc.ty) > Priority::cutoff()
                         && !entry.valid.contains_mesh(mesh.desc.ty)
                     {
                         pending_generate.push(entry.node);
@@ -477,10 +627,48 @@ impl TileCache {
         queue: &wgpu::Queue,
         textures: &VecMap<Vec<(wgpu::Texture, wgpu::TextureView)>>,
     ) {
-        while let Some(mut tile) = self.streamer.try_complete() {
+        // Tiles beyond the budget stay queued in the streamer until a later frame.
+        let mut uploaded_bytes = 0;
+        let budget = self.frame_upload_budget();
+        while budget.map_or(true, |budget| uploaded_bytes == 0 || uploaded_bytes < budget) {
+            let mut tile = match self.streamer.try_complete() {
+                Some(tile) => tile,
+                None => break,
+            };
+            if let TileResult::Failed(node, layer, failure) = tile {
+                // Leave the tile invalid so the parent is drawn in its place, and clear the
+                // streaming bit so it will be requested again. Corrupt tiles were removed from
+                // disk and can be downloaded again straight away, but failed fetches are retried
+                // with exponential backoff so an unreachable tile server isn't hammered.
+                if failure == TileFailure::Corrupt {
+                    self.tile_decode_failures += 1;
+                } else {
+                    self.tile_fetch_failures += 1;
+                    let (retry_at, attempts) = self
+                        .failed_fetches
+                        .entry(layer.index())
+                        .or_insert_with(Default::default)
+                        .entry(node)
+                        .or_insert((Instant::now(), 0));
+                    *attempts += 1;
+                    *retry_at = Instant::now() + Duration::from_secs(2u64.pow((*attempts).min(8)));
+                }
+                if let Some(entry) = self.levels[node.level() as usize].entry_mut(&node) {
+                    entry.streaming &= !layer.bit_mask();
+                }
+                continue;
+            }
+
+            if let Some(failed) = self.failed_fetches.get_mut(tile.layer().index()) {
+                failed.remove(&tile.node());
+            }
+
             if let Some(entry) = self.levels[tile.node().level() as usize].entry_mut(&tile.node()) {
                 entry.valid |= tile.layer().bit_mask();
                 entry.streaming &= !tile.layer().bit_mask();
+                if let TileResult::Generic(_, LayerType::BaseAlbedo, ref d) = tile {
+                    entry.albedo = (!d.is_empty()).then(|| Arc::new(d.clone()));
+                }
 
                 let index = self.get_slot(tile.node()).unwrap();
                 let layer = tile.layer();
@@ -494,19 +682,24 @@ impl TileCache {
                 let mut owned_data;
                 match tile {
                     TileResult::Heightmaps(node, ref heights) => {
+                        let (sea_level, height_floor) = (self.sea_level, self.height_floor);
                         if let Some(entry) = self.levels[node.level() as usize].entry_mut(&node) {
-                            let min = *heights.iter().min().unwrap() as f32;
-                            let max = *heights.iter().max().unwrap() as f32;
+                            let min = *heights.iter().min().unwrap() as f32 - sea_level;
+                            let max = *heights.iter().max().unwrap() as f32 - sea_level;
                             entry.heightmap =
                                 Some(CpuHeightmap::I16 { min, max, heights: Arc::clone(&heights) });
                         }
                         let heights: Vec<_> = heights
                             .iter()
                             .map(|&h| {
-                                if h <= 0 {
-                                    0x800000 | (((h + 1024).max(0) as u32) << 9)
+                                // Heights are stored relative to sea level, with the high bit
+                                // marking samples that are underwater.
+                                let h = (h as f32 - sea_level).max(height_floor);
+                                let encoded = (((h + 1024.0) * 512.0) as u32).min(0x7fffff);
+                                if h <= 0.0 {
+                                    0x800000 | encoded
                                 } else {
-                                    (((h as u32) + 1024) << 9).min(0x7fffff)
+                                    encoded
                                 }
                             })
                             .collect();
@@ -519,6 +712,7 @@ impl TileCache {
                         data = &mut owned_data;
                     }
                     TileResult::Generic(_, _, ref mut d) => data = &mut *d,
+                    TileResult::Failed(..) => unreachable!(),
                 }
 
                 if cfg!(feature = "small-trace") {
@@ -534,6 +728,7 @@ impl TileCache {
                     }
                 }
 
+                uploaded_bytes += data.len();
                 assert_eq!(textures[layer].len(), 1);
                 queue.write_texture(
                     wgpu::ImageCopyTexture {
@@ -624,6 +819,7 @@ impl TileCache {
     ) -> VecMap<Vec<(wgpu::Texture, wgpu::TextureView)>> {
         self.layers
             .iter()
+            .filter(|(ty, _)| self.enabled_layers.contains_layer(LayerType::from_index(*ty)))
             .map(|(ty, layer)| {
                 assert!(layer.min_level <= layer.max_level);
                 let textures = layer
@@ -673,6 +869,27 @@ impl TileCache {
             .collect()
     }
 
+    /// Bytes of GPU memory taken by the textures that `make_cache_textures` allocates for the
+    /// layers in `enabled_layers`.
+    pub(crate) fn cache_texture_bytes(
+        layers: &VecMap<LayerParams>,
+        enabled_layers: LayerMask,
+    ) -> u64 {
+        layers
+            .iter()
+            .filter(|(ty, _)| enabled_layers.contains_layer(LayerType::from_index(*ty)))
+            .flat_map(|(_, layer)| {
+                let slots = (Self::base_slot(layer.max_level + 1)
+                    - Self::base_slot(layer.min_level)) as u64;
+                layer.texture_format.iter().map(move |format| {
+                    let blocks = ((layer.texture_resolution + format.block_size() - 1)
+                        / format.block_size()) as u64;
+                    blocks * blocks * format.bytes_per_block() as u64 * slots
+                })
+            })
+            .sum()
+    }
+
     pub fn compute_visible(&self, layer_mask: LayerMask) -> Vec<(VNode, u8)> {
         // Any node with all needed layers in cache is visible...
         let mut node_visibilities: FnvHashMap<VNode, bool> = FnvHashMap::default();
@@ -729,6 +946,39 @@ impl TileCache {
             .unwrap_or(false)
     }
 
+    /// Returns whether every enabled layer is resident for all nodes that `quadtree` currently
+    /// wants, not just the roots.
+    pub fn fully_resident(&self, quadtree: &QuadTree) -> bool {
+        let mut resident = true;
+        VNode::breadth_first(|node| {
+            if !resident || quadtree.node_priority(node) < Priority::cutoff() {
+                return false;
+            }
+            let level = node.level();
+            let mask = self
+                .layers
+                .values()
+                .filter(|l| self.enabled_layers.contains_layer(l.layer_type))
+                .filter(|l| (l.min_level..=l.max_level).contains(&level))
+                .fold(LayerMask::empty(), |mask, l| mask | l.layer_type.bit_mask());
+            resident = self.contains_all(node, mask);
+            resident && level < MAX_QUADTREE_LEVEL
+        });
+        resident
+    }
+
+    pub fn enabled_layers(&self) -> LayerMask {
+        self.enabled_layers
+    }
+
+    pub fn tile_fetch_failures(&self) -> u64 {
+        self.tile_fetch_failures
+    }
+
+    pub fn tile_decode_failures(&self) -> u64 {
+        self.tile_decode_failures
+    }
+
     pub fn base_slot(level: u8) -> usize {
         if level == 0 {
             0
@@ -760,11 +1010,42 @@ impl TileCache {
         let cspace = ecef / ecef.x.abs().max(ecef.y.abs()).max(ecef.z.abs());
 
         let (node, x, y) = VNode::from_cspace(cspace, level);
+        let heightmap = self.levels[node.level() as usize].entry(&node)?.heightmap.as_ref()?;
+        Some(self.sample_heightmap(heightmap, x, y))
+    }
+
+    /// Whether the ground at the given point is below sea level, or `None` if no heightmap at
+    /// `level` covering it is resident.
+    pub fn is_water(&self, latitude: f64, longitude: f64, level: u8) -> Option<bool> {
+        let ecef = coordinates::polar_to_ecef(Vector3::new(latitude, longitude, 0.0));
+        let cspace = ecef / ecef.x.abs().max(ecef.y.abs()).max(ecef.z.abs());
+
+        let (node, x, y) = VNode::from_cspace(cspace, level);
+        let heightmap = self.levels[node.level() as usize].entry(&node)?.heightmap.as_ref()?;
+        Some(self.sample_ground_height(heightmap, x, y) < 0.0)
+    }
 
-        let border = self.layers[LayerType::Heightmaps].texture_border_size as usize;
-        let resolution = self.layers[LayerType::Heightmaps].texture_resolution as usize;
-        let x = (x * (resolution - 2 * border - 1) as f32) + border as f32;
-        let y = (y * (resolution - 2 * border - 1) as f32) + border as f32;
+    /// Height above sea level of texel `i` of a heightmap tile, or below sea level for ocean floor.
+    fn heightmap_texel(&self, heightmap: &CpuHeightmap, i: usize) -> f32 {
+        match heightmap {
+            CpuHeightmap::I16 { heights, .. } => heights[i] as f32 - self.sea_level,
+            CpuHeightmap::F32 { heights, .. } => heights[i],
+        }
+    }
+
+    /// Bilinearly interpolate the height of the ground or water surface at position `(x, y)`
+    /// within a heightmap tile, where both range from zero to one across the node.
+    fn sample_heightmap(&self, heightmap: &CpuHeightmap, x: f32, y: f32) -> f32 {
+        self.sample_ground_height(heightmap, x, y).max(0.0)
+    }
+
+    /// Like `sample_heightmap`, but returns the height of the ground even where it is below sea
+    /// level.
+    fn sample_ground_height(&self, heightmap: &CpuHeightmap, x: f32, y: f32) -> f32 {
+        let layer = &self.layers[LayerType::Heightmaps];
+        let resolution = layer.texture_resolution as usize;
+        let x = layer.texel_coordinate(x) - 0.5;
+        let y = layer.texel_coordinate(y) - 0.5;
 
         let w00 = (1.0 - x.fract()) * (1.0 - y.fract());
         let w10 = x.fract() * (1.0 - y.fract());
@@ -776,19 +1057,309 @@ impl TileCache {
         let i01 = x.floor() as usize + y.ceil() as usize * resolution;
         let i11 = x.ceil() as usize + y.ceil() as usize * resolution;
 
-        self.levels[node.level() as usize]
-            .entry(&node)
-            .and_then(|entry| Some(entry.heightmap.as_ref()?))
-            .map(|h| match h {
-                CpuHeightmap::I16 { heights: h, .. } => (h[i00] as f32 * w00
-                    + h[i10] as f32 * w10
-                    + h[i01] as f32 * w01
-                    + h[i11] as f32 * w11)
-                    .max(0.0),
-                CpuHeightmap::F32 { heights: h, .. } => {
-                    (h[i00] * w00 + h[i10] * w10 + h[i01] * w01 + h[i11] * w11).max(0.0)
+        (self.heightmap_texel(heightmap, i00) * w00
+            + self.heightmap_texel(heightmap, i10) * w10
+            + self.heightmap_texel(heightmap, i01) * w01
+            + self.heightmap_texel(heightmap, i11) * w11)
+    }
+
+    /// Sample every CPU-resident layer at a point from the finest node at or below `max_level`
+    /// that has a heightmap, so that all values come from the same level of detail.
+    pub fn sample_surface(
+        &self,
+        latitude: f64,
+        longitude: f64,
+        max_level: u8,
+    ) -> Option<SurfaceSample> {
+        let ecef = coordinates::polar_to_ecef(Vector3::new(latitude, longitude, 0.0));
+        let cspace = ecef / ecef.x.abs().max(ecef.y.abs()).max(ecef.z.abs());
+
+        for level in (0..=max_level).rev() {
+            let (node, x, y) = VNode::from_cspace(cspace, level);
+            let entry = match self.levels[level as usize].entry(&node) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let heightmap = match entry.heightmap.as_ref() {
+                Some(heightmap) => heightmap,
+                None => continue,
+            };
+
+            // Take the normal of the heightmap cell containing the point.
+            let layer = &self.layers[LayerType::Heightmaps];
+            let border = layer.texture_border_size as usize;
+            let resolution = layer.texture_resolution as usize;
+            let tx = ((layer.texel_coordinate(x) - 0.5) as usize).min(resolution - border - 2);
+            let ty = ((layer.texel_coordinate(y) - 0.5) as usize).min(resolution - border - 2);
+            let position = |tx: usize, ty: usize| {
+                let height = self.heightmap_texel(heightmap, tx + ty * resolution).max(0.0);
+                node.grid_position_cspace(tx as i32, ty as i32, border as u32, resolution as u32)
+                    .normalize()
+                    * (coordinates::PLANET_RADIUS + height as f64)
+            };
+            let (p00, p10, p01, p11) = (
+                position(tx, ty),
+                position(tx + 1, ty),
+                position(tx, ty + 1),
+                position(tx + 1, ty + 1),
+            );
+            let mut normal = (p10 - p00 + p11 - p01).cross(p01 - p00 + p11 - p10).normalize();
+            if normal.dot(p00) < 0.0 {
+                normal = -normal;
+            }
+
+            return Some(SurfaceSample {
+                level,
+                height: self.sample_heightmap(heightmap, x, y),
+                normal: normal.cast::<f32>().unwrap().into(),
+                albedo: entry.albedo.as_ref().map(|albedo| self.albedo_texel(albedo, x, y)),
+            });
+        }
+        None
+    }
+
+    pub fn region_elevation_stats(
+        &self,
+        south_west: (f64, f64),
+        north_east: (f64, f64),
+        level: u8,
+        max_samples: usize,
+    ) -> Option<ElevationStats> {
+        // Space samples about as far apart as heightmap texels at this level, where each face of
+        // the cube spans a quarter turn.
+        let samples_per_face =
+            (self.layers[LayerType::Heightmaps].inner_resolution() << level) as f64;
+        let step = std::f64::consts::FRAC_PI_2 / samples_per_face;
+        let (latitudes, longitudes) =
+            Self::region_sample_grid(south_west, north_east, step, max_samples)?;
+
+        let mut min = f32::INFINITY;
+        let mut max = f32::NEG_INFINITY;
+        let mut sum = 0.0f64;
+        for &latitude in &latitudes {
+            for &longitude in &longitudes {
+                let height = self.get_height(latitude, longitude, level)?;
+                min = min.min(height);
+                max = max.max(height);
+                sum += height as f64;
+            }
+        }
+
+        let samples = latitudes.len() * longitudes.len();
+        Some(ElevationStats { min, max, mean: (sum / samples as f64) as f32, samples })
+    }
+
+    /// Latitudes and longitudes of a grid over the region between `south_west` and `north_east`,
+    /// with points about `step` radians apart along the ground that is coarsened to at most
+    /// `max_samples` points. A region crosses the antimeridian if its east edge is west of its
+    /// west edge or past PI, in which case it is split into a span ending at PI and one starting
+    /// at -PI.
+    fn region_sample_grid(
+        south_west: (f64, f64),
+        north_east: (f64, f64),
+        step: f64,
+        max_samples: usize,
+    ) -> Option<(Vec<f64>, Vec<f64>)> {
+        use std::f64::consts::PI;
+        if south_west.0 > north_east.0 || max_samples == 0 {
+            return None;
+        }
+        let west = south_west.1;
+        let east = if north_east.1 > PI { north_east.1 - 2.0 * PI } else { north_east.1 };
+        let width = if west <= east { east - west } else { east + 2.0 * PI - west };
+
+        let mid_latitude = 0.5 * (south_west.0 + north_east.0);
+        let mut latitude_points = ((north_east.0 - south_west.0) / step).ceil() as usize + 1;
+        let mut longitude_points =
+            (width * mid_latitude.cos().max(0.01) / step).ceil() as usize + 1;
+        if latitude_points * longitude_points > max_samples {
+            let scale = ((latitude_points * longitude_points) as f64 / max_samples as f64).sqrt();
+            latitude_points = ((latitude_points as f64 / scale) as usize).clamp(1, max_samples);
+            longitude_points = longitude_points.min(max_samples / latitude_points).max(1);
+        }
+
+        let along = |start: f64, extent: f64, points: usize| -> Vec<f64> {
+            (0..points)
+                .map(|i| match points {
+                    1 => start + 0.5 * extent,
+                    _ => start + extent * i as f64 / (points - 1) as f64,
+                })
+                .collect()
+        };
+        let latitudes = along(south_west.0, north_east.0 - south_west.0, latitude_points);
+        let longitudes = along(west, width, longitude_points)
+            .into_iter()
+            .map(|longitude| if longitude > PI { longitude - 2.0 * PI } else { longitude })
+            .collect();
+        Some((latitudes, longitudes))
+    }
+
+    /// Mark `layers` as not resident for every cached node overlapping the region between
+    /// `south_west` and `north_east`, along with any layers generated from them, so they are
+    /// streamed or generated again. Returns the number of nodes affected.
+    pub fn invalidate_region(
+        &mut self,
+        south_west: (f64, f64),
+        north_east: (f64, f64),
+        layers: LayerMask,
+    ) -> usize {
+        self.invalidate_nodes(layers, |node| {
+            Self::node_overlaps_region(node, south_west, north_east)
+        })
+    }
+
+    /// Mark `layer` as not resident for every cached node in `nodes` and every cached descendant
+    /// of them, along with any layers generated from it. Descendants are included because their
+    /// generated layers may have been derived from their ancestors. Returns the number of nodes
+    /// affected.
+    pub fn invalidate_tiles(&mut self, layer: LayerType, nodes: &HashSet<VNode>) -> usize {
+        self.streamer.invalidate_tiles(layer, nodes.clone());
+        self.invalidate_nodes(layer.bit_mask(), |mut node| loop {
+            if nodes.contains(&node) {
+                return true;
+            }
+            match node.parent() {
+                Some((parent, _)) => node = parent,
+                None => return false,
+            }
+        })
+    }
+
+    fn invalidate_nodes(&mut self, layers: LayerMask, affected: impl Fn(VNode) -> bool) -> usize {
+        // Anything generated from an invalidated layer would be stale too.
+        let mut mask = layers;
+        loop {
+            let dependents = self
+                .generators
+                .iter()
+                .filter(|g| {
+                    (g.peer_inputs() | g.parent_inputs() | g.ancestor_inputs()) & mask
+                        != LayerMask::empty()
+                })
+                .fold(mask, |m, g| m | g.outputs());
+            if dependents == mask {
+                break;
+            }
+            mask = dependents;
+        }
+
+        let mut invalidated = 0;
+        for cache in &mut self.levels {
+            for entry in cache.slots_mut() {
+                if entry.valid & mask == LayerMask::empty() || !affected(entry.node) {
+                    continue;
                 }
-            })
+                entry.valid &= !mask;
+                if mask.contains_layer(LayerType::Heightmaps) {
+                    entry.heightmap = None;
+                }
+                if mask.contains_layer(LayerType::BaseAlbedo) {
+                    entry.albedo = None;
+                }
+                for layer in LayerType::iter().filter(|&l| mask.contains_layer(l)) {
+                    entry.generators.remove(layer.index());
+                }
+                invalidated += 1;
+            }
+        }
+        invalidated
+    }
+
+    /// Approximate test for whether `node` overlaps a latitude and longitude region, done by
+    /// checking points spread over each of them against the other. Regions much narrower than
+    /// the node that cross it without any sample point landing inside may be missed.
+    fn node_overlaps_region(node: VNode, south_west: (f64, f64), north_east: (f64, f64)) -> bool {
+        const SAMPLES: i32 = 9;
+        let in_region = |(latitude, longitude): (f64, f64)| {
+            (south_west.0..=north_east.0).contains(&latitude)
+                && (south_west.1..=north_east.1).contains(&longitude)
+        };
+        for y in 0..SAMPLES {
+            for x in 0..SAMPLES {
+                let polar = coordinates::cspace_to_polar(node.grid_position_cspace(
+                    x,
+                    y,
+                    0,
+                    SAMPLES as u32,
+                ));
+                if in_region((polar.x, polar.y)) {
+                    return true;
+                }
+            }
+        }
+        for y in 0..SAMPLES {
+            for x in 0..SAMPLES {
+                let latitude =
+                    south_west.0 + (north_east.0 - south_west.0) * y as f64 / (SAMPLES - 1) as f64;
+                let longitude =
+                    south_west.1 + (north_east.1 - south_west.1) * x as f64 / (SAMPLES - 1) as f64;
+                let ecef = coordinates::polar_to_ecef(Vector3::new(latitude, longitude, 0.0));
+                let cspace = ecef / ecef.x.abs().max(ecef.y.abs()).max(ecef.z.abs());
+                if VNode::from_cspace(cspace, node.level()).0 == node {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    pub fn get_albedo(&self, latitude: f64, longitude: f64, level: u8) -> Option<[u8; 4]> {
+        let ecef = coordinates::polar_to_ecef(Vector3::new(latitude, longitude, 0.0));
+        let cspace = ecef / ecef.x.abs().max(ecef.y.abs()).max(ecef.z.abs());
+
+        let (node, x, y) = VNode::from_cspace(cspace, level);
+        let albedo = self.levels[node.level() as usize].entry(&node)?.albedo.as_ref()?;
+        Some(self.albedo_texel(albedo, x, y))
+    }
+
+    /// Look up the texel of a base albedo tile containing position `(x, y)`, where both range
+    /// from zero to one across the node.
+    fn albedo_texel(&self, albedo: &[u8], x: f32, y: f32) -> [u8; 4] {
+        // Pick the texel containing the point, keeping points on the far edges of the node
+        // inside it.
+        let layer = &self.layers[LayerType::BaseAlbedo];
+        let border = layer.texture_border_size as usize;
+        let resolution = layer.texture_resolution as usize;
+        let max = resolution - border - 1;
+        let x = (layer.texel_coordinate(x) as usize).min(max);
+        let y = (layer.texel_coordinate(y) as usize).min(max);
+
+        let i = (x + y * resolution) * 4;
+        [albedo[i], albedo[i + 1], albedo[i + 2], albedo[i + 3]]
+    }
+
+    /// Build a CPU-side copy of the terrain mesh for `node` using the same tessellation as
+    /// rendering. Vertex positions are relative to `node.center_wspace()`.
+    pub fn collision_mesh(&self, node: VNode) -> Option<(Vec<[f32; 3]>, Vec<u32>)> {
+        const MESH_RESOLUTION: usize = 64;
+
+        let heightmap = self.levels[node.level() as usize].entry(&node)?.heightmap.as_ref()?;
+
+        let layer = &self.layers[LayerType::Heightmaps];
+        let border = layer.texture_border_size as usize;
+        let resolution = layer.texture_resolution as usize;
+        let stride = layer.inner_resolution() as usize / MESH_RESOLUTION;
+
+        let center = node.center_wspace();
+        let mut vertices = Vec::with_capacity((MESH_RESOLUTION + 1) * (MESH_RESOLUTION + 1));
+        for y in 0..=MESH_RESOLUTION {
+            for x in 0..=MESH_RESOLUTION {
+                let i = (x * stride + border) + (y * stride + border) * resolution;
+                let height = match heightmap {
+                    CpuHeightmap::I16 { heights, .. } => {
+                        (heights[i] as f32 - self.sea_level) as f64
+                    }
+                    CpuHeightmap::F32 { heights, .. } => heights[i] as f64,
+                };
+                let position = node
+                    .grid_position_cspace(x as i32, y as i32, 0, MESH_RESOLUTION as u32 + 1)
+                    .normalize()
+                    * (coordinates::PLANET_RADIUS + height.max(0.0));
+                vertices.push((position - center).cast::<f32>().unwrap().into());
+            }
+        }
+
+        Some((vertices, QuadTree::create_index_buffer(MESH_RESOLUTION as u32, 1)))
     }
 
     /// Returns a conservative estimate of the minimum and maximum heights in the given node.
@@ -807,3 +1378,85 @@ impl TileCache {
         (0.0, 9000.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(
+        texture_resolution: u32,
+        texture_border_size: u32,
+        grid_registration: bool,
+    ) -> LayerParams {
+        LayerParams {
+            layer_type: LayerType::Heightmaps,
+            texture_resolution,
+            texture_border_size,
+            texture_format: &[TextureFormat::R32],
+            grid_registration,
+            min_level: 0,
+            max_level: 0,
+        }
+    }
+
+    #[test]
+    fn registrations_agree_on_tile_edges() {
+        let heightmaps = layer(521, 4, true);
+        let albedo = layer(516, 2, false);
+
+        // The first and last samples inside the border of a grid registered layer lie on the
+        // edges of the node, while a cell registered layer's edges fall between texels.
+        assert_eq!(heightmaps.texel_coordinate(0.0), 4.5);
+        assert_eq!(heightmaps.texel_coordinate(1.0), 516.5);
+        assert_eq!(albedo.texel_coordinate(0.0), 2.0);
+        assert_eq!(albedo.texel_coordinate(1.0), 514.0);
+
+        // Both map texels back to where tile generation placed them. Samples on the near edge of
+        // the node are skipped because rounding could place them in the neighboring node.
+        let node = VNode::from_cspace(Vector3::new(0.3, 1.0, -0.6), 5).0;
+        for i in 5..516 {
+            let cspace = node.grid_position_cspace(i, i, 4, 521);
+            let (n, x, y) = VNode::from_cspace(cspace, node.level());
+            assert_eq!(n, node);
+            assert!((heightmaps.texel_coordinate(x) - (i as f32 + 0.5)).abs() < 1e-2);
+            assert!((heightmaps.texel_coordinate(y) - (i as f32 + 0.5)).abs() < 1e-2);
+        }
+        for i in 2..514 {
+            let cspace = node.cell_position_cspace(i, i, 2, 516);
+            let (n, x, y) = VNode::from_cspace(cspace, node.level());
+            assert_eq!(n, node);
+            assert!((albedo.texel_coordinate(x) - (i as f32 + 0.5)).abs() < 1e-2);
+            assert!((albedo.texel_coordinate(y) - (i as f32 + 0.5)).abs() < 1e-2);
+        }
+    }
+
+    #[test]
+    fn region_sample_grid_wraps_and_caps() {
+        use std::f64::consts::PI;
+        let step = 0.01;
+
+        // Regions crossing the antimeridian are sampled on both sides of it, whether the east
+        // edge is given past PI or wrapped around to be less than the west edge.
+        for &east in &[-3.0, -3.0 + 2.0 * PI] {
+            let (latitudes, longitudes) =
+                TileCache::region_sample_grid((0.0, 3.0), (0.1, east), step, usize::MAX).unwrap();
+            assert_eq!(latitudes.len(), 11);
+            assert_eq!(longitudes.len(), 30);
+            assert_eq!(longitudes[0], 3.0);
+            assert!((longitudes[29] + 3.0).abs() < 1e-9);
+            assert!(longitudes.iter().all(|l| l.abs() <= PI && (l.abs() >= 3.0 - 1e-9)));
+        }
+
+        let (latitudes, longitudes) =
+            TileCache::region_sample_grid((-0.5, -0.5), (0.5, 0.5), step, 1000).unwrap();
+        assert!(latitudes.len() * longitudes.len() <= 1000);
+        assert_eq!((latitudes[0], *latitudes.last().unwrap()), (-0.5, 0.5));
+
+        let (latitudes, longitudes) =
+            TileCache::region_sample_grid((0.0, -PI), (0.0, PI), step, 10).unwrap();
+        assert_eq!((latitudes.len(), longitudes.len()), (1, 10));
+
+        assert!(TileCache::region_sample_grid((0.0, 0.0), (0.1, 0.1), step, 0).is_none());
+        assert!(TileCache::region_sample_grid((0.1, 0.0), (0.0, 0.1), step, 100).is_none());
+    }
+}
@@ -24,6 +24,8 @@ const WSG84_SEMI_MINOR_AXIS_METERS: f64 =
     WGS84_SEMI_MAJOR_AXIS_METERS * (1.0 - 1.0 / WGS84_INV_FLATTENING);
 
 pub const PLANET_RADIUS: f64 = 6371000.0;
+/// Radius of the top of the atmosphere, matching `atmosphereRadius` in atmosphere.glsl.
+pub const ATMOSPHERE_RADIUS: f64 = PLANET_RADIUS + 100000.0;
 
 #[inline]
 #[allow(unused)]
@@ -61,27 +63,44 @@ pub fn warped_to_ecef(warped: Vector3<f64>) -> Vector3<f64> {
     )
 }
 
-#[allow(unused)]
-pub fn sun_direction() -> Vector3<f64> {
-    use astro::{coords, sun};
+/// Converts a time given as seconds since the Unix epoch into a Julian day.
+pub fn julian_day(unix_seconds: f64) -> f64 {
+    2440587.5 + unix_seconds / 86400.0
+}
+
+/// Mean sidereal time at the Greenwich meridian, in radians.
+pub fn greenwich_sidereal_time(julian_day: f64) -> f64 {
+    astro::time::mn_sidr(julian_day)
+}
+
+/// Position of the sun in ecef coordinates at the given Julian day.
+pub fn sun_position(julian_day: f64) -> Vector3<f64> {
+    let (ecl, distance_au) = astro::sun::geocent_ecl_pos(julian_day);
+    ecliptic_to_ecef(julian_day, ecl, distance_au * 149597870700.0)
+}
 
-    let (ecl, distance_au) = sun::geocent_ecl_pos(180.0);
-    let distance = distance_au * 149597870700.0;
+/// Position of the moon in ecef coordinates at the given Julian day.
+pub fn moon_position(julian_day: f64) -> Vector3<f64> {
+    let (ecl, distance_km) = astro::lunar::geocent_ecl_pos(julian_day);
+    ecliptic_to_ecef(julian_day, ecl, distance_km * 1000.0)
+}
+
+/// Convert a geocentric ecliptic position `distance` meters away into ecef coordinates.
+fn ecliptic_to_ecef(julian_day: f64, ecl: astro::coords::EclPoint, distance: f64) -> Vector3<f64> {
+    use astro::{coords, ecliptic};
 
-    let e = 0.40905;
+    let e = ecliptic::mn_oblq_IAU(julian_day);
     let declination = coords::dec_frm_ecl(ecl.long, ecl.lat, e);
     let right_ascension = coords::asc_frm_ecl(ecl.long, ecl.lat, e);
 
-    let eq_rect = Vector3::new(
-        distance * declination.cos() * right_ascension.cos(),
-        distance * declination.cos() * right_ascension.sin(),
+    // Equatorial coordinates are fixed relative to the stars, so rotate by the sidereal time to
+    // get the longitude the body is currently above.
+    let longitude = right_ascension - greenwich_sidereal_time(julian_day);
+    Vector3::new(
+        distance * declination.cos() * longitude.cos(),
+        distance * declination.cos() * longitude.sin(),
         distance * declination.sin(),
-    );
-
-    // TODO: Is this conversion from equatorial coordinates to ECEF actually valid?
-    let ecef = Vector3::new(eq_rect.x, -eq_rect.y, eq_rect.z);
-
-    ecef.normalize()
+    )
 }
 
 pub fn cspace_to_polar(position: Vector3<f64>) -> Vector3<f64> {
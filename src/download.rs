@@ -17,7 +17,7 @@ fn s3_download(bucket: &Bucket, remote_path: &str, local_path: &Path) -> Result<
                 Ok((c, code)) => {
                     contents = c;
                     if code != 200 {
-                        println!("{}", remote_path);
+                        log::warn!("Download of '{}' failed with status {}", remote_path, code);
                         return Ok(());
                     }
                     break;
@@ -25,6 +25,7 @@ fn s3_download(bucket: &Bucket, remote_path: &str, local_path: &Path) -> Result<
                 Err(e) => {
                     if i < 20 {
                         i += 1;
+                        log::warn!("Retrying download of '{}' ({}/20): {}", remote_path, i, e);
                         continue;
                     }
                     return Err(e.into());
@@ -54,8 +55,8 @@ fn make_vrt(directory: &Path, extension: &OsStr) -> Result<(), anyhow::Error> {
         .output()
         .expect("Failed to run gdalbuildvrt. Is gdal installed?");
 
-    println!("stdout: {}", String::from_utf8_lossy(&output.stdout));
-    println!("stderr: {}", String::from_utf8_lossy(&output.stderr));
+    log::debug!("gdalbuildvrt stdout: {}", String::from_utf8_lossy(&output.stdout));
+    log::debug!("gdalbuildvrt stderr: {}", String::from_utf8_lossy(&output.stderr));
 
     Ok(())
 }
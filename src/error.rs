@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+/// Errors returned by terra's public API.
+///
+/// The variants cover the broad categories of failure that callers may want to handle
+/// differently. The underlying error is kept as the source where there is one.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The `TerrainConfig` was rejected.
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+    /// A source dataset needed for generation is missing or couldn't be read.
+    #[error("failed to read dataset")]
+    Dataset(#[source] Box<dyn std::error::Error + Send + Sync>),
+    /// Downloading tiles or assets failed.
+    #[error("download failed")]
+    Download(#[source] Box<dyn std::error::Error + Send + Sync>),
+    /// The map file cache on disk couldn't be opened, or was written by an incompatible version.
+    #[error("failed to open map file")]
+    MapFile(#[source] Box<dyn std::error::Error + Send + Sync>),
+    /// A downloaded or cached file couldn't be decoded.
+    #[error("unsupported or corrupt file format")]
+    UnsupportedFormat(#[source] Box<dyn std::error::Error + Send + Sync>),
+    /// Streaming didn't complete before the deadline.
+    #[error("streaming didn't complete within {0:?}")]
+    Timeout(Duration),
+    /// Any other failure.
+    #[error(transparent)]
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl From<anyhow::Error> for Error {
+    /// Classify an internal error by the kinds of errors in its chain of causes.
+    fn from(e: anyhow::Error) -> Self {
+        if e.chain().any(|c| c.is::<curl::Error>() || c.is::<hyper::Error>()) {
+            Error::Download(e.into())
+        } else if e.chain().any(|c| c.is::<sled::Error>()) {
+            Error::MapFile(e.into())
+        } else if e.chain().any(|c| c.is::<image::ImageError>() || c.is::<tiff::TiffError>()) {
+            Error::UnsupportedFormat(e.into())
+        } else {
+            Error::Other(e.into())
+        }
+    }
+}
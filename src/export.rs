@@ -0,0 +1,175 @@
+use std::f64::consts::PI;
+use std::ops::RangeInclusive;
+
+/// Latitude in radians beyond which Web Mercator tiles don't extend.
+const MAX_MERCATOR_LATITUDE: f64 = 1.4844222297453324;
+
+/// Projection of the tiles written by `Terrain::export_map_tiles`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MapProjection {
+    /// Spherical Mercator (EPSG:3857) with a single tile at zoom level 0, as used by most slippy
+    /// map viewers. Latitudes beyond about 85.05 degrees aren't covered.
+    WebMercator,
+    /// Plate carrée (EPSG:4326) with two tiles side by side at zoom level 0, each covering one
+    /// hemisphere of longitudes.
+    Equirectangular,
+}
+
+/// Numbering of tile rows within a zoom level.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TileScheme {
+    /// Row 0 is at the north edge of the map.
+    Xyz,
+    /// Row 0 is at the south edge of the map.
+    Tms,
+}
+
+/// What the pixels of exported tiles contain.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MapTileContents {
+    /// sRGB base albedo, shaded by slope like `Terrain::render_overview`.
+    Albedo,
+    /// Heights in Terrarium encoding, `(red * 256 + green + blue / 256) - 32768` meters, which
+    /// MapLibre and other viewers can use to build 3D terrain.
+    TerrariumElevation,
+}
+
+/// Options for `Terrain::export_map_tiles`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MapExport {
+    pub projection: MapProjection,
+    pub scheme: TileScheme,
+    pub contents: MapTileContents,
+    /// Zoom levels to write tiles for. The number of tiles grows fourfold with every level.
+    pub zoom_levels: RangeInclusive<u8>,
+    /// Width and height of each tile in pixels. Defaults to 256.
+    pub tile_size: u32,
+}
+impl Default for MapExport {
+    fn default() -> Self {
+        Self {
+            projection: MapProjection::WebMercator,
+            scheme: TileScheme::Xyz,
+            contents: MapTileContents::Albedo,
+            zoom_levels: 0..=8,
+            tile_size: 256,
+        }
+    }
+}
+
+impl MapProjection {
+    /// Number of tile columns and rows at `zoom`.
+    pub(crate) fn tiles(self, zoom: u8) -> (u32, u32) {
+        let n = 1u32 << zoom;
+        match self {
+            MapProjection::WebMercator => (n, n),
+            MapProjection::Equirectangular => (2 * n, n),
+        }
+    }
+
+    /// Latitude and longitude in radians of a point given in tile units at `zoom`, with rows
+    /// counted from the north edge.
+    pub(crate) fn unproject(self, zoom: u8, x: f64, y: f64) -> (f64, f64) {
+        let (columns, rows) = self.tiles(zoom);
+        let longitude = x / columns as f64 * 2.0 * PI - PI;
+        let latitude = match self {
+            MapProjection::WebMercator => (PI * (1.0 - 2.0 * y / rows as f64)).sinh().atan(),
+            MapProjection::Equirectangular => PI / 2.0 - y / rows as f64 * PI,
+        };
+        (latitude, longitude)
+    }
+
+    /// Inverse of `unproject`.
+    pub(crate) fn project(self, zoom: u8, latitude: f64, longitude: f64) -> (f64, f64) {
+        let (columns, rows) = self.tiles(zoom);
+        let x = (longitude + PI) / (2.0 * PI) * columns as f64;
+        let y = match self {
+            MapProjection::WebMercator => {
+                let latitude = latitude.max(-MAX_MERCATOR_LATITUDE).min(MAX_MERCATOR_LATITUDE);
+                (1.0 - latitude.tan().asinh() / PI) / 2.0 * rows as f64
+            }
+            MapProjection::Equirectangular => (PI / 2.0 - latitude) / PI * rows as f64,
+        };
+        (x, y)
+    }
+
+    /// Columns and rows of the tiles at `zoom` that overlap the region between `south_west` and
+    /// `north_east`. A region whose western edge is east of its eastern edge wraps across the
+    /// antimeridian, so its columns are split into one range on each side of it.
+    pub(crate) fn tile_range(
+        self,
+        zoom: u8,
+        south_west: (f64, f64),
+        north_east: (f64, f64),
+    ) -> (Vec<std::ops::Range<u32>>, std::ops::Range<u32>) {
+        let (columns, rows) = self.tiles(zoom);
+        let clamp = |v: f64, max: u32| (v.max(0.0) as u32).min(max);
+        let column_range = |west: f64, east: f64| {
+            let x0 = self.project(zoom, 0.0, west).0;
+            let x1 = self.project(zoom, 0.0, east).0;
+            clamp(x0.floor(), columns)..clamp(x1.ceil(), columns)
+        };
+
+        let column_ranges = if south_west.1 > north_east.1 {
+            vec![column_range(south_west.1, PI), column_range(-PI, north_east.1)]
+        } else {
+            vec![column_range(south_west.1, north_east.1)]
+        };
+        let y1 = self.project(zoom, south_west.0, 0.0).1;
+        let y0 = self.project(zoom, north_east.0, 0.0).1;
+        (column_ranges, clamp(y0.floor(), rows)..clamp(y1.ceil(), rows))
+    }
+}
+
+impl TileScheme {
+    /// Row of the tile in this scheme, given its row counted from the north edge.
+    pub(crate) fn row(self, y: u32, rows: u32) -> u32 {
+        match self {
+            TileScheme::Xyz => y,
+            TileScheme::Tms => rows - 1 - y,
+        }
+    }
+}
+
+/// Encode `height` in meters as a Terrarium RGB pixel.
+pub(crate) fn terrarium(height: f32) -> [u8; 4] {
+    let v = (height as f64 + 32768.0).max(0.0).min(65535.99);
+    [(v / 256.0) as u8, (v % 256.0) as u8, (v.fract() * 256.0) as u8, 255]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn projection_round_trip() {
+        for &projection in &[MapProjection::WebMercator, MapProjection::Equirectangular] {
+            for &(latitude, longitude) in &[(0.0, 0.0), (0.7, -2.1), (-1.2, 3.0)] {
+                let (x, y) = projection.project(5, latitude, longitude);
+                let (lat, lon) = projection.unproject(5, x, y);
+                assert!((lat - latitude).abs() < 1e-9, "{:?}", projection);
+                assert!((lon - longitude).abs() < 1e-9, "{:?}", projection);
+            }
+        }
+    }
+
+    #[test]
+    fn tile_range_wraps_across_antimeridian() {
+        let projection = MapProjection::Equirectangular;
+        let (columns, rows) = projection.tile_range(2, (-0.1, 3.0), (0.1, -3.0));
+        assert_eq!(columns, [7..8, 0..1]);
+        assert_eq!(rows, 1..3);
+
+        let (columns, _) = projection.tile_range(2, (-0.1, -3.0), (0.1, 3.0));
+        assert_eq!(columns, [0..8]);
+    }
+
+    #[test]
+    fn terrarium_encoding() {
+        for &height in &[-420.5f32, 0.0, 8848.25] {
+            let [r, g, b, _] = terrarium(height);
+            let decoded = r as f32 * 256.0 + g as f32 + b as f32 / 256.0 - 32768.0;
+            assert!((decoded - height).abs() < 1.0 / 256.0);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use crate::Error;
+use std::io::Cursor;
+use std::marker::PhantomData;
+
+/// Encoding of the intermediate raster files that a dataset passes through on its way to tiles.
+///
+/// Reprojected sectors of a dataset are written with `encode` and read back with `decode` when
+/// they are merged into tiles, which are themselves written with `encode`. Implementing this
+/// allows datasets whose sectors are stored in formats other than TIFF, such as a proprietary
+/// elevation encoding, to be merged with `Terrain::merge_dataset_tiles`. The original source
+/// rasters that sectors are reprojected from are not read through a codec.
+pub trait RasterCodec<T>: Send + Sync {
+    /// Extension, without the leading period, of the files in this encoding.
+    fn extension(&self) -> &str;
+
+    /// Encode a `width` by `height` raster stored in row-major order.
+    fn encode(&self, width: u32, height: u32, values: &[T]) -> Result<Vec<u8>, Error>;
+
+    /// Decode a raster written by `encode`, returning its samples in row-major order.
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<T>, Error>;
+}
+
+/// LZW compressed TIFF files with a single channel of color type `C`. This is the encoding
+/// terra uses for the datasets it generates itself.
+pub struct TiffCodec<C>(PhantomData<fn() -> C>);
+impl<C> Default for TiffCodec<C> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+impl<C> RasterCodec<C::Inner> for TiffCodec<C>
+where
+    C: tiff::encoder::colortype::ColorType,
+    C::Inner: bytemuck::Pod,
+    [C::Inner]: tiff::encoder::TiffValue,
+{
+    fn extension(&self) -> &str {
+        "tiff"
+    }
+
+    fn encode(&self, width: u32, height: u32, values: &[C::Inner]) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::new();
+        tiff::encoder::TiffEncoder::new(Cursor::new(&mut bytes))
+            .and_then(|mut encoder| {
+                encoder.write_image_with_compression::<C, _>(
+                    width,
+                    height,
+                    tiff::encoder::compression::Lzw,
+                    values,
+                )
+            })
+            .map_err(|e| Error::Other(e.into()))?;
+        Ok(bytes)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<C::Inner>, Error> {
+        let image = tiff::decoder::Decoder::new(Cursor::new(bytes))
+            .and_then(|mut decoder| decoder.read_image())
+            .map_err(|e| Error::UnsupportedFormat(e.into()))?;
+        match image {
+            tiff::decoder::DecodingResult::U8(v) => cast_samples(v),
+            tiff::decoder::DecodingResult::U16(v) => cast_samples(v),
+            tiff::decoder::DecodingResult::I16(v) => cast_samples(v),
+            _ => Err(Error::UnsupportedFormat("unsupported TIFF sample format".into())),
+        }
+    }
+}
+
+/// Reinterpret decoded samples as `T`, which must have the same size and alignment.
+fn cast_samples<S: bytemuck::Pod, T: bytemuck::Pod>(samples: Vec<S>) -> Result<Vec<T>, Error> {
+    bytemuck::allocation::try_cast_vec(samples).map_err(|(e, _)| {
+        Error::UnsupportedFormat(format!("TIFF sample format doesn't match raster: {:?}", e).into())
+    })
+}
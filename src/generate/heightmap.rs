@@ -1,12 +1,15 @@
 use crate::cache::LayerType;
 use crate::mapfile::MapFile;
+use crate::stream::CorruptTile;
 use anyhow::Error;
 use crossbeam::channel::{self, Receiver, Sender};
 use futures::future::{self, BoxFuture, FutureExt};
 use lru::LruCache;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::panic::AssertUnwindSafe;
 use std::path::PathBuf;
 use std::sync::{Arc, Weak};
+use tokio::sync::Semaphore;
 use types::{VFace, VNode, NODE_OFFSETS};
 
 #[derive(Copy, Clone, Hash, Eq, PartialEq, Debug)]
@@ -60,6 +63,16 @@ impl<K: std::hash::Hash + Eq + Copy, T> Cache<K, T> {
     fn sender(&self) -> Sender<(K, Arc<T>)> {
         self.sender.clone()
     }
+    fn remove_where(&mut self, predicate: impl Fn(&K) -> bool) {
+        while let Ok(t) = self.receiver.try_recv() {
+            self.insert(t.0, t.1);
+        }
+        let keys: Vec<K> = self.strong.iter().map(|(k, _)| *k).filter(|k| predicate(k)).collect();
+        for k in keys {
+            self.strong.pop(&k);
+        }
+        self.weak.retain(|k, _| !predicate(k));
+    }
 }
 
 pub(crate) struct HeightmapCache {
@@ -72,10 +85,13 @@ impl HeightmapCache {
         Self { resolution, border_size, tiles: Cache::new(capacity) }
     }
 
+    /// Load the heightmap for `node`, decoding it from the nearest cached ancestor. Decoding only
+    /// starts once a permit has been acquired from `decode_limit`.
     pub(crate) fn get_tile<'a>(
         &mut self,
         mapfile: &'a MapFile,
         node: VNode,
+        decode_limit: Arc<Semaphore>,
     ) -> BoxFuture<'a, Result<Arc<Vec<i16>>, Error>> {
         let mut tiles_pending = Vec::new();
         let mut root = None;
@@ -99,9 +115,18 @@ impl HeightmapCache {
         let (resolution, border_size) = (self.resolution, self.border_size);
         async move {
             let tiles = future::join_all(tiles_pending.into_iter()).await;
+            let _permit = match tiles.is_empty() {
+                true => None,
+                false => Some(decode_limit.acquire_owned().await?),
+            };
             for (n, t) in tiles.into_iter().rev() {
-                let tile = Arc::new(match root.take() {
-                    None => tilefmt::uncompress_heightmap_tile(None, &*t?.unwrap()).1,
+                let bytes =
+                    t?.ok_or_else(|| anyhow::format_err!("Heightmap tile {} is missing", n))?;
+                let parent_tile = root.take();
+
+                // tilefmt panics on malformed input, so catch that to report the tile as corrupt.
+                let tile = std::panic::catch_unwind(AssertUnwindSafe(|| match parent_tile {
+                    None => tilefmt::uncompress_heightmap_tile(None, &*bytes).1,
                     Some(parent_tile) => {
                         tilefmt::uncompress_heightmap_tile(
                             Some((
@@ -110,11 +135,13 @@ impl HeightmapCache {
                                 resolution,
                                 &*parent_tile,
                             )),
-                            &*t?.unwrap(),
+                            &*bytes,
                         )
                         .1
                     }
-                });
+                }))
+                .map_err(|_| Error::new(CorruptTile(n)))?;
+                let tile = Arc::new(tile);
                 let _ = sender.send((n, Arc::clone(&tile)));
                 root = Some(tile);
             }
@@ -122,14 +149,31 @@ impl HeightmapCache {
         }
         .boxed()
     }
+
+    /// Drop the decoded tiles for `nodes` and all their descendants, since descendants are decoded
+    /// relative to their ancestors.
+    pub(crate) fn invalidate(&mut self, nodes: &HashSet<VNode>) {
+        self.tiles.remove_where(|&node| {
+            let mut node = node;
+            loop {
+                if nodes.contains(&node) {
+                    return true;
+                }
+                match node.parent() {
+                    Some((parent, _)) => node = parent,
+                    None => return false,
+                }
+            }
+        });
+    }
 }
 
 pub(crate) struct SectorCache<T, F: 'static> {
     sectors: Cache<Sector, Vec<T>>,
-    parse: &'static F,
+    parse: Arc<F>,
     directory: PathBuf,
     filename_prefix: &'static str,
-    filename_extension: &'static str,
+    filename_extension: String,
 }
 impl<T, F> SectorCache<T, F>
 where
@@ -140,15 +184,15 @@ where
         capacity: usize,
         directory: PathBuf,
         filename_prefix: &'static str,
-        filename_extension: &'static str,
-        f: &'static F,
+        filename_extension: String,
+        f: F,
     ) -> Self {
         Self {
             sectors: Cache::new(capacity),
             directory,
             filename_prefix,
             filename_extension,
-            parse: f,
+            parse: Arc::new(f),
         }
     }
 
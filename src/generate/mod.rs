@@ -1,12 +1,14 @@
 use crate::asset::{AssetLoadContext, AssetLoadContextBuf, WebAsset};
-use crate::cache::{LayerParams, LayerType, TextureFormat};
+use crate::cache::{LayerParams, LayerType, TextureFormat, TileLayout};
 use crate::coordinates;
 use crate::generate::heightmap::{Sector, SectorCache};
 use crate::mapfile::{MapFile, TextureDescriptor};
 use crate::srgb::SRGB_TO_LINEAR;
+use crate::terrain::dataset::DatasetTileSource;
+use crate::terrain::procedural::ProceduralTileSource;
 use crate::terrain::raster::GlobalRaster;
+use crate::tile_source::{self, TileSink, TileSource};
 use anyhow::Error;
-use atomicwrites::{AtomicFile, OverwriteBehavior};
 use basis_universal::Transcoder;
 use fnv::FnvHashMap;
 use futures::stream::FuturesUnordered;
@@ -15,22 +17,25 @@ use image::{codecs::png::PngDecoder, ColorType, ImageDecoder};
 use itertools::Itertools;
 use rayon::prelude::*;
 use std::collections::HashSet;
-use std::io::Cursor;
+use std::ops::{Index, RangeInclusive};
 use std::sync::atomic::AtomicUsize;
 use std::{fs, mem};
 use std::{fs::File, path::PathBuf};
 use std::{
-    io::{Read, Write},
+    io::Read,
     path::Path,
-    sync::Mutex,
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant},
 };
 use types::{VFace, VNode};
 use vec_map::VecMap;
 
+mod codec;
 mod gpu;
 pub mod heightmap;
 mod material;
 
+pub use codec::{RasterCodec, TiffCodec};
 pub(crate) use gpu::*;
 
 pub const BLUE_MARBLE_URLS: [&str; 8] = [
@@ -44,9 +49,131 @@ pub const BLUE_MARBLE_URLS: [&str; 8] = [
     "https://eoimages.gsfc.nasa.gov/images/imagerecords/76000/76487/world.200406.3x21600x21600.D2.png",
 ];
 
-pub(crate) struct MapFileBuilder(MapFile);
+/// Parameters of the tiling noise texture used for fine surface detail.
+///
+/// Each octave is stored in its own channel of an RGBA texture, so at most four octaves are
+/// supported. Octave `i` has `base_frequency << i` noise cells across the texture.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct NoiseConfig {
+    /// Width and height of the noise texture in texels.
+    pub resolution: u32,
+    /// Number of octaves, between one and four. Unused channels are set to 0.5.
+    pub octaves: u32,
+    /// Number of noise cells across the texture in the lowest frequency octave. Must be even.
+    pub base_frequency: u32,
+}
+impl Default for NoiseConfig {
+    fn default() -> Self {
+        Self { resolution: 2048, octaves: 4, base_frequency: 64 }
+    }
+}
+impl NoiseConfig {
+    fn validate(&self) -> Result<(), Error> {
+        if self.octaves == 0 || self.octaves > 4 {
+            anyhow::bail!("noise must have between one and four octaves, not {}", self.octaves);
+        }
+        if self.base_frequency == 0 || self.base_frequency % 2 != 0 {
+            anyhow::bail!("noise base frequency must be even, not {}", self.base_frequency);
+        }
+        let max_frequency = self.base_frequency << (self.octaves - 1);
+        if self.resolution % max_frequency != 0 {
+            anyhow::bail!(
+                "noise resolution {} is not a multiple of the highest octave's frequency {}",
+                self.resolution,
+                max_frequency
+            );
+        }
+        Ok(())
+    }
+    fn fingerprint(&self) -> String {
+        format!("{}x{}x{}", self.resolution, self.octaves, self.base_frequency)
+    }
+}
+
+/// Wall-clock time spent in each phase of preparing the map file, in the order they ran.
+///
+/// Phases whose output was already cached on disk are still listed, but usually take close to
+/// no time.
+#[derive(Clone, Debug, Default)]
+pub struct GenerationTimings {
+    pub phases: Vec<(&'static str, Duration)>,
+}
+impl GenerationTimings {
+    /// Run `f` and record how long it took under `name`.
+    pub(crate) fn time<T>(&mut self, name: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let ret = f();
+        self.record(name, start.elapsed());
+        ret
+    }
+
+    pub(crate) fn record(&mut self, name: &'static str, duration: Duration) {
+        log::info!("{} took {:.1}s", name, duration.as_secs_f64());
+        self.phases.push((name, duration));
+    }
+
+    /// Total time across all phases.
+    pub fn total(&self) -> Duration {
+        self.phases.iter().map(|(_, d)| *d).sum()
+    }
+}
+impl std::fmt::Display for GenerationTimings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, duration) in &self.phases {
+            writeln!(f, "{:<24} {:>8.1}s", name, duration.as_secs_f64())?;
+        }
+        write!(f, "{:<24} {:>8.1}s", "total", self.total().as_secs_f64())
+    }
+}
+
+/// A progress update from generating terrain, as received from `progress_channel`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GenerationProgress {
+    /// Description of what is currently being generated.
+    pub stage: String,
+    /// Number of items of the current stage completed so far.
+    pub completed: usize,
+    /// Total number of items in the current stage.
+    pub total: usize,
+}
+
+/// Sending half of a `progress_channel`.
+#[derive(Clone, Debug)]
+pub struct ProgressSender(mpsc::Sender<GenerationProgress>);
+impl ProgressSender {
+    /// Send a progress update. Updates are discarded once the receiver has been dropped, so
+    /// generation carries on even if nothing is listening anymore.
+    pub fn send(&self, stage: &str, completed: usize, total: usize) {
+        let _ = self.0.send(GenerationProgress { stage: stage.to_owned(), completed, total });
+    }
+
+    /// Turn this into a progress callback for `Terrain::generate_and_new` and
+    /// `Terrain::generate_and_new_with_config`.
+    pub fn into_callback(self) -> impl FnMut(String, usize, usize) + Send + 'static {
+        move |stage, completed, total| {
+            let _ = self.0.send(GenerationProgress { stage, completed, total });
+        }
+    }
+}
+
+/// Create a channel that generation progress is sent over, so that a UI can poll for updates on
+/// its own thread rather than having the generation thread call into UI code.
+///
+/// Pass `ProgressSender::into_callback` as the progress callback of `Terrain::generate_and_new`,
+/// or call `ProgressSender::send` from the callback of `Terrain::merge_dataset_tiles`, then
+/// drain the receiver with `try_recv` once per frame.
+pub fn progress_channel() -> (ProgressSender, mpsc::Receiver<GenerationProgress>) {
+    let (sender, receiver) = mpsc::channel();
+    (ProgressSender(sender), receiver)
+}
+
+pub(crate) struct MapFileBuilder {
+    mapfile: MapFile,
+    noise: NoiseConfig,
+    procedural: bool,
+}
 impl MapFileBuilder {
-    pub(crate) async fn new() -> Self {
+    pub(crate) async fn new() -> Result<Self, Error> {
         let layers: VecMap<LayerParams> = LayerType::iter()
             .map(|layer_type| {
                 let params = match layer_type {
@@ -154,14 +281,97 @@ impl MapFileBuilder {
             })
             .collect();
 
-        let mapfile = MapFile::new(layers);
-        for layer in LayerType::iter() {
-            if layer.streamed_levels() > 0 {
-                mapfile.reload_tile_states(layer).await.unwrap();
-            }
+        Ok(Self {
+            mapfile: MapFile::new(layers)?,
+            noise: NoiseConfig::default(),
+            procedural: false,
+        })
+    }
+
+    /// Set the elevation in the source heightmaps that is treated as sea level. Terrain at or
+    /// below this elevation is considered to be underwater, and all heights reported by terra are
+    /// relative to it. Defaults to zero.
+    pub(crate) fn sea_level(mut self, sea_level: f32) -> Self {
+        self.mapfile.set_sea_level(sea_level);
+        self
+    }
+
+    /// Set the lowest height, relative to sea level, that terrain can reach. Anything deeper is
+    /// clamped to this value during both generation and streaming. Defaults to -1024 meters, which
+    /// is also the lowest supported value.
+    pub(crate) fn height_floor(mut self, height_floor: f32) -> Self {
+        self.mapfile.set_height_floor(height_floor);
+        self
+    }
+
+    /// Set the resolution and octaves of the noise texture, regenerating it if they differ from
+    /// the cached copy.
+    pub(crate) fn noise(mut self, noise: NoiseConfig) -> Self {
+        self.noise = noise;
+        self
+    }
+
+    /// Fetch streamed tiles that aren't already on disk from `source` rather than from terra's
+    /// tile server.
+    pub(crate) fn tile_source(mut self, source: Arc<dyn TileSource>) -> Self {
+        self.mapfile.set_tile_source(source);
+        self
+    }
+
+    /// Write generated tiles to `sink` rather than into the tile cache on disk.
+    pub(crate) fn tile_sink(mut self, sink: Arc<dyn TileSink>) -> Self {
+        self.mapfile.set_tile_sink(sink);
+        self
+    }
+
+    /// Set the largest response, in bytes, that will be accepted when downloading tiles or tile
+    /// lists from terra's tile server. Larger downloads are aborted with an error.
+    pub(crate) fn max_download_size(mut self, max_download_size: u64) -> Self {
+        self.mapfile.set_max_download_size(max_download_size);
+        self
+    }
+
+    /// Download tiles, tile lists and assets from each of `urls` in turn rather than only from
+    /// terra's tile server, moving on to the next whenever a download fails.
+    pub(crate) fn tile_server_urls(mut self, urls: Vec<String>) -> Self {
+        self.mapfile.set_tile_server_urls(urls);
+        self
+    }
+
+    /// Synthesize heightmap and base albedo tiles from noise rather than streaming real world
+    /// data. The synthesized tiles bypass the tile cache on disk, so they never mix with or
+    /// replace downloaded ones.
+    pub(crate) fn procedural(mut self) -> Self {
+        let source = ProceduralTileSource::new(self.mapfile.layers().clone());
+        self.mapfile.set_tile_source(Arc::new(source));
+        self.mapfile.set_cache_fetched_tiles(false);
+        self.procedural = true;
+        self
+    }
+
+    /// Stream `layer` from the dataset `name` that was merged into tiles within `directory`,
+    /// instead of from the tile source. Only heightmaps can be streamed from a dataset.
+    pub(crate) fn dataset(
+        mut self,
+        layer: LayerType,
+        directory: &Path,
+        name: &str,
+    ) -> Result<Self, Error> {
+        if layer != LayerType::Heightmaps {
+            anyhow::bail!("{:?} can't be streamed from a generated dataset", layer);
         }
+        let tiles_directory = directory.join("tiles").join(name);
+        let merged = match MergedTiles::read(&tiles_directory)? {
+            Some(merged) => merged,
+            None => {
+                anyhow::bail!("No merged tiles for dataset '{}' in '{}'", name, directory.display())
+            }
+        };
 
-        Self(mapfile)
+        let params = self.mapfile.layers()[layer].clone();
+        let source = DatasetTileSource::new(tiles_directory, name.to_owned(), params, &merged)?;
+        self.mapfile.set_layer_source(layer, Arc::new(source));
+        Ok(self)
     }
 
     /// Actually construct the `QuadTree`.
@@ -175,24 +385,86 @@ impl MapFileBuilder {
     /// of CPU resources. You can expect it to run at full load continiously for several full
     /// minutes, even in release builds (you *really* don't want to wait for generation in debug
     /// mode...).
-    pub(crate) async fn build(mut self) -> Result<MapFile, Error> {
+    pub(crate) async fn build(mut self) -> Result<(MapFile, GenerationTimings), Error> {
+        let mut timings = GenerationTimings::default();
+
+        // Procedural tiles are always available, so there is no need to learn which tiles exist.
+        if !self.procedural {
+            let start = Instant::now();
+            for layer in LayerType::iter() {
+                if layer.streamed_levels() > 0 {
+                    self.mapfile.reload_tile_states(layer).await?;
+                }
+            }
+            timings.record("tile states", start.elapsed());
+        }
+
         let mut context = AssetLoadContextBuf::new();
         let mut context = context.context("Building Terrain...", 1);
         // generate_heightmaps(&mut mapfile, &mut context).await?;
         // generate_albedo(&mut mapfile, &mut context)?;
         // generate_roughness(&mut mapfile, &mut context)?;
-        generate_noise(&mut self.0, &mut context)?;
-        generate_sky(&mut self.0, &mut context)?;
+        let (mapfile, noise) = (&mut self.mapfile, &self.noise);
+        timings.time("noise", || generate_noise(mapfile, noise, &mut context))?;
+        timings.time("sky", || generate_sky(mapfile, &mut context))?;
+
+        timings.time("cloudcover", || download_cloudcover(mapfile, &mut context))?;
+        timings.time("night lights", || download_night_lights(mapfile, &mut context))?;
+        timings.time("ground albedo", || download_ground_albedo(mapfile, &mut context))?;
+        timings.time("models", || download_models(mapfile, &mut context))?;
 
-        download_cloudcover(&mut self.0, &mut context)?;
-        download_ground_albedo(&mut self.0, &mut context)?;
-        download_models(&mut context)?;
+        Ok((self.mapfile, timings))
+    }
+}
 
-        Ok(self.0)
+/// Layout of the tiles produced by `reproject_dataset` and `merge_datasets_to_tiles`. Both must be
+/// called with the same parameters, or the sectors written by the first won't line up with the
+/// tiles the second assembles from them.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct ReprojectionParams {
+    /// Number of samples in each dimension of an output tile, including borders.
+    pub tile_resolution: usize,
+    /// Number of samples outside the tile on each side.
+    pub border_size: usize,
+    /// Number of sectors along each side of a cube face that reprojected datasets are split into.
+    pub sectors_per_side: u32,
+}
+impl Default for ReprojectionParams {
+    fn default() -> Self {
+        Self { tile_resolution: 516, border_size: 2, sectors_per_side: 65 }
     }
 }
+impl ReprojectionParams {
+    fn inner_resolution(&self) -> usize {
+        self.tile_resolution - self.border_size * 2
+    }
 
-const SECTORS_PER_SIDE: u32 = 65;
+    /// Panics unless every level's tiles divide evenly into sectors, with sectors at least wide
+    /// enough to supply the border of the coarsest tiles.
+    fn validate(&self) {
+        assert!(self.sectors_per_side >= 2, "sectors_per_side must be at least 2");
+        assert!(
+            self.tile_resolution > self.border_size * 2,
+            "tile_resolution {} leaves no samples inside a border of {}",
+            self.tile_resolution,
+            self.border_size
+        );
+        let inner_resolution = self.inner_resolution();
+        let sector_intervals = (self.sectors_per_side - 1) as usize;
+        assert!(
+            inner_resolution % sector_intervals == 0,
+            "inner tile resolution {} isn't divisible by sectors_per_side - 1 = {}",
+            inner_resolution,
+            sector_intervals
+        );
+        assert!(
+            inner_resolution / sector_intervals >= self.border_size * 2,
+            "sectors of {} samples are too small for a border of {}",
+            inner_resolution / sector_intervals,
+            self.border_size
+        );
+    }
+}
 
 fn scan_directory(
     base: &Path,
@@ -211,37 +483,238 @@ fn scan_directory(
     Ok((directory, existing))
 }
 
-pub(crate) fn reproject_dataset<T, C, F, Downsample>(
+/// Name of the file recording the parameters that the tiles in a directory were generated with.
+const FINGERPRINT_FILENAME: &str = "fingerprint.txt";
+
+/// Name of the file that `merge_datasets_to_tiles` writes the tile for `node` of `dataset_name`
+/// to.
+pub(crate) fn merged_tile_filename(dataset_name: &str, node: VNode, extension: &str) -> String {
+    format!(
+        "{}_{}_{}_{}x{}.{}",
+        dataset_name,
+        node.level(),
+        VFace(node.face()),
+        node.x(),
+        node.y(),
+        extension
+    )
+}
+
+/// Format of the tiles that a dataset was merged into, as recorded in the fingerprint file that
+/// `merge_datasets_to_tiles` writes once every tile is complete.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct MergedTiles {
+    pub tile_resolution: usize,
+    pub border_size: usize,
+    pub grid_registration: bool,
+    /// Extension of the tile files, which identifies the codec they were encoded with.
+    pub extension: String,
+    /// Name of the Rust type of the samples, such as `i16`.
+    pub sample_type: String,
+}
+impl MergedTiles {
+    /// Placement of the merged samples relative to their node. Merged tiles span the same number
+    /// of sample spacings with either registration, so grid registered ones have one fewer border
+    /// sample past their far edge.
+    pub(crate) fn layout(&self) -> TileLayout {
+        let border = self.border_size as f64;
+        TileLayout {
+            inner_resolution: (self.tile_resolution - 2 * self.border_size) as u32,
+            origin: if self.grid_registration { border + 0.5 } else { border },
+        }
+    }
+
+    fn fingerprint(&self) -> String {
+        format!(
+            "tile_resolution={}\nborder_size={}\ngrid_registration={}\nextension={}\nsample_type={}\n",
+            self.tile_resolution,
+            self.border_size,
+            self.grid_registration,
+            self.extension,
+            self.sample_type
+        )
+    }
+
+    /// Read the format of the merged tiles in `directory`. Returns `None` if they are incomplete,
+    /// or were merged before the format was recorded.
+    pub(crate) fn read(directory: &Path) -> Result<Option<Self>, Error> {
+        let fingerprint = match fs::read_to_string(directory.join(FINGERPRINT_FILENAME)) {
+            Ok(fingerprint) => fingerprint,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let fields: FnvHashMap<&str, &str> =
+            fingerprint.lines().filter_map(|line| line.split_once('=')).collect();
+        Ok((|| {
+            Some(Self {
+                tile_resolution: fields.get("tile_resolution")?.parse().ok()?,
+                border_size: fields.get("border_size")?.parse().ok()?,
+                grid_registration: fields.get("grid_registration")?.parse().ok()?,
+                extension: fields.get("extension")?.to_string(),
+                sample_type: fields.get("sample_type")?.to_string(),
+            })
+        })())
+    }
+}
+
+/// The datasets that have been completely merged into tiles within `base` along with the format
+/// of their tiles, sorted alphabetically by name.
+pub(crate) fn merged_datasets(base: &Path) -> Result<Vec<(String, MergedTiles)>, Error> {
+    let directory = base.join("tiles");
+    if !directory.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut datasets = Vec::new();
+    for entry in fs::read_dir(&directory)? {
+        let entry = entry?;
+        if let Some(tiles) = MergedTiles::read(&entry.path())? {
+            if let Ok(name) = entry.file_name().into_string() {
+                datasets.push((name, tiles));
+            }
+        }
+    }
+    datasets.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(datasets)
+}
+
+/// Store a file generated from a dataset in `sink`, or on disk at `path` if there is none.
+fn write_dataset_file(sink: Option<&dyn TileSink>, path: &Path, bytes: &[u8]) -> Result<(), Error> {
+    match sink {
+        Some(sink) => sink.write_dataset_file(path, bytes),
+        None => tile_source::write_file_atomically(path, bytes),
+    }
+}
+
+/// Returns whether the tiles in `directory` were generated with parameters matching `fingerprint`.
+///
+/// If they weren't, the stale fingerprint is removed so that an interrupted regeneration can't
+/// leave behind a mix of old and new tiles that appears valid.
+fn check_fingerprint(directory: &Path, fingerprint: &str) -> Result<bool, Error> {
+    let path = directory.join(FINGERPRINT_FILENAME);
+    match fs::read_to_string(&path) {
+        Ok(existing) if existing == fingerprint => Ok(true),
+        Ok(_) => {
+            fs::remove_file(&path)?;
+            Ok(false)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// How source dataset pixels are sampled by `reproject_dataset`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum SourceSampling {
+    /// Take the source pixel containing each sample point. Suited to categorical data, but
+    /// produces stair-steps wherever the output is finer than the source.
+    Nearest,
+    /// Interpolate between the four source pixels whose centers surround each sample point.
+    /// Points next to a pixel with no data fall back to nearest sampling.
+    Bilinear,
+}
+
+/// Sample a raster at fractional pixel coordinates, where pixel `(i, j)` covers coordinates from
+/// `(i, j)` to `(i + 1, j + 1)`. `lookup` must return the pixel containing each coordinate it is
+/// passed. For bilinear sampling it is only passed coordinates a quarter pixel from pixel
+/// corners, so it may equally round to the nearest pixel center.
+fn sample_raster<T, L>(
+    coordinates: &[(f64, f64)],
+    sampling: SourceSampling,
+    no_data_value: T,
+    lookup: L,
+    output: &mut [T],
+) where
+    T: num_traits::NumCast + Copy + PartialEq,
+    L: Fn(&[(f64, f64)], &mut [T]),
+{
+    lookup(coordinates, output);
+    if sampling == SourceSampling::Nearest {
+        return;
+    }
+
+    let mut sum = vec![0.0f64; coordinates.len()];
+    let mut valid = vec![true; coordinates.len()];
+    let mut corner_coordinates = Vec::with_capacity(coordinates.len());
+    let mut corner_values = vec![no_data_value; coordinates.len()];
+    for (dx, dy) in [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)] {
+        corner_coordinates.clear();
+        corner_coordinates.extend(
+            coordinates
+                .iter()
+                .map(|&(x, y)| ((x - 0.5).floor() + dx + 0.25, (y - 0.5).floor() + dy + 0.25)),
+        );
+        lookup(&corner_coordinates, &mut corner_values);
+
+        for (i, &(x, y)) in coordinates.iter().enumerate() {
+            let fx = (x - 0.5) - (x - 0.5).floor();
+            let fy = (y - 0.5) - (y - 0.5).floor();
+            let weight =
+                (if dx == 0.0 { 1.0 - fx } else { fx }) * (if dy == 0.0 { 1.0 - fy } else { fy });
+            match num_traits::cast::<T, f64>(corner_values[i]) {
+                Some(v) if corner_values[i] != no_data_value => sum[i] += v * weight,
+                _ => valid[i] = false,
+            }
+        }
+    }
+
+    for i in 0..coordinates.len() {
+        if valid[i] {
+            if let Some(v) = num_traits::cast(sum[i].round()) {
+                output[i] = v;
+            }
+        }
+    }
+}
+
+pub(crate) fn reproject_dataset<T, F, Downsample>(
     base_directory: PathBuf,
     dataset_name: &'static str,
     max_level: u8,
+    params: ReprojectionParams,
     progress_callback: F,
     grid_registration: bool,
+    sampling: SourceSampling,
+    memory_budget: Option<u64>,
+    sink: Option<&dyn TileSink>,
+    codec: &dyn RasterCodec<T>,
     vrt_file: vrt_file::VrtFile,
     downsample: &'static Downsample,
     no_data_value: T,
+    min_value: T,
 ) -> Result<(), anyhow::Error>
 where
-    T: vrt_file::Scalar + Ord + Copy + bytemuck::Pod + Send + Sync + 'static + From<i16>,
+    T: vrt_file::Scalar
+        + Ord
+        + Copy
+        + bytemuck::Pod
+        + Send
+        + Sync
+        + 'static
+        + From<i16>
+        + num_traits::NumCast,
     F: FnMut(String, usize, usize) + Send,
     Downsample: Fn(T, T, T, T) -> T + Sync + 'static,
-    C: tiff::encoder::colortype::ColorType<Inner = T>,
-    [T]: tiff::encoder::TiffValue,
 {
+    params.validate();
+    let sectors_per_side = params.sectors_per_side;
+
     let (reprojected_directory, reprojected) =
         scan_directory(&base_directory, format!("{}_reprojected", dataset_name))?;
 
     let mut missing = Vec::new();
     for root_node in VNode::roots() {
-        for y in 0..SECTORS_PER_SIDE {
-            for x in 0..SECTORS_PER_SIDE {
+        for y in 0..sectors_per_side {
+            for x in 0..sectors_per_side {
                 let is_missing = (VNode::LEVEL_CELL_1KM.min(max_level)..=max_level).any(|level| {
                     !reprojected.contains(&format!(
-                        "{}_S-{}-{:02}x{:02}.tiff",
+                        "{}_S-{}-{:02}x{:02}.{}",
                         VFace(root_node.face()),
                         level,
                         x,
-                        y
+                        y,
+                        codec.extension()
                     ))
                 });
 
@@ -254,14 +727,12 @@ where
 
     let min_level = VNode::LEVEL_CELL_1KM.min(max_level);
 
-    const TILE_RESOLUTION: usize = 516;
-    const BORDER_SIZE: usize = 2;
-    const TILE_INNER_RESOLUTION: usize = TILE_RESOLUTION - BORDER_SIZE * 2;
+    let tile_inner_resolution = params.inner_resolution();
 
     let base_sector_resolution = if grid_registration {
-        1 + (TILE_INNER_RESOLUTION << max_level) as u32 / (SECTORS_PER_SIDE - 1)
+        1 + (tile_inner_resolution << max_level) as u32 / (sectors_per_side - 1)
     } else {
-        (TILE_INNER_RESOLUTION << max_level) as u32 / (SECTORS_PER_SIDE - 1)
+        (tile_inner_resolution << max_level) as u32 / (sectors_per_side - 1)
     };
     let root_border_size = base_sector_resolution / 2;
 
@@ -269,18 +740,22 @@ where
         .checked_mul(base_sector_resolution)
         .expect("TODO: Handle sector resolution overflow");
 
-    let total_sectors = (6 * SECTORS_PER_SIDE * SECTORS_PER_SIDE) as usize;
+    let total_sectors = (6 * sectors_per_side * sectors_per_side) as usize;
     let sectors_processed = AtomicUsize::new(total_sectors - missing.len());
 
     let progress_callback = Mutex::new(progress_callback);
     let geotransform = vrt_file.geotransform();
 
-    vrt_file.alloc_user_bytes(
-        u64::from(base_sector_resolution * base_sector_resolution)
-            * (16 + mem::size_of::<T>()) as u64
-            * 16,
-    );
-    missing.chunks(16).try_for_each(|chunk| {
+    // Each sector in flight holds its sample coordinates and values.
+    let sector_bytes = u64::from(base_sector_resolution * base_sector_resolution)
+        * (16 + mem::size_of::<T>()) as u64;
+    let concurrent_sectors = match memory_budget {
+        Some(budget) => (budget / sector_bytes).max(1) as usize,
+        None => 16,
+    };
+
+    vrt_file.alloc_user_bytes(sector_bytes * concurrent_sectors as u64);
+    missing.chunks(concurrent_sectors).try_for_each(|chunk| {
         chunk.into_par_iter().try_for_each(|(root, x, y)| -> Result<(), anyhow::Error> {
             (progress_callback.lock().unwrap())(
                 format!("reprojecting {}...", dataset_name),
@@ -300,7 +775,7 @@ where
                             (y * (base_sector_resolution - 1) + (i / base_sector_resolution))
                                 as i32,
                             root_border_size as u32,
-                            ((base_sector_resolution - 1) * SECTORS_PER_SIDE + 1) as u32,
+                            ((base_sector_resolution - 1) * sectors_per_side + 1) as u32,
                         );
                         let polar = coordinates::cspace_to_polar(cspace);
                         let latitude = polar.x.to_degrees();
@@ -318,7 +793,7 @@ where
                             (x * base_sector_resolution + (i % base_sector_resolution)) as i32,
                             (y * base_sector_resolution + (i / base_sector_resolution)) as i32,
                             root_border_size as u32,
-                            base_sector_resolution * SECTORS_PER_SIDE,
+                            base_sector_resolution * sectors_per_side,
                         );
                         let polar = coordinates::cspace_to_polar(cspace);
                         let latitude = polar.x.to_degrees();
@@ -335,7 +810,16 @@ where
             let resolution = base_sector_resolution as usize;
             let mut heightmap = vec![no_data_value; resolution * resolution];
 
-            vrt_file.batch_lookup(&*coordinates, &mut heightmap);
+            sample_raster(
+                &*coordinates,
+                sampling,
+                no_data_value,
+                |c: &[(f64, f64)], v: &mut [T]| vrt_file.batch_lookup(c, v),
+                &mut heightmap,
+            );
+            for value in &mut heightmap {
+                *value = (*value).max(min_value);
+            }
 
             drop(coordinates);
 
@@ -344,33 +828,25 @@ where
             let mut resolution = base_sector_resolution;
             let mut downsampled: Vec<T> = heightmap.clone();
             for level in (min_level..=max_level).rev() {
-                let mut bytes = Vec::new();
-
                 let mut min = downsampled[0];
                 let mut max = downsampled[0];
                 for &v in &downsampled {
                     min = min.min(v);
                     max = max.max(v);
                 }
-                if min == max {
-                    tiff::encoder::TiffEncoder::new(std::io::Cursor::new(&mut bytes))?
-                        .write_image::<C>(1, 1, &[min])?;
+                let bytes = if min == max {
+                    codec.encode(1, 1, &[min])?
                 } else {
-                    tiff::encoder::TiffEncoder::new(std::io::Cursor::new(&mut bytes))?
-                        .write_image_with_compression::<C, _>(
-                            resolution as u32,
-                            resolution as u32,
-                            tiff::encoder::compression::Lzw,
-                            &downsampled,
-                        )?;
-                }
+                    codec.encode(resolution as u32, resolution as u32, &downsampled)?
+                };
 
                 let filename = reprojected_directory.join(&format!(
-                    "{}_S-{}-{:02}x{:02}.tiff",
+                    "{}_S-{}-{:02}x{:02}.{}",
                     VFace(root.face()),
                     level,
                     x,
-                    y
+                    y,
+                    codec.extension()
                 ));
                 output_files.push((filename, bytes));
 
@@ -409,38 +885,42 @@ where
             }
 
             for (filename, bytes) in output_files.into_iter().rev() {
-                AtomicFile::new(filename, OverwriteBehavior::AllowOverwrite)
-                    .write(|f| f.write_all(&bytes))?;
+                write_dataset_file(sink, &filename, &bytes)?;
             }
 
             sectors_processed.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
             Ok(())
         })
     })?;
-    vrt_file.free_user_bytes(
-        u64::from(base_sector_resolution * base_sector_resolution)
-            * (16 + mem::size_of::<T>()) as u64
-            * 16,
-    );
+    vrt_file.free_user_bytes(sector_bytes * concurrent_sectors as u64);
     Ok(())
 }
 
-pub(crate) fn merge_datasets_to_tiles<T, C, F, Downsample, FromF64>(
+/// Levels that `merge_datasets_to_tiles` produces tiles at. Merged tiles are streamed as
+/// heightmaps, so there is no point merging any finer than heightmaps are streamed.
+fn merged_levels(max_level: u8) -> RangeInclusive<u8> {
+    0..=max_level.min(LayerType::Heightmaps.streamed_levels() - 1)
+}
+
+pub(crate) fn merge_datasets_to_tiles<T, F>(
     base_directory: PathBuf,
     dataset_name: &'static str,
     max_level: u8,
+    params: ReprojectionParams,
     mut progress_callback: F,
     grid_registration: bool,
+    sink: Option<Arc<dyn TileSink>>,
+    codec: Arc<dyn RasterCodec<T>>,
 ) -> impl Future<Output = Result<(), anyhow::Error>>
 where
-    T: Into<f64> + num_traits::Zero + Ord + Copy + bytemuck::Pod + Send + Sync + 'static,
+    T: Default + PartialEq + Copy + Send + Sync + 'static,
     F: FnMut(&str, usize, usize) + Send,
-    Downsample: Fn(T, T, T, T) -> T + Sync + 'static,
-    FromF64: Fn(f64) -> T + Sync + 'static,
-    C: tiff::encoder::colortype::ColorType<Inner = T>,
-    [T]: tiff::encoder::TiffValue,
 {
     async move {
+        params.validate();
+        let ReprojectionParams { tile_resolution, border_size, sectors_per_side } = params;
+        let tile_inner_resolution = params.inner_resolution();
+
         let (reprojected_directory, _reprojected) =
             scan_directory(&base_directory, format!("{}_reprojected", dataset_name))?;
         let (tiles_directory, existing_tiles) =
@@ -448,14 +928,10 @@ where
 
         let min_level = VNode::LEVEL_CELL_1KM.min(max_level);
 
-        const TILE_RESOLUTION: usize = 516;
-        const BORDER_SIZE: usize = 2;
-        const TILE_INNER_RESOLUTION: usize = TILE_RESOLUTION - BORDER_SIZE * 2;
-
         let base_sector_resolution = if grid_registration {
-            1 + (TILE_INNER_RESOLUTION << max_level) as u32 / (SECTORS_PER_SIDE - 1)
+            1 + (tile_inner_resolution << max_level) as u32 / (sectors_per_side - 1)
         } else {
-            (TILE_INNER_RESOLUTION << max_level) as u32 / (SECTORS_PER_SIDE - 1)
+            (tile_inner_resolution << max_level) as u32 / (sectors_per_side - 1)
         };
 
         base_sector_resolution
@@ -465,70 +941,71 @@ where
         const MAX_CONCURRENT: usize = 1;
         const MAX_RASTERS: usize = 8;
 
-        let mut total_tiles = 0;
+        let fingerprint = MergedTiles {
+            tile_resolution,
+            border_size,
+            grid_registration,
+            extension: codec.extension().to_owned(),
+            sample_type: std::any::type_name::<T>().to_owned(),
+        }
+        .fingerprint();
+        let fingerprint_matches = check_fingerprint(&tiles_directory, &fingerprint)?;
+
+        let levels = merged_levels(max_level);
+        let total_tiles = LayerType::Heightmaps.tile_count(levels.clone(), [true; 6]);
         let mut missing_tiles = Vec::new();
         VNode::breadth_first(|n| {
-            let filename = format!(
-                "{}_{}_{}_{}x{}.tiff",
-                dataset_name,
-                n.level(),
-                VFace(n.face()),
-                n.x(),
-                n.y()
-            );
-
-            total_tiles += 1;
-            if !existing_tiles.contains(&filename) {
+            let filename = merged_tile_filename(dataset_name, n, codec.extension());
+            if !fingerprint_matches || !existing_tiles.contains(&filename) {
                 missing_tiles.push((tiles_directory.join(filename), n));
             }
 
-            n.level() < max_level
+            n.level() < *levels.end()
         });
         missing_tiles.reverse();
 
-        let mut sector_cache =
-            SectorCache::new(32, reprojected_directory.to_owned(), "", "tiff", &|bytes| -> Result<
-                Vec<T>,
-                _,
-            > {
-                let mut decoder = tiff::decoder::Decoder::new(Cursor::new(bytes))?;
-                Ok(match decoder.read_image()? {
-                    tiff::decoder::DecodingResult::U8(v) => bytemuck::cast_vec(v),
-                    _ => unimplemented!(),
-                })
-            });
+        let mut sector_cache = SectorCache::new(
+            32,
+            reprojected_directory.to_owned(),
+            "",
+            codec.extension().to_owned(),
+            {
+                let codec = Arc::clone(&codec);
+                move |bytes: &[u8]| Ok(codec.decode(bytes)?)
+            },
+        );
         let mut unordered = FuturesUnordered::new();
         let mut tiles_processed = total_tiles - missing_tiles.len();
         while !missing_tiles.is_empty() || !unordered.is_empty() {
             if unordered.len() < 16 && !missing_tiles.is_empty() {
                 let (filename, node) = missing_tiles.pop().unwrap();
 
-                let mut heights = vec![T::zero(); TILE_RESOLUTION * TILE_RESOLUTION];
+                let mut heights = vec![T::default(); tile_resolution * tile_resolution];
 
                 let step = 1 << min_level.saturating_sub(node.level());
                 let sector_level = node.level().max(min_level);
                 let sector_inner_resolution =
-                    (512 << sector_level) / (SECTORS_PER_SIDE - 1) as usize;
+                    (tile_inner_resolution << sector_level) / (sectors_per_side - 1) as usize;
                 let sector_resolution = if grid_registration {
                     1 + sector_inner_resolution
                 } else {
                     sector_inner_resolution
                 };
-                let root_x = node.x() as usize * TILE_INNER_RESOLUTION * step
+                let root_x = node.x() as usize * tile_inner_resolution * step
                     + sector_resolution / 2
-                    - BORDER_SIZE * step;
-                let root_y = node.y() as usize * TILE_INNER_RESOLUTION * step
+                    - border_size * step;
+                let root_y = node.y() as usize * tile_inner_resolution * step
                     + sector_resolution / 2
-                    - BORDER_SIZE * step;
+                    - border_size * step;
 
                 let mut sectors = FnvHashMap::default();
 
                 let min_sector_x = (root_x / sector_inner_resolution) as u32;
                 let min_sector_y = (root_y / sector_inner_resolution) as u32;
                 let max_sector_x =
-                    ((root_x + (TILE_RESOLUTION - 1) * step) / sector_inner_resolution) as u32;
+                    ((root_x + (tile_resolution - 1) * step) / sector_inner_resolution) as u32;
                 let max_sector_y =
-                    ((root_y + (TILE_RESOLUTION - 1) * step) / sector_inner_resolution) as u32;
+                    ((root_y + (tile_resolution - 1) * step) / sector_inner_resolution) as u32;
                 for y in min_sector_y..=max_sector_y {
                     for x in min_sector_x..=max_sector_x {
                         let s = Sector { face: node.face(), x, y };
@@ -552,6 +1029,7 @@ where
                 //     }
                 // }
 
+                let codec = Arc::clone(&codec);
                 unordered.push(async move {
                     let sectors: Vec<(Sector, Result<_, _>)> = futures::future::join_all(
                         sectors.into_iter().map(|s| async move { (s.0, s.1.await) }),
@@ -564,8 +1042,8 @@ where
                     }
 
                     let encoded = tokio::task::spawn_blocking(move || {
-                        for y in 0..TILE_RESOLUTION {
-                            for x in 0..TILE_RESOLUTION {
+                        for y in 0..tile_resolution {
+                            for x in 0..tile_resolution {
                                 let s = Sector {
                                     face: node.face(),
                                     x: ((x * step + root_x) / sector_inner_resolution) as u32,
@@ -573,26 +1051,24 @@ where
                                 };
                                 let sector = &sectors_map[&s];
                                 if sector.len() == 1 {
-                                    heights[y * TILE_RESOLUTION + x] = sector[0];
+                                    heights[y * tile_resolution + x] = sector[0];
                                 } else {
                                     let sector_x = (x * step + root_x) % sector_inner_resolution;
                                     let sector_y = (y * step + root_y) % sector_inner_resolution;
 
-                                    heights[y * TILE_RESOLUTION + x] =
+                                    heights[y * tile_resolution + x] =
                                         sector[sector_y * sector_resolution + sector_x];
                                 }
                             }
                         }
 
                         let mut bytes = Vec::new();
-                        if heights.iter().any(|&h| h != T::zero()) {
-                            tiff::encoder::TiffEncoder::new(std::io::Cursor::new(&mut bytes))?
-                                .write_image_with_compression::<C, _>(
-                                    TILE_RESOLUTION as u32,
-                                    TILE_RESOLUTION as u32,
-                                    tiff::encoder::compression::Lzw,
-                                    &heights,
-                                )?;
+                        if heights.iter().any(|&h| h != T::default()) {
+                            bytes = codec.encode(
+                                tile_resolution as u32,
+                                tile_resolution as u32,
+                                &heights,
+                            )?;
                             // } else {
                             //     tiff::encoder::TiffEncoder::new(std::io::Cursor::new(&mut bytes))?
                             //         .write_image_with_compression::<C, _>(
@@ -611,9 +1087,7 @@ where
                 })
             } else {
                 let (filename, bytes) = unordered.next().await.unwrap()??;
-
-                AtomicFile::new(filename, OverwriteBehavior::AllowOverwrite)
-                    .write(|f| f.write_all(&bytes))?;
+                write_dataset_file(sink.as_deref(), &filename, &bytes)?;
 
                 tiles_processed += 1;
                 progress_callback(
@@ -624,6 +1098,12 @@ where
             }
         }
 
+        write_dataset_file(
+            sink.as_deref(),
+            &tiles_directory.join(FINGERPRINT_FILENAME),
+            fingerprint.as_bytes(),
+        )?;
+
         Ok(())
     }
 }
@@ -1035,14 +1515,19 @@ pub(crate) async fn generate_albedos<F: FnMut(&str, usize, usize) + Send>(
     blue_marble_directory: impl AsRef<Path>,
     mut progress_callback: F,
 ) -> Result<(), Error> {
-    let (missing, total_tiles) = mapfile.get_missing_base(LayerType::BaseAlbedo);
-    if missing.is_empty() {
+    if mapfile.get_missing_base(LayerType::BaseAlbedo).0.is_empty() {
         return Ok(());
     }
 
-    let layer = mapfile.layers()[LayerType::BaseAlbedo].clone();
-    assert!(layer.texture_border_size >= 2);
+    let bluemarble = load_blue_marble(blue_marble_directory.as_ref(), &mut progress_callback)?;
+    generate_albedos_from_raster(mapfile, &bluemarble, progress_callback).await
+}
 
+/// Load NASA's Blue Marble: Next Generation imagery into a single global raster.
+fn load_blue_marble<F: FnMut(&str, usize, usize)>(
+    blue_marble_directory: &Path,
+    progress_callback: &mut F,
+) -> Result<GlobalRaster<u8>, Error> {
     let bm_dimensions = 21600;
     let mut values = vec![0u8; bm_dimensions * bm_dimensions * 8 * 3];
 
@@ -1055,12 +1540,11 @@ pub(crate) async fn generate_albedos<F: FnMut(&str, usize, usize) + Send>(
     let mut decoders = Vec::new();
     for x in 0..4 {
         for y in 0..2 {
-            let decoder =
-                PngDecoder::new(File::open(blue_marble_directory.as_ref().join(format!(
-                    "world.200406.3x21600x21600.{}{}.png",
-                    "ABCD".chars().nth(x).unwrap(),
-                    "12".chars().nth(y).unwrap()
-                )))?)?;
+            let decoder = PngDecoder::new(File::open(blue_marble_directory.join(format!(
+                "world.200406.3x21600x21600.{}{}.png",
+                "ABCD".chars().nth(x).unwrap(),
+                "12".chars().nth(y).unwrap()
+            )))?)?;
             assert_eq!(decoder.dimensions(), (bm_dimensions as u32, bm_dimensions as u32));
             assert_eq!(decoder.color_type(), ColorType::Rgb8);
             decoders.push(decoder.into_reader()?);
@@ -1076,8 +1560,33 @@ pub(crate) async fn generate_albedos<F: FnMut(&str, usize, usize) + Send>(
         decoders.par_iter_mut().zip(chunk).try_for_each(|(d, s)| d.read_exact(s))?;
     }
 
-    let bluemarble =
-        GlobalRaster { width: bm_dimensions * 4, height: bm_dimensions * 2, bands: 3, values };
+    Ok(GlobalRaster { width: bm_dimensions * 4, height: bm_dimensions * 2, bands: 3, values })
+}
+
+/// Generate albedo tiles from an arbitrary global raster.
+///
+/// `raster` must be in an equirectangular projection spanning the whole globe, with at least three
+/// bands holding sRGB encoded red, green and blue values. Any additional bands are ignored.
+pub(crate) async fn generate_albedos_from_raster<C, F>(
+    mapfile: &MapFile,
+    raster: &GlobalRaster<u8, C>,
+    progress_callback: F,
+) -> Result<(), Error>
+where
+    C: Index<usize, Output = u8> + Sync,
+    F: FnMut(&str, usize, usize) + Send,
+{
+    if raster.bands < 3 {
+        anyhow::bail!("albedo raster must have at least three bands, but has {}", raster.bands);
+    }
+
+    let (missing, total_tiles) = mapfile.get_missing_base(LayerType::BaseAlbedo);
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let layer = mapfile.layers()[LayerType::BaseAlbedo].clone();
+    assert!(layer.texture_border_size >= 2);
 
     let mapfile = &mapfile;
     let progress = &Mutex::new((total_tiles - missing.len(), progress_callback));
@@ -1110,9 +1619,9 @@ pub(crate) async fn generate_albedos<F: FnMut(&str, usize, usize) + Send>(
 
         for (lat, long) in coordinates {
             colormap.extend_from_slice(&[
-                SRGB_TO_LINEAR[bluemarble.interpolate(lat, long, 0) as u8],
-                SRGB_TO_LINEAR[bluemarble.interpolate(lat, long, 1) as u8],
-                SRGB_TO_LINEAR[bluemarble.interpolate(lat, long, 2) as u8],
+                SRGB_TO_LINEAR[raster.interpolate(lat, long, 0) as u8],
+                SRGB_TO_LINEAR[raster.interpolate(lat, long, 1) as u8],
+                SRGB_TO_LINEAR[raster.interpolate(lat, long, 2) as u8],
                 255,
             ]);
         }
@@ -1187,24 +1696,39 @@ pub(crate) async fn generate_materials<F: FnMut(String, usize, usize) + Send>(
     Ok(())
 }
 
-fn generate_noise(mapfile: &mut MapFile, context: &mut AssetLoadContext) -> Result<(), Error> {
-    if !mapfile.reload_texture("noise") {
+fn generate_noise(
+    mapfile: &mut MapFile,
+    config: &NoiseConfig,
+    context: &mut AssetLoadContext,
+) -> Result<(), Error> {
+    config.validate()?;
+    let fingerprint = config.fingerprint();
+    if !mapfile.reload_texture("noise")
+        || mapfile.texture_fingerprint("noise")? != Some(fingerprint.clone())
+    {
         // wavelength = 1.0 / 256.0;
         let noise_desc = TextureDescriptor {
-            width: 2048,
-            height: 2048,
+            width: config.resolution,
+            height: config.resolution,
             depth: 1,
             format: TextureFormat::RGBA8,
             array_texture: false,
         };
 
-        let noise_heightmaps: Vec<_> =
-            (0..4).map(|i| crate::terrain::heightmap::wavelet_noise(64 << i, 32 >> i)).collect();
+        let noise_heightmaps: Vec<_> = (0..config.octaves)
+            .map(|i| {
+                let frequency = (config.base_frequency << i) as usize;
+                crate::terrain::heightmap::wavelet_noise(
+                    frequency,
+                    config.resolution as usize / frequency,
+                )
+            })
+            .collect();
 
         context.reset("Generating noise textures... ", noise_heightmaps.len());
 
-        let len = noise_heightmaps[0].heights.len();
-        let mut heights = vec![0u8; len * 4];
+        let len = (config.resolution * config.resolution) as usize;
+        let mut heights = vec![128u8; len * 4];
         for (i, heightmap) in noise_heightmaps.into_iter().enumerate() {
             context.set_progress(i as u64);
             let mut dist: Vec<(usize, f32)> = heightmap.heights.into_iter().enumerate().collect();
@@ -1215,6 +1739,7 @@ fn generate_noise(mapfile: &mut MapFile, context: &mut AssetLoadContext) -> Resu
         }
 
         mapfile.write_texture("noise", noise_desc, &heights[..])?;
+        mapfile.set_texture_fingerprint("noise", &fingerprint)?;
     }
     Ok(())
 }
@@ -1223,7 +1748,9 @@ fn generate_sky(mapfile: &mut MapFile, context: &mut AssetLoadContext) -> Result
     if !mapfile.reload_texture("sky") {
         context.reset("Generating sky texture... ", 1);
         let sky = WebTextureAsset {
-            url: "https://www.eso.org/public/archives/images/original/eso0932a.tif".to_owned(),
+            urls: vec![
+                "https://www.eso.org/public/archives/images/original/eso0932a.tif".to_owned()
+            ],
             filename: "eso0932a.tif".to_owned(),
             format: TextureFormat::RGBA8,
         }
@@ -1261,7 +1788,7 @@ fn generate_sky(mapfile: &mut MapFile, context: &mut AssetLoadContext) -> Result
 fn download_cloudcover(mapfile: &mut MapFile, context: &mut AssetLoadContext) -> Result<(), Error> {
     if !mapfile.reload_texture("cloudcover") {
         let cloudcover = WebTextureAsset {
-            url: "https://terra.fintelia.io/file/terra-tiles/clouds_combined.png".to_owned(),
+            urls: mapfile.tile_server_file_urls("clouds_combined.png"),
             filename: "clouds_combined.png".to_owned(),
             format: TextureFormat::RGBA8,
         }
@@ -1272,13 +1799,37 @@ fn download_cloudcover(mapfile: &mut MapFile, context: &mut AssetLoadContext) ->
     Ok(())
 }
 
+fn download_night_lights(
+    mapfile: &mut MapFile,
+    context: &mut AssetLoadContext,
+) -> Result<(), Error> {
+    if !mapfile.reload_texture("night_lights") {
+        // Not every tile server hosts night lights, so carry on without them if they're missing.
+        // The renderer substitutes a black texture and disables the night light term.
+        let night_lights = WebTextureAsset {
+            urls: mapfile.tile_server_file_urls("night_lights.png"),
+            filename: "night_lights.png".to_owned(),
+            format: TextureFormat::RGBA8,
+        }
+        .load(context);
+        match night_lights {
+            Ok(night_lights) => {
+                mapfile.write_texture("night_lights", night_lights.0, &night_lights.1)?
+            }
+            Err(e) => log::warn!("Night lights are unavailable: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
 fn download_ground_albedo(
     mapfile: &mut MapFile,
     context: &mut AssetLoadContext,
 ) -> Result<(), Error> {
     if !mapfile.reload_texture("ground_albedo") {
         let texture = WebTextureAsset {
-            url: "https://terra.fintelia.io/file/terra-tiles/ground_albedo.basis".to_owned(),
+            urls: mapfile.tile_server_file_urls("ground_albedo.basis"),
             filename: "ground_albedo.basis".to_owned(),
             format: TextureFormat::UASTC,
         }
@@ -1289,24 +1840,24 @@ fn download_ground_albedo(
     Ok(())
 }
 
-fn download_models(context: &mut AssetLoadContext) -> Result<(), Error> {
+fn download_models(mapfile: &MapFile, context: &mut AssetLoadContext) -> Result<(), Error> {
     WebModel {
-        url: "https://terra.fintelia.io/file/terra-tiles/Oak_English_Sapling.zip".to_owned(),
+        urls: mapfile.tile_server_file_urls("Oak_English_Sapling.zip"),
         filename: "Oak_English_Sapling.zip".to_owned(),
     }
     .load(context)
 }
 
 struct WebTextureAsset {
-    url: String,
+    urls: Vec<String>,
     filename: String,
     format: TextureFormat,
 }
 impl WebAsset for WebTextureAsset {
     type Type = (TextureDescriptor, Vec<u8>);
 
-    fn url(&self) -> String {
-        self.url.clone()
+    fn urls(&self) -> Vec<String> {
+        self.urls.clone()
     }
     fn filename(&self) -> String {
         self.filename.clone()
@@ -1347,14 +1898,14 @@ impl WebAsset for WebTextureAsset {
 }
 
 struct WebModel {
-    url: String,
+    urls: Vec<String>,
     filename: String,
 }
 impl WebAsset for WebModel {
     type Type = ();
 
-    fn url(&self) -> String {
-        self.url.clone()
+    fn urls(&self) -> Vec<String> {
+        self.urls.clone()
     }
     fn filename(&self) -> String {
         self.filename.clone()
@@ -1362,4 +1913,89 @@ impl WebAsset for WebModel {
     fn parse(&self, _context: &mut AssetLoadContext, _data: Vec<u8>) -> Result<(), Error> {
         Ok(())
     }
+    fn max_size(&self) -> u64 {
+        256 << 20
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Look up pixels of a synthetic raster that increases by 100 per pixel from west to east.
+    fn gradient_lookup(coordinates: &[(f64, f64)], values: &mut [i16]) {
+        for (&(x, _), v) in coordinates.iter().zip(values.iter_mut()) {
+            *v = x.floor() as i16 * 100;
+        }
+    }
+
+    #[test]
+    fn bilinear_sampling_has_no_stair_steps() {
+        let coordinates: Vec<_> = (0..200).map(|i| (10.0 + i as f64 * 0.05, 5.5)).collect();
+
+        let mut nearest = vec![0i16; coordinates.len()];
+        sample_raster(
+            &coordinates,
+            SourceSampling::Nearest,
+            i16::MIN,
+            gradient_lookup,
+            &mut nearest,
+        );
+        assert!(nearest.windows(2).any(|w| w[1] - w[0] == 100));
+
+        let mut bilinear = vec![0i16; coordinates.len()];
+        sample_raster(
+            &coordinates,
+            SourceSampling::Bilinear,
+            i16::MIN,
+            gradient_lookup,
+            &mut bilinear,
+        );
+        for w in bilinear.windows(2) {
+            // Each step covers 0.05 pixels, so should rise by 5 give or take rounding.
+            assert!((4..=6).contains(&(w[1] - w[0])), "step from {} to {}", w[0], w[1]);
+        }
+        // Pixel centers are exact.
+        assert_eq!(bilinear[10], 1000);
+    }
+
+    #[test]
+    fn merged_tile_count_matches_merged_nodes() {
+        for max_level in [0, VNode::LEVEL_CELL_1KM, VNode::LEVEL_CELL_76M, VNode::LEVEL_CELL_5M] {
+            let levels = merged_levels(max_level);
+            let mut nodes = 0;
+            VNode::breadth_first(|n| {
+                nodes += 1;
+                n.level() < *levels.end()
+            });
+            assert_eq!(LayerType::Heightmaps.tile_count(levels, [true; 6]), nodes);
+        }
+        assert_eq!(merged_levels(VNode::LEVEL_CELL_5M), 0..=VNode::LEVEL_CELL_76M);
+    }
+
+    #[test]
+    fn progress_channel_delivers_updates_across_threads() {
+        let (sender, receiver) = progress_channel();
+        let mut callback = sender.clone().into_callback();
+        std::thread::spawn(move || {
+            callback("reprojecting...".to_owned(), 1, 2);
+            sender.send("merging...", 2, 2);
+        })
+        .join()
+        .unwrap();
+
+        let updates: Vec<_> = receiver.try_iter().collect();
+        assert_eq!(
+            updates,
+            [
+                GenerationProgress { stage: "reprojecting...".to_owned(), completed: 1, total: 2 },
+                GenerationProgress { stage: "merging...".to_owned(), completed: 2, total: 2 },
+            ]
+        );
+
+        // Updates sent after the receiver is gone are silently dropped.
+        let (sender, receiver) = progress_channel();
+        drop(receiver);
+        sender.send("done", 1, 1);
+    }
 }
@@ -1,4 +1,4 @@
-use std::{borrow::Cow, collections::HashMap};
+use std::{borrow::Cow, collections::HashMap, num::NonZeroU8};
 
 use crate::{
     billboards::Models,
@@ -27,14 +27,49 @@ pub(crate) struct GlobalUniformBlock {
     pub view_proj: mint::ColumnMatrix4<f32>,
     pub view_proj_inverse: mint::ColumnMatrix4<f32>,
     pub shadow_view_proj: mint::ColumnMatrix4<f32>,
-    pub frustum_planes: [[f32; 4]; 5],
+    pub frustum_planes: [[f32; 4]; 6],
     pub camera: [f32; 3],
     pub screen_width: f32,
     pub sun_direction: [f32; 3],
     pub screen_height: f32,
     pub sidereal_time: f32,
     pub exposure: f32,
-    pub _padding: [f32; 2],
+    pub sun_angular_radius: f32,
+    pub sun_limb_darkening: u32,
+    pub sun_illuminance: [f32; 3],
+    pub shadow_softness: f32,
+    pub lod_morph_range: f32,
+    pub space_view: u32,
+    pub season: f32,
+    pub terrain_mesh_entries: u32,
+    pub night_lights_intensity: f32,
+    pub texture_lod_bias: f32,
+    pub far_depth: f32,
+    pub time: f32,
+    pub wind_direction: f32,
+    pub wind_strength: f32,
+    pub wind_gust_frequency: f32,
+    pub tree_model_distance: f32,
+    pub tree_model_fade: f32,
+    pub shadow_kernel_radius: u32,
+    pub ao_strength: f32,
+    pub star_visibility_threshold: f32,
+    pub visualization: u32,
+    pub hillshade_azimuth: f32,
+    pub hillshade_altitude: f32,
+    pub _padding0: f32,
+    pub camera_offset: [f32; 3],
+    pub vertical_exaggeration: f32,
+    pub contrast: f32,
+    pub saturation: f32,
+    pub gamma: f32,
+    pub lighting: u32,
+    pub sky_zenith_color: [f32; 3],
+    pub ambient: f32,
+    pub sky_horizon_color: [f32; 3],
+    pub limb_antialiasing: f32,
+    pub moon_direction: [f32; 3],
+    pub _padding1: f32,
 }
 unsafe impl bytemuck::Pod for GlobalUniformBlock {}
 unsafe impl bytemuck::Zeroable for GlobalUniformBlock {}
@@ -49,6 +84,8 @@ pub(crate) struct GpuState {
 
     pub model_storage: wgpu::Buffer,
     pub model_indices: wgpu::Buffer,
+    pub tree_model_instances: wgpu::Buffer,
+    pub tree_model_indirect: wgpu::Buffer,
 
     pub globals: wgpu::Buffer,
     pub generate_uniforms: wgpu::Buffer,
@@ -60,6 +97,8 @@ pub(crate) struct GpuState {
     noise: (wgpu::Texture, wgpu::TextureView),
     sky: (wgpu::Texture, wgpu::TextureView),
     cloudcover: (wgpu::Texture, wgpu::TextureView),
+    night_lights: (wgpu::Texture, wgpu::TextureView),
+    pub has_night_lights: bool,
     transmittance: (wgpu::Texture, wgpu::TextureView),
     inscattering: (wgpu::Texture, wgpu::TextureView),
     skyview: (wgpu::Texture, wgpu::TextureView),
@@ -81,6 +120,7 @@ pub(crate) struct GpuState {
     nearest: wgpu::Sampler,
     linear: wgpu::Sampler,
     linear_wrap: wgpu::Sampler,
+    anisotropic: wgpu::Sampler,
     shadow_sampler: wgpu::Sampler,
 }
 impl GpuState {
@@ -90,7 +130,19 @@ impl GpuState {
         mapfile: &MapFile,
         cache: &TileCache,
         models: &Models,
+        anisotropy: u8,
+        downlevel_flags: wgpu::DownlevelFlags,
+        shadow_map_resolution: u32,
     ) -> Result<Self, anyhow::Error> {
+        // wgpu only accepts powers of two up to 16, so round down to the nearest one. Adapters
+        // without anisotropic filtering reject any clamp at all.
+        let anisotropic = downlevel_flags.contains(wgpu::DownlevelFlags::ANISOTROPIC_FILTERING);
+        let anisotropy = if anisotropic { anisotropy } else { 1 };
+        let anisotropy_clamp = match anisotropy.min(16) {
+            0 | 1 => None,
+            a => NonZeroU8::new(1 << (7 - a.leading_zeros())),
+        };
+
         let with_view = |name: &'static str, t: wgpu::Texture| {
             let view = t.create_view(&wgpu::TextureViewDescriptor {
                 label: Some(&format!("texture.{}.view", name)),
@@ -99,12 +151,37 @@ impl GpuState {
             (t, view)
         };
 
+        // Night lights are optional, so fall back to a black placeholder if they weren't downloaded.
+        let has_night_lights = mapfile.reload_texture("night_lights");
+
         let (model_storage, model_indices) = models.make_buffers(device);
+        let (tree_model_instances, tree_model_indirect) = models.make_tree_model_buffers(device);
 
         Ok(GpuState {
             noise: with_view("noise", mapfile.read_texture(device, queue, "noise")?),
             sky: with_view("sky", mapfile.read_texture(device, queue, "sky")?),
             cloudcover: with_view("sky", mapfile.read_texture(device, queue, "cloudcover")?),
+            night_lights: with_view(
+                "night_lights",
+                if has_night_lights {
+                    mapfile.read_texture(device, queue, "night_lights")?
+                } else {
+                    device.create_texture_with_data(
+                        queue,
+                        &wgpu::TextureDescriptor {
+                            size: wgpu::Extent3d { width: 1, height: 1, depth_or_array_layers: 1 },
+                            format: wgpu::TextureFormat::Rgba8Unorm,
+                            mip_level_count: 1,
+                            sample_count: 1,
+                            dimension: wgpu::TextureDimension::D2,
+                            usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                            label: Some("texture.night_lights"),
+                        },
+                        &[0, 0, 0, 255],
+                    )
+                },
+            ),
+            has_night_lights,
             transmittance: with_view(
                 "transmittance",
                 mapfile.read_texture(device, queue, "transmittance")?,
@@ -148,7 +225,11 @@ impl GpuState {
             shadowmap: with_view(
                 "shadowmap",
                 device.create_texture(&wgpu::TextureDescriptor {
-                    size: wgpu::Extent3d { width: 8192, height: 8192, depth_or_array_layers: 1 },
+                    size: wgpu::Extent3d {
+                        width: shadow_map_resolution,
+                        height: shadow_map_resolution,
+                        depth_or_array_layers: 1,
+                    },
                     format: wgpu::TextureFormat::Depth24Plus,
                     mip_level_count: 1,
                     sample_count: 1,
@@ -182,6 +263,8 @@ impl GpuState {
             }),
             model_storage,
             model_indices,
+            tree_model_instances,
+            tree_model_indirect,
             starfield: crate::sky::create_starfield(device),
             globals: device.create_buffer(&wgpu::BufferDescriptor {
                 size: std::mem::size_of::<GlobalUniformBlock>() as u64,
@@ -239,10 +322,22 @@ impl GpuState {
                 address_mode_w: wgpu::AddressMode::Repeat,
                 mag_filter: wgpu::FilterMode::Linear,
                 min_filter: wgpu::FilterMode::Linear,
-                mipmap_filter: wgpu::FilterMode::Nearest,
+                mipmap_filter: wgpu::FilterMode::Linear,
+                anisotropy_clamp,
                 label: Some("sampler.linear_wrap"),
                 ..Default::default()
             }),
+            anisotropic: device.create_sampler(&wgpu::SamplerDescriptor {
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                mipmap_filter: wgpu::FilterMode::Linear,
+                anisotropy_clamp,
+                label: Some("sampler.anisotropic"),
+                ..Default::default()
+            }),
             shadow_sampler: device.create_sampler(&wgpu::SamplerDescriptor {
                 address_mode_u: wgpu::AddressMode::ClampToEdge,
                 address_mode_v: wgpu::AddressMode::ClampToEdge,
@@ -257,6 +352,33 @@ impl GpuState {
         })
     }
 
+    /// Bytes of GPU memory that `new` allocates for everything other than the tile cache, mesh
+    /// caches and shadow map: the sky, noise and ground textures, models, billboards, and the
+    /// node and uniform buffers.
+    pub(crate) fn other_bytes(mapfile: &MapFile, models: &Models) -> Result<u64, anyhow::Error> {
+        let mut bytes = 0;
+        for name in [
+            "noise",
+            "sky",
+            "cloudcover",
+            "night_lights",
+            "transmittance",
+            "inscattering",
+            "ground_albedo",
+        ] {
+            bytes += mapfile.texture_bytes(name)?;
+        }
+
+        let skyview = 128 * 128 * 8;
+        let slots = TileCache::base_slot(MAX_QUADTREE_LEVEL + 1) as u64;
+        let buffers = std::mem::size_of::<GlobalUniformBlock>() as u64
+            + 256 * 1024
+            + 16 * crate::sky::NUM_STARS as u64
+            + (4 + 1024) * slots;
+
+        Ok(bytes + skyview + buffers + models.gpu_bytes())
+    }
+
     pub(crate) fn bind_group_for_shader(
         &self,
         device: &wgpu::Device,
@@ -281,6 +403,7 @@ impl GpuState {
                                 "noise" => &self.noise.1,
                                 "sky" => &self.sky.1,
                                 "cloudcover" => &self.cloudcover.1,
+                                "night_lights" => &self.night_lights.1,
                                 "transmittance" => &self.transmittance.1,
                                 "inscattering" => &self.inscattering.1,
                                 "skyview" => &self.skyview.1,
@@ -293,14 +416,24 @@ impl GpuState {
                                 "topdown_normals" => &self.topdown_normals.1,
                                 "shadowmap" => &self.shadowmap.1,
                                 "ground_albedo" => &self.ground_albedo.1,
-                                _ => match name.rsplit_once(char::is_numeric) {
-                                    Some((name, suffix)) => {
-                                        &self.tile_cache[LAYERS_BY_NAME[name]]
-                                            [suffix.parse::<usize>().unwrap()]
-                                        .1
-                                    }
-                                    None => &self.tile_cache[LAYERS_BY_NAME[name]][0].1,
-                                },
+                                _ => {
+                                    let (layer, index) = match name.rsplit_once(char::is_numeric) {
+                                        Some((name, suffix)) => {
+                                            (name, suffix.parse::<usize>().unwrap())
+                                        }
+                                        None => (name, 0),
+                                    };
+                                    let textures = self
+                                        .tile_cache
+                                        .get(LAYERS_BY_NAME[layer].index())
+                                        .unwrap_or_else(|| {
+                                            panic!(
+                                                "'{}' binds the {} layer, which is disabled",
+                                                group_name, layer
+                                            )
+                                        });
+                                    &textures[index].1
+                                }
                             },
                         );
                     }
@@ -311,6 +444,8 @@ impl GpuState {
                             "mesh_indirect" => &self.mesh_indirect,
                             "mesh_bounding" => &self.mesh_bounding,
                             "model_storage" => &self.model_storage,
+                            "tree_model_instances" => &self.tree_model_instances,
+                            "tree_model_indirect" => &self.tree_model_indirect,
                             "grass_storage" => &self.mesh_storage[MeshType::Grass],
                             "tree_billboards_storage" => {
                                 &self.mesh_storage[MeshType::TreeBillboards]
@@ -347,6 +482,7 @@ impl GpuState {
                             }
                             "linear" => &self.linear,
                             "linear_wrap" => &self.linear_wrap,
+                            "anisotropic" => &self.anisotropic,
                             "shadow_sampler" => &self.shadow_sampler,
                             _ => unreachable!("unrecognized sampler: {}", name),
                         })
@@ -391,3 +527,30 @@ impl GpuState {
         (bind_group, bind_group_layout)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::GlobalUniformBlock;
+
+    // Must match the std140 layout of `Globals` in declarations.glsl, where every vec3 is aligned
+    // to 16 bytes.
+    #[test]
+    fn global_uniform_block_layout() {
+        fn offset<T>(block: &GlobalUniformBlock, field: &T) -> usize {
+            field as *const T as usize - block as *const GlobalUniformBlock as usize
+        }
+        let b: GlobalUniformBlock = bytemuck::Zeroable::zeroed();
+
+        assert_eq!(std::mem::size_of::<GlobalUniformBlock>(), 512);
+        assert_eq!(offset(&b, &b.sun_illuminance), 336);
+        assert_eq!(offset(&b, &b.camera_offset), 432);
+        assert_eq!(offset(&b, &b.vertical_exaggeration), 444);
+        assert_eq!(offset(&b, &b.gamma), 456);
+        assert_eq!(offset(&b, &b.lighting), 460);
+        assert_eq!(offset(&b, &b.sky_zenith_color), 464);
+        assert_eq!(offset(&b, &b.ambient), 476);
+        assert_eq!(offset(&b, &b.sky_horizon_color), 480);
+        assert_eq!(offset(&b, &b.limb_antialiasing), 492);
+        assert_eq!(offset(&b, &b.moon_direction), 496);
+    }
+}
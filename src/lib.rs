@@ -12,6 +12,8 @@ mod billboards;
 mod cache;
 mod coordinates;
 pub mod download;
+mod error;
+mod export;
 mod generate;
 mod gpu_state;
 mod mapfile;
@@ -20,47 +22,684 @@ mod speedtree_xml;
 mod srgb;
 mod stream;
 mod terrain;
+mod tile_source;
 
-use crate::cache::{LayerType, MeshCacheDesc, MeshType};
+use crate::cache::MeshCacheDesc;
 use crate::generate::MapFileBuilder;
 use crate::mapfile::MapFile;
-use anyhow::Error;
+use anyhow::Context;
 use billboards::Models;
 use cache::TileCache;
-use cgmath::{SquareMatrix, Zero};
+use cgmath::{InnerSpace, SquareMatrix, Zero};
 use generate::ComputeShader;
 use gpu_state::{GlobalUniformBlock, GpuState};
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use terrain::quadtree::QuadTree;
-use types::{InfiniteFrustum, VNode};
+use types::InfiniteFrustum;
 
-pub use crate::generate::BLUE_MARBLE_URLS;
+pub use crate::cache::{EvictionPolicy, LayerMask, LayerType, MeshType, VegetationConfig};
+pub use crate::error::Error;
+pub use crate::export::{MapExport, MapProjection, MapTileContents, TileScheme};
+pub use crate::generate::{
+    progress_channel, GenerationProgress, GenerationTimings, NoiseConfig, ProgressSender,
+    RasterCodec, TiffCodec, BLUE_MARBLE_URLS,
+};
+pub use crate::tile_source::{TileSink, TileSource};
+pub use types::{ParseVNodeError, VNode, MAX_QUADTREE_LEVEL, NODE_OFFSETS};
+
+/// Appearance of the sun disc drawn in the sky, and of the light it casts.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SunConfig {
+    /// Angular radius of the sun disc in radians.
+    pub angular_radius: f32,
+    /// Linear RGB tint of sunlight before it enters the atmosphere. Applies to the sun disc, the
+    /// light falling on terrain and vegetation, and the light scattered by the atmosphere, so a
+    /// warm tint gives a golden hour look. The atmosphere already reddens the sun near the
+    /// horizon on its own.
+    pub color: [f32; 3],
+    /// Illuminance of sunlight before it enters the atmosphere, in the units that `exposure`
+    /// expects. Scales the sun disc, direct and ambient lighting and the sky together, so they
+    /// stay consistent. Defaults to 100000, about the illuminance of real sunlight in lux.
+    pub intensity: f32,
+    /// Whether to darken the disc towards its edge.
+    pub limb_darkening: bool,
+}
+impl Default for SunConfig {
+    fn default() -> Self {
+        Self {
+            angular_radius: 0.00465,
+            color: [1.0, 1.0, 1.0],
+            intensity: 100000.0,
+            limb_darkening: true,
+        }
+    }
+}
+impl SunConfig {
+    /// Tinted illuminance of sunlight, as passed to the shaders.
+    fn illuminance(&self) -> [f32; 3] {
+        let [r, g, b] = self.color;
+        [r * self.intensity, g * self.intensity, b * self.intensity]
+    }
+}
+
+/// Wind that sways grass and, more subtly, tree billboards.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WindConfig {
+    /// Direction the wind blows towards, in radians clockwise from north.
+    pub direction: f32,
+    /// How far vegetation bends, from zero for still air to one for a strong wind.
+    pub strength: f32,
+    /// Number of gusts per second that sweep across the ground in the wind direction.
+    pub gust_frequency: f32,
+}
+impl Default for WindConfig {
+    fn default() -> Self {
+        Self { direction: 0.0, strength: 0.3, gust_frequency: 0.2 }
+    }
+}
+
+/// Color adjustments applied after tonemapping, set with `Terrain::set_color_grading`. The
+/// defaults leave colors unchanged.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ColorGrading {
+    /// Scale applied to the distance of each channel from middle gray. Defaults to 1.
+    pub contrast: f32,
+    /// Scale applied to the distance of each color from the gray of the same luminance, from 0
+    /// for grayscale upwards. Defaults to 1.
+    pub saturation: f32,
+    /// Gamma correction on top of the display encoding, where values above 1 brighten midtones
+    /// and values below 1 darken them. Defaults to 1.
+    pub gamma: f32,
+}
+impl Default for ColorGrading {
+    fn default() -> Self {
+        Self { contrast: 1.0, saturation: 1.0, gamma: 1.0 }
+    }
+}
+
+/// What the terrain surface shows, set with `Terrain::set_visualization`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Viz {
+    /// Albedo lit by the sun, with shadows and the atmosphere applied.
+    Realistic,
+    /// Classic grayscale relief shading, as on a topographic map. Each point is shaded by the
+    /// cosine of the angle between its surface normal and a light at `azimuth` radians clockwise
+    /// from north and `altitude` radians above the local horizon. The sun, shadows and atmosphere
+    /// have no effect. Vegetation is still drawn unless disabled in `TerrainConfig::layers`.
+    Hillshade { azimuth: f32, altitude: f32 },
+}
+impl Default for Viz {
+    fn default() -> Self {
+        Viz::Realistic
+    }
+}
+
+/// How the sky and the light reaching the ground are computed, set with `Terrain::set_lighting`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Lighting {
+    /// Physically based scattering in the atmosphere, which shades the sky and the haze over
+    /// distant terrain.
+    Atmosphere,
+    /// A cheap analytic sky with flat ambient light and no haze, for stylized rendering or
+    /// slower hardware. Skips generating the skyview LUT and aerial perspective, and sampling
+    /// them while shading. The sun disc is drawn without atmospheric attenuation, and the sky is
+    /// black in space view.
+    Simple(SimpleLighting),
+}
+impl Default for Lighting {
+    fn default() -> Self {
+        Lighting::Atmosphere
+    }
+}
+
+/// Parameters of `Lighting::Simple`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SimpleLighting {
+    /// Linear RGB color of the sky straight overhead, as a fraction of the brightness of a white
+    /// surface facing the sun. The sky fades to black as the sun sets. Defaults to a light blue.
+    pub zenith_color: [f32; 3],
+    /// Linear RGB color of the sky at and below the horizon, in the same units as
+    /// `zenith_color`. Defaults to a pale blue.
+    pub horizon_color: [f32; 3],
+    /// Light reaching every surface regardless of its orientation, as a fraction of the
+    /// illuminance of the sun at noon. It fades as the sun sets. Defaults to 0.15.
+    pub ambient: f32,
+}
+impl Default for SimpleLighting {
+    fn default() -> Self {
+        Self { zenith_color: [0.08, 0.16, 0.35], horizon_color: [0.3, 0.38, 0.45], ambient: 0.15 }
+    }
+}
+
+/// Clip space depth convention of the view-projection matrices passed to `update` and `render`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ClipDepth {
+    /// Depth goes from 1 at the near plane to 0 at the far plane or at infinity.
+    ReversedZ,
+    /// Depth goes from 0 at the near plane to 1 at the far plane, wgpu's native range.
+    ZeroToOne,
+    /// Depth goes from -1 at the near plane to 1 at the far plane, as in OpenGL. Matrices are
+    /// remapped to wgpu's 0 to 1 range, so depth is written as for `ZeroToOne`.
+    NegativeOneToOne,
+}
+impl ClipDepth {
+    fn reversed(self) -> bool {
+        self == ClipDepth::ReversedZ
+    }
+
+    /// Depth value of points infinitely far away, which the depth buffer is cleared to.
+    fn far_depth(self) -> f32 {
+        if self.reversed() {
+            0.0
+        } else {
+            1.0
+        }
+    }
+
+    /// Comparison that passes for fragments closer to the camera than the stored depth.
+    fn depth_compare(self, or_equal: bool) -> wgpu::CompareFunction {
+        match (self.reversed(), or_equal) {
+            (true, false) => wgpu::CompareFunction::Greater,
+            (true, true) => wgpu::CompareFunction::GreaterEqual,
+            (false, false) => wgpu::CompareFunction::Less,
+            (false, true) => wgpu::CompareFunction::LessEqual,
+        }
+    }
+
+    /// Convert a view-projection matrix in this convention to one with wgpu's depth range.
+    fn to_wgpu(self, view_proj: mint::ColumnMatrix4<f32>) -> mint::ColumnMatrix4<f32> {
+        match self {
+            ClipDepth::ReversedZ | ClipDepth::ZeroToOne => view_proj,
+            ClipDepth::NegativeOneToOne => {
+                let remap = cgmath::Matrix4::new(
+                    1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.5, 0.0, 0.0, 0.0, 0.5, 1.0,
+                );
+                (remap * cgmath::Matrix4::from(view_proj)).into()
+            }
+        }
+    }
+}
+impl Default for ClipDepth {
+    fn default() -> Self {
+        ClipDepth::ReversedZ
+    }
+}
+
+/// Preset trading shadow quality for GPU time and memory.
+///
+/// | Preset   | Shadow map    | Filter kernel       |
+/// |----------|---------------|---------------------|
+/// | `Low`    | 2048 x 2048   | 1 tap (hard edges)  |
+/// | `Medium` | 4096 x 4096   | 3 x 3 taps          |
+/// | `High`   | 8192 x 8192   | 5 x 5 taps          |
+///
+/// There is a single shadow map covering the nearby part of the view, so no preset adds
+/// cascades. The width of the filter kernel is still set by `Terrain::set_shadow_softness`,
+/// the preset only controls how many samples it is made of.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ShadowQuality {
+    Low,
+    Medium,
+    High,
+}
+impl ShadowQuality {
+    fn resolution(self) -> u32 {
+        match self {
+            ShadowQuality::Low => 2048,
+            ShadowQuality::Medium => 4096,
+            ShadowQuality::High => 8192,
+        }
+    }
+
+    /// Bytes of GPU memory taken by the shadow map, which stores four bytes per texel.
+    fn shadow_map_bytes(self) -> u64 {
+        self.resolution() as u64 * self.resolution() as u64 * 4
+    }
+
+    /// Filter kernel samples on each side of the center tap.
+    fn kernel_radius(self) -> u32 {
+        match self {
+            ShadowQuality::Low => 0,
+            ShadowQuality::Medium => 1,
+            ShadowQuality::High => 2,
+        }
+    }
+}
+impl Default for ShadowQuality {
+    fn default() -> Self {
+        ShadowQuality::High
+    }
+}
+
+/// How the sky is shaded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SkyMode {
+    /// Use `Surface` while the camera is inside the atmosphere and `Space` once it is above it.
+    Automatic,
+    /// Look up the sky color from a view LUT that is regenerated every frame. Fast, but only
+    /// accurate for observers within the atmosphere.
+    Surface,
+    /// Ray march the atmosphere per pixel, which correctly shows the planet's limb from orbit.
+    /// The view LUT isn't generated in this mode.
+    Space,
+}
+impl Default for SkyMode {
+    fn default() -> Self {
+        SkyMode::Automatic
+    }
+}
+
+/// Options that are fixed when a `Terrain` is constructed.
+#[derive(Clone)]
+pub struct TerrainConfig {
+    /// Which side of the terrain mesh to cull. Grass and tree billboards are always drawn double
+    /// sided.
+    pub terrain_cull_mode: Option<wgpu::Face>,
+    /// Winding order of front facing triangles, applied to every mesh type. Engines whose
+    /// projection flips handedness relative to terra's (right handed, reversed-Z) should use
+    /// `wgpu::FrontFace::Cw`, otherwise terrain will only be visible from below.
+    pub front_face: wgpu::FrontFace,
+    /// How the tile cache chooses which tiles to evict.
+    pub eviction_policy: EvictionPolicy,
+    /// Layers and meshes to stream, generate and allocate GPU memory for. Applications that
+    /// only need heights can pass `LayerType::Heightmaps.bit_mask()` to skip everything else.
+    /// Heightmaps cannot be disabled, and construction fails if anything that is enabled depends
+    /// on a disabled layer.
+    pub layers: LayerMask,
+    /// Elevation in the source heightmaps that is treated as sea level. Terrain at or below it is
+    /// considered underwater, and heights returned by `get_height` are measured from it.
+    pub sea_level: f32,
+    /// Lowest height relative to `sea_level` that terrain can reach. Deeper values are clamped
+    /// both when generating and when streaming heightmaps. Cannot be lower than -1024 meters.
+    pub height_floor: f32,
+    /// Backend to fetch streamed tiles from when they aren't already cached on disk. Defaults to
+    /// downloading them from terra's tile server.
+    pub tile_source: Option<Arc<dyn TileSource>>,
+    /// Destination for generated tiles, and for the sectors and tiles that
+    /// `generate_and_new_with_config` produces from source datasets. Defaults to writing them to
+    /// disk, in the tile cache and the dataset directory respectively.
+    pub tile_sink: Option<Arc<dyn TileSink>>,
+    /// Resolution and octaves of the noise texture that provides fine surface detail.
+    pub noise: NoiseConfig,
+    /// Approximate limit in bytes on the working memory of the sectors that
+    /// `generate_and_new_with_config` reprojects at once, not counting the cache of source
+    /// rasters. Fewer sectors are processed in parallel to stay within it, down to one at a
+    /// time, which keeps high detail generation from running out of memory at the cost of
+    /// speed. `None` processes 16 sectors at a time regardless of their size. Defaults to `None`.
+    pub reprojection_memory_budget: Option<u64>,
+    /// Finest level that `generate_and_new_with_config` reprojects datasets to. Lowering it,
+    /// for instance to `VNode::LEVEL_CELL_1KM`, gives draft quality terrain in a fraction of
+    /// the time. Raising it again later only generates the missing finer levels. Defaults to
+    /// `VNode::LEVEL_CELL_76M`.
+    pub max_generation_level: u8,
+    /// Format of the depth buffer passed to `render`. Must be a depth format.
+    pub depth_format: wgpu::TextureFormat,
+    /// How tree cover data is turned into rendered trees.
+    pub vegetation: VegetationConfig,
+    /// Maximum anisotropic filtering level for ground and model textures, which keeps them sharp
+    /// at grazing angles. Rounded down to a power of two and clamped to 16, the most wgpu allows.
+    /// Zero or one disables anisotropic filtering, as does an adapter without
+    /// `DownlevelFlags::ANISOTROPIC_FILTERING`. Defaults to 16.
+    pub anisotropy: u8,
+    /// Downlevel capabilities of the adapter `device` was requested from, as reported by
+    /// `wgpu::Adapter::get_downlevel_properties`. Features the adapter lacks are disabled rather
+    /// than triggering validation errors. Defaults to a fully compliant adapter.
+    pub downlevel_flags: wgpu::DownlevelFlags,
+    /// Which of the six cube faces the planet is split into are streamed and rendered, indexed by
+    /// `VNode::face`. The faces are centered on 0°E, 180°E, 90°E and 90°W at the equator, then the
+    /// north and south poles. Regional applications can disable all but one to avoid loading
+    /// tiles for the rest of the planet. At least one face must be enabled.
+    pub faces: [bool; 6],
+    /// Largest response, in bytes, accepted when downloading tiles or tile lists from terra's
+    /// tile server. Larger downloads fail with an error instead of being read into memory.
+    /// Doesn't apply to a custom `tile_source`. Defaults to 64 MiB.
+    pub max_download_size: u64,
+    /// Base URLs that tiles, tile lists and the textures and models hosted with them are
+    /// downloaded from. Each download tries the URLs in order and moves on to the next if one
+    /// fails, so a local mirror or CDN can be listed ahead of terra's own tile server, which is
+    /// the default. Tile lists are instead downloaded from every URL that answers and merged, so
+    /// mirrors only need to hold part of the data, as long as another URL has the rest. Doesn't
+    /// apply to a custom `tile_source`. Must not be empty.
+    pub tile_server_urls: Vec<String>,
+    /// Maximum number of streamed tiles that are decoded at once, including heightmaps that have
+    /// to be reconstructed from a compressed ancestor. This is separate from the limit on tiles
+    /// being read from disk or downloaded, so a burst of arriving tiles queues up instead of
+    /// occupying every CPU core while the application is running. Decoding happens on the
+    /// streaming thread's own tokio runtime and never uses rayon's global thread pool, which is
+    /// only used when generating tiles from source datasets. Must be at least one. Defaults to 4.
+    pub max_concurrent_tile_decodes: usize,
+    /// Number of pieces each terrain node's 64x64 quad grid is split into for frustum culling.
+    /// The grid is divided into an n x n arrangement of equally sized blocks, so this must be
+    /// 1, 4 or 16 (n = 1, 2 or 4): each piece is a separate entry in the terrain mesh cache with
+    /// its own bounding sphere and indirect draw, and covers a contiguous range of the shared
+    /// index buffer. Tessellation is unaffected, since every node is still drawn as 64x64 quads.
+    /// More entries cull tighter around the view frustum at the cost of more indirect draws and
+    /// more bounding sphere memory per node. Defaults to 4.
+    pub terrain_mesh_entries: u32,
+    /// Approximate limit in bytes on the GPU memory allocated when the terrain is created, for
+    /// GPUs with little video memory. If the configured settings would exceed it,
+    /// `terrain_mesh_entries` is lowered first and then `shadow_quality`, one step at a time,
+    /// until they fit. Construction fails with `Error::InvalidConfig` if even the lowest settings
+    /// don't; disabling `layers` is the main way to shrink the tile cache further. The settings
+    /// that were chosen are reported by `Terrain::gpu_memory_usage`. Defaults to `None`.
+    pub gpu_memory_budget: Option<u64>,
+    /// Depth convention of the view-projection matrices passed to `update` and `render`, which
+    /// determines the depth comparisons used and the value the depth buffer is cleared to.
+    /// Defaults to `ClipDepth::ReversedZ`.
+    pub clip_depth: ClipDepth,
+    /// Resolution of the shadow map and number of samples used to filter it. Defaults to
+    /// `ShadowQuality::High`.
+    pub shadow_quality: ShadowQuality,
+    /// Depth bias applied while rendering the shadow map, to keep sloped terrain from shadowing
+    /// itself (shadow acne). `constant` is in units of the smallest representable depth
+    /// difference and `slope_scale` is multiplied by the depth slope of each triangle as seen
+    /// from the sun. Too much bias detaches shadows from the objects casting them. Defaults to a
+    /// constant of 2 and a slope scale of 2.
+    pub shadow_depth_bias: wgpu::DepthBiasState,
+    /// GLSL source inserted into the terrain fragment shader to customize its surface, such as
+    /// tinting regions or applying custom biome rules. It must define
+    ///
+    /// ```glsl
+    /// void custom_shading(inout vec3 albedo, inout float roughness, inout vec3 normal,
+    ///                     vec3 world_position, float height);
+    /// ```
+    ///
+    /// which is called for every terrain fragment before lighting, after seasonal effects have
+    /// been applied. `normal` is the unit surface normal and `world_position` the position in
+    /// meters relative to the center of the planet, with the same axes as the `camera` passed to
+    /// `update`. `height` is the distance above a sphere of radius 6371 km. Both are single
+    /// precision, so are only accurate to around a meter. The snippet is included after all of
+    /// the shader's uniform and texture declarations, so it can also read `globals` and sample
+    /// the terrain textures. Construction fails with `Error::InvalidConfig` if the shader doesn't
+    /// compile. Defaults to `None`.
+    pub custom_shading: Option<String>,
+    /// Generated datasets to stream layers from in place of `tile_source`, at most one per
+    /// layer. Only `LayerType::Heightmaps` can be selected, which makes it possible to switch
+    /// between elevation sources without regenerating anything. Defaults to empty.
+    pub datasets: Vec<DatasetSelection>,
+}
+impl std::fmt::Debug for TerrainConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TerrainConfig")
+            .field("terrain_cull_mode", &self.terrain_cull_mode)
+            .field("front_face", &self.front_face)
+            .field("eviction_policy", &self.eviction_policy)
+            .field("layers", &self.layers)
+            .field("sea_level", &self.sea_level)
+            .field("height_floor", &self.height_floor)
+            .field("tile_source", &self.tile_source.as_ref().map(|_| "TileSource"))
+            .field("tile_sink", &self.tile_sink.as_ref().map(|_| "TileSink"))
+            .field("noise", &self.noise)
+            .field("reprojection_memory_budget", &self.reprojection_memory_budget)
+            .field("max_generation_level", &self.max_generation_level)
+            .field("depth_format", &self.depth_format)
+            .field("vegetation", &self.vegetation)
+            .field("anisotropy", &self.anisotropy)
+            .field("downlevel_flags", &self.downlevel_flags)
+            .field("faces", &self.faces)
+            .field("max_download_size", &self.max_download_size)
+            .field("tile_server_urls", &self.tile_server_urls)
+            .field("max_concurrent_tile_decodes", &self.max_concurrent_tile_decodes)
+            .field("terrain_mesh_entries", &self.terrain_mesh_entries)
+            .field("gpu_memory_budget", &self.gpu_memory_budget)
+            .field("clip_depth", &self.clip_depth)
+            .field("shadow_quality", &self.shadow_quality)
+            .field("shadow_depth_bias", &self.shadow_depth_bias)
+            .field("custom_shading", &self.custom_shading)
+            .field("datasets", &self.datasets)
+            .finish()
+    }
+}
+impl Default for TerrainConfig {
+    fn default() -> Self {
+        Self {
+            terrain_cull_mode: Some(wgpu::Face::Front),
+            front_face: wgpu::FrontFace::Ccw,
+            eviction_policy: EvictionPolicy::default(),
+            layers: LayerMask::all(),
+            sea_level: 0.0,
+            height_floor: -1024.0,
+            tile_source: None,
+            tile_sink: None,
+            noise: NoiseConfig::default(),
+            reprojection_memory_budget: None,
+            max_generation_level: VNode::LEVEL_CELL_76M,
+            depth_format: wgpu::TextureFormat::Depth32Float,
+            vegetation: VegetationConfig::default(),
+            anisotropy: 16,
+            downlevel_flags: wgpu::DownlevelFlags::all(),
+            faces: [true; 6],
+            max_download_size: 64 << 20,
+            tile_server_urls: vec![tile_source::DEFAULT_TILE_SERVER_URL.to_owned()],
+            max_concurrent_tile_decodes: 4,
+            terrain_mesh_entries: 4,
+            gpu_memory_budget: None,
+            clip_depth: ClipDepth::default(),
+            shadow_quality: ShadowQuality::default(),
+            shadow_depth_bias: wgpu::DepthBiasState { constant: 2, slope_scale: 2.0, clamp: 0.0 },
+            custom_shading: None,
+            datasets: Vec::new(),
+        }
+    }
+}
+
+/// A dataset generated by `Terrain::generate_and_new` or `Terrain::merge_dataset_tiles` to stream
+/// a layer from, as selected with `TerrainConfig::datasets`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DatasetSelection {
+    pub layer: LayerType,
+    /// Directory the dataset was generated in, which contains its tiles in `tiles/{name}`.
+    pub directory: PathBuf,
+    /// Name of the dataset, as returned by `Terrain::generated_datasets`.
+    pub name: String,
+}
+
+/// Counters describing the internal state of a `Terrain`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Stats {
+    /// Number of times a streamed tile couldn't be fetched from disk or the tile server since the
+    /// terrain was created. These tiles are retried with exponential backoff, and the parent tile
+    /// is drawn in their place until then.
+    pub tile_fetch_failures: u64,
+    /// Number of times a streamed tile was fetched but failed to decode since the terrain was
+    /// created. These tiles are deleted from disk and downloaded again.
+    pub tile_decode_failures: u64,
+}
+
+/// GPU memory allocated by a `Terrain`, as returned by `Terrain::gpu_memory_usage`, along with
+/// the settings it was created with after fitting them to `TerrainConfig::gpu_memory_budget`.
+///
+/// Sizes are in bytes and are computed from the dimensions and formats of each allocation, so
+/// they don't include any padding or bookkeeping added by the driver.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct GpuMemoryUsage {
+    /// Texture arrays holding the cached tiles of every enabled layer.
+    pub tile_cache: u64,
+    /// Vertex storage, indirect draws, bounding spheres and indices of the terrain, grass and
+    /// tree billboard meshes.
+    pub meshes: u64,
+    pub shadow_map: u64,
+    /// Sky, noise and ground textures, models, billboards, and node and uniform buffers.
+    pub other: u64,
+    /// Value of `TerrainConfig::terrain_mesh_entries` that was used.
+    pub terrain_mesh_entries: u32,
+    /// Value of `TerrainConfig::shadow_quality` that was used.
+    pub shadow_quality: ShadowQuality,
+}
+impl GpuMemoryUsage {
+    pub fn total(&self) -> u64 {
+        self.tile_cache + self.meshes + self.shadow_map + self.other
+    }
+}
+
+/// Summary of terrain heights over a region, in meters above sea level. Like `get_height`, points
+/// below sea level count as zero.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ElevationStats {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    /// Number of points that were sampled.
+    pub samples: usize,
+}
+
+/// Latitude and longitude bounds in radians, as returned by `Terrain::visible_region`.
+///
+/// Longitudes increase eastwards from `south_west.1` to `north_east.1`. When the region crosses
+/// the antimeridian `north_east.1` is greater than PI, so that it is never less than
+/// `south_west.1`. A region containing a pole spans every longitude.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GeoBounds {
+    pub south_west: (f64, f64),
+    pub north_east: (f64, f64),
+}
+
+/// Heightmap covering an entire cube face, as returned by `Terrain::coarse_heightmap`.
+///
+/// Samples are grid registered: sample `(x, y)` lies on the unit cube at
+/// `VNode::roots()[face].grid_position_cspace(x, y, border, resolution)`, so the outermost
+/// `border` samples on each side extend past the edges of the face.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CoarseHeightmap {
+    /// Row major heights in meters, `resolution` samples on each side. Heights are relative to
+    /// the source data rather than `TerrainConfig::sea_level`, and aren't clamped to
+    /// `TerrainConfig::height_floor`.
+    pub heights: Vec<i16>,
+    pub resolution: u32,
+    pub border: u32,
+}
+
+/// Tiles of a layer that aren't yet on disk, as returned by `Terrain::missing_tiles`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MissingTiles {
+    /// Nodes whose tiles are missing, coarsest levels first.
+    pub nodes: Vec<VNode>,
+    /// Number of tiles the layer has in total on the enabled faces, missing or not.
+    pub total: usize,
+}
+
+/// Values of several layers at a single point, all taken from the same resident node.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SurfaceSample {
+    /// Level of the node that was sampled.
+    pub level: u8,
+    /// Height in meters above sea level, or zero for points below sea level.
+    pub height: f32,
+    /// Unit surface normal in world space, computed from the heightmap.
+    pub normal: [f32; 3],
+    /// RGBA color of the base albedo, if the sampled node has a base albedo tile.
+    pub albedo: Option<[u8; 4]>,
+}
 
 pub struct Terrain {
     sky_shader: rshader::ShaderSet,
     sky_bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
     stars_shader: rshader::ShaderSet,
     stars_bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
+    limb_shader: rshader::ShaderSet,
+    limb_bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
     gpu_state: GpuState,
     quadtree: QuadTree,
     mapfile: Arc<MapFile>,
     cache: TileCache,
     generate_skyview: ComputeShader<()>,
+    /// Sun direction and camera position that the skyview LUT was last requested for.
+    skyview_inputs: Option<([f32; 3], mint::Point3<f64>)>,
+    skyview_dirty: AtomicBool,
     view_proj: mint::ColumnMatrix4<f32>,
     shadow_view_proj: mint::ColumnMatrix4<f32>,
     camera: mint::Point3<f64>,
-    _models: Models,
+    /// Point that shaders measure world-space effects like wind sway from, kept near the camera
+    /// so that positions relative to it fit in an f32 without losing precision.
+    render_origin: mint::Point3<f64>,
+    render_origin_recenter_distance: f64,
+    sun: SunConfig,
+    wind: WindConfig,
+    tree_model_distance: f32,
+    tree_model_fade: f32,
+    start_time: Instant,
+    time: f32,
+    sky_mode: SkyMode,
+    limb_antialiasing: f32,
+    visualization: Viz,
+    lighting: Lighting,
+    vertical_exaggeration: f32,
+    color_grading: ColorGrading,
+    sun_direction: [f32; 3],
+    moon_direction: [f32; 3],
+    sidereal_time: f32,
+    shadow_softness: f32,
+    shadow_quality: ShadowQuality,
+    ao_strength: f32,
+    star_visibility_threshold: f32,
+    lod_morph_range: f32,
+    season: f32,
+    terrain_mesh_entries: u32,
+    night_lights_intensity: f32,
+    texture_lod_bias: f32,
+    clip_depth: ClipDepth,
+    frustum_culling: bool,
+    near_plane_culling: bool,
+    far_plane_culling: bool,
+    /// Whether `render` only draws terrain over the existing buffer contents, for compositing.
+    coverage_output: bool,
+    max_render_distance: Option<f32>,
+    lod_reference_fov: Option<f32>,
+    depth_format: wgpu::TextureFormat,
+    generation_timings: GenerationTimings,
+    gpu_memory_usage: GpuMemoryUsage,
+    models: Models,
+    atmosphere: sky::CpuAtmosphere,
 }
 impl Terrain {
+    /// Generate tiles from the source datasets in `dataset_directory`, then create a Terrain
+    /// object that uses them.
+    ///
+    /// Like `new_with_config`, this must be awaited on a multi-threaded tokio runtime.
     pub async fn generate_and_new<P: AsRef<Path>, F: FnMut(String, usize, usize) + Send>(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         dataset_directory: P,
-        mut progress_callback: F,
+        progress_callback: F,
     ) -> Result<Self, Error> {
-        let mapfile = Arc::new(MapFileBuilder::new().await.build().await?);
+        Self::generate_and_new_with_config(
+            device,
+            queue,
+            dataset_directory,
+            TerrainConfig::default(),
+            progress_callback,
+        )
+        .await
+    }
+
+    /// Like `generate_and_new`, but using the provided configuration both for generation and for
+    /// the resulting Terrain object.
+    pub async fn generate_and_new_with_config<P, F>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        dataset_directory: P,
+        config: TerrainConfig,
+        mut progress_callback: F,
+    ) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+        F: FnMut(String, usize, usize) + Send,
+    {
+        // Finer levels would overflow the sample count of a single reprojected sector.
+        if config.max_generation_level > VNode::LEVEL_CELL_5M {
+            return Err(Error::InvalidConfig(format!(
+                "max_generation_level {} is finer than the supported maximum of {}",
+                config.max_generation_level,
+                VNode::LEVEL_CELL_5M
+            )));
+        }
+
+        let (mapfile, mut timings) = Self::build_mapfile(&config, false).await?;
+        let mapfile = Arc::new(mapfile);
 
         let dataset_directory = dataset_directory.as_ref();
 
@@ -90,17 +729,27 @@ impl Terrain {
         //     i16::MIN,
         // )?;
 
-        generate::reproject_dataset::<i16, tiff::encoder::colortype::GrayI16, _, _>(
+        let start = Instant::now();
+        generate::reproject_dataset::<i16, _, _>(
             dataset_directory.to_owned(),
             "copernicus-hgt",
-            VNode::LEVEL_CELL_76M,
+            config.max_generation_level,
+            generate::ReprojectionParams::default(),
             &mut progress_callback,
             false,
-            vrt_file::VrtFile::new(&dataset_directory.join("copernicus-hgt/merged.vrt"))?,
+            generate::SourceSampling::Bilinear,
+            config.reprojection_memory_budget,
+            config.tile_sink.as_deref(),
+            &TiffCodec::<tiff::encoder::colortype::GrayI16>::default(),
+            vrt_file::VrtFile::new(&dataset_directory.join("copernicus-hgt/merged.vrt"))
+                .map_err(|e| Error::Dataset(e.into()))?,
             //terrain::dem::make_nasadem_raster_cache(&dataset_directory.join("nasadem"), 64),
             &|_, _, _, _| 0,
             0,
-        )?;
+            (mapfile.sea_level() + mapfile.height_floor()).max(i16::MIN as f32) as i16,
+        )
+        .map_err(|e| Error::Dataset(e.into()))?;
+        timings.record("reproject copernicus-hgt", start.elapsed());
 
         // generate::generate_heightmaps(
         //     &*mapfile,
@@ -123,41 +772,245 @@ impl Terrain {
         // )
         // .await?;
 
-        Self::new_impl(device, queue, mapfile)
+        Self::new_impl(device, queue, mapfile, config, timings)
+    }
+
+    /// Merge the reprojected sectors of a dataset into tiles, using `codec` to read the sectors
+    /// and to write the tiles. This lets datasets whose sectors are stored in formats other than
+    /// TIFF go through the same merging as terra's own datasets.
+    ///
+    /// Only the merging step is covered: the sectors must already have been reprojected onto
+    /// terra's cube faces by the caller, since reprojecting from source rasters always goes
+    /// through GDAL VRT files. Sectors are read from `{dataset_name}_reprojected` within
+    /// `dataset_directory`, laid out as terra writes its own reprojected datasets: each cube face
+    /// is split into 65x65 sectors named `{face}_S-{level}-{x:02}x{y:02}.{extension}`, for every
+    /// level from the 1 km level up to `max_level`. Tiles are merged for every level up to
+    /// `max_level`, or the finest level heightmaps are streamed at if that is coarser, so
+    /// `LayerType::Heightmaps.tile_count` gives how many there will be. They are written to
+    /// `tiles/{dataset_name}`, or handed to `tile_sink` if one is provided, and tiles that already
+    /// exist are skipped. Tiles whose samples all equal `T::default()` are written as empty files.
+    ///
+    /// `grid_registration` must match how the sectors were reprojected: whether their samples lie
+    /// on a grid that includes the edges of each sector, or at the centers of the cells that tile
+    /// it. Datasets of i16 TIFF tiles can be streamed with either registration.
+    ///
+    /// Like `new_with_config`, this must be awaited on a multi-threaded tokio runtime.
+    pub async fn merge_dataset_tiles<T, P, F>(
+        dataset_directory: P,
+        dataset_name: &'static str,
+        max_level: u8,
+        grid_registration: bool,
+        codec: Arc<dyn RasterCodec<T>>,
+        tile_sink: Option<Arc<dyn TileSink>>,
+        progress_callback: F,
+    ) -> Result<(), Error>
+    where
+        T: Default + PartialEq + Copy + Send + Sync + 'static,
+        P: AsRef<Path>,
+        F: FnMut(&str, usize, usize) + Send,
+    {
+        generate::merge_datasets_to_tiles(
+            dataset_directory.as_ref().to_owned(),
+            dataset_name,
+            max_level,
+            generate::ReprojectionParams::default(),
+            progress_callback,
+            grid_registration,
+            tile_sink,
+            codec,
+        )
+        .await
+        .map_err(|e| Error::Dataset(e.into()))
+    }
+
+    /// Names of the datasets whose tiles have been completely generated within
+    /// `dataset_directory`, such as `copernicus-hgt`, which can be selected with
+    /// `TerrainConfig::datasets`. Datasets merged in a format that can't be streamed as
+    /// heightmaps, such as with a custom codec, are left out.
+    pub fn generated_datasets<P: AsRef<Path>>(dataset_directory: P) -> Result<Vec<String>, Error> {
+        Ok(generate::merged_datasets(dataset_directory.as_ref())
+            .map_err(|e| Error::Dataset(e.into()))?
+            .into_iter()
+            .filter(|(_, tiles)| terrain::dataset::DatasetTileSource::can_stream(tiles))
+            .map(|(name, _)| name)
+            .collect())
     }
 
     /// Create a new Terrain object.
+    ///
+    /// See `new_with_config` for the async runtime this must be awaited on.
     pub async fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Result<Self, Error> {
-        let mapfile = Arc::new(MapFileBuilder::new().await.build().await?);
-        Self::new_impl(device, queue, mapfile)
+        Self::new_with_config(device, queue, TerrainConfig::default()).await
+    }
+
+    /// Create a new Terrain object using the provided configuration.
+    ///
+    /// This must be awaited on a multi-threaded tokio runtime, since file IO and encoding are
+    /// offloaded with `tokio::task::spawn_blocking` and `block_in_place`. Terra never creates a
+    /// runtime on the calling thread: the application's own runtime is used here, and tile
+    /// streaming afterwards runs on background threads with runtimes of their own, so calling
+    /// `update` and `render` from within async code is fine. Applications without a runtime
+    /// can use `new_blocking` instead.
+    pub async fn new_with_config(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        config: TerrainConfig,
+    ) -> Result<Self, Error> {
+        let (mapfile, timings) = Self::build_mapfile(&config, false).await?;
+        Self::new_impl(device, queue, Arc::new(mapfile), config, timings)
+    }
+
+    /// Blocking version of `new_with_config`, for callers that aren't running async code.
+    ///
+    /// Runs on the multi-threaded tokio runtime behind `runtime` if one is given, or otherwise
+    /// on a temporary runtime created just for this call. Blocking inside async code would
+    /// deadlock or panic, so calling this from within a runtime returns an error instead; await
+    /// `new_with_config` there.
+    pub fn new_blocking(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        config: TerrainConfig,
+        runtime: Option<&tokio::runtime::Handle>,
+    ) -> Result<Self, Error> {
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return Err(Error::InvalidConfig(
+                "Terrain::new_blocking cannot be called from within an async runtime".to_owned(),
+            ));
+        }
+        match runtime {
+            Some(handle) => handle.block_on(Self::new_with_config(device, queue, config)),
+            None => tokio::runtime::Runtime::new()
+                .map_err(|e| Error::Other(e.into()))?
+                .block_on(Self::new_with_config(device, queue, config)),
+        }
+    }
+
+    /// Create a Terrain object that renders procedurally generated terrain instead of real world
+    /// data, so that terra can be tried out without generating or downloading any tiles.
+    ///
+    /// Heightmap and base albedo tiles are synthesized from noise as they are streamed, and are
+    /// never written to the tile cache. There are no trees, since tree cover is left empty. The
+    /// noise and atmosphere textures are generated as usual, but the remaining textures and the
+    /// tree model that `new` downloads on first use are still needed.
+    ///
+    /// See `new_with_config` for the async runtime this must be awaited on.
+    pub async fn new_preview(device: &wgpu::Device, queue: &wgpu::Queue) -> Result<Self, Error> {
+        let config = TerrainConfig::default();
+        let (mapfile, timings) = Self::build_mapfile(&config, true).await?;
+        Self::new_impl(device, queue, Arc::new(mapfile), config, timings)
+    }
+
+    async fn build_mapfile(
+        config: &TerrainConfig,
+        procedural: bool,
+    ) -> Result<(MapFile, GenerationTimings), Error> {
+        if config.tile_server_urls.is_empty() {
+            return Err(Error::InvalidConfig(
+                "At least one tile server URL is required".to_owned(),
+            ));
+        }
+        let mut builder = MapFileBuilder::new()
+            .await
+            .map_err(|e| Error::MapFile(e.into()))?
+            .sea_level(config.sea_level)
+            .height_floor(config.height_floor)
+            .noise(config.noise)
+            .max_download_size(config.max_download_size)
+            .tile_server_urls(config.tile_server_urls.clone());
+        if let Some(source) = &config.tile_source {
+            builder = builder.tile_source(Arc::clone(source));
+        }
+        if let Some(sink) = &config.tile_sink {
+            builder = builder.tile_sink(Arc::clone(sink));
+        }
+        for (i, dataset) in config.datasets.iter().enumerate() {
+            if dataset.layer != LayerType::Heightmaps {
+                return Err(Error::InvalidConfig(format!(
+                    "{:?} can't be streamed from a generated dataset",
+                    dataset.layer
+                )));
+            }
+            if config.datasets[..i].iter().any(|d| d.layer == dataset.layer) {
+                return Err(Error::InvalidConfig(format!(
+                    "More than one dataset selected for {:?}",
+                    dataset.layer
+                )));
+            }
+            builder = builder
+                .dataset(dataset.layer, &dataset.directory, &dataset.name)
+                .map_err(|e| Error::Dataset(e.into()))?;
+        }
+        if procedural {
+            builder = builder.procedural();
+        }
+        Ok(builder.build().await?)
     }
 
     fn new_impl(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         mapfile: Arc<MapFile>,
+        config: TerrainConfig,
+        generation_timings: GenerationTimings,
     ) -> Result<Self, Error> {
-        let mesh_layers = MeshType::iter()
+        if config.depth_format.describe().sample_type != wgpu::TextureSampleType::Depth {
+            return Err(Error::InvalidConfig(format!(
+                "{:?} is not a depth format",
+                config.depth_format
+            )));
+        }
+        if !config.faces.contains(&true) {
+            return Err(Error::InvalidConfig("At least one face must be enabled".to_owned()));
+        }
+        if config.max_concurrent_tile_decodes == 0 {
+            return Err(Error::InvalidConfig(
+                "max_concurrent_tile_decodes must be at least one".to_owned(),
+            ));
+        }
+        let blocks_per_side = |entries| match entries {
+            1 => Ok(1),
+            4 => Ok(2),
+            16 => Ok(4),
+            n => Err(Error::InvalidConfig(format!(
+                "terrain_mesh_entries must be 1, 4 or 16, not {}",
+                n
+            ))),
+        };
+        let terrain_blocks_per_side = blocks_per_side(config.terrain_mesh_entries)?;
+
+        let mut terrain_fragment =
+            rshader::shader_source!("shaders", "terrain.frag", "declarations.glsl", "pbr.glsl");
+        if let Some(custom_shading) = &config.custom_shading {
+            terrain_fragment = terrain_fragment
+                .with_header("custom_shading.glsl", custom_shading.clone())
+                .with_define("CUSTOM_SHADING", "1");
+        }
+        let mut terrain_shader = Some(
+            rshader::ShaderSet::simple(
+                rshader::shader_source!("shaders", "terrain.vert", "declarations.glsl"),
+                terrain_fragment,
+            )
+            .map_err(|e| {
+                Error::InvalidConfig(format!("Failed to compile the terrain shader: {}", e))
+            })?,
+        );
+
+        let mut mesh_layers: Vec<MeshCacheDesc> = MeshType::iter()
             .map(|ty| match ty {
                 MeshType::Terrain => MeshCacheDesc {
                     ty,
                     max_bytes_per_node: 0,
-                    entries_per_node: 4,
+                    entries_per_node: config.terrain_mesh_entries as usize,
                     min_level: 0,
                     max_level: VNode::LEVEL_CELL_5MM,
-                    index_buffer: QuadTree::create_index_buffer(64),
+                    index_buffer: QuadTree::create_index_buffer(64, terrain_blocks_per_side),
                     render_overlapping_levels: false,
-                    cull_mode: Some(wgpu::Face::Front),
-                    render: rshader::ShaderSet::simple(
-                        rshader::shader_source!("shaders", "terrain.vert", "declarations.glsl"),
-                        rshader::shader_source!(
-                            "shaders",
-                            "terrain.frag",
-                            "declarations.glsl",
-                            "pbr.glsl"
-                        ),
-                    )
-                    .unwrap(),
+                    cull_mode: config.terrain_cull_mode,
+                    front_face: config.front_face,
+                    depth_format: config.depth_format,
+                    depth_compare: config.clip_depth.depth_compare(false),
+                    shadow_depth_bias: config.shadow_depth_bias,
+                    render: terrain_shader.take().unwrap(),
                     render_shadow: Some(
                         rshader::ShaderSet::simple(
                             rshader::shader_source!("shaders", "terrain.vert", "declarations.glsl"),
@@ -173,6 +1026,10 @@ impl Terrain {
                     min_level: VNode::LEVEL_SIDE_19M,
                     max_level: VNode::LEVEL_SIDE_5M,
                     cull_mode: None,
+                    front_face: config.front_face,
+                    depth_format: config.depth_format,
+                    depth_compare: config.clip_depth.depth_compare(false),
+                    shadow_depth_bias: config.shadow_depth_bias,
                     render_overlapping_levels: true,
                     index_buffer: (0..32 * 32)
                         .flat_map(|i| {
@@ -201,6 +1058,10 @@ impl Terrain {
                     min_level: VNode::LEVEL_SIDE_1KM,
                     max_level: VNode::LEVEL_SIDE_1KM,
                     cull_mode: None,
+                    front_face: config.front_face,
+                    depth_format: config.depth_format,
+                    depth_compare: config.clip_depth.depth_compare(false),
+                    shadow_depth_bias: config.shadow_depth_bias,
                     render_overlapping_levels: true,
                     index_buffer: (0..32 * 32)
                         .flat_map(|i| {
@@ -217,7 +1078,8 @@ impl Terrain {
                             "shaders",
                             "tree-billboards.frag",
                             "declarations.glsl",
-                            "pbr.glsl"
+                            "pbr.glsl",
+                            "hash.glsl"
                         ),
                     )
                     .unwrap(),
@@ -233,7 +1095,8 @@ impl Terrain {
                                 "shaders",
                                 "tree-billboards.frag",
                                 "declarations.glsl",
-                                "pbr.glsl";
+                                "pbr.glsl",
+                                "hash.glsl";
                                 "SHADOWPASS" = "1"
                             ),
                         )
@@ -244,9 +1107,36 @@ impl Terrain {
             .collect();
 
         let models = Models::new()?;
-        let cache = TileCache::new(device, Arc::clone(&mapfile), mesh_layers);
-        let gpu_state = GpuState::new(device, queue, &mapfile, &cache, &models)?;
-        let quadtree = QuadTree::new();
+        let atmosphere = sky::CpuAtmosphere::new(&mapfile)?;
+        let gpu_memory_usage =
+            Self::fit_gpu_memory_budget(&config, &mapfile, &mesh_layers, &models)?;
+        if gpu_memory_usage.terrain_mesh_entries != config.terrain_mesh_entries {
+            let entries = gpu_memory_usage.terrain_mesh_entries;
+            for desc in mesh_layers.iter_mut().filter(|desc| desc.ty == MeshType::Terrain) {
+                desc.entries_per_node = entries as usize;
+                desc.index_buffer = QuadTree::create_index_buffer(64, blocks_per_side(entries)?);
+            }
+        }
+        let cache = TileCache::new(
+            device,
+            Arc::clone(&mapfile),
+            mesh_layers,
+            config.eviction_policy,
+            config.layers,
+            config.vegetation,
+            config.max_concurrent_tile_decodes,
+        )?;
+        let gpu_state = GpuState::new(
+            device,
+            queue,
+            &mapfile,
+            &cache,
+            &models,
+            config.anisotropy,
+            config.downlevel_flags,
+            gpu_memory_usage.shadow_quality.resolution(),
+        )?;
+        let quadtree = QuadTree::new(config.faces);
 
         models.render_billboards(device, queue, &gpu_state);
 
@@ -275,6 +1165,20 @@ impl Terrain {
         )
         .unwrap();
 
+        let limb_shader = rshader::ShaderSet::simple(
+            rshader::shader_source!("shaders", "sky.vert", "declarations.glsl"),
+            rshader::shader_source!(
+                "shaders",
+                "sky.frag",
+                "declarations.glsl",
+                "pbr.glsl",
+                "atmosphere.glsl",
+                "hash.glsl";
+                "LIMB" = "1"
+            ),
+        )
+        .unwrap();
+
         let generate_skyview = ComputeShader::new(
             rshader::shader_source!(
                 "shaders",
@@ -290,23 +1194,121 @@ impl Terrain {
             sky_bindgroup_pipeline: None,
             stars_shader,
             stars_bindgroup_pipeline: None,
+            limb_shader,
+            limb_bindgroup_pipeline: None,
             gpu_state,
             quadtree,
             mapfile,
             cache,
             generate_skyview,
+            skyview_inputs: None,
+            skyview_dirty: AtomicBool::new(true),
             view_proj: cgmath::Matrix4::zero().into(),
             shadow_view_proj: cgmath::Matrix4::zero().into(),
             camera: mint::Point3::from_slice(&[0.0, 0.0, 0.0]),
-            _models: models,
+            render_origin: mint::Point3::from_slice(&[0.0, 0.0, 0.0]),
+            render_origin_recenter_distance: 65536.0,
+            sun: SunConfig::default(),
+            wind: WindConfig::default(),
+            tree_model_distance: config.vegetation.tree_model_distance,
+            tree_model_fade: config.vegetation.tree_model_fade,
+            start_time: Instant::now(),
+            time: 0.0,
+            sky_mode: SkyMode::default(),
+            limb_antialiasing: 1.0,
+            visualization: Viz::default(),
+            lighting: Lighting::default(),
+            vertical_exaggeration: 1.0,
+            color_grading: ColorGrading::default(),
+            sun_direction: [0.4, 0.7, 0.2],
+            moon_direction: [-0.4, -0.7, -0.2],
+            sidereal_time: 0.0,
+            shadow_softness: 1.5,
+            ao_strength: 1.0,
+            star_visibility_threshold: 0.02,
+            shadow_quality: gpu_memory_usage.shadow_quality,
+            lod_morph_range: 0.1,
+            season: 0.0,
+            terrain_mesh_entries: gpu_memory_usage.terrain_mesh_entries,
+            night_lights_intensity: 1.0,
+            texture_lod_bias: 0.0,
+            clip_depth: config.clip_depth,
+            frustum_culling: true,
+            near_plane_culling: true,
+            far_plane_culling: true,
+            coverage_output: false,
+            max_render_distance: None,
+            lod_reference_fov: None,
+            depth_format: config.depth_format,
+            generation_timings,
+            gpu_memory_usage,
+            models,
+            atmosphere,
         })
     }
 
+    /// Lower `terrain_mesh_entries` and then `shadow_quality` from their configured values until
+    /// the GPU memory the terrain would allocate fits within `gpu_memory_budget`, if one is set.
+    fn fit_gpu_memory_budget(
+        config: &TerrainConfig,
+        mapfile: &MapFile,
+        mesh_layers: &[MeshCacheDesc],
+        models: &Models,
+    ) -> Result<GpuMemoryUsage, Error> {
+        let tile_cache = TileCache::cache_texture_bytes(mapfile.layers(), config.layers);
+        let other = GpuState::other_bytes(mapfile, models)?;
+        let usage = |terrain_mesh_entries: u32, shadow_quality: ShadowQuality| GpuMemoryUsage {
+            tile_cache,
+            meshes: mesh_layers
+                .iter()
+                .filter(|desc| config.layers.contains_mesh(desc.ty))
+                .map(|desc| match desc.ty {
+                    MeshType::Terrain => desc.gpu_bytes(terrain_mesh_entries as usize),
+                    _ => desc.gpu_bytes(desc.entries_per_node),
+                })
+                .sum(),
+            shadow_map: shadow_quality.shadow_map_bytes(),
+            other,
+            terrain_mesh_entries,
+            shadow_quality,
+        };
+
+        let fewer_entries = [16, 4, 1]
+            .into_iter()
+            .filter(|&entries| entries <= config.terrain_mesh_entries)
+            .map(|entries| usage(entries, config.shadow_quality));
+        let lower_quality = [ShadowQuality::High, ShadowQuality::Medium, ShadowQuality::Low]
+            .into_iter()
+            .skip_while(|&quality| quality != config.shadow_quality)
+            .skip(1)
+            .map(|quality| usage(1, quality));
+        let mut candidates = fewer_entries.chain(lower_quality);
+
+        let budget = match config.gpu_memory_budget {
+            Some(budget) => budget,
+            None => return Ok(candidates.next().unwrap()),
+        };
+        let mut smallest = None;
+        for candidate in candidates {
+            if candidate.total() <= budget {
+                return Ok(candidate);
+            }
+            smallest = Some(candidate);
+        }
+        Err(Error::InvalidConfig(format!(
+            "The terrain needs at least {} bytes of GPU memory, more than the budget of {} bytes",
+            smallest.unwrap().total(),
+            budget
+        )))
+    }
+
     fn loading_complete(&self) -> bool {
-        VNode::roots().iter().copied().all(|root| {
+        let mut roots = VNode::roots().into_iter().filter(|r| self.quadtree.face_enabled(r.face()));
+        roots.all(|root| {
             self.cache.contains_all(
                 root,
-                LayerType::Heightmaps.bit_mask() | LayerType::BaseAlbedo.bit_mask(),
+                (LayerType::Heightmaps.bit_mask() | LayerType::BaseAlbedo.bit_mask())
+                    & self.cache.enabled_layers(),
             )
         })
     }
@@ -339,11 +1341,93 @@ impl Terrain {
         }
     }
 
+    /// Time spent in each phase of generating or loading the map file when this terrain was
+    /// created, for finding out which datasets or assets dominate startup.
+    pub fn generation_timings(&self) -> &GenerationTimings {
+        &self.generation_timings
+    }
+
+    /// GPU memory allocated for this terrain, and the `terrain_mesh_entries` and
+    /// `shadow_quality` it was created with after fitting them to
+    /// `TerrainConfig::gpu_memory_budget`.
+    pub fn gpu_memory_usage(&self) -> GpuMemoryUsage {
+        self.gpu_memory_usage
+    }
+
+    /// Returns which of the streamed tiles of `layer` on the faces enabled by
+    /// `TerrainConfig::faces` aren't on disk yet, along with the total number of such tiles.
+    /// Missing tiles are downloaded or generated as they are needed, so launchers can use this to
+    /// estimate how much work remains before the whole layer is available offline. Layers that
+    /// aren't streamed have no tiles, so report a total of zero.
+    pub fn missing_tiles(&self, layer: LayerType) -> MissingTiles {
+        if layer.streamed_levels() == 0 {
+            return MissingTiles { nodes: Vec::new(), total: 0 };
+        }
+        let (mut nodes, _) = self.mapfile.get_missing_base(layer);
+        nodes.retain(|n| self.quadtree.face_enabled(n.face()));
+        let mut faces = [false; 6];
+        for (face, enabled) in faces.iter_mut().enumerate() {
+            *enabled = self.quadtree.face_enabled(face as u8);
+        }
+        MissingTiles { nodes, total: layer.tile_count(0..=MAX_QUADTREE_LEVEL, faces) }
+    }
+
+    /// Returns whether calling `update` would currently block, which is the case until the root
+    /// tiles are resident.
+    ///
+    /// Unlike `poll_loading_status`, this doesn't initiate any streaming or otherwise change the
+    /// terrain's state, so it is suitable for deciding whether to show a loading indicator. Use
+    /// `stream_to_completion` to wait for everything visible from a given camera instead.
+    pub fn would_block(&self) -> bool {
+        !self.loading_complete()
+    }
+
+    /// Stream tiles until everything that would be rendered from `camera` is resident on the
+    /// GPU, or until `timeout` elapses.
+    ///
+    /// Where `poll_loading_status` only waits for the root tiles, this waits for all nodes the
+    /// camera needs at full detail, so that screenshots and exports never capture partially
+    /// loaded terrain. Must be awaited from within a tokio runtime. Returns an error if the
+    /// timeout is reached first.
+    pub async fn stream_to_completion(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        camera: mint::Point3<f64>,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.quadtree.update_priorities(&self.cache, camera);
+            self.cache.update(
+                device,
+                queue,
+                &self.gpu_state,
+                &self.mapfile,
+                &mut self.quadtree,
+                camera,
+            );
+            device.poll(wgpu::Maintain::Poll);
+
+            if self.cache.fully_resident(&self.quadtree) {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::Timeout(timeout));
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
     /// Update the terrain.
     ///
     /// This function will block if the root tiles haven't been downloaded/loaded from disk. If
     /// you want to avoid this, call `poll_loading_status` first to see whether this function will
-    /// block.
+    /// block, or `would_block` to check without initiating streaming.
+    ///
+    /// `view_proj` may be a perspective or an orthographic projection. Level of detail is chosen
+    /// by distance from `camera`, so for top-down orthographic views place the camera above the
+    /// view at a height comparable to the visible width.
     pub fn update(
         &mut self,
         device: &wgpu::Device,
@@ -351,93 +1435,36 @@ impl Terrain {
         view_proj: mint::ColumnMatrix4<f32>,
         camera: mint::Point3<f64>,
     ) {
-        self.view_proj = view_proj;
-        let shadow_view = cgmath::Matrix4::look_to_rh(
-            cgmath::Point3::new(0., 0., 0.),
-            cgmath::Vector3::new(-0.4, -0.7, -0.2),
-            cgmath::Vector3::unit_z(),
-        );
-        let shadow_proj = cgmath::Matrix4::new(
-            1.0 / 8192.0,
-            0.0,
-            0.0,
-            0.0,
-            0.0,
-            1.0 / 8192.0,
-            0.0,
-            0.0,
-            0.0,
-            0.0,
-            -1.0 / 102400.0,
-            0.0,
-            0.0,
-            0.0,
-            0.5,
-            1.0,
-        );
-        self.shadow_view_proj = (shadow_proj * shadow_view).into();
+        self.view_proj = self.clip_depth.to_wgpu(view_proj);
+        self.time = self.start_time.elapsed().as_secs_f32();
+        self.shadow_view_proj =
+            Self::shadow_view_proj(self.sun_direction, self.view_proj, self.clip_depth);
         self.camera = camera;
+        if (cgmath::Point3::from(camera) - cgmath::Point3::from(self.render_origin)).magnitude()
+            > self.render_origin_recenter_distance
+        {
+            self.render_origin = camera;
+        }
 
-        if self._models.refresh() {
-            self._models.render_billboards(device, queue, &self.gpu_state);
+        if self.models.refresh() {
+            self.models.render_billboards(device, queue, &self.gpu_state);
         }
 
         if self.sky_shader.refresh() {
             self.sky_bindgroup_pipeline = None;
         }
         if self.sky_bindgroup_pipeline.is_none() {
-            let (bind_group, bind_group_layout) = self.gpu_state.bind_group_for_shader(
+            self.sky_bindgroup_pipeline = Some(Self::fullscreen_pipeline(
                 device,
+                &self.gpu_state,
                 &self.sky_shader,
-                HashMap::new(),
-                HashMap::new(),
                 "sky",
-            );
-            let render_pipeline_layout =
-                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                    bind_group_layouts: [&bind_group_layout][..].into(),
-                    push_constant_ranges: &[],
-                    label: Some("pipeline.sky.layout"),
-                });
-            self.sky_bindgroup_pipeline = Some((
-                bind_group,
-                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-                    layout: Some(&render_pipeline_layout),
-                    vertex: wgpu::VertexState {
-                        module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
-                            label: Some("shader.sky.vertex"),
-                            source: self.sky_shader.vertex(),
-                        }),
-                        entry_point: "main",
-                        buffers: &[],
-                    },
-                    fragment: Some(wgpu::FragmentState {
-                        module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
-                            label: Some("shader.sky.fragment"),
-                            source: self.sky_shader.fragment(),
-                        }),
-                        entry_point: "main",
-                        targets: &[wgpu::ColorTargetState {
-                            format: wgpu::TextureFormat::Bgra8UnormSrgb,
-                            blend: Some(wgpu::BlendState {
-                                color: wgpu::BlendComponent::REPLACE,
-                                alpha: wgpu::BlendComponent::REPLACE,
-                            }),
-                            write_mask: wgpu::ColorWrites::ALL,
-                        }],
-                    }),
-                    primitive: Default::default(),
-                    depth_stencil: Some(wgpu::DepthStencilState {
-                        format: wgpu::TextureFormat::Depth32Float,
-                        depth_compare: wgpu::CompareFunction::GreaterEqual,
-                        depth_write_enabled: false,
-                        bias: Default::default(),
-                        stencil: Default::default(),
-                    }),
-                    multisample: Default::default(),
-                    multiview: None,
-                    label: Some("pipeline.sky"),
-                }),
+                wgpu::BlendState {
+                    color: wgpu::BlendComponent::REPLACE,
+                    alpha: wgpu::BlendComponent::REPLACE,
+                },
+                self.depth_format,
+                self.clip_depth.depth_compare(true),
             ));
         }
 
@@ -445,58 +1472,39 @@ impl Terrain {
             self.stars_bindgroup_pipeline = None;
         }
         if self.stars_bindgroup_pipeline.is_none() {
-            let (bind_group, bind_group_layout) = self.gpu_state.bind_group_for_shader(
+            self.stars_bindgroup_pipeline = Some(Self::fullscreen_pipeline(
                 device,
+                &self.gpu_state,
                 &self.stars_shader,
-                HashMap::new(),
-                HashMap::new(),
                 "stars",
-            );
-            let render_pipeline_layout =
-                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                    bind_group_layouts: [&bind_group_layout][..].into(),
-                    push_constant_ranges: &[],
-                    label: Some("pipeline.stars.layout"),
-                });
-            self.stars_bindgroup_pipeline = Some((
-                bind_group,
-                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-                    layout: Some(&render_pipeline_layout),
-                    vertex: wgpu::VertexState {
-                        module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
-                            label: Some("shader.stars.vertex"),
-                            source: self.stars_shader.vertex(),
-                        }),
-                        entry_point: "main",
-                        buffers: &[],
-                    },
-                    fragment: Some(wgpu::FragmentState {
-                        module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
-                            label: Some("shader.stars.fragment"),
-                            source: self.stars_shader.fragment(),
-                        }),
-                        entry_point: "main",
-                        targets: &[wgpu::ColorTargetState {
-                            format: wgpu::TextureFormat::Bgra8UnormSrgb,
-                            blend: Some(wgpu::BlendState::ALPHA_BLENDING),
-                            write_mask: wgpu::ColorWrites::ALL,
-                        }],
-                    }),
-                    primitive: Default::default(),
-                    depth_stencil: Some(wgpu::DepthStencilState {
-                        format: wgpu::TextureFormat::Depth32Float,
-                        depth_compare: wgpu::CompareFunction::GreaterEqual,
-                        depth_write_enabled: false,
-                        bias: Default::default(),
-                        stencil: Default::default(),
-                    }),
-                    multisample: Default::default(),
-                    multiview: None,
-                    label: Some("pipeline.stars"),
-                }),
+                wgpu::BlendState::ALPHA_BLENDING,
+                self.depth_format,
+                self.clip_depth.depth_compare(true),
+            ));
+        }
+
+        if self.limb_shader.refresh() {
+            self.limb_bindgroup_pipeline = None;
+        }
+        if self.limb_bindgroup_pipeline.is_none() {
+            // Drawn over the terrain's outermost pixels, regardless of their depth.
+            self.limb_bindgroup_pipeline = Some(Self::fullscreen_pipeline(
+                device,
+                &self.gpu_state,
+                &self.limb_shader,
+                "limb",
+                wgpu::BlendState::ALPHA_BLENDING,
+                self.depth_format,
+                wgpu::CompareFunction::Always,
             ));
         }
 
+        let lod_scale = self
+            .lod_reference_fov
+            .and_then(|fov| Self::projection_zoom(view_proj, fov))
+            .map_or(1.0, |zoom| (zoom * zoom).max(1.0 / 16.0).min(256.0));
+        self.quadtree.set_lod_scale(lod_scale);
+        self.quadtree.set_view_direction(Self::view_direction(view_proj));
         self.quadtree.update_priorities(&self.cache, camera);
 
         // Update the tile cache and then block until root tiles have been downloaded and streamed
@@ -513,14 +1521,519 @@ impl Terrain {
             std::thread::sleep(std::time::Duration::from_millis(10));
         }
 
-        self.generate_skyview.refresh(device, &self.gpu_state);
+        if self.generate_skyview.refresh(device, &self.gpu_state) {
+            self.skyview_dirty.store(true, Ordering::Relaxed);
+        }
+        self.update_skyview_inputs();
         self.cache.update_meshes(device, &self.gpu_state);
+        self.models.update_tree_models(
+            device,
+            &self.gpu_state,
+            self.depth_format,
+            self.clip_depth.depth_compare(false),
+        );
     }
 
-    pub fn render_shadows(&self, device: &wgpu::Device, queue: &wgpu::Queue) {
-        let relative_frustum = InfiniteFrustum::from_matrix(
-            cgmath::Matrix4::<f32>::from(self.shadow_view_proj).cast().unwrap(),
-        );
+    /// Bind group and pipeline for a shader that draws over the frame without vertex buffers and
+    /// without writing depth, as the sky, stars and limb passes do.
+    fn fullscreen_pipeline(
+        device: &wgpu::Device,
+        gpu_state: &GpuState,
+        shader: &rshader::ShaderSet,
+        name: &str,
+        blend: wgpu::BlendState,
+        depth_format: wgpu::TextureFormat,
+        depth_compare: wgpu::CompareFunction,
+    ) -> (wgpu::BindGroup, wgpu::RenderPipeline) {
+        let (bind_group, bind_group_layout) =
+            gpu_state.bind_group_for_shader(device, shader, HashMap::new(), HashMap::new(), name);
+        let render_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: [&bind_group_layout][..].into(),
+                push_constant_ranges: &[],
+                label: Some(&format!("pipeline.{}.layout", name)),
+            });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            layout: Some(&render_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                    label: Some(&format!("shader.{}.vertex", name)),
+                    source: shader.vertex(),
+                }),
+                entry_point: "main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                    label: Some(&format!("shader.{}.fragment", name)),
+                    source: shader.fragment(),
+                }),
+                entry_point: "main",
+                targets: &[wgpu::ColorTargetState {
+                    format: wgpu::TextureFormat::Bgra8UnormSrgb,
+                    blend: Some(blend),
+                    write_mask: wgpu::ColorWrites::ALL,
+                }],
+            }),
+            primitive: Default::default(),
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: depth_format,
+                depth_compare,
+                depth_write_enabled: false,
+                bias: Default::default(),
+                stencil: Default::default(),
+            }),
+            multisample: Default::default(),
+            multiview: None,
+            label: Some(&format!("pipeline.{}", name)),
+        });
+        (bind_group, pipeline)
+    }
+
+    /// Orthographic view-projection for rendering the shadow map, looking along the sunlight. It
+    /// is fitted around the part of the frustum of `view_proj` up to `SHADOW_DISTANCE` in front of
+    /// the camera, and reaches `SHADOW_CASTER_DISTANCE` further towards the sun so that terrain
+    /// outside the view still casts shadows into it. Like `view_proj` it is in camera relative
+    /// coordinates. Depth increases away from the sun.
+    fn shadow_view_proj(
+        sun_direction: [f32; 3],
+        view_proj: mint::ColumnMatrix4<f32>,
+        clip_depth: ClipDepth,
+    ) -> mint::ColumnMatrix4<f32> {
+        /// Furthest distance along the view direction at which shadows are drawn.
+        const SHADOW_DISTANCE: f64 = 8192.0;
+        /// Distance beyond the shadowed region towards the sun from which shadows are cast.
+        const SHADOW_CASTER_DISTANCE: f64 = 51200.0;
+
+        let sun = cgmath::Vector3::from(sun_direction).cast::<f64>().unwrap().normalize();
+
+        // Any up vector works as long as it isn't parallel to the sunlight, so switch axes well
+        // before they line up to keep the basis well conditioned.
+        let up = if sun.z.abs() < 0.9 {
+            cgmath::Vector3::unit_z()
+        } else if sun.x.abs() < 0.9 {
+            cgmath::Vector3::unit_x()
+        } else {
+            cgmath::Vector3::unit_y()
+        };
+        let shadow_view = cgmath::Matrix4::look_to_rh(cgmath::Point3::new(0., 0., 0.), -sun, up);
+
+        // Corners of the frustum, cut off at the shadow distance. Without a valid view there is
+        // nothing to fit, so cover a cube around the camera instead.
+        let view_proj = cgmath::Matrix4::<f32>::from(view_proj).cast::<f64>().unwrap();
+        let mut corners = Vec::with_capacity(8);
+        match view_proj.invert() {
+            Some(inverse) => {
+                let (near, far) =
+                    (1.0 - clip_depth.far_depth() as f64, clip_depth.far_depth() as f64);
+                let unproject = |x: f64, y: f64, depth: f64| {
+                    let p = inverse * cgmath::Vector4::new(x, y, depth, 1.0);
+                    p.truncate() / p.w
+                };
+                let ray = |x: f64, y: f64| {
+                    let origin = unproject(x, y, near);
+                    (origin, (unproject(x, y, near + (far - near) * 1e-6) - origin).normalize())
+                };
+
+                let forward = ray(0.0, 0.0).1;
+                for &(x, y) in &[(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)] {
+                    let (origin, direction) = ray(x, y);
+
+                    // The far plane may be at infinity, where unprojecting doesn't give a point.
+                    let length = (unproject(x, y, far) - origin).magnitude();
+                    let length = if length.is_finite() { length } else { f64::INFINITY };
+                    let shadow_length = SHADOW_DISTANCE / direction.dot(forward);
+                    corners.push(origin);
+                    corners.push(origin + direction * length.min(shadow_length));
+                }
+            }
+            None => {
+                for i in 0..8 {
+                    let sign = |bit: usize| if i & bit == 0 { -1.0 } else { 1.0 };
+                    corners.push(cgmath::Vector3::new(sign(1), sign(2), sign(4)) * SHADOW_DISTANCE);
+                }
+            }
+        }
+
+        let mut min = cgmath::Vector3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY);
+        let mut max = -min;
+        for corner in corners {
+            let p = (shadow_view * corner.extend(1.0)).truncate();
+            for i in 0..3 {
+                min[i] = min[i].min(p[i]);
+                max[i] = max[i].max(p[i]);
+            }
+        }
+
+        // The shadow view looks away from the sun, so the sun is towards positive z.
+        let width = (max.x - min.x).max(1.0);
+        let height = (max.y - min.y).max(1.0);
+        let near = max.z + SHADOW_CASTER_DISTANCE;
+        let depth = near - min.z;
+        let shadow_proj = cgmath::Matrix4::new(
+            2.0 / width,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            2.0 / height,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            -1.0 / depth,
+            0.0,
+            -(max.x + min.x) / width,
+            -(max.y + min.y) / height,
+            near / depth,
+            1.0,
+        );
+        (shadow_proj * shadow_view).cast::<f32>().unwrap().into()
+    }
+
+    /// Flag the skyview LUT for regeneration if the sun has changed direction or the camera has
+    /// moved far enough to visibly change the sky. Moves are measured relative to the camera's
+    /// altitude, since the sky changes more slowly the higher up it is seen from.
+    fn update_skyview_inputs(&mut self) {
+        let camera = cgmath::Vector3::new(self.camera.x, self.camera.y, self.camera.z);
+        let stale = match self.skyview_inputs {
+            None => true,
+            Some((sun_direction, last_camera)) => {
+                let last_camera = cgmath::Vector3::new(last_camera.x, last_camera.y, last_camera.z);
+                let altitude = (camera.magnitude() - coordinates::PLANET_RADIUS).abs();
+                sun_direction != self.sun_direction
+                    || (camera - last_camera).magnitude() > 10.0 + 0.01 * altitude
+            }
+        };
+        if stale {
+            self.skyview_inputs = Some((self.sun_direction, self.camera));
+            self.skyview_dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Force the skyview LUT used to shade the sky from within the atmosphere to be regenerated
+    /// on the next `render`. This normally happens automatically whenever the sun direction
+    /// changes or the camera moves, so is only needed if lighting changed some other way.
+    pub fn refresh_skyview(&mut self) {
+        self.skyview_dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Set the size and appearance of the sun disc, and the color and intensity of sunlight.
+    pub fn set_sun(&mut self, sun: SunConfig) {
+        if sun.illuminance() != self.sun.illuminance() {
+            self.refresh_skyview();
+        }
+        self.sun = sun;
+    }
+
+    /// Set the wind that animates grass and tree billboards. A strength of zero stops all
+    /// movement.
+    pub fn set_wind(&mut self, wind: WindConfig) {
+        self.wind = WindConfig { strength: wind.strength.max(0.0).min(1.0), ..wind };
+    }
+
+    /// Change the distance within which trees are drawn as 3D models and the width of the band
+    /// beyond it where they cross-fade into billboards, both in meters. These start out as
+    /// `VegetationConfig::tree_model_distance` and `VegetationConfig::tree_model_fade`.
+    pub fn set_tree_model_distances(&mut self, distance: f32, fade: f32) {
+        self.tree_model_distance = distance.max(0.0);
+        self.tree_model_fade = fade.max(0.0);
+    }
+
+    /// Position the sun, moon and stars for the given time, as seen by an observer at `latitude`
+    /// and `longitude` (in radians). The direction to the moon is available to shaders as
+    /// `globals.moon_direction`.
+    ///
+    /// Takes effect on the next call to `update`.
+    pub fn set_time_and_location(
+        &mut self,
+        utc: chrono::DateTime<chrono::Utc>,
+        latitude: f64,
+        longitude: f64,
+    ) {
+        let unix_seconds = utc.timestamp() as f64 + utc.timestamp_subsec_nanos() as f64 * 1e-9;
+        let julian_day = coordinates::julian_day(unix_seconds);
+
+        let observer = coordinates::polar_to_ecef(cgmath::Vector3::new(latitude, longitude, 0.0));
+        let sun = (coordinates::sun_position(julian_day) - observer).normalize();
+        self.sun_direction = [sun.x as f32, sun.y as f32, sun.z as f32];
+        let moon = (coordinates::moon_position(julian_day) - observer).normalize();
+        self.moon_direction = [moon.x as f32, moon.y as f32, moon.z as f32];
+
+        // The star shader adds this to each star's right ascension to find its longitude.
+        self.sidereal_time = -coordinates::greenwich_sidereal_time(julian_day) as f32;
+    }
+
+    /// Set the fraction of each level's distance band over which terrain vertices blend towards
+    /// the positions of the next coarser level. Larger values hide level transitions better but
+    /// lose detail sooner, and zero disables blending so transitions pop. Defaults to 0.1.
+    pub fn set_lod_morph_range(&mut self, range: f32) {
+        self.lod_morph_range = range.max(0.0).min(1.0);
+    }
+
+    /// Set the brightness of city lights on the night side of the planet, as a multiple of their
+    /// default brightness. Zero disables them. Has no effect if the tile server doesn't provide
+    /// night lights. Defaults to 1.0.
+    pub fn set_night_lights_intensity(&mut self, intensity: f32) {
+        self.night_lights_intensity = intensity.max(0.0);
+    }
+
+    /// Set the bias added to the mipmap level chosen when sampling streamed albedo and normal
+    /// tiles. Negative values pick more detailed levels, making the ground look sharper at the
+    /// cost of shimmering in motion, while positive values soften it. With anisotropic filtering
+    /// enabled (see `TerrainConfig::anisotropy`) surfaces seen at grazing angles already stay
+    /// sharp, so only a small bias, if any, is usually needed. Clamped to [-4, 4]. Defaults to 0.
+    pub fn set_texture_lod_bias(&mut self, bias: f32) {
+        self.texture_lod_bias = bias.max(-4.0).min(4.0);
+    }
+
+    /// Choose how the sky is shaded. The default, `SkyMode::Automatic`, switches to space view
+    /// when the camera rises above the atmosphere.
+    pub fn set_sky_mode(&mut self, mode: SkyMode) {
+        self.sky_mode = mode;
+    }
+
+    /// Width in pixels of the band along the planet's silhouette that is blended into space while
+    /// in space view, which hides the stair stepping of the terrain's edge against the stars. The
+    /// band lies just inside the silhouette of the planet at sea level, so it only matches the
+    /// rendered edge once the camera is high enough for mountains to be smaller than a pixel at
+    /// the horizon. Zero disables it. Defaults to 1.
+    pub fn set_limb_antialiasing(&mut self, pixels: f32) {
+        self.limb_antialiasing = pixels.max(0.0);
+    }
+
+    /// Choose what the terrain surface shows. Defaults to `Viz::Realistic`.
+    pub fn set_visualization(&mut self, visualization: Viz) {
+        self.visualization = visualization;
+    }
+
+    /// Choose how the sky and ambient light are computed. Only affects shading, so it can be
+    /// changed every frame. Defaults to `Lighting::Atmosphere`.
+    pub fn set_lighting(&mut self, lighting: Lighting) {
+        self.lighting = lighting;
+    }
+
+    /// Scale the height of the rendered terrain above sea level by `exaggeration`, to make
+    /// subtle relief easier to see. Surface normals are steepened to match. Only the terrain
+    /// mesh is affected: grass and trees stay at their true heights, and `get_height` and
+    /// `camera_altitude_above_ground` still report unexaggerated heights. Defaults to 1.0.
+    pub fn set_vertical_exaggeration(&mut self, exaggeration: f32) {
+        self.vertical_exaggeration = exaggeration.max(0.0);
+    }
+
+    /// Set the contrast, saturation and gamma adjustments applied to the rendered image after
+    /// tonemapping, for matching an art target without a separate post-processing pass. Only
+    /// affects shading, so it can be changed every frame. Defaults to `ColorGrading::default()`,
+    /// which leaves colors unchanged.
+    pub fn set_color_grading(&mut self, grading: ColorGrading) {
+        self.color_grading = ColorGrading { gamma: grading.gamma.max(0.01), ..grading };
+    }
+
+    /// Mode, azimuth and altitude of the visualization, as passed to shaders.
+    fn visualization_uniforms(&self) -> (u32, f32, f32) {
+        match self.visualization {
+            Viz::Realistic => (0, 0.0, 0.0),
+            Viz::Hillshade { azimuth, altitude } => (1, azimuth, altitude),
+        }
+    }
+
+    /// Mode, sky zenith and horizon colors and ambient level of the lighting, as passed to
+    /// shaders.
+    fn lighting_uniforms(&self) -> (u32, [f32; 3], [f32; 3], f32) {
+        match self.lighting {
+            Lighting::Atmosphere => (0, [0.0; 3], [0.0; 3], 0.15),
+            Lighting::Simple(s) => (1, s.zenith_color, s.horizon_color, s.ambient.max(0.0)),
+        }
+    }
+
+    fn space_view(&self) -> bool {
+        match self.sky_mode {
+            SkyMode::Automatic => {
+                cgmath::Vector3::new(self.camera.x, self.camera.y, self.camera.z).magnitude()
+                    > coordinates::ATMOSPHERE_RADIUS
+            }
+            SkyMode::Surface => false,
+            SkyMode::Space => true,
+        }
+    }
+
+    /// Set the time of year used to tint vegetation, from 0 for summer through yellow and brown
+    /// autumn colors to 1 for winter, when flat ground is also covered in snow. This only affects
+    /// shading, so it can be changed every frame. Defaults to 0.
+    pub fn set_season(&mut self, season: f32) {
+        self.season = season.max(0.0).min(1.0);
+    }
+
+    /// Switch `render` to drawing terra as a layer of a larger scene. Instead of clearing the
+    /// depth buffer and filling every pixel with terrain or sky, `render` then keeps the depth
+    /// buffer's contents and only draws terrain and vegetation, with an alpha of 1. Disabled by
+    /// default.
+    ///
+    /// Anything the host drew beforehand into the same depth buffer, with the same
+    /// `render_view_proj` and the depth convention of `TerrainConfig::clip_depth`, occludes the
+    /// terrain. To layer terra under a separate render instead, clear the color buffer to
+    /// transparent black and the depth buffer to the far depth, call `render`, and composite
+    /// the result over the background with `wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING`,
+    /// since alpha is then exactly the terrain's coverage. Outside of this mode the sky covers
+    /// every pixel, so the alpha written is always 1.
+    pub fn set_coverage_output(&mut self, enabled: bool) {
+        self.coverage_output = enabled;
+    }
+
+    /// Enable or disable GPU frustum culling of meshes. With culling disabled every resident mesh
+    /// is drawn, which is useful for telling culling bugs apart from tiles that haven't streamed
+    /// in yet. Enabled by default.
+    pub fn set_frustum_culling(&mut self, enabled: bool) {
+        self.frustum_culling = enabled;
+    }
+
+    /// Choose whether frustum culling also discards meshes in front of the near plane and beyond
+    /// the far plane of the `view_proj` passed to `update`, in addition to those outside its
+    /// sides. Infinite projections have no far plane, so only the near plane applies to them.
+    /// Disable them if the near or far plane of `view_proj` is tighter than that of the
+    /// projections passed to `render`. Both are enabled by default.
+    pub fn set_frustum_planes(&mut self, near: bool, far: bool) {
+        self.near_plane_culling = near;
+        self.far_plane_culling = far;
+    }
+
+    /// Limit how many bytes of streamed tiles are uploaded to the GPU during each call to
+    /// `update`. Tiles that don't fit are deferred to later frames, which smooths out frame times
+    /// when many tiles arrive at once at the cost of slower loading. At least one tile is always
+    /// uploaded per frame. `None` removes the limit, which is the default.
+    pub fn set_upload_budget(&mut self, bytes: Option<usize>) {
+        self.cache.set_upload_budget(bytes);
+    }
+
+    /// Prepare for the camera to jump to a distant location, such as when teleporting. Call this
+    /// just before the `update` that moves the camera.
+    ///
+    /// Until every tile streamed for the new location has arrived, at most `bytes` of them are
+    /// uploaded during each `update`, or less if `set_upload_budget` is lower. The frame that
+    /// reveals the new location is drawn from whatever coarse tiles are already resident rather
+    /// than stalling on uploads, and finer tiles replace them over the following frames. At
+    /// least one tile is always uploaded per frame. Afterwards uploads return to the limit set
+    /// by `set_upload_budget`.
+    pub fn begin_teleport(&mut self, bytes: usize) {
+        self.cache.begin_teleport(bytes);
+    }
+
+    /// Show or hide every mesh of the given type, along with the shadows it casts. Hidden meshes
+    /// are still streamed and generated, so showing them again is instant. All mesh types are
+    /// visible by default.
+    pub fn set_mesh_type_visible(&mut self, ty: MeshType, visible: bool) {
+        self.cache.set_mesh_visible(ty, visible);
+    }
+
+    /// Cull meshes whose bounds are entirely more than `distance` meters from the camera,
+    /// regardless of whether they are in view. `None` removes the limit, which is the default.
+    pub fn set_max_render_distance(&mut self, distance: Option<f32>) {
+        self.max_render_distance = distance;
+    }
+
+    /// Scale level of detail with the field of view of the `view_proj` matrices passed to
+    /// `update`, so that zoomed in views select finer tiles for the smaller region they show and
+    /// wide views select coarser ones. Detail matches the unscaled selection when the vertical
+    /// field of view equals `reference_fov` (in radians), and each halving of the view's extent
+    /// selects roughly one finer level, up to four finer or two coarser levels. Orthographic
+    /// projections are unaffected. `None` disables scaling, which is the default.
+    pub fn set_lod_reference_fov(&mut self, reference_fov: Option<f32>) {
+        self.lod_reference_fov =
+            reference_fov.filter(|fov| *fov > 0.0 && *fov < std::f32::consts::PI);
+    }
+
+    /// How many times `view_proj` magnifies the scene relative to a perspective projection with
+    /// a vertical field of view of `reference_fov`, or `None` if it isn't a perspective projection.
+    fn projection_zoom(view_proj: mint::ColumnMatrix4<f32>, reference_fov: f32) -> Option<f32> {
+        // With a rigid view transform, the rotation leaves the lengths of the rows of the
+        // projection unchanged: the y row has length 1/tan(fov/2) and the w row length one.
+        let m = view_proj;
+        let y_row = cgmath::Vector3::new(m.x.y, m.y.y, m.z.y).magnitude();
+        let w_row = cgmath::Vector3::new(m.x.w, m.y.w, m.z.w).magnitude();
+        if w_row < 1e-6 || !y_row.is_finite() {
+            return None;
+        }
+        Some(y_row / w_row * (reference_fov * 0.5).tan())
+    }
+
+    /// Unit vector that `view_proj` looks along, or `None` if it isn't a perspective projection.
+    fn view_direction(view_proj: mint::ColumnMatrix4<f32>) -> Option<cgmath::Vector3<f64>> {
+        // The w row of a perspective projection measures depth along the view direction.
+        let m = view_proj;
+        let w_row = cgmath::Vector3::new(m.x.w, m.y.w, m.z.w).cast::<f64>()?;
+        if w_row.magnitude() < 1e-6 || !w_row.magnitude().is_finite() {
+            return None;
+        }
+        Some(w_row.normalize())
+    }
+
+    /// Skew the level of detail of grass and trees towards where the camera is looking, so that
+    /// foliage density stays more consistent on screen as the camera pitches between looking down
+    /// and looking out towards the horizon. Grass and trees on nodes straight ahead have their
+    /// priority raised by a factor of `2^strength`, while those off to the side are lowered by the
+    /// same factor and those behind by its cube. Vegetation can only be raised on nodes that are
+    /// already cached, and the terrain itself is still selected by distance alone. Orthographic
+    /// projections are unaffected. `strength` is clamped to between 0 and 4. Defaults to 0, which
+    /// selects vegetation detail by distance alone.
+    pub fn set_vegetation_lod_anisotropy(&mut self, strength: f32) {
+        self.quadtree.set_vegetation_anisotropy(strength.max(0.0).min(4.0));
+    }
+
+    /// Set the finest quadtree level that will be streamed, generated or rendered, regardless of
+    /// how close the camera gets. Levels above the maximum supported level are clamped to it.
+    pub fn set_max_render_level(&mut self, level: u8) {
+        self.quadtree.set_max_level(level);
+    }
+
+    /// Set the radius of the shadow filter kernel, measured in shadow map texels for a sun of
+    /// default size. The radius scales with `SunConfig::angular_radius`, and a softness of zero
+    /// produces hard shadows.
+    pub fn set_shadow_softness(&mut self, softness: f32) {
+        self.shadow_softness = softness.max(0.0);
+    }
+
+    /// Set how strongly ambient occlusion from the generated bent normals darkens cavities. Zero
+    /// disables occlusion, one applies it as generated and larger values exaggerate it. Defaults
+    /// to one.
+    pub fn set_ao_strength(&mut self, strength: f32) {
+        self.ao_strength = strength.max(0.0);
+    }
+
+    /// Set how bright the sky behind a star can be before the star is hidden, as a fraction of
+    /// the brightness that is displayed as white. Stars fade out as the sky brightens towards the
+    /// threshold, so they disappear during the day and return at dusk. Stars are always shown
+    /// in space view. Defaults to 0.02.
+    pub fn set_star_visibility_threshold(&mut self, threshold: f32) {
+        self.star_visibility_threshold = threshold.max(0.0);
+    }
+
+    /// Set how far in meters the camera may move from the render origin before the origin is
+    /// moved to the camera. Vertex positions are always relative to the camera, but effects that
+    /// depend on absolute position such as wind sway are evaluated relative to the render origin,
+    /// and lose precision (visible as jitter) the further the camera is from it. Each time the
+    /// origin moves the sway pattern shifts, so this trades rare discontinuities against
+    /// precision. Defaults to 65536 meters.
+    pub fn set_render_origin_recenter_distance(&mut self, distance: f64) {
+        self.render_origin_recenter_distance = distance.max(0.0);
+    }
+
+    /// Move the render origin to the camera passed to the last `update`, instead of waiting for
+    /// the camera to move further than the distance set with
+    /// `set_render_origin_recenter_distance`. Useful for scripted traversals that want to pick
+    /// exactly when the sway pattern shifts. Nothing stored on the GPU is relative to the
+    /// origin, so rebasing never forces tiles or meshes to be uploaded again, and takes effect
+    /// on the next `render`.
+    pub fn rebase_origin(&mut self) {
+        self.render_origin = self.camera;
+    }
+
+    pub fn render_shadows(&self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let relative_frustum = InfiniteFrustum::from_matrix(
+            cgmath::Matrix4::<f32>::from(self.shadow_view_proj).cast().unwrap(),
+            false,
+        );
+        let (visualization, hillshade_azimuth, hillshade_altitude) = self.visualization_uniforms();
+        let (lighting, sky_zenith_color, sky_horizon_color, ambient) = self.lighting_uniforms();
         queue.write_buffer(
             &self.gpu_state.globals,
             0,
@@ -530,21 +2043,54 @@ impl Terrain {
                     .invert()
                     .unwrap()
                     .into(),
-                frustum_planes: [
-                    relative_frustum.planes[0].cast().unwrap().into(),
-                    relative_frustum.planes[1].cast().unwrap().into(),
-                    relative_frustum.planes[2].cast().unwrap().into(),
-                    relative_frustum.planes[3].cast().unwrap().into(),
-                    relative_frustum.planes[4].cast().unwrap().into(),
-                ],
+                frustum_planes: relative_frustum.planes.map(|p| p.cast().unwrap().into()),
                 shadow_view_proj: self.shadow_view_proj,
                 camera: [self.camera.x as f32, self.camera.y as f32, self.camera.z as f32],
                 screen_width: 2048.0,
-                sun_direction: [0.4, 0.7, 0.2],
+                sun_direction: self.sun_direction,
                 screen_height: 2048.0,
-                sidereal_time: 0.0,
+                sidereal_time: self.sidereal_time,
                 exposure: 1.0,
-                _padding: [0.0; 2],
+                sun_angular_radius: self.sun.angular_radius,
+                sun_limb_darkening: self.sun.limb_darkening as u32,
+                sun_illuminance: self.sun.illuminance(),
+                shadow_softness: self.shadow_softness,
+                lod_morph_range: self.lod_morph_range,
+                space_view: self.space_view() as u32,
+                season: self.season,
+                terrain_mesh_entries: self.terrain_mesh_entries,
+                night_lights_intensity: if self.gpu_state.has_night_lights {
+                    self.night_lights_intensity
+                } else {
+                    0.0
+                },
+                texture_lod_bias: self.texture_lod_bias,
+                far_depth: self.clip_depth.far_depth(),
+                time: self.time,
+                wind_direction: self.wind.direction,
+                wind_strength: self.wind.strength,
+                wind_gust_frequency: self.wind.gust_frequency,
+                tree_model_distance: self.tree_model_distance,
+                tree_model_fade: self.tree_model_fade,
+                shadow_kernel_radius: self.shadow_quality.kernel_radius(),
+                ao_strength: self.ao_strength,
+                star_visibility_threshold: self.star_visibility_threshold,
+                visualization,
+                hillshade_azimuth,
+                hillshade_altitude,
+                _padding0: 0.0,
+                camera_offset: self.camera_offset(),
+                vertical_exaggeration: self.vertical_exaggeration,
+                contrast: self.color_grading.contrast,
+                saturation: self.color_grading.saturation,
+                gamma: self.color_grading.gamma,
+                lighting,
+                sky_zenith_color,
+                ambient,
+                sky_horizon_color,
+                limb_antialiasing: self.limb_antialiasing,
+                moon_direction: self.moon_direction,
+                _padding1: 0.0,
             }),
         );
 
@@ -553,7 +2099,13 @@ impl Terrain {
         });
 
         {
-            self.cache.cull_meshes(device, &mut encoder, &self.gpu_state);
+            self.cache.cull_meshes(
+                device,
+                &mut encoder,
+                &self.gpu_state,
+                self.frustum_culling,
+                self.max_render_distance,
+            );
 
             let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 color_attachments: &[],
@@ -573,6 +2125,88 @@ impl Terrain {
         queue.submit(Some(encoder.finish()));
     }
 
+    /// Uniforms for drawing from the camera passed to `update` with `render_view_proj`.
+    fn view_globals(
+        &self,
+        frame_size: (u32, u32),
+        render_view_proj: mint::ColumnMatrix4<f32>,
+    ) -> GlobalUniformBlock {
+        let render_view_proj = self.clip_depth.to_wgpu(render_view_proj);
+        let mut relative_frustum = InfiniteFrustum::from_matrix(
+            cgmath::Matrix4::<f32>::from(self.view_proj).cast().unwrap(),
+            self.clip_depth.reversed(),
+        );
+        if !self.near_plane_culling {
+            relative_frustum.remove_plane(InfiniteFrustum::NEAR_PLANE);
+        }
+        if !self.far_plane_culling {
+            relative_frustum.remove_plane(InfiniteFrustum::FAR_PLANE);
+        }
+        let (visualization, hillshade_azimuth, hillshade_altitude) = self.visualization_uniforms();
+        let (lighting, sky_zenith_color, sky_horizon_color, ambient) = self.lighting_uniforms();
+        GlobalUniformBlock {
+            view_proj: render_view_proj,
+            view_proj_inverse: cgmath::Matrix4::from(render_view_proj).invert().unwrap().into(),
+            shadow_view_proj: self.shadow_view_proj,
+            frustum_planes: relative_frustum.planes.map(|p| p.cast().unwrap().into()),
+            camera: [self.camera.x as f32, self.camera.y as f32, self.camera.z as f32],
+            screen_width: frame_size.0 as f32,
+            sun_direction: self.sun_direction,
+            screen_height: frame_size.1 as f32,
+            sidereal_time: self.sidereal_time,
+            exposure: 1.0 / (f32::powf(2.0, 15.0) * 1.2),
+            sun_angular_radius: self.sun.angular_radius,
+            sun_limb_darkening: self.sun.limb_darkening as u32,
+            sun_illuminance: self.sun.illuminance(),
+            shadow_softness: self.shadow_softness,
+            lod_morph_range: self.lod_morph_range,
+            space_view: self.space_view() as u32,
+            season: self.season,
+            terrain_mesh_entries: self.terrain_mesh_entries,
+            night_lights_intensity: if self.gpu_state.has_night_lights {
+                self.night_lights_intensity
+            } else {
+                0.0
+            },
+            texture_lod_bias: self.texture_lod_bias,
+            far_depth: self.clip_depth.far_depth(),
+            time: self.time,
+            wind_direction: self.wind.direction,
+            wind_strength: self.wind.strength,
+            wind_gust_frequency: self.wind.gust_frequency,
+            tree_model_distance: self.tree_model_distance,
+            tree_model_fade: self.tree_model_fade,
+            shadow_kernel_radius: self.shadow_quality.kernel_radius(),
+            ao_strength: self.ao_strength,
+            star_visibility_threshold: self.star_visibility_threshold,
+            visualization,
+            hillshade_azimuth,
+            hillshade_altitude,
+            _padding0: 0.0,
+            camera_offset: self.camera_offset(),
+            vertical_exaggeration: self.vertical_exaggeration,
+            contrast: self.color_grading.contrast,
+            saturation: self.color_grading.saturation,
+            gamma: self.color_grading.gamma,
+            lighting,
+            sky_zenith_color,
+            ambient,
+            sky_horizon_color,
+            limb_antialiasing: self.limb_antialiasing,
+            moon_direction: self.moon_direction,
+            _padding1: 0.0,
+        }
+    }
+
+    /// Position of the camera relative to the render origin, computed in f64 before narrowing.
+    fn camera_offset(&self) -> [f32; 3] {
+        [
+            (self.camera.x - self.render_origin.x) as f32,
+            (self.camera.y - self.render_origin.y) as f32,
+            (self.camera.z - self.render_origin.z) as f32,
+        ]
+    }
+
     /// Render the terrain.
     ///
     /// Terrain::update must be called first.
@@ -585,31 +2219,10 @@ impl Terrain {
         frame_size: (u32, u32),
         render_view_proj: mint::ColumnMatrix4<f32>,
     ) {
-        let relative_frustum = InfiniteFrustum::from_matrix(
-            cgmath::Matrix4::<f32>::from(self.view_proj).cast().unwrap(),
-        );
         queue.write_buffer(
             &self.gpu_state.globals,
             0,
-            bytemuck::bytes_of(&GlobalUniformBlock {
-                view_proj: render_view_proj,
-                view_proj_inverse: cgmath::Matrix4::from(render_view_proj).invert().unwrap().into(),
-                shadow_view_proj: self.shadow_view_proj,
-                frustum_planes: [
-                    relative_frustum.planes[0].cast().unwrap().into(),
-                    relative_frustum.planes[1].cast().unwrap().into(),
-                    relative_frustum.planes[2].cast().unwrap().into(),
-                    relative_frustum.planes[3].cast().unwrap().into(),
-                    relative_frustum.planes[4].cast().unwrap().into(),
-                ],
-                camera: [self.camera.x as f32, self.camera.y as f32, self.camera.z as f32],
-                screen_width: frame_size.0 as f32,
-                sun_direction: [0.4, 0.7, 0.2],
-                screen_height: frame_size.1 as f32,
-                sidereal_time: 0.0,
-                exposure: 1.0 / (f32::powf(2.0, 15.0) * 1.2),
-                _padding: [0.0; 2],
-            }),
+            bytemuck::bytes_of(&self.view_globals(frame_size, render_view_proj)),
         );
 
         let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
@@ -617,10 +2230,39 @@ impl Terrain {
         });
 
         {
-            self.cache.run_dynamic_generators(queue, &mut encoder, &self.gpu_state);
-            self.cache.cull_meshes(device, &mut encoder, &self.gpu_state);
+            // Aerial perspective is only sampled when shading with the atmosphere.
+            if self.lighting == Lighting::Atmosphere {
+                self.cache.run_dynamic_generators(queue, &mut encoder, &self.gpu_state);
+            }
+            self.cache.cull_meshes(
+                device,
+                &mut encoder,
+                &self.gpu_state,
+                self.frustum_culling,
+                self.max_render_distance,
+            );
+            let tree_model_entries = self
+                .cache
+                .visible_mesh_entries(MeshType::TreeBillboards)
+                .filter(|_| self.tree_model_distance > 0.0);
+            if let Some(ref entries) = tree_model_entries {
+                self.models.cull_tree_models(
+                    device,
+                    queue,
+                    &mut encoder,
+                    &self.gpu_state,
+                    entries.clone(),
+                );
+            }
 
-            self.generate_skyview.run(device, &mut encoder, &self.gpu_state, (16, 16, 1), &());
+            // The LUT isn't used in space view or without the atmosphere, so leave it marked dirty
+            // until it is needed again.
+            if !self.space_view()
+                && self.lighting == Lighting::Atmosphere
+                && self.skyview_dirty.swap(false, Ordering::Relaxed)
+            {
+                self.generate_skyview.run(device, &mut encoder, &self.gpu_state, (16, 16, 1), &());
+            }
 
             let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 color_attachments: &[wgpu::RenderPassColorAttachment {
@@ -630,37 +2272,587 @@ impl Terrain {
                 }],
                 depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
                     view: depth_buffer,
-                    depth_ops: Some(wgpu::Operations::default()),
+                    depth_ops: Some(wgpu::Operations {
+                        load: if self.coverage_output {
+                            wgpu::LoadOp::Load
+                        } else {
+                            wgpu::LoadOp::Clear(self.clip_depth.far_depth())
+                        },
+                        store: true,
+                    }),
                     stencil_ops: None,
                 }),
                 label: Some("renderpass"),
             });
+            // Drawn before the billboards they replace, since billboard pixels given over to a
+            // model are discarded but still write depth.
+            if tree_model_entries.is_some() {
+                self.models.render_tree_models(&mut rpass, &self.gpu_state);
+            }
             self.cache.render_meshes(device, &mut rpass, &self.gpu_state);
 
-            rpass.set_pipeline(&self.sky_bindgroup_pipeline.as_ref().unwrap().1);
-            rpass.set_bind_group(0, &self.sky_bindgroup_pipeline.as_ref().unwrap().0, &[]);
-            rpass.draw(0..3, 0..1);
+            if !self.coverage_output {
+                rpass.set_pipeline(&self.sky_bindgroup_pipeline.as_ref().unwrap().1);
+                rpass.set_bind_group(0, &self.sky_bindgroup_pipeline.as_ref().unwrap().0, &[]);
+                rpass.draw(0..3, 0..1);
 
-            rpass.set_pipeline(&self.stars_bindgroup_pipeline.as_ref().unwrap().1);
-            rpass.set_bind_group(0, &self.stars_bindgroup_pipeline.as_ref().unwrap().0, &[]);
-            rpass.draw(0..9096 * 6, 0..1);
+                rpass.set_pipeline(&self.stars_bindgroup_pipeline.as_ref().unwrap().1);
+                rpass.set_bind_group(0, &self.stars_bindgroup_pipeline.as_ref().unwrap().0, &[]);
+                rpass.draw(0..9096 * 6, 0..1);
+
+                if self.space_view() && self.limb_antialiasing > 0.0 {
+                    rpass.set_pipeline(&self.limb_bindgroup_pipeline.as_ref().unwrap().1);
+                    rpass.set_bind_group(0, &self.limb_bindgroup_pipeline.as_ref().unwrap().0, &[]);
+                    rpass.draw(0..3, 0..1);
+                }
+            }
         }
 
         queue.submit(Some(encoder.finish()));
     }
 
-    pub fn get_height(&self, latitude: f64, longitude: f64) -> f32 {
+    /// Render only the depth of the terrain surface into `depth_buffer`, without any color
+    /// output, so that applications can run their own screen space effects such as SSAO or fog.
+    ///
+    /// Takes the same `frame_size` and `render_view_proj` as `render` and, like it, requires
+    /// `update` to be called first. `depth_buffer` must have the format given by
+    /// `TerrainConfig::depth_format` and is cleared first. Depth values match those written by
+    /// `render`, following `TerrainConfig::clip_depth`: with the default `ClipDepth::ReversedZ`
+    /// they go from 1.0 at the near plane to 0.0 at infinity. Grass and trees aren't drawn.
+    pub fn render_depth(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        depth_buffer: &wgpu::TextureView,
+        frame_size: (u32, u32),
+        render_view_proj: mint::ColumnMatrix4<f32>,
+    ) {
+        queue.write_buffer(
+            &self.gpu_state.globals,
+            0,
+            bytemuck::bytes_of(&self.view_globals(frame_size, render_view_proj)),
+        );
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("encoder.render_depth"),
+        });
+
+        {
+            self.cache.cull_meshes(
+                device,
+                &mut encoder,
+                &self.gpu_state,
+                self.frustum_culling,
+                self.max_render_distance,
+            );
+
+            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: depth_buffer,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(self.clip_depth.far_depth()),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+                label: Some("depthpass"),
+            });
+            self.cache.render_terrain_depth(device, &mut rpass, &self.gpu_state);
+        }
+
+        queue.submit(Some(encoder.finish()));
+    }
+
+    /// Return counters describing the internal state of the terrain.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            tile_fetch_failures: self.cache.tile_fetch_failures(),
+            tile_decode_failures: self.cache.tile_decode_failures(),
+        }
+    }
+
+    /// Build a triangle mesh of `node` suitable for physics engines, using the same tessellation
+    /// as rendering. Vertex positions are relative to `node.center_wspace()`. Returns `None` if
+    /// the node's heightmap isn't resident on the CPU.
+    pub fn collision_mesh(&self, node: VNode) -> Option<(Vec<[f32; 3]>, Vec<u32>)> {
+        self.cache.collision_mesh(node)
+    }
+
+    /// Returns the world space position of the point at the given latitude and longitude (in
+    /// radians) and `altitude` meters above sea level. This is the coordinate system that camera
+    /// positions passed to `update` and `render` are expected in.
+    pub fn world_position(latitude: f64, longitude: f64, altitude: f64) -> mint::Point3<f64> {
+        let p = coordinates::polar_to_ecef(cgmath::Vector3::new(latitude, longitude, altitude));
+        mint::Point3 { x: p.x, y: p.y, z: p.z }
+    }
+
+    /// Inverse of `world_position`. Returns the latitude and longitude (in radians) and altitude
+    /// above sea level (in meters) of a world space position.
+    pub fn geographic_position(position: mint::Point3<f64>) -> (f64, f64, f64) {
+        let lla =
+            coordinates::ecef_to_polar(cgmath::Vector3::new(position.x, position.y, position.z));
+        (lla.x, lla.y, lla.z)
+    }
+
+    /// Returns the bounds of the part of the planet's surface visible through the `view_proj`
+    /// passed to the last `update`, for syncing minimaps or culling external data to what's on
+    /// screen. The surface is treated as a sphere at sea level, so terrain hiding what lies
+    /// behind it isn't accounted for. Returns `None` if the view doesn't intersect the surface,
+    /// for instance when looking up at the sky.
+    ///
+    /// The region is found by casting rays through a grid of points across the screen and adding
+    /// any part of the horizon inside the view, so it may be slightly smaller than the true
+    /// footprint along curved edges.
+    pub fn visible_region(&self) -> Option<GeoBounds> {
+        const GRID: u32 = 16;
+
+        let view_proj = cgmath::Matrix4::<f32>::from(self.view_proj).cast::<f64>().unwrap();
+        let inverse = view_proj.invert()?;
+        let frustum = InfiniteFrustum::from_matrix(view_proj, self.clip_depth.reversed());
+        let camera = cgmath::Vector3::new(self.camera.x, self.camera.y, self.camera.z);
+        let radius = coordinates::PLANET_RADIUS;
+
+        let mut points = Vec::new();
+        let (near, far) =
+            (1.0 - self.clip_depth.far_depth() as f64, self.clip_depth.far_depth() as f64);
+        for y in 0..=GRID {
+            for x in 0..=GRID {
+                let ndc = (x as f64 / GRID as f64 * 2.0 - 1.0, y as f64 / GRID as f64 * 2.0 - 1.0);
+                let unproject = |depth: f64| {
+                    let p = inverse * cgmath::Vector4::new(ndc.0, ndc.1, depth, 1.0);
+                    p.truncate() / p.w
+                };
+                let origin = unproject(near);
+                let direction = (unproject(near + (far - near) * 1e-6) - origin).normalize();
+
+                // Nearest intersection in front of the near plane with the sphere, which is
+                // centered at -camera in camera relative coordinates.
+                let offset = origin + camera;
+                let b = offset.dot(direction);
+                let discriminant = b * b - (offset.magnitude2() - radius * radius);
+                if discriminant < 0.0 {
+                    continue;
+                }
+                let t = if -b - discriminant.sqrt() >= 0.0 {
+                    -b - discriminant.sqrt()
+                } else {
+                    -b + discriminant.sqrt()
+                };
+                if t >= 0.0 {
+                    points.push(offset + direction * t);
+                }
+            }
+        }
+
+        let inside = |p: cgmath::Vector3<f64>| {
+            frustum.planes.iter().all(|q| q.truncate().dot(p - camera) + q.w >= 0.0)
+        };
+
+        // Points on the horizon between the grid rays, which otherwise miss the far edge of the
+        // surface whenever the sky is in view.
+        let distance = camera.magnitude();
+        if distance > radius {
+            let up = camera / distance;
+            let a = up
+                .cross(if up.z.abs() < 0.999 {
+                    cgmath::Vector3::unit_z()
+                } else {
+                    cgmath::Vector3::unit_x()
+                })
+                .normalize();
+            let b = up.cross(a);
+            let (cos_theta, sin_theta) =
+                (radius / distance, (1.0 - (radius / distance).powi(2)).sqrt());
+            for i in 0..256 {
+                let phi = i as f64 / 256.0 * 2.0 * std::f64::consts::PI;
+                let p = radius * (up * cos_theta + (a * phi.cos() + b * phi.sin()) * sin_theta);
+                if inside(p) {
+                    points.push(p);
+                }
+            }
+        }
+
+        if points.is_empty() {
+            return None;
+        }
+
+        // A visible pole makes every longitude visible.
+        let pole_visible = |z: f64| {
+            let pole = cgmath::Vector3::new(0.0, 0.0, z * radius);
+            pole.dot(camera) > radius * radius && inside(pole)
+        };
+        Some(Self::geo_bounds(&points, (pole_visible(-1.0), pole_visible(1.0))))
+    }
+
+    /// Bounds of the given points on the surface, extended to a full band of longitudes reaching
+    /// the south or north pole when `poles.0` or `poles.1` is set.
+    fn geo_bounds(points: &[cgmath::Vector3<f64>], poles: (bool, bool)) -> GeoBounds {
+        let mut latitudes = (f64::MAX, f64::MIN);
+        let mut longitudes = Vec::with_capacity(points.len());
+        for p in points {
+            let lla = coordinates::ecef_to_polar(*p);
+            latitudes = (latitudes.0.min(lla.x), latitudes.1.max(lla.x));
+            longitudes.push(lla.y);
+        }
+
+        if poles.0 {
+            latitudes.0 = -std::f64::consts::FRAC_PI_2;
+        }
+        if poles.1 {
+            latitudes.1 = std::f64::consts::FRAC_PI_2;
+        }
+        let longitudes = if poles.0 || poles.1 {
+            (-std::f64::consts::PI, std::f64::consts::PI)
+        } else {
+            Self::longitude_range(longitudes)
+        };
+
+        GeoBounds {
+            south_west: (latitudes.0, longitudes.0),
+            north_east: (latitudes.1, longitudes.1),
+        }
+    }
+
+    /// Smallest range of longitudes that contains all of `longitudes`, found by leaving out the
+    /// largest gap between them. The end of the range may exceed PI if it wraps around.
+    fn longitude_range(mut longitudes: Vec<f64>) -> (f64, f64) {
+        use std::f64::consts::PI;
+        longitudes.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let (first, last) = (longitudes[0], longitudes[longitudes.len() - 1]);
+
+        // The gap across the antimeridian, then the gaps between consecutive longitudes.
+        let mut range = (first, last);
+        let mut largest_gap = first + 2.0 * PI - last;
+        for w in longitudes.windows(2) {
+            if w[1] - w[0] > largest_gap {
+                largest_gap = w[1] - w[0];
+                range = (w[1], w[0] + 2.0 * PI);
+            }
+        }
+        range
+    }
+
+    /// Stream and render terrain at `level` or finer around the given latitude and longitude (in
+    /// radians), regardless of how far away the camera is. This lasts until `unpin_detail` is
+    /// called with the returned id, and any number of pins can be active at once. Levels finer
+    /// than the one set with `set_max_render_level` are not selected.
+    pub fn pin_detail(&mut self, latitude: f64, longitude: f64, level: u8) -> u64 {
+        let ecef = coordinates::polar_to_ecef(cgmath::Vector3::new(latitude, longitude, 0.0));
+        let cspace = ecef / ecef.x.abs().max(ecef.y.abs()).max(ecef.z.abs());
+        let (node, _, _) = VNode::from_cspace(cspace, level.min(MAX_QUADTREE_LEVEL));
+        self.quadtree.pin(node)
+    }
+
+    /// Remove a pin created by `pin_detail`, letting the affected area return to distance based
+    /// detail. Returns false if there was no such pin.
+    pub fn unpin_detail(&mut self, id: u64) -> bool {
+        self.quadtree.unpin(id)
+    }
+
+    /// Drop `layers` from the cache for the region between `south_west` and `north_east`, each
+    /// given as a latitude and longitude in radians, so that they are streamed or generated
+    /// afresh on the next `update`. Layers generated from the dropped ones are dropped too.
+    ///
+    /// Useful after regenerating part of the dataset on disk, or to stop holding tiles for a
+    /// region that is no longer being viewed: dropped tiles are only loaded again once the
+    /// camera needs them. Cache slots are preallocated on the GPU, so this frees their CPU side
+    /// copies but doesn't shrink GPU memory use. Returns the number of cached nodes affected.
+    pub fn invalidate_region(
+        &mut self,
+        south_west: (f64, f64),
+        north_east: (f64, f64),
+        layers: LayerMask,
+    ) -> usize {
+        self.cache.invalidate_region(south_west, north_east, layers)
+    }
+
+    /// Rescan the tile cache on disk and drop cached tiles whose files were added, removed or
+    /// modified since the last scan, so that they are streamed afresh on the next `update`. This
+    /// lets tiles be regenerated in the background while the results are viewed live.
+    ///
+    /// Only tiles whose on-disk content changed are affected, as judged by their size and
+    /// modification time, along with the layers generated from them. Cached descendants of
+    /// changed tiles are reloaded too, since layers generated for them may depend on their
+    /// ancestors. Tiles that terra itself downloaded into the cache don't count as changed.
+    /// Layers streamed from `TerrainConfig::datasets`, or from a `TileSource` whose tiles aren't
+    /// cached on disk, are never reloaded. Returns the number of cached nodes affected. Must be
+    /// awaited on a multi-threaded tokio runtime.
+    pub async fn reload_dataset(&mut self) -> Result<usize, Error> {
+        let mut invalidated = 0;
+        for layer in LayerType::iter().filter(|layer| layer.streamed_levels() > 0) {
+            let changed = self.mapfile.reload_tile_states(layer).await?;
+            if !changed.is_empty() {
+                invalidated += self.cache.invalidate_tiles(layer, &changed);
+            }
+        }
+        Ok(invalidated)
+    }
+
+    /// Returns the height in meters above sea level at the given latitude and longitude (in
+    /// radians). Points below sea level report the height of the water surface, which is zero.
+    /// Returns `None` if no heightmap covering the point is resident, which is always the case on
+    /// faces excluded by `TerrainConfig::faces`.
+    pub fn get_height(&self, latitude: f64, longitude: f64) -> Option<f32> {
         for level in (0..=VNode::LEVEL_CELL_1M).rev() {
             if let Some(height) = self.cache.get_height(latitude, longitude, level) {
-                return height;
+                return Some(height);
+            }
+        }
+        None
+    }
+
+    /// Returns the finest level at which a heightmap covering the given latitude and longitude
+    /// (in radians) is resident, which is the level `get_height` samples from. Returns `None` if
+    /// no covering heightmap is resident. Useful for waiting until the terrain around a point is
+    /// detailed enough before placing objects on it.
+    pub fn resident_level(&self, latitude: f64, longitude: f64) -> Option<u8> {
+        (0..=VNode::LEVEL_CELL_1M)
+            .rev()
+            .find(|&level| self.cache.get_height(latitude, longitude, level).is_some())
+    }
+
+    /// Returns the whole-planet heightmap for one cube face, decoded from the root tile of
+    /// `face`. Useful for building low resolution global models without calling `get_height`
+    /// many times. Must be awaited on a multi-threaded tokio runtime.
+    pub async fn coarse_heightmap(&self, face: u8) -> Result<CoarseHeightmap, Error> {
+        let layer = &self.mapfile.layers()[LayerType::Heightmaps];
+        let (resolution, border) = (layer.texture_resolution, layer.texture_border_size);
+        let heights =
+            self.mapfile.coarse_heightmap(face).await.map_err(|e| Error::MapFile(e.into()))?;
+        Ok(CoarseHeightmap { heights, resolution, border })
+    }
+
+    /// Returns whether the given latitude and longitude (in radians) is covered by water, or
+    /// `None` if no heightmap covering the point is resident. Like `get_height`, this uses the
+    /// finest resident heightmap.
+    ///
+    /// There is no water mask, so points count as water exactly when the ground is below sea
+    /// level, matching where water is rendered. Lakes above sea level are therefore reported as
+    /// land, while dry depressions below sea level such as Death Valley are reported as water.
+    pub fn is_water(&self, latitude: f64, longitude: f64) -> Option<bool> {
+        for level in (0..=VNode::LEVEL_CELL_1M).rev() {
+            if let Some(water) = self.cache.is_water(latitude, longitude, level) {
+                return Some(water);
+            }
+        }
+        None
+    }
+
+    /// Returns how far in meters the camera passed to the last `update` is above the terrain
+    /// directly below it, or a negative value if it is underground. Returns `None` if no
+    /// heightmap covering that point is resident.
+    pub fn camera_altitude_above_ground(&self) -> Option<f64> {
+        let (latitude, longitude, altitude) = Self::geographic_position(self.camera);
+        Some(altitude - self.get_height(latitude, longitude)? as f64)
+    }
+
+    /// Returns the aerial perspective along a view ray, matching what is applied to terrain. The
+    /// ray starts at `origin`, a world space position like those returned by `world_position`,
+    /// and travels `distance` meters along `direction`.
+    ///
+    /// The first three components are the light scattered into the ray by the atmosphere, in
+    /// the same linear units as `SunConfig::intensity` and before any exposure or tonemapping.
+    /// The last is the fraction of light from the end of the ray that reaches `origin`. Fog an
+    /// object's color with `color * a + rgb`. Uses the sun direction passed to the last `update`
+    /// and the transmittance lookup table generated along with the map file. Returns no fog
+    /// (`[0.0, 0.0, 0.0, 1.0]`) under `Lighting::Simple`, which has no atmosphere.
+    pub fn aerial_perspective(
+        &self,
+        origin: mint::Point3<f64>,
+        direction: mint::Vector3<f64>,
+        distance: f64,
+    ) -> [f32; 4] {
+        if matches!(self.lighting, Lighting::Simple(_)) || distance <= 0.0 {
+            return [0.0, 0.0, 0.0, 1.0];
+        }
+        let origin = cgmath::Vector3::new(origin.x, origin.y, origin.z);
+        let direction = cgmath::Vector3::from(direction).normalize();
+        let sun_direction = cgmath::Vector3::from(self.sun_direction).cast::<f64>().unwrap();
+        let sun_illuminance = cgmath::Vector3::from(self.sun.illuminance()).cast::<f64>().unwrap();
+        self.atmosphere.aerial_perspective(
+            origin,
+            origin + direction * distance,
+            sun_direction,
+            sun_illuminance,
+        )
+    }
+
+    /// Sample the height, normal and base albedo at the given latitude and longitude (in radians)
+    /// with a single lookup. Unlike calling `get_height` and `get_albedo` separately, every
+    /// value comes from the same node, the finest resident one with a heightmap. Returns `None`
+    /// if no heightmap covering the point is resident.
+    pub fn sample_surface(&self, latitude: f64, longitude: f64) -> Option<SurfaceSample> {
+        self.cache.sample_surface(latitude, longitude, VNode::LEVEL_CELL_1M)
+    }
+
+    /// Compute elevation statistics over the region between `south_west` and `north_east`, each
+    /// given as a latitude and longitude in radians. The region is sampled on a regular latitude
+    /// and longitude grid at roughly the heightmap resolution of `level`, coarsened if needed so
+    /// that no more than `max_samples` points are taken, using only resident tiles from that
+    /// level. Returns `None` if any part of the region isn't covered.
+    ///
+    /// Regions may cross the antimeridian, either with `north_east.1` greater than PI as in
+    /// `GeoBounds` or with it less than `south_west.1`.
+    pub fn region_elevation_stats(
+        &self,
+        south_west: (f64, f64),
+        north_east: (f64, f64),
+        level: u8,
+        max_samples: usize,
+    ) -> Option<ElevationStats> {
+        self.cache.region_elevation_stats(south_west, north_east, level, max_samples)
+    }
+
+    /// Returns the RGBA color of the streamed base albedo at the given latitude and longitude (in
+    /// radians), sampled from the finest resident tile. Returns `None` if no tile covering the
+    /// point has been loaded yet.
+    ///
+    /// Only the base albedo is available on the CPU, so detail added by tile generation on the
+    /// GPU isn't reflected here.
+    pub fn get_albedo(&self, latitude: f64, longitude: f64) -> Option<[u8; 4]> {
+        for level in (0..LayerType::BaseAlbedo.streamed_levels()).rev() {
+            if let Some(albedo) = self.cache.get_albedo(latitude, longitude, level) {
+                return Some(albedo);
+            }
+        }
+        None
+    }
+
+    /// Render a top-down map of the region between `south_west` and `north_east`, each given as a
+    /// latitude and longitude in radians. Colors come from the resident base albedo tiles and are
+    /// shaded by the slope of the resident heightmaps, lit from the north west. Pixels without a
+    /// resident albedo tile are left transparent.
+    ///
+    /// This runs entirely on the CPU and never requests tiles, so it is cheap enough to call for
+    /// minimaps but only shows as much detail as is currently loaded.
+    pub fn render_overview(
+        &self,
+        south_west: (f64, f64),
+        north_east: (f64, f64),
+        size: (u32, u32),
+    ) -> image::RgbaImage {
+        let latitude_step = (north_east.0 - south_west.0) / size.1 as f64;
+        let longitude_step = (north_east.1 - south_west.1) / size.0 as f64;
+        image::RgbaImage::from_fn(size.0, size.1, |x, y| {
+            let latitude = north_east.0 - (y as f64 + 0.5) * latitude_step;
+            let longitude = south_west.1 + (x as f64 + 0.5) * longitude_step;
+            self.overview_pixel(latitude, longitude, latitude_step, longitude_step)
+        })
+    }
+
+    /// Color of the overview map at the given latitude and longitude, with slopes measured over
+    /// a pixel of `latitude_step` by `longitude_step` radians.
+    fn overview_pixel(
+        &self,
+        latitude: f64,
+        longitude: f64,
+        latitude_step: f64,
+        longitude_step: f64,
+    ) -> image::Rgba<u8> {
+        use crate::srgb::LINEAR_TO_SRGB;
+
+        // Light direction in east, north, up coordinates.
+        let light = cgmath::Vector3::new(-1.0, 1.0, 1.0).normalize();
+
+        let albedo = match self.get_albedo(latitude, longitude) {
+            Some(albedo) => albedo,
+            None => return image::Rgba([0, 0, 0, 0]),
+        };
+
+        let height = |latitude, longitude| self.get_height(latitude, longitude).unwrap_or(0.0);
+        let dx = longitude_step * latitude.cos() * coordinates::PLANET_RADIUS;
+        let dy = latitude_step * coordinates::PLANET_RADIUS;
+        let slope_x = (height(latitude, longitude + longitude_step * 0.5)
+            - height(latitude, longitude - longitude_step * 0.5)) as f64
+            / dx.max(1e-6);
+        let slope_y = (height(latitude + latitude_step * 0.5, longitude)
+            - height(latitude - latitude_step * 0.5, longitude)) as f64
+            / dy.max(1e-6);
+        let normal = cgmath::Vector3::new(-slope_x, -slope_y, 1.0).normalize();
+
+        // Scale so that flat ground keeps its original color.
+        let shade = (normal.dot(light) / light.z).max(0.0).min(1.5);
+        let channel = |v: u8| LINEAR_TO_SRGB[(v as f64 * shade).min(255.0) as u8];
+        image::Rgba([channel(albedo[0]), channel(albedo[1]), channel(albedo[2]), 255])
+    }
+
+    /// Resample resident tiles over the region between `south_west` and `north_east`, each given
+    /// as a latitude and longitude in radians, into a pyramid of PNG map tiles in a standard
+    /// projection, for use with slippy map viewers such as Leaflet or MapLibre. Tiles are written
+    /// to `{directory}/{z}/{x}/{y}.png` for every zoom level in `export.zoom_levels`, with rows
+    /// numbered according to `export.scheme`. If `south_west` is east of `north_east`, the region
+    /// wraps across the antimeridian.
+    ///
+    /// Like `render_overview` this runs on the CPU and only samples what is currently resident,
+    /// so stream the region in at the needed detail first, for instance with `pin_detail`.
+    /// Pixels without resident data are left transparent and tiles with no resident data at all
+    /// aren't written. Returns the number of tiles written.
+    pub fn export_map_tiles<P: AsRef<Path>>(
+        &self,
+        directory: P,
+        south_west: (f64, f64),
+        north_east: (f64, f64),
+        export: &MapExport,
+    ) -> Result<usize, Error> {
+        let size = export.tile_size;
+        let mut written = 0;
+        for zoom in export.zoom_levels.clone() {
+            let (xs, ys) = export.projection.tile_range(zoom, south_west, north_east);
+            let rows = export.projection.tiles(zoom).1;
+            for x in xs.into_iter().flatten() {
+                for y in ys.clone() {
+                    let unproject = |px: f64, py: f64| {
+                        export.projection.unproject(
+                            zoom,
+                            x as f64 + px / size as f64,
+                            y as f64 + py / size as f64,
+                        )
+                    };
+
+                    let mut empty = true;
+                    let tile = image::RgbaImage::from_fn(size, size, |px, py| {
+                        let (px, py) = (px as f64, py as f64);
+                        let (latitude, longitude) = unproject(px + 0.5, py + 0.5);
+                        let pixel = match export.contents {
+                            MapTileContents::Albedo => {
+                                let (north, west) = unproject(px, py);
+                                let (south, east) = unproject(px + 1.0, py + 1.0);
+                                self.overview_pixel(latitude, longitude, north - south, east - west)
+                            }
+                            MapTileContents::TerrariumElevation => {
+                                match self.get_height(latitude, longitude) {
+                                    Some(height) => image::Rgba(export::terrarium(height)),
+                                    None => image::Rgba([0, 0, 0, 0]),
+                                }
+                            }
+                        };
+                        empty &= pixel[3] == 0;
+                        pixel
+                    });
+                    if empty {
+                        continue;
+                    }
+
+                    let column = directory.as_ref().join(zoom.to_string()).join(x.to_string());
+                    std::fs::create_dir_all(&column).with_context(|| {
+                        format!("Failed to create directory '{}'", column.display())
+                    })?;
+                    let path = column.join(format!("{}.png", export.scheme.row(y, rows)));
+                    tile.save(&path)
+                        .with_context(|| format!("Failed to write '{}'", path.display()))?;
+                    written += 1;
+                }
             }
         }
-        0.0
+        Ok(written)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use cgmath::{InnerSpace, Matrix4, Point3, SquareMatrix, Vector2, Vector3, Vector4};
+
     #[test]
     fn check_send() {
         struct Helper<T>(T);
@@ -670,4 +2862,117 @@ mod tests {
         impl<T: Send> AssertImpl for Helper<T> {}
         Helper::<super::Terrain>::assert();
     }
+
+    /// Reversed-Z view-projection with an infinite far plane, looking from the origin along
+    /// `forward` with a vertical field of view of `fovy` radians.
+    fn reversed_perspective(fovy: f64, forward: Vector3<f64>) -> Matrix4<f64> {
+        let f = 1.0 / (fovy / 2.0).tan();
+        #[rustfmt::skip]
+        let proj = Matrix4::new(
+            f, 0.0, 0.0, 0.0,
+            0.0, f, 0.0, 0.0,
+            0.0, 0.0, 0.0, -1.0,
+            0.0, 0.0, 0.1, 0.0,
+        );
+        proj * Matrix4::look_to_rh(Point3::new(0.0, 0.0, 0.0), forward, Vector3::unit_z())
+    }
+
+    #[test]
+    fn longitude_range_wraps_across_antimeridian() {
+        use std::f64::consts::PI;
+        let range = super::Terrain::longitude_range;
+        assert_eq!(range(vec![-1.0, 0.5, 0.2]), (-1.0, 0.5));
+        assert_eq!(range(vec![3.0, -3.0, 3.1]), (3.0, -3.0 + 2.0 * PI));
+        assert_eq!(range(vec![-3.1, 3.1]), (3.1, -3.1 + 2.0 * PI));
+        assert_eq!(range(vec![0.7]), (0.7, 0.7));
+
+        // Points circling a pole cover nearly every longitude, which only the pole itself
+        // rounds out to the full band.
+        let ring: Vec<_> = (0..8)
+            .map(|i| {
+                let longitude = (i as f64 / 8.0 - 0.5) * 2.0 * PI + 0.1;
+                super::coordinates::polar_to_ecef(Vector3::new(1.4, longitude, 0.0))
+            })
+            .collect();
+        let (west, east) =
+            range(ring.iter().map(|&p| super::coordinates::ecef_to_polar(p).y).collect());
+        assert!((east - west - 1.75 * PI).abs() < 1e-9, "{} {}", west, east);
+
+        let bounds = super::Terrain::geo_bounds(&ring, (false, true));
+        assert_eq!(bounds.south_west.1, -PI);
+        assert_eq!(bounds.north_east, (PI / 2.0, PI));
+        assert!((bounds.south_west.0 - 1.4).abs() < 1e-9);
+
+        let bounds = super::Terrain::geo_bounds(&ring, (true, false));
+        assert_eq!(bounds.south_west, (-PI / 2.0, -PI));
+        assert_eq!(bounds.north_east.1, PI);
+    }
+
+    #[test]
+    fn shadow_direction_matches_sun() {
+        let view_proj = reversed_perspective(1.0, Vector3::unit_x()).cast().unwrap().into();
+        for &sun in &[
+            [0.4, 0.7, 0.2],
+            [0.0, 0.0, 1.0],
+            [0.0, 0.0, -1.0],
+            [1.0, 0.0, 0.0],
+            [-0.3, 0.1, -0.9],
+        ] {
+            let m = super::Terrain::shadow_view_proj(sun, view_proj, super::ClipDepth::ReversedZ);
+            let m = Matrix4::from(m);
+            let s = Vector3::from(sun).normalize();
+
+            // The direction towards the sun doesn't move across the shadow map, and depth
+            // decreases towards the light.
+            let towards_sun = m * Vector4::new(s.x, s.y, s.z, 0.0);
+            assert!(towards_sun.x.abs() < 1e-6 && towards_sun.y.abs() < 1e-6, "{:?}", sun);
+            assert!(towards_sun.z < 0.0, "{:?}", sun);
+
+            // Directions perpendicular to it shift position in the map without changing depth.
+            let side = s.cross(if s.z.abs() < 0.9 { Vector3::unit_z() } else { Vector3::unit_x() });
+            let across = m * side.normalize().extend(0.0);
+            assert!(across.z.abs() < 1e-6, "{:?}", sun);
+            assert!(across.truncate().truncate().magnitude() > 0.0, "{:?}", sun);
+        }
+    }
+
+    #[test]
+    fn shadow_map_fits_view() {
+        let sun = [0.4, 0.7, 0.2];
+        for &(fovy, forward) in &[
+            (1.0, Vector3::unit_x()),
+            (0.2, Vector3::new(0.3, -1.0, -0.5)),
+            (2.0, Vector3::new(0.0, 0.2, -1.0)),
+        ] {
+            let view_proj = reversed_perspective(fovy, forward);
+            let inverse = view_proj.invert().unwrap();
+            let m = super::Terrain::shadow_view_proj(
+                sun,
+                view_proj.cast().unwrap().into(),
+                super::ClipDepth::ReversedZ,
+            );
+            let m = Matrix4::from(m).cast::<f64>().unwrap();
+
+            // Points in view up to the shadow distance land within the shadow map, and the
+            // furthest ones reach its edges.
+            let forward = forward.normalize();
+            let mut extent = Vector2::new(0.0f64, 0.0);
+            for y in -4..=4 {
+                for x in -4..=4 {
+                    let ndc = Vector4::new(x as f64 / 4.0, y as f64 / 4.0, 0.5, 1.0);
+                    let p = inverse * ndc;
+                    let direction = (p.truncate() / p.w).normalize();
+                    for &distance in &[1.0, 100.0, 8000.0] {
+                        let p = direction * distance / direction.dot(forward);
+                        let q = m * p.extend(1.0);
+                        assert!(q.x.abs() <= 1.0 && q.y.abs() <= 1.0, "{:?}", q);
+                        assert!(q.z >= 0.0 && q.z <= 1.0, "{:?}", q);
+                        extent.x = extent.x.max(q.x.abs());
+                        extent.y = extent.y.max(q.y.abs());
+                    }
+                }
+            }
+            assert!(extent.x > 0.9 && extent.y > 0.9, "{:?}", extent);
+        }
+    }
 }
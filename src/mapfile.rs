@@ -1,20 +1,21 @@
 use crate::asset::TERRA_DIRECTORY;
 use crate::cache::{LayerParams, LayerType, TextureFormat};
+use crate::tile_source::{self, TerraTileServer, TileSink, TileSource};
 use anyhow::Error;
 use atomicwrites::{AtomicFile, OverwriteBehavior};
 use basis_universal::{TranscodeParameters, Transcoder, TranscoderTextureFormat};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::io::{Read, Write};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 use std::{fs, num::NonZeroU32};
 use tokio::io::AsyncReadExt;
 use types::VNode;
 use vec_map::VecMap;
 
-const TERRA_TILES_URL: &str = "https://terra.fintelia.io/file/terra-tiles/";
-
 #[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) enum TileState {
     Base,
@@ -22,6 +23,9 @@ pub(crate) enum TileState {
     MissingBase,
 }
 
+/// Modification time and length of a tile file.
+type TileStamp = (Option<SystemTime>, u64);
+
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub(crate) struct TextureDescriptor {
     pub width: u32,
@@ -39,14 +43,39 @@ pub(crate) struct ShaderDescriptor {
 
 pub(crate) struct MapFile {
     layers: VecMap<LayerParams>,
-    _db: sled::Db,
+    db: sled::Db,
     textures: sled::Tree,
 
     remote_tiles: Arc<Mutex<VecMap<HashSet<VNode>>>>,
     local_tiles: Arc<Mutex<VecMap<HashSet<VNode>>>>,
+    /// Modification time and size of every tile on disk when it was last scanned or written, to
+    /// tell which tiles `reload_tile_states` finds changed.
+    tile_stamps: Mutex<VecMap<HashMap<VNode, TileStamp>>>,
+
+    /// Where tiles that aren't already on disk are fetched from.
+    source: Arc<dyn TileSource>,
+    /// Where generated tiles are written, or `None` to write them into the tile cache on disk.
+    sink: Option<Arc<dyn TileSink>>,
+    /// Whether tiles fetched from `source` are written into the tile cache on disk, and tiles
+    /// already there are read instead of fetching them again.
+    cache_fetched_tiles: bool,
+    /// Sources that replace `source` for individual layers. Tiles from them are never cached on
+    /// disk, so that switching sources can't leave stale tiles behind.
+    layer_sources: VecMap<Arc<dyn TileSource>>,
+    /// Base URLs of terra's tile server and its mirrors, tried in order for every download.
+    tile_server_urls: Arc<Mutex<Vec<String>>>,
+    /// Largest tile or tile list that will be downloaded from terra's tile server, in bytes.
+    max_download_size: Arc<AtomicU64>,
+
+    /// Elevation in the source heightmaps that corresponds to sea level.
+    sea_level: f32,
+    /// Lowest height, relative to sea level, that terrain is allowed to reach.
+    height_floor: f32,
 }
 impl MapFile {
-    pub(crate) fn new(layers: VecMap<LayerParams>) -> Self {
+    /// Open the tile cache, failing if it was written by a newer version of terra or with layer
+    /// parameters that don't match `layers`.
+    pub(crate) fn new(layers: VecMap<LayerParams>) -> Result<Self, Error> {
         let directory = TERRA_DIRECTORY.join("tiles/meta");
         let db = sled::open(&directory).expect(&format!(
             "Failed to open/create sled database. Deleting the '{}' directory may fix this",
@@ -61,19 +90,115 @@ impl MapFile {
             .map(|s| s.parse())
             .unwrap_or(Ok(CURRENT_VERSION))
             .unwrap();
+        if version > CURRENT_VERSION {
+            anyhow::bail!(
+                "The tile cache in '{}' was written by a newer version of terra (format {}, \
+                 expected {})",
+                directory.display(),
+                version,
+                CURRENT_VERSION
+            );
+        }
         if version < CURRENT_VERSION {
             db.drop_tree("tiles").unwrap();
             db.drop_tree("textures").unwrap();
         }
+
+        // Tiles don't record the parameters they were generated with, so reading them with
+        // different ones would silently produce garbage. Record a fingerprint of every layer and
+        // refuse to open caches whose layers have since changed.
+        let streamed_layers = || layers.values().filter(|p| p.layer_type.streamed_levels() > 0);
+        let mut changed = Vec::new();
+        for params in streamed_layers() {
+            let key = format!("layer/{}", params.layer_type.name());
+            match db.get(&key)? {
+                Some(existing) if &*existing != params.fingerprint().as_bytes() => {
+                    changed.push(params.layer_type.name())
+                }
+                _ => {}
+            }
+        }
+        if !changed.is_empty() {
+            anyhow::bail!(
+                "The tile cache format changed for layers [{}]. Delete the '{}' directory to \
+                 regenerate it",
+                changed.join(", "),
+                TERRA_DIRECTORY.join("tiles").display()
+            );
+        }
+        for params in streamed_layers() {
+            let key = format!("layer/{}", params.layer_type.name());
+            db.insert(key, &*params.fingerprint())?;
+        }
         db.insert("version", &*format!("{}", CURRENT_VERSION)).unwrap();
 
-        Self {
+        let remote_tiles: Arc<Mutex<VecMap<HashSet<VNode>>>> = Default::default();
+        let tile_server_urls =
+            Arc::new(Mutex::new(vec![tile_source::DEFAULT_TILE_SERVER_URL.to_owned()]));
+        let max_download_size = Arc::new(AtomicU64::new(tile_source::DEFAULT_MAX_DOWNLOAD_SIZE));
+        Ok(Self {
             layers,
             textures: db.open_tree("textures").unwrap(),
-            _db: db,
-            remote_tiles: Default::default(),
+            db,
+            remote_tiles: Arc::clone(&remote_tiles),
             local_tiles: Default::default(),
-        }
+            tile_stamps: Default::default(),
+            source: Arc::new(TerraTileServer::new(
+                remote_tiles,
+                Arc::clone(&tile_server_urls),
+                Arc::clone(&max_download_size),
+            )),
+            tile_server_urls,
+            max_download_size,
+            sink: None,
+            cache_fetched_tiles: true,
+            layer_sources: VecMap::new(),
+            sea_level: 0.0,
+            height_floor: -1024.0,
+        })
+    }
+
+    pub(crate) fn sea_level(&self) -> f32 {
+        self.sea_level
+    }
+    pub(crate) fn set_sea_level(&mut self, sea_level: f32) {
+        self.sea_level = sea_level;
+    }
+    pub(crate) fn height_floor(&self) -> f32 {
+        self.height_floor
+    }
+    pub(crate) fn set_height_floor(&mut self, height_floor: f32) {
+        // Heights are encoded on the GPU with a fixed offset of 1024 meters.
+        self.height_floor = height_floor.max(-1024.0);
+    }
+
+    pub(crate) fn set_tile_source(&mut self, source: Arc<dyn TileSource>) {
+        self.source = source;
+    }
+    pub(crate) fn set_tile_sink(&mut self, sink: Arc<dyn TileSink>) {
+        self.sink = Some(sink);
+    }
+    pub(crate) fn set_layer_source(&mut self, layer: LayerType, source: Arc<dyn TileSource>) {
+        self.layer_sources.insert(layer.index(), source);
+    }
+    pub(crate) fn set_cache_fetched_tiles(&mut self, cache_fetched_tiles: bool) {
+        self.cache_fetched_tiles = cache_fetched_tiles;
+    }
+    pub(crate) fn set_max_download_size(&mut self, max_download_size: u64) {
+        self.max_download_size.store(max_download_size, Ordering::Relaxed);
+    }
+    pub(crate) fn set_tile_server_urls(&mut self, urls: Vec<String>) {
+        *self.tile_server_urls.lock().unwrap() = urls;
+    }
+
+    /// URLs to download a file hosted alongside the tiles from, in the order they should be tried.
+    pub(crate) fn tile_server_file_urls(&self, filename: &str) -> Vec<String> {
+        self.tile_server_urls
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|base_url| format!("{}/{}", base_url.trim_end_matches('/'), filename))
+            .collect()
     }
 
     pub(crate) fn tile_state(&self, layer: LayerType, node: VNode) -> Result<TileState, Error> {
@@ -98,24 +223,22 @@ impl MapFile {
     pub(crate) async fn read_tile(&self, layer: LayerType, node: VNode) -> Result<Option<Vec<u8>>, Error> {
         assert!(layer.streamed_levels() > 0);
 
+        if let Some(source) = self.layer_sources.get(layer.index()) {
+            return source.fetch(layer, node).await;
+        }
+        if !self.cache_fetched_tiles {
+            return self.source.fetch(layer, node).await;
+        }
+
         let filename = Self::tile_path(layer, node);
         if !filename.exists() {
-            if !self.remote_tiles.lock().unwrap()[layer].contains(&node) {
-                return Ok(None);
-            }
-            // unreachable!("{:?}", filename)
-            let url = Self::tile_url(layer, node);
-            let client = hyper::Client::builder()
-                .build::<_, hyper::Body>(hyper_tls::HttpsConnector::new());
-            let resp = client.get(url.parse()?).await?;
-            if resp.status().is_success() {
-                let data = hyper::body::to_bytes(resp.into_body()).await?.to_vec();
-                // TODO: Fix lifetime issues so we can do this tile write asynchronously.
-                tokio::task::block_in_place(|| self.write_tile(layer, node, &data))?;
-                return Ok(Some(data));
-            } else {
-                panic!("Tile download failed with {:?} for URL '{}'", resp.status(), url);
-            }
+            let data = match self.source.fetch(layer, node).await? {
+                Some(data) => data,
+                None => return Ok(None),
+            };
+            // TODO: Fix lifetime issues so we can do this tile write asynchronously.
+            tokio::task::block_in_place(|| self.write_tile_to_disk(layer, node, &data))?;
+            return Ok(Some(data));
         }
 
         let mut contents = Vec::new();
@@ -123,20 +246,40 @@ impl MapFile {
         Ok(Some(contents))
     }
 
+    /// Store a generated tile, either in the tile sink if one was provided or on disk.
     pub(crate) fn write_tile(
         &self,
         layer: LayerType,
         node: VNode,
         data: &[u8],
+    ) -> Result<(), Error> {
+        match self.sink {
+            Some(ref sink) => sink.write(layer, node, data),
+            None => self.write_tile_to_disk(layer, node, data),
+        }
+    }
+
+    fn write_tile_to_disk(
+        &self,
+        layer: LayerType,
+        node: VNode,
+        data: &[u8],
     ) -> Result<(), Error> {
         let filename = Self::tile_path(layer, node);
         if let Some(parent) = filename.parent() {
             fs::create_dir_all(parent)?;
         }
 
-        AtomicFile::new(filename, OverwriteBehavior::AllowOverwrite)
+        AtomicFile::new(&filename, OverwriteBehavior::AllowOverwrite)
             .write(|f| f.write_all(data))?;
 
+        let metadata = fs::metadata(&filename)?;
+        self.tile_stamps
+            .lock()
+            .unwrap()
+            .entry(layer.index())
+            .or_insert_with(Default::default)
+            .insert(node, (metadata.modified().ok(), metadata.len()));
         self.local_tiles
             .lock()
             .unwrap()
@@ -147,6 +290,36 @@ impl MapFile {
         Ok(())
     }
 
+    /// Read and decode the root heightmap tile of `face`, downloading it first if necessary.
+    /// The result is a square, row major grid of `texture_resolution` samples on each side
+    /// including the border, in the same units as streamed heightmap tiles.
+    pub(crate) async fn coarse_heightmap(&self, face: u8) -> Result<Vec<i16>, Error> {
+        if face >= 6 {
+            anyhow::bail!("Invalid face {}", face);
+        }
+        let root = VNode::roots()[face as usize];
+        match self.read_tile(LayerType::Heightmaps, root).await? {
+            Some(data) => Ok(tilefmt::uncompress_heightmap_tile(None, &data).1),
+            None => anyhow::bail!("Missing root heightmap tile for face {}", face),
+        }
+    }
+
+    /// Remove a tile from disk so that it will be downloaded again the next time it is read.
+    pub(crate) fn invalidate_tile(&self, layer: LayerType, node: VNode) -> Result<(), Error> {
+        if let Some(tiles) = self.local_tiles.lock().unwrap().get_mut(layer.index()) {
+            tiles.remove(&node);
+        }
+        if let Some(stamps) = self.tile_stamps.lock().unwrap().get_mut(layer.index()) {
+            stamps.remove(&node);
+        }
+
+        let filename = Self::tile_path(layer, node);
+        if filename.exists() {
+            fs::remove_file(filename)?;
+        }
+        Ok(())
+    }
+
     pub(crate) fn read_texture(
         &self,
         device: &wgpu::Device,
@@ -315,6 +488,58 @@ impl MapFile {
         Ok(texture)
     }
 
+    /// Bytes of GPU memory taken by the texture that `read_texture` creates for `name`. Only
+    /// UASTC textures have mipmaps, which are assumed to go all the way down to a single block.
+    pub(crate) fn texture_bytes(&self, name: &str) -> Result<u64, Error> {
+        let desc = match self.lookup_texture(name)? {
+            Some(desc) => desc,
+            None => return Ok(0),
+        };
+
+        let block_size = desc.format.block_size();
+        let mut bytes = 0;
+        for mip in 0.. {
+            let (width, height) = (desc.width >> mip, desc.height >> mip);
+            let depth = if desc.array_texture { desc.depth } else { desc.depth >> mip };
+            if mip > 0 && (desc.format != TextureFormat::UASTC || width == 0 && height == 0) {
+                break;
+            }
+
+            let width = (width.max(1) - 1) / block_size + 1;
+            let height = (height.max(1) - 1) / block_size + 1;
+            bytes += (width * height * depth.max(1)) as u64 * desc.format.bytes_per_block() as u64;
+        }
+        Ok(bytes)
+    }
+
+    /// Read back a texture that `write_texture` stored uncompressed, such as the atmosphere
+    /// lookup tables, without uploading it to the GPU. Fails unless the texture was stored in
+    /// `format` and the file holds every texel of its base level.
+    pub(crate) fn read_raw_texture(
+        &self,
+        name: &str,
+        format: TextureFormat,
+    ) -> Result<(TextureDescriptor, Vec<u8>), Error> {
+        let desc = match self.lookup_texture(name)? {
+            Some(desc) => desc,
+            None => anyhow::bail!("The {} texture hasn't been generated", name),
+        };
+        if desc.format != format {
+            anyhow::bail!("The {} texture is {:?} rather than {:?}", name, desc.format, format);
+        }
+        if format == TextureFormat::RGBA8 || format == TextureFormat::UASTC {
+            anyhow::bail!("The {} texture isn't stored uncompressed", name);
+        }
+
+        let bytes = fs::read(TERRA_DIRECTORY.join(format!("{}.raw", name)))?;
+        let expected =
+            (desc.width * desc.height * desc.depth.max(1)) as usize * format.bytes_per_block();
+        if bytes.len() < expected {
+            anyhow::bail!("The {} texture is truncated", name);
+        }
+        Ok((desc, bytes))
+    }
+
     pub(crate) fn write_texture(
         &self,
         name: &str,
@@ -359,6 +584,21 @@ impl MapFile {
         }
     }
 
+    /// Returns the fingerprint recorded for a texture by `set_texture_fingerprint`, if any.
+    pub(crate) fn texture_fingerprint(&self, name: &str) -> Result<Option<String>, Error> {
+        Ok(self
+            .db
+            .get(format!("fingerprint.{}", name))?
+            .map(|v| String::from_utf8_lossy(&v).into_owned()))
+    }
+
+    /// Record the parameters that a texture was generated with, so that it can be regenerated
+    /// when they change.
+    pub(crate) fn set_texture_fingerprint(&self, name: &str, fingerprint: &str) -> Result<(), Error> {
+        self.db.insert(format!("fingerprint.{}", name), fingerprint)?;
+        Ok(())
+    }
+
     pub(crate) fn layers(&self) -> &VecMap<LayerParams> {
         &self.layers
     }
@@ -371,7 +611,7 @@ impl MapFile {
             _ => unreachable!(),
         }
     }
-    fn tile_name(layer: LayerType, node: VNode) -> String {
+    pub(crate) fn tile_name(layer: LayerType, node: VNode) -> String {
         let face = match node.face() {
             0 => "0E",
             1 => "180E",
@@ -389,11 +629,14 @@ impl MapFile {
         TERRA_DIRECTORY.join("tiles").join(&Self::tile_name(layer, node))
     }
 
-    fn tile_url(layer: LayerType, node: VNode) -> String {
-        format!("{}{}", TERRA_TILES_URL, Self::tile_name(layer, node))
-    }
-
-    pub(crate) async fn reload_tile_states(&self, layer: LayerType) -> Result<(), Error> {
+    /// Scan the tile cache on disk to learn which tiles of `layer` exist locally and, downloading
+    /// the list first if needed, which exist on the tile server. Returns the tiles that were
+    /// added, removed or modified on disk since the previous scan, other than by terra itself.
+    /// Every tile on disk counts as added the first time.
+    pub(crate) async fn reload_tile_states(
+        &self,
+        layer: LayerType,
+    ) -> Result<HashSet<VNode>, Error> {
         let (target_layer, target_ext) = Self::layer_name_ext_strs(layer);
 
         fn face_index(s: &str) -> Option<u8>{
@@ -409,13 +652,14 @@ impl MapFile {
         }
 
         let mut all = HashSet::new();
-        let mut existing = HashSet::new();
+        let mut existing = HashMap::new();
 
         // Scan local files.
         let directory = TERRA_DIRECTORY.join("tiles").join(target_layer);
         std::fs::create_dir_all(&directory)?;
         for file in fs::read_dir(directory)? {
-            let filename = file?.file_name();
+            let file = file?;
+            let filename = file.file_name();
             let filename = filename.to_string_lossy();
 
             if let Ok((layer, level, face, x, y, ext)) =
@@ -423,25 +667,38 @@ impl MapFile {
             {
                 let face = face_index(&face);
                 if layer == target_layer && ext == target_ext && face.is_some(){
-                    existing.insert((level, face.unwrap(), x, y));
+                    let metadata = file.metadata()?;
+                    existing.insert(
+                        (level, face.unwrap(), x, y),
+                        (metadata.modified().ok(), metadata.len()),
+                    );
                 }
             }
         }
 
-        // Download file list if necessary.
+        // Download file list if necessary. Mirrors don't necessarily hold the same tiles, so the
+        // lists from every mirror that answers are merged. Each tile is then fetched from the
+        // first mirror that has it.
         let file_list_path =
             TERRA_DIRECTORY.join(&format!("tiles/{}_tile_list.txt.gz", target_layer));
         if !file_list_path.exists() {
-            let url = format!("{}{}_tile_list.txt.lz4", TERRA_TILES_URL, target_layer);
-            let client =
-                hyper::Client::builder().build::<_, hyper::Body>(hyper_tls::HttpsConnector::new());
-            let resp = client.get(url.parse()?).await?;
-            if resp.status().is_success() {
-                let contents = hyper::body::to_bytes(resp.into_body()).await?.to_vec();
-                tokio::fs::write(&file_list_path, contents).await?;
-            } else {
-                anyhow::bail!("Failed to download '{}'", url);
+            let path = format!("{}_tile_list.txt.lz4", target_layer);
+            let base_urls = self.tile_server_urls.lock().unwrap().clone();
+            let max_size = self.max_download_size.load(Ordering::Relaxed);
+            let lists = tile_source::download_from_all(&base_urls, &path, max_size).await?;
+
+            let mut merged = String::new();
+            for list in lists {
+                lz4::Decoder::new(std::io::Cursor::new(&list))?.read_to_string(&mut merged)?;
+                if !merged.ends_with('\n') {
+                    merged.push('\n');
+                }
             }
+            let mut encoder = lz4::EncoderBuilder::new().build(Vec::new())?;
+            encoder.write_all(merged.as_bytes())?;
+            let (contents, result) = encoder.finish();
+            result?;
+            tokio::fs::write(&file_list_path, contents).await?;
         }
         // Parse file list to learn all files available from the remote.
         let mut remote_files = String::new();
@@ -460,15 +717,24 @@ impl MapFile {
 
         let mut local_tiles = self.local_tiles.lock().unwrap();
         let mut remote_tiles = self.remote_tiles.lock().unwrap();
+        let mut tile_stamps = self.tile_stamps.lock().unwrap();
 
         let local_tiles = local_tiles.entry(layer.index()).or_insert_with(Default::default);
         let remote_tiles = remote_tiles.entry(layer.index()).or_insert_with(Default::default);
+        let tile_stamps = tile_stamps.entry(layer.index()).or_insert_with(Default::default);
 
+        let mut changed = HashSet::new();
         VNode::breadth_first(|n| {
-            if existing.contains(&(n.level(), n.face(), n.x(), n.y())) {
+            let stamp = existing.get(&(n.level(), n.face(), n.x(), n.y())).copied();
+            if stamp != tile_stamps.get(&n).copied() {
+                changed.insert(n);
+            }
+            if let Some(stamp) = stamp {
                 local_tiles.insert(n);
+                tile_stamps.insert(n, stamp);
             } else {
                 local_tiles.remove(&n);
+                tile_stamps.remove(&n);
             }
 
             if all.contains(&(n.level(), n.face(), n.x(), n.y())) {
@@ -478,7 +744,7 @@ impl MapFile {
             n.level() + 1 < layer.streamed_levels()
         });
 
-        Ok(())
+        Ok(changed)
     }
 
     /// Return a list of the missing bases for a layer, as well as the total number bases in the layer.
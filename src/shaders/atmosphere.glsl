@@ -31,8 +31,7 @@ vec2 rsi(vec3 r0, vec3 rd, float sr) {
     );
 }
 
-vec3 atmosphere(vec3 r0, vec3 r1, vec3 pSun) {
-	float iSun = 100000.0;
+vec3 atmosphere(vec3 r0, vec3 r1, vec3 pSun, vec3 iSun) {
 	vec3 kRlh = vec3(5.8e-6, 13.5e-6, 33.1e-6);
 	float kMie = 2.0e-6;
 	float shRlh = 8000.0;
@@ -8,15 +8,92 @@ struct Globals {
     mat4 view_proj;
 	mat4 view_proj_inverse;
 	mat4 shadow_view_proj;
-	vec4 frustum_planes[5];
+	vec4 frustum_planes[6];
 	vec3 camera;
 	float screen_width;
 	vec3 sun_direction;
 	float screen_height;
 	float sidereal_time;
 	float exposure;
+	float sun_angular_radius;
+	uint sun_limb_darkening;
+	vec3 sun_illuminance;
+	float shadow_softness;
+	float lod_morph_range;
+	uint space_view;
+	float season;
+	uint terrain_mesh_entries;
+	float night_lights_intensity;
+	float texture_lod_bias;
+	float far_depth;
+	float time;
+	float wind_direction;
+	float wind_strength;
+	float wind_gust_frequency;
+	float tree_model_distance;
+	float tree_model_fade;
+	uint shadow_kernel_radius;
+	float ao_strength;
+	float star_visibility_threshold;
+	uint visualization;
+	float hillshade_azimuth;
+	float hillshade_altitude;
+	float _padding0;
+	vec3 camera_offset;
+	float vertical_exaggeration;
+	float contrast;
+	float saturation;
+	float gamma;
+	uint lighting;
+	vec3 sky_zenith_color;
+	float ambient;
+	vec3 sky_horizon_color;
+	float limb_antialiasing;
+	vec3 moon_direction;
+	float _padding1;
 };
 
+// Height of the tallest mountain, in meters above sea level.
+const float MAX_TERRAIN_HEIGHT = 8850.0;
+
+const uint VISUALIZATION_REALISTIC = 0;
+const uint VISUALIZATION_HILLSHADE = 1;
+
+const uint LIGHTING_ATMOSPHERE = 0;
+const uint LIGHTING_SIMPLE = 1;
+
+// Horizontal displacement of vegetation at `origin_position` caused by the wind, per meter of
+// height above the ground. Positions are relative to the render origin rather than the center of
+// the planet, so that they keep full precision. A slow gust wave travels in the wind direction, with a faster flutter
+// on top that varies from plant to plant.
+vec3 wind_sway(Globals g, vec3 origin_position, vec3 up) {
+	vec3 east = normalize(cross(abs(up.z) < 0.999 ? vec3(0, 0, 1) : vec3(1, 0, 0), up));
+	vec3 north = cross(up, east);
+	vec3 direction = sin(g.wind_direction) * east + cos(g.wind_direction) * north;
+
+	float s = dot(origin_position, direction);
+	float gust = 0.5 + 0.5 * sin(6.2831853 * g.wind_gust_frequency * g.time - s * 0.05);
+	float flutter = sin(3.0 * g.time + s * 0.7 + dot(origin_position, up - direction));
+	return direction * g.wind_strength * (0.3 + 0.7 * gust + 0.15 * flutter);
+}
+
+// Radiance of the gradient sky drawn in direction `r` under LIGHTING_SIMPLE, which stands in for
+// the atmosphere. It brightens and dims with the height of the sun above the camera's horizon.
+vec3 simple_sky(Globals g, vec3 r) {
+	vec3 up = normalize(g.camera);
+	float daylight = smoothstep(-0.1, 0.2, dot(up, g.sun_direction));
+	vec3 color = mix(g.sky_horizon_color, g.sky_zenith_color, sqrt(max(dot(r, up), 0.0)));
+	return color * g.sun_illuminance / 3.1415926535 * daylight;
+}
+
+// Fraction of a tree at `distance` meters from the camera that is drawn as a billboard rather
+// than a 3D model. In between the two are cross-faded by drawing each pixel from one or the other.
+float tree_billboard_fade(Globals g, float distance) {
+	if (g.tree_model_fade <= 0.0)
+		return float(distance >= g.tree_model_distance);
+	return clamp((distance - g.tree_model_distance) / g.tree_model_fade, 0.0, 1.0);
+}
+
 struct Indirect {
     uint vertex_count;
     uint instance_count;
@@ -45,7 +122,8 @@ struct Node {
 	uvec2 coords;
 
 	int parent;
-	uint padding[43];
+	uint coarser_neighbors;
+	uint padding[42];
 };
 
 struct GenMeshUniforms {
@@ -53,6 +131,7 @@ struct GenMeshUniforms {
     uint storage_base_entry;
     uint mesh_base_entry;
     uint entries_per_node;
+    uint seed;
 };
 
 float extract_height(uint encoded) {
@@ -13,7 +13,8 @@ struct Node {
     coords: vec2<u32>;
 
     parent: i32;
-	padding2: array<u32, 43>;
+	coarser_neighbors: u32;
+	padding2: array<u32, 42>;
 };
 struct Nodes {
     entries: array<Node>;
@@ -24,6 +25,7 @@ struct GenMeshUniforms {
     storage_base_entry: u32;
     mesh_base_entry: u32;
     entries_per_node: u32;
+    seed: u32;
 };
 
 struct Indirect {
@@ -74,6 +76,8 @@ fn random(x: f32) -> f32 { return floatConstruct(hash(bitcast<u32>(x))); }
 fn random2(x: vec2<f32>) -> f32 { return floatConstruct(hash2(bitcast<vec2<u32>>(x))); }
 fn random3(x: vec3<f32>) -> f32 { return floatConstruct(hash3(bitcast<vec3<u32>>(x))); }
 fn random4(x: vec4<f32>) -> f32 { return floatConstruct(hash4(bitcast<vec4<u32>>(x))); }
+// Same as `random3`, but varied by `seed`. A seed of zero gives the same values as `random3`.
+fn seeded_random3(x: vec3<f32>, seed: u32) -> f32 { return floatConstruct(hash4(vec4<u32>(bitcast<vec3<u32>>(x), seed))); }
 
 fn extract_normal(n: vec2<f32>) -> vec3<f32> {
     let n = n * 2.0 - vec2<f32>(1.0);
@@ -55,11 +55,11 @@ fn main(
     let index = global_id.xy % vec2<u32>(32u);
     let entry = 4u * (global_id.y / 32u) + (global_id.x / 32u);
 
-    let rnd1 = random3(vec3<f32>(vec2<f32>(index), 1.0));
-    let rnd2 = random3(vec3<f32>(vec2<f32>(index), 2.0));
-    let rnd3 = random3(vec3<f32>(vec2<f32>(index), 3.0));
-    let rnd4 = random3(vec3<f32>(vec2<f32>(index), 4.0));
-    let rnd5 = random3(vec3<f32>(vec2<f32>(index), 5.0));
+    let rnd1 = seeded_random3(vec3<f32>(vec2<f32>(index), 1.0), ubo.seed);
+    let rnd2 = seeded_random3(vec3<f32>(vec2<f32>(index), 2.0), ubo.seed);
+    let rnd3 = seeded_random3(vec3<f32>(vec2<f32>(index), 3.0), ubo.seed);
+    let rnd4 = seeded_random3(vec3<f32>(vec2<f32>(index), 4.0), ubo.seed);
+    let rnd5 = seeded_random3(vec3<f32>(vec2<f32>(index), 5.0), ubo.seed);
 
     // let texcoord = vec2<f32>(global_id.xy) / 128.0;
     let normal = extract_normal(read_texture(NORMALS_LAYER, global_id).xy);
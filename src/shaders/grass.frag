@@ -47,23 +47,24 @@ void main() {
     // vec3 albedo_value = texture(sampler2DArray(albedo, linear), vec3(texcoord, node.nodes_slot)).xyz;
     // vec3 snormal = extract_normal(texture(sampler2DArray(normals, linear), layer_to_texcoord(NORMALS_LAYER)).xy);
 	float roughness_value = 0.5;
+	vec3 albedo = seasonal_tint(color, globals.season, 1.0);
 
 	out_color = vec4(1);
-	out_color.rgb = pbr(color,
+	out_color.rgb = pbr(albedo,
 						roughness_value,
 						position,
 						normal,
-						globals.camera,
+						vec3(0),
 						globals.sun_direction,
-						vec3(100000.0));
+						globals.sun_illuminance);
 
-	out_color.rgb += pbr(color,
+	out_color.rgb += pbr(albedo,
 						roughness_value,
 						position,
 						-normal,
-						globals.camera,
+						vec3(0),
 						globals.sun_direction,
-						vec3(100000.0));
+						globals.sun_illuminance);
 
 	// out_color.rgb = out_color.rgb * 0.3 + 0.7 * pbr(color,
 	// 					roughness_value,
@@ -74,4 +75,5 @@ void main() {
 	// 					vec3(100000.0));
 
 	out_color = tonemap(out_color, globals.exposure, 2.2);
+	out_color = color_grade(out_color, globals.contrast, globals.saturation, globals.gamma);
 }
\ No newline at end of file
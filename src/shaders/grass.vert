@@ -82,6 +82,7 @@ void main() {
     vec3 u = cos(entry.angle) * tangent + sin(entry.angle) * bitangent;
     vec3 w = -sin(entry.angle) * tangent + cos(entry.angle) * bitangent;
     position += (u*width*uv.x + (up + w*uv.y*entry.slant)*height*uv.y) * morph;
+    position += wind_sway(globals, entry.position - node.relative_position + globals.camera_offset, up) * height * uv.y * uv.y * morph;
 
     color = mix(entry.albedo, vec3(0, .4, .01), .0*uv.y);
     texcoord = entry.texcoord;
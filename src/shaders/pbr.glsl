@@ -20,6 +20,26 @@ const float PBR_WORKFLOW_SPECULAR_GLOSINESS = 1.0f;
 
 #define MANUAL_SRGB 1
 
+// Estimate how much of a surface with the given albedo is foliage, based on how green it is.
+float vegetation_fraction(vec3 albedo) {
+	return clamp(4.0 * (albedo.g - max(albedo.r, albedo.b)) / max(albedo.g, 1e-4), 0.0, 1.0);
+}
+
+// Shift foliage colors for the given season: unchanged at 0, turning yellow and then brown
+// towards 1. Brightness is preserved so only the hue changes.
+vec3 seasonal_tint(vec3 albedo, float season, float vegetation) {
+	const vec3 luminance = vec3(0.2126, 0.7152, 0.0722);
+	vec3 autumn = mix(vec3(0.9, 0.7, 0.1), vec3(0.45, 0.25, 0.1), smoothstep(0.4, 0.9, season));
+	vec3 tinted = autumn * dot(albedo, luminance) / dot(autumn, luminance);
+	return mix(albedo, tinted, vegetation * smoothstep(0.0, 0.4, season));
+}
+
+// Cover surfaces facing upwards (up = 1) with snow as the season approaches winter.
+vec3 seasonal_snow(vec3 albedo, float season, float up) {
+	float coverage = smoothstep(0.75, 1.0, season) * smoothstep(0.6, 0.9, up);
+	return mix(albedo, vec3(0.8), coverage);
+}
+
 vec3 Uncharted2Tonemap(vec3 color)
 {
 	float A = 0.15;
@@ -39,6 +59,16 @@ vec4 tonemap(vec4 color, float exposure, float gamma)
 	return vec4(pow(outcol, vec3(1.0f / gamma)), color.a);
 }
 
+// Simple color grading applied to tonemapped colors. Contrast stretches values away from middle
+// gray, saturation scales the distance from the Rec. 709 luminance, and gamma brightens midtones
+// when above one. All three are the identity at one.
+vec4 color_grade(vec4 color, float contrast, float saturation, float gamma)
+{
+	vec3 c = (color.rgb - 0.5) * contrast + 0.5;
+	c = mix(vec3(dot(c, vec3(0.2126, 0.7152, 0.0722))), c, saturation);
+	return vec4(pow(clamp(c, 0, 1), vec3(1.0 / gamma)), color.a);
+}
+
 // vec4 SRGBtoLINEAR(vec4 srgbIn)
 // {
 // 	#ifdef MANUAL_SRGB
@@ -21,10 +21,22 @@ layout(location = 0) out vec4 OutColor;
 const float PI = 3.1415926535;
 
 void main() {
-	vec4 r0 = globals.view_proj_inverse * vec4(position.xy, 1, 1);
-	vec4 r1 = globals.view_proj_inverse * vec4(position.xy, 1e-9, 1);
+	vec4 r0 = globals.view_proj_inverse * vec4(position.xy, 1 - globals.far_depth, 1);
+	vec4 r1 = globals.view_proj_inverse * vec4(position.xy, mix(globals.far_depth, 1 - globals.far_depth, 1e-6), 1);
 	vec3 r = normalize(r1.xyz / r1.w - r0.xyz / r0.w);
 
+#ifdef LIMB
+	// Fraction of the pixel covered by the planet, ramping up over the innermost
+	// `limb_antialiasing` pixels of its disc at sea level. The sky is blended over those pixels
+	// so that the terrain's hard edge against space fades out.
+	vec3 down = -normalize(globals.camera);
+	float angle = atan(length(cross(r, down)), dot(r, down));
+	float limb_angle = asin(min(planetRadius / length(globals.camera), 1.0));
+	float coverage = (limb_angle - angle) / (fwidth(angle) * globals.limb_antialiasing);
+	if (coverage <= 0.0 || coverage >= 1.0)
+		discard;
+#endif
+
 	vec3 camera = normalize(globals.camera);
     vec3 sun = normalize(globals.sun_direction);
     vec3 a = normalize(cross(camera, sun));
@@ -40,9 +52,49 @@ void main() {
 	float u = (theta - min_theta) / (max_theta - min_theta);
 	u = sqrt(u);
 
-	vec4 sv = texture(sampler2D(skyview, linear), (vec2(u, phi) * 127 + 0.5) / 128);
-	OutColor.rgb = sv.rgb * 16;
+	if (globals.lighting == LIGHTING_SIMPLE) {
+		OutColor.rgb = globals.space_view != 0 ? vec3(0) : simple_sky(globals, r);
+	} else if (globals.space_view != 0) {
+		// The skyview LUT assumes an observer inside the atmosphere, so from orbit integrate the
+		// in-scattering along the view ray directly. Rays that hit the planet stop at the ground,
+		// which leaves a glowing limb around the disc.
+		vec3 x0 = globals.camera;
+		vec2 p = rsi(x0, r, atmosphereRadius);
+		OutColor.rgb = vec3(0);
+		if (p.x < p.y && p.y > 0.0) {
+			vec2 g = rsi(x0, r, planetRadius);
+			float t1 = (g.x < g.y && g.x > 0.0) ? g.x : p.y;
+			OutColor.rgb = atmosphere(x0 + r * max(p.x, 0.0), x0 + r * t1, sun, globals.sun_illuminance);
+		}
+	} else {
+		vec4 sv = texture(sampler2D(skyview, linear), (vec2(u, phi) * 127 + 0.5) / 128);
+		OutColor.rgb = sv.rgb * 16;
+	}
+
+	// Sun disc, attenuated by the atmosphere along the view ray if there is one.
+	float sun_cos = dot(r, sun);
+	vec2 ground = rsi(globals.camera, r, planetRadius);
+	if (sun_cos > cos(globals.sun_angular_radius) && (ground.x > ground.y || ground.y < 0.0)) {
+		float sin_radius = sin(globals.sun_angular_radius);
+		vec3 radiance = globals.sun_illuminance / (PI * sin_radius * sin_radius);
+		if (globals.sun_limb_darkening != 0) {
+			float d = sqrt(max(1.0 - sun_cos * sun_cos, 0.0)) / sin_radius;
+			float mu = sqrt(max(1.0 - d * d, 0.0));
+			radiance *= 1.0 - 0.6 * (1.0 - mu);
+		}
+		if (globals.lighting != LIGHTING_SIMPLE) {
+			float camera_r = max(camera_distance, planetRadius);
+			radiance *= precomputed_transmittance(camera_r, dot(r, camera));
+		}
+		OutColor.rgb += radiance;
+	}
 
+#ifdef LIMB
+	OutColor.a = 1 - coverage;
+#else
+	OutColor.a = 1;
+#endif
 	OutColor = tonemap(OutColor, globals.exposure, 2.2);
+	OutColor = color_grade(OutColor, globals.contrast, globals.saturation, globals.gamma);
 	OutColor.rgb += dither(gl_FragCoord.xy);
 }
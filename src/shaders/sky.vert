@@ -1,11 +1,15 @@
 #version 450 core
 #include "declarations.glsl"
 
+layout(set = 0, binding = 0) uniform UniformBlock {
+	Globals globals;
+};
+
 layout(location = 0) out vec4 position;
 
 void main() {
-	if(gl_VertexIndex == 0) position = vec4(-1, -1, 0, 1);
-	if(gl_VertexIndex == 1) position = vec4(-1,  3, 0, 1);
-	if(gl_VertexIndex == 2) position = vec4( 3, -1, 0, 1);
+	if(gl_VertexIndex == 0) position = vec4(-1, -1, globals.far_depth, 1);
+	if(gl_VertexIndex == 1) position = vec4(-1,  3, globals.far_depth, 1);
+	if(gl_VertexIndex == 2) position = vec4( 3, -1, globals.far_depth, 1);
 	gl_Position = position;
 }
@@ -47,6 +47,33 @@ void main() {
 	// Non-physical transformation to make sure stars aren't visible from the ground.
 	// alpha *= pow(sv.a * 16, 100);
 
+	// Fade stars out against a bright sky, based on the sky radiance behind each star. The
+	// skyview LUT isn't kept up to date in space view, where the sky is black anyway.
+	if (globals.space_view == 0) {
+		vec2 ndc = position.xy / position.w;
+		vec4 r0 = globals.view_proj_inverse * vec4(ndc, 1 - globals.far_depth, 1);
+		vec4 r1 = globals.view_proj_inverse * vec4(ndc, mix(globals.far_depth, 1 - globals.far_depth, 1e-6), 1);
+		vec3 r = normalize(r1.xyz / r1.w - r0.xyz / r0.w);
+
+		vec3 camera = normalize(globals.camera);
+		vec3 sun = normalize(globals.sun_direction);
+		vec3 a = normalize(cross(camera, sun));
+		vec3 b = normalize(cross(camera, a));
+		float theta = asin(dot(r, camera));
+		float phi = atan(dot(r, b), dot(r, a)) / PI * 0.5 + 0.5;
+		float camera_distance = length(globals.camera);
+		float min_theta = -PI/2 + asin(planetRadius / camera_distance);
+		float max_theta = camera_distance < atmosphereRadius ? PI/2 : -PI/2 + asin(atmosphereRadius / camera_distance);
+		float u = sqrt((theta - min_theta) / (max_theta - min_theta));
+		vec3 sky_radiance = globals.lighting == LIGHTING_SIMPLE
+			? simple_sky(globals, r)
+			: texture(sampler2D(skyview, linear), (vec2(u, phi) * 127 + 0.5) / 128).rgb * 16;
+
+		float brightness = dot(sky_radiance, vec3(0.2126, 0.7152, 0.0722)) * globals.exposure;
+		float threshold = globals.star_visibility_threshold;
+		alpha *= 1 - smoothstep(0.25 * threshold, threshold, brightness);
+	}
+
 	OutColor = vec4(vec3(1), alpha);
 	// if (alpha <= 0)
 	// 	OutColor = vec4(vec3(1,0,0), 1);
@@ -22,6 +22,9 @@ layout(set = 0, binding = 10) uniform sampler nearest;
 layout(set = 0, binding = 11) uniform texture2DArray bent_normals;
 layout(set = 0, binding = 12) uniform texture2D shadowmap;
 layout(set = 0, binding = 13) uniform samplerShadow shadow_sampler;
+layout(set = 0, binding = 14) uniform sampler anisotropic;
+layout(set = 0, binding = 15) uniform texture2D night_lights;
+layout(set = 0, binding = 16) uniform sampler linear_wrap;
 
 layout(location = 0) in vec3 position;
 layout(location = 1) in vec2 texcoord;
@@ -34,6 +37,10 @@ layout(location = 7) flat in uint instance;
 
 layout(location = 0) out vec4 out_color;
 
+#ifdef CUSTOM_SHADING
+#include "custom_shading.glsl"
+#endif
+
 // float mipmap_level(in vec2 texture_coordinate)
 // {
 //     vec2  dx_vtc        = dFdx(texture_coordinate);
@@ -207,10 +214,11 @@ vec3 debug_overlay(vec3 color) {
  	return color;
 }
 
+// Decode a tangent space normal, steepening its slope to match the vertical exaggeration.
 vec3 extract_normal(vec2 n) {
 	n = n * 2.0 - vec2(1.0);
 	float y = sqrt(max(1.0 - dot(n, n),0));
-	return normalize(vec3(n.x, y, n.y));
+	return normalize(vec3(n.x * globals.vertical_exaggeration, y, n.y * globals.vertical_exaggeration));
 }
 
 vec3 layer_to_texcoord(uint layer) {
@@ -221,18 +229,37 @@ vec3 layer_to_texcoord(uint layer) {
 void main() {
 	Node node = nodes[instance];
 
-	vec3 tex_normal = extract_normal(texture(sampler2DArray(normals, linear), layer_to_texcoord(NORMALS_LAYER)).xy);
+	vec3 tex_normal = extract_normal(texture(sampler2DArray(normals, anisotropic), layer_to_texcoord(NORMALS_LAYER), globals.texture_lod_bias).xy);
 	if (node.layer_slots[PARENT_NORMALS_LAYER] >= 0) {
 		vec3 pn = extract_normal(textureLod(sampler2DArray(normals, linear), layer_to_texcoord(PARENT_NORMALS_LAYER), 0).xy);
 		tex_normal = mix(pn, tex_normal, morph);
 	}
 	vec3 bent_normal = mat3(tangent, normal, bitangent) * tex_normal;
 
-	vec4 albedo_roughness = texture(sampler2DArray(albedo, linear), layer_to_texcoord(ALBEDO_LAYER));
+	// Grayscale relief shading from a fixed light, measured relative to the local horizon.
+	if (globals.visualization == VISUALIZATION_HILLSHADE) {
+		vec3 up = normalize(position + globals.camera);
+		vec3 east = normalize(cross(abs(up.z) < 0.999 ? vec3(0, 0, 1) : vec3(1, 0, 0), up));
+		vec3 north = cross(up, east);
+		vec3 light = cos(globals.hillshade_altitude) * (sin(globals.hillshade_azimuth) * east
+			+ cos(globals.hillshade_azimuth) * north) + sin(globals.hillshade_altitude) * up;
+		out_color = vec4(vec3(max(dot(bent_normal, light), 0)), 1);
+		out_color.rgb = debug_overlay(out_color.rgb);
+		return;
+	}
+
+	vec4 albedo_roughness = texture(sampler2DArray(albedo, anisotropic), layer_to_texcoord(ALBEDO_LAYER), globals.texture_lod_bias);
 	if (node.layer_slots[PARENT_ALBEDO_LAYER] >= 0) {
 		vec4 parent_albedo_roughness = textureLod(sampler2DArray(albedo, linear), layer_to_texcoord(PARENT_ALBEDO_LAYER), 0);
 		albedo_roughness = mix(parent_albedo_roughness, albedo_roughness, morph);
 	}
+	albedo_roughness.rgb = seasonal_tint(albedo_roughness.rgb, globals.season, vegetation_fraction(albedo_roughness.rgb));
+	albedo_roughness.rgb = seasonal_snow(albedo_roughness.rgb, globals.season, dot(bent_normal, normal));
+
+#ifdef CUSTOM_SHADING
+	vec3 world_position = position + globals.camera;
+	custom_shading(albedo_roughness.rgb, albedo_roughness.a, bent_normal, world_position, length(world_position) - 6371000.0);
+#endif
 
 	vec4 bn_value = texture(sampler2DArray(bent_normals, linear), layer_to_texcoord(BENT_NORMALS_LAYER));
 
@@ -250,7 +277,18 @@ void main() {
 	vec2 shadow_coord = proj_position.xy * 0.5 * vec2(1,-1) + 0.5;
 	if (all(greaterThan(shadow_coord,vec2(0))) && all(lessThan(shadow_coord,vec2(1)))) {
 		float depth = proj_position.z - 4.0 / 102400.0;
-		shadow = textureLod(sampler2DShadow(shadowmap, shadow_sampler), vec3(shadow_coord, depth), 0);
+
+		// Percentage closer filtering, with a kernel that widens for larger suns.
+		float radius = globals.shadow_softness * globals.sun_angular_radius / 0.00465
+			/ float(textureSize(shadowmap, 0).x);
+		int k = int(globals.shadow_kernel_radius);
+		for (int y = -k; y <= k; y++) {
+			for (int x = -k; x <= k; x++) {
+				vec2 offset = vec2(x, y) * radius / max(k, 1);
+				shadow += textureLod(sampler2DShadow(shadowmap, shadow_sampler), vec3(shadow_coord + offset, depth), 0);
+			}
+		}
+		shadow /= float((2 * k + 1) * (2 * k + 1));
 	}
 
 	out_color = vec4(1);
@@ -258,26 +296,42 @@ void main() {
 						albedo_roughness.a,
 						position,
 						bent_normal,
-						globals.camera,
+						vec3(0),
 						globals.sun_direction,
-						vec3(100000.0)) * (1-shadow);
+						globals.sun_illuminance) * (1-shadow);
 
-	float ambient_strength = max(0, dot(normal, globals.sun_direction)) * max(0, tex_normal.y);
+	// The simple lighting model's ambient term is flat, ignoring the orientation of the surface.
+	float ambient_strength = globals.lighting == LIGHTING_SIMPLE
+		? max(0, dot(normalize(position + globals.camera), globals.sun_direction))
+		: max(0, dot(normal, globals.sun_direction)) * max(0, tex_normal.y);
 	if (node.layer_slots[BENT_NORMALS_LAYER] >= 0)
-		out_color.rgb += bn_value.a * 15000 * albedo_roughness.rgb * ambient_strength;
+		out_color.rgb += pow(max(bn_value.a, 1e-4), globals.ao_strength) * globals.ambient * globals.sun_illuminance * albedo_roughness.rgb * ambient_strength;
 	else
-		out_color.rgb += 15000 * albedo_roughness.rgb * ambient_strength;
+		out_color.rgb += globals.ambient * globals.sun_illuminance * albedo_roughness.rgb * ambient_strength;
+
+	// City lights, from an equirectangular map of the whole planet. They fade in across the
+	// terminator so that they only show up on the night side.
+	if (globals.night_lights_intensity > 0) {
+		vec3 up = normalize(position + globals.camera);
+		vec2 lights_texcoord = vec2(atan(up.y, up.x) / (2 * M_PI) + 0.5, 0.5 - asin(up.z) / M_PI);
+		vec3 lights = texture(sampler2D(night_lights, linear_wrap), lights_texcoord).rgb;
+		float night = smoothstep(0.05, -0.1, dot(up, globals.sun_direction));
+		out_color.rgb += globals.night_lights_intensity * 1000 * night * lights;
+	}
 
-	vec4 ap;
-	if (node.layer_slots[AERIAL_PERSPECTIVE_LAYER] >= 0) {
-		ap = textureLod(sampler2DArray(aerial_perspective, linear), layer_to_texcoord(AERIAL_PERSPECTIVE_LAYER), 0);
-	} else {
-		ap = textureLod(sampler2DArray(root_aerial_perspective, linear), layer_to_texcoord(ROOT_AERIAL_PERSPECTIVE_LAYER), 0);
+	if (globals.lighting != LIGHTING_SIMPLE) {
+		vec4 ap;
+		if (node.layer_slots[AERIAL_PERSPECTIVE_LAYER] >= 0) {
+			ap = textureLod(sampler2DArray(aerial_perspective, linear), layer_to_texcoord(AERIAL_PERSPECTIVE_LAYER), 0);
+		} else {
+			ap = textureLod(sampler2DArray(root_aerial_perspective, linear), layer_to_texcoord(ROOT_AERIAL_PERSPECTIVE_LAYER), 0);
+		}
+		out_color.rgb *= ap.a * 16.0;
+		out_color.rgb += ap.rgb * 16.0;
 	}
-	out_color.rgb *= ap.a * 16.0;
-	out_color.rgb += ap.rgb * 16.0;
 
 	out_color = tonemap(out_color, globals.exposure, 2.2);
+	out_color = color_grade(out_color, globals.contrast, globals.saturation, globals.gamma);
 
 	out_color.rgb = debug_overlay(out_color.rgb);
 }
@@ -27,40 +27,59 @@ const vec3 tangents[6] = vec3[6](
 	vec3(-1,0,0)
 );
 
-vec3 sample_displacements(vec3 texcoord) {
+vec4 sample_displacements(vec3 texcoord) {
 	vec2 t = texcoord.xy * textureSize(displacements, 0).xy - 0.5;
 	vec2 f = fract(t);
 	vec4 w = vec4(f.x * (1-f.y), (1-f.x)*(1-f.y), (1-f.x)*f.y, f.x * f.y);
-	return texelFetch(displacements, ivec3(t, texcoord.z), 0).xyz* (1-f.x) * (1-f.y)
-		+ texelFetch(displacements, ivec3(t+ivec2(1,0), texcoord.z), 0).xyz * (f.x) * (1-f.y)
-		+ texelFetch(displacements, ivec3(t+ivec2(1,1), texcoord.z), 0).xyz * (f.x) * (f.y)
-		+ texelFetch(displacements, ivec3(t+ivec2(0,1), texcoord.z), 0).xyz * (1-f.x) * (f.y);
+	return texelFetch(displacements, ivec3(t, texcoord.z), 0) * (1-f.x) * (1-f.y)
+		+ texelFetch(displacements, ivec3(t+ivec2(1,0), texcoord.z), 0) * (f.x) * (1-f.y)
+		+ texelFetch(displacements, ivec3(t+ivec2(1,1), texcoord.z), 0) * (f.x) * (f.y)
+		+ texelFetch(displacements, ivec3(t+ivec2(0,1), texcoord.z), 0) * (1-f.x) * (f.y);
+}
+
+// Camera relative position of a displacement sample, moved along the vertical so that its
+// height above the water (stored in w) is scaled by the vertical exaggeration.
+vec3 displaced_position(vec4 displacement, vec3 relative_position) {
+	vec3 position = displacement.xyz - relative_position;
+	return position + normalize(position + globals.camera) * displacement.w * (globals.vertical_exaggeration - 1);
 }
 
 void main() {
 	uint resolution = 64;//nodes[gl_InstanceIndex].resolution;
 	uvec2 base_origin = uvec2(0);//nodes[gl_InstanceIndex].base_origin;
-	Node node = nodes[gl_InstanceIndex/4];
+	Node node = nodes[gl_InstanceIndex/globals.terrain_mesh_entries];
 
 	ivec2 iPosition = ivec2((gl_VertexIndex) % (resolution+1),
 							(gl_VertexIndex) / (resolution+1)) + ivec2(base_origin);
 
 	int displacements_slot = node.layer_slots[DISPLACEMENTS_LAYER];
 	vec3 texcoord = vec3(node.layer_origins[DISPLACEMENTS_LAYER] + vec2(iPosition)/64.0 * node.layer_ratios[DISPLACEMENTS_LAYER], displacements_slot); //vec3(0.5 / 65.0 + desc.origin * (64.0 / 65.0), desc.slot) + vec3(vec2(iPosition) / 64.0 * pow(0.5, node.layers[DISPLACEMENTS_LAYER]), 0);
-	vec3 position = sample_displacements(texcoord) - nodes[displacements_slot].relative_position;
+	vec3 position = displaced_position(sample_displacements(texcoord), nodes[displacements_slot].relative_position);
 
-	float morph = 1 - smoothstep(0.9, 1, length(position) / node.min_distance);
+	// Blend towards the parent's vertex positions over the outer part of this node's level band,
+	// so that it matches its parent by the time it is replaced.
+	float morph_range = max(globals.lod_morph_range, 1e-4);
+	float morph = 1 - smoothstep(1 - morph_range, 1, length(position) / node.min_distance);
+
+	// Edges bordering a node drawn at a coarser level are fully morphed, so that their vertices
+	// line up with the neighbor's instead of leaving cracks between them.
+	if (((node.coarser_neighbors & 1) != 0 && iPosition.x == 0)
+		|| ((node.coarser_neighbors & 2) != 0 && iPosition.x == int(resolution))
+		|| ((node.coarser_neighbors & 4) != 0 && iPosition.y == 0)
+		|| ((node.coarser_neighbors & 8) != 0 && iPosition.y == int(resolution))) {
+		morph = 0;
+	}
 	vec2 nPosition = mix(vec2((iPosition / 2) * 2), vec2(iPosition), morph);
 
 	if (morph < 1.0) {
 		int parent_displacements_slot = node.layer_slots[PARENT_DISPLACEMENTS_LAYER];
 		if (parent_displacements_slot >= 0) {
 			vec3 ptexcoord = vec3(node.layer_origins[PARENT_DISPLACEMENTS_LAYER] + vec2((iPosition/2)*2)/64.0 * node.layer_ratios[PARENT_DISPLACEMENTS_LAYER], parent_displacements_slot);
-			vec3 displacement = sample_displacements(ptexcoord) - nodes[parent_displacements_slot].relative_position;
+			vec3 displacement = displaced_position(sample_displacements(ptexcoord), nodes[parent_displacements_slot].relative_position);
 			position = mix(displacement, position, morph);
 		} else {
 			vec3 itexcoord =  vec3(node.layer_origins[DISPLACEMENTS_LAYER] + vec2((iPosition/2)*2)/64.0 * node.layer_ratios[DISPLACEMENTS_LAYER], displacements_slot);
-			vec3 displacement = sample_displacements(itexcoord) - nodes[displacements_slot].relative_position;
+			vec3 displacement = displaced_position(sample_displacements(itexcoord), nodes[displacements_slot].relative_position);
 			position = mix(displacement, position, morph);
 		}
 	}
@@ -76,7 +95,7 @@ void main() {
 	out_tangent = tangent;
 	out_bitangent = bitangent;
 	out_i_position = vec2(iPosition);
-	out_instance = gl_InstanceIndex/4;
+	out_instance = gl_InstanceIndex/globals.terrain_mesh_entries;
 
 	gl_Position = globals.view_proj * vec4(position, 1.0);
 }
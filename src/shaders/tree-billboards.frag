@@ -1,6 +1,7 @@
 #version 450 core
 #include "declarations.glsl"
 #include "pbr.glsl"
+#include "hash.glsl"
 
 layout(early_fragment_tests) in;
 
@@ -32,6 +33,7 @@ layout(location = 3) in vec3 normal;
 layout(location = 4) flat in uint slot;
 layout(location = 5) in vec3 right;
 layout(location = 6) in vec3 up;
+layout(location = 7) flat in float billboard_fade;
 
 vec3 extract_normal(vec2 n) {
 	n = n * 2.0 - vec2(1.0);
@@ -52,6 +54,12 @@ float mip_map_level(in vec2 texture_coordinate)
 }
 
 void main() {
+#ifndef SHADOWPASS
+	// Pixels claimed by the 3D model for this tree, which tree-models.frag draws instead.
+	if (random(uvec2(gl_FragCoord.xy)) >= billboard_fade)
+		discard;
+#endif
+
 	vec4 albedo = texture(sampler2DArray(billboards_albedo, linear), vec3(texcoord/6.0, 0));
 	vec2 tx_normal = texture(sampler2DArray(billboards_normals, linear), vec3(texcoord/6.0, 0)).xy;
 	float ao = texture(sampler2DArray(billboards_ao, linear), vec3(texcoord/6.0+1./6, 0), 0).x;
@@ -59,6 +67,7 @@ void main() {
 
 	albedo.rgb *= 0.15;
 	albedo.rgb += (color-0.5) * 0.01;
+	albedo.rgb = seasonal_tint(albedo.rgb, globals.season, 1.0);
 
 	float tx_normal_z = sqrt(max(0, 1-dot(tx_normal, tx_normal)));
 	vec3 true_normal = normalize(tx_normal.x * right - tx_normal.y * up + tx_normal_z * normal);
@@ -89,12 +98,12 @@ void main() {
 						0.4,
 						position,
 						true_normal,
-						globals.camera,
+						vec3(0),
 						globals.sun_direction,
-						vec3(100000.0)) * (1-shadow);
+						globals.sun_illuminance) * (1-shadow);
 
 
-	out_color.rgb += (1 - ao) * albedo.rgb * 15000 * max(0, dot(up, globals.sun_direction));// * max(dot(true_normal, up), 0);
+	out_color.rgb += (1 - ao) * albedo.rgb * globals.ambient * globals.sun_illuminance * max(0, dot(up, globals.sun_direction));// * max(dot(true_normal, up), 0);
 
 	// vec4 ap = texture(sampler2DArray(aerial_perspective, linear), layer_to_texcoord(AERIAL_PERSPECTIVE_LAYER));
 	// out_color.rgb *= ap.a * 16.0;
@@ -102,6 +111,7 @@ void main() {
 
 
 	out_color = tonemap(out_color, globals.exposure, 2.2);
+	out_color = color_grade(out_color, globals.contrast, globals.saturation, globals.gamma);
 
 	// out_color.rgb = vec3(dot(globals.sun_direction,true_normal));
 
@@ -31,6 +31,7 @@ layout(location = 3) out vec3 normal;
 layout(location = 4) out uint slot;
 layout(location = 5) out vec3 right;
 layout(location = 6) out vec3 up;
+layout(location = 7) flat out float billboard_fade;
 
 const vec3 tangents[6] = vec3[6](
 	vec3(0,1,0),
@@ -49,6 +50,7 @@ void main() {
     Node node = nodes[slot];
     Entry entry = tree_billboards_storage.entries[(slot - TREE_BILLBOARDS_BASE_SLOT) * 16 + gl_InstanceIndex % 16][entry_index];
     position = entry.position - node.relative_position;
+    billboard_fade = tree_billboard_fade(globals, length(position));
 
     up = normalize(position + globals.camera);
 	vec3 bitangent = normalize(cross(up, tangents[node.face]));
@@ -69,8 +71,10 @@ void main() {
 
     right = normalize(cross(position, up));
 
-    if (morph > 0)
+    if (morph > 0) {
         position += 30*(up * (1-uv.y) + right * (uv.x-0.5));
+        position += wind_sway(globals, entry.position - node.relative_position + globals.camera_offset, up) * 0.6 * (1-uv.y) * (1-uv.y);
+    }
 
     color = entry.albedo;//vec3(0.33,0.57,0.0)*.13;
     texcoord = uv;
@@ -0,0 +1,59 @@
+#version 450 core
+#include "declarations.glsl"
+#include "pbr.glsl"
+#include "hash.glsl"
+
+layout(set = 0, binding = 0) uniform UniformBlock {
+	Globals globals;
+};
+
+layout(set = 0, binding = 3) uniform sampler linear_wrap;
+layout(set = 0, binding = 4) uniform texture2D models_albedo;
+layout(set = 0, binding = 5) uniform texture2D shadowmap;
+layout(set = 0, binding = 6) uniform samplerShadow shadow_sampler;
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 color;
+layout(location = 2) in vec2 texcoord;
+layout(location = 3) in vec3 normal;
+layout(location = 4) in float ao;
+layout(location = 5) in vec3 up;
+layout(location = 6) flat in float billboard_fade;
+
+layout(location = 0) out vec4 out_color;
+
+void main() {
+	// Pixels claimed by the billboard for this tree, which tree-billboards.frag draws instead.
+	if (random(uvec2(gl_FragCoord.xy)) < billboard_fade)
+		discard;
+
+	vec4 albedo = texture(sampler2D(models_albedo, linear_wrap), texcoord);
+	if (albedo.a < 0.5)
+		discard;
+
+	albedo.rgb *= 0.15;
+	albedo.rgb += (color-0.5) * 0.01;
+	albedo.rgb = seasonal_tint(albedo.rgb, globals.season, 1.0);
+
+	float shadow = 0;
+	vec4 proj_position = globals.shadow_view_proj * vec4(position, 1);
+	vec2 shadow_coord = proj_position.xy * 0.5 * vec2(1,-1) + 0.5;
+	if (all(greaterThan(shadow_coord,vec2(0))) && all(lessThan(shadow_coord,vec2(1)))) {
+		float depth = proj_position.z - 4.0 / 102400.0;
+		shadow = textureLod(sampler2DShadow(shadowmap, shadow_sampler), vec3(shadow_coord, depth), 0);
+	}
+
+	vec3 n = gl_FrontFacing ? normal : -normal;
+	out_color = vec4(1);
+	out_color.rgb = pbr(albedo.rgb,
+						0.4,
+						position,
+						n,
+						vec3(0),
+						globals.sun_direction,
+						globals.sun_illuminance) * (1-shadow);
+	out_color.rgb += ao * albedo.rgb * globals.ambient * globals.sun_illuminance * max(0, dot(up, globals.sun_direction));
+
+	out_color = tonemap(out_color, globals.exposure, 2.2);
+	out_color = color_grade(out_color, globals.contrast, globals.saturation, globals.gamma);
+}
@@ -0,0 +1,74 @@
+#version 450 core
+#include "declarations.glsl"
+
+layout(set = 0, binding = 0, std140) uniform UniformBlock {
+    Globals globals;
+};
+
+struct Vertex {
+    vec3 position;
+    float ao;
+    vec3 lod_position;
+    uint color;
+    vec3 normal;
+    float texcoord_u;
+    vec3 binormal;
+    float texcoord_v;
+};
+layout(std430, binding = 1) readonly buffer DataBlock {
+    Vertex vertices[];
+} model_storage;
+
+struct Instance {
+    vec3 position;
+    float angle;
+    vec3 albedo;
+    uint face;
+};
+layout(std430, binding = 2) readonly buffer InstanceBlock {
+    Instance instances[];
+} tree_model_instances;
+
+layout(location = 0) out vec3 position;
+layout(location = 1) out vec3 color;
+layout(location = 2) out vec2 texcoord;
+layout(location = 3) out vec3 normal;
+layout(location = 4) out float ao;
+layout(location = 5) out vec3 up;
+layout(location = 6) flat out float billboard_fade;
+
+const vec3 tangents[6] = vec3[6](
+	vec3(0,1,0),
+	vec3(0,-1,0),
+	vec3(1,0,0),
+	vec3(-1,0,0),
+	vec3(1,0,0),
+	vec3(-1,0,0)
+);
+
+// Meters per model unit. Matches the size the model is rendered at in the billboard textures.
+const float MODEL_SCALE = 1.5;
+
+void main() {
+    Vertex vertex = model_storage.vertices[gl_VertexIndex];
+    Instance instance = tree_model_instances.instances[gl_InstanceIndex];
+
+    up = normalize(instance.position + globals.camera);
+    vec3 bitangent = normalize(cross(up, tangents[instance.face]));
+    vec3 tangent = normalize(cross(up, bitangent));
+    vec3 x = cos(instance.angle) * tangent + sin(instance.angle) * bitangent;
+    vec3 z = cross(x, up);
+
+    vec3 p = vertex.position * MODEL_SCALE;
+    position = instance.position + p.x * x + p.y * up + p.z * z;
+    float sway = max(p.y, 0.0) / 30.0;
+    position += wind_sway(globals, instance.position + globals.camera_offset, up) * 0.6 * sway * sway;
+
+    normal = normalize(vertex.normal.x * x + vertex.normal.y * up + vertex.normal.z * z);
+    color = instance.albedo;
+    texcoord = vec2(vertex.texcoord_u, 1 - vertex.texcoord_v);
+    ao = vertex.ao;
+    billboard_fade = tree_billboard_fade(globals, length(instance.position));
+
+    gl_Position = globals.view_proj * vec4(position, 1.0);
+}
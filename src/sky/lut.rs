@@ -2,6 +2,42 @@ use crate::asset::AssetLoadContext;
 use anyhow::Error;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of invalid values clamped during atmosphere precomputation. Only the first is logged
+/// as a warning, since a bad parameter combination tends to affect many table entries.
+static INVALID_VALUES: AtomicU64 = AtomicU64::new(0);
+
+pub(super) fn report_invalid(what: &str, value: f64) {
+    if INVALID_VALUES.fetch_add(1, Ordering::Relaxed) == 0 {
+        log::warn!("Atmosphere precomputation produced invalid {} ({}), clamping it", what, value);
+    } else {
+        log::debug!("Atmosphere precomputation produced invalid {} ({}), clamping it", what, value);
+    }
+}
+
+/// Returns the number of invalid values reported so far.
+pub(super) fn invalid_values() -> u64 {
+    INVALID_VALUES.load(Ordering::Relaxed)
+}
+
+/// Returns `value` clamped to `[min, max]`, or `fallback` if it is NaN. Out of range values are
+/// reported through `report_invalid`.
+pub(super) fn guard(value: f64, min: f64, max: f64, fallback: f64, what: &str) -> f64 {
+    if value.is_nan() || value < min || value > max {
+        report_invalid(what, value);
+        if value.is_nan() {
+            return fallback;
+        }
+    }
+    value.max(min).min(max)
+}
+
+/// Replaces NaN components of a table entry with zero and clamps the rest to be non-negative and
+/// finite.
+fn guard_entry(value: [f32; 4]) -> [f32; 4] {
+    value.map(|c| guard(c as f64, 0.0, f32::MAX as f64, 0.0, "table entry") as f32)
+}
 
 pub(crate) trait LookupTableDefinition: Sync {
     fn name(&self) -> String;
@@ -13,35 +49,32 @@ pub(crate) trait LookupTableDefinition: Sync {
         [1.0 / f64::from(s[0]), 1.0 / f64::from(s[1]), 1.0 / f64::from(s[2])]
     }
 
+    /// Compute every entry of the table, one row at a time. Progress is reported through
+    /// `context` after each row so that slow tables don't appear to hang. Invalid entries are
+    /// clamped by `guard_entry` rather than failing generation.
     fn generate(&self, context: &mut AssetLoadContext) -> Result<LookupTable, Error> {
         let size = self.size();
-        let total = size[0] as u64 * size[1] as u64 * size[2] as u64;
-        context.reset(format!("Generating {}... ", &self.name()), total / 1000);
-
-        let data = (0..total)
-            .into_iter()
-            .collect::<Vec<_>>()
-            .chunks(1000)
-            .enumerate()
-            .flat_map(|(i, chunk)| {
-                context.set_progress(i);
-                chunk
-                    .into_par_iter()
-                    .map(|i| {
-                        let x = i % size[0] as u64;
-                        let y = (i / size[0] as u64) % size[1] as u64;
-                        let z = i / (size[0] as u64 * size[1] as u64) % size[2] as u64;
-                        let value = self.compute([x as u16, y as u16, z as u16]);
-                        for c in &value {
-                            assert!(!c.is_nan())
-                        }
-                        value
-                    })
-                    .collect::<Vec<_>>()
-            })
-            .collect();
-
-        context.set_progress(total / 1000);
+        let rows = size[1] as u64 * size[2] as u64;
+        context.reset(format!("Generating {}... ", &self.name()), rows);
+
+        let invalid = invalid_values();
+        let mut data = Vec::with_capacity(rows as usize * size[0] as usize);
+        for row in 0..rows {
+            let y = (row % size[1] as u64) as u16;
+            let z = (row / size[1] as u64) as u16;
+            data.par_extend(
+                (0..size[0]).into_par_iter().map(|x| guard_entry(self.compute([x, y, z]))),
+            );
+            context.set_progress(row + 1);
+        }
+        if invalid_values() > invalid {
+            log::warn!(
+                "Clamped {} invalid values while generating the {}",
+                invalid_values() - invalid,
+                self.name()
+            );
+        }
+
         Ok(LookupTable { size, data })
     }
 }
@@ -63,6 +96,35 @@ impl LookupTable {
         let y = (y * (self.size[1] - 1) as f64).round() as usize;
         self.data[x + y * self.size[0] as usize]
     }
+
+    /// Like `get2`, but interpolating bilinearly between the four nearest entries rather than
+    /// picking the closest one.
+    pub fn get2_bilinear(&self, x: f64, y: f64) -> [f32; 4] {
+        assert_eq!(self.size[2], 1);
+        assert!(x >= 0.0);
+        assert!(y >= 0.0);
+        assert!(x <= 1.0);
+        assert!(y <= 1.0);
+
+        let coordinate = |v: f64, size: u16| {
+            let v = v * (size - 1) as f64;
+            let i0 = (v.floor() as usize).min(size.saturating_sub(2) as usize);
+            let i1 = (i0 + 1).min(size as usize - 1);
+            (i0, i1, (v - i0 as f64) as f32)
+        };
+        let (x0, x1, fx) = coordinate(x, self.size[0]);
+        let (y0, y1, fy) = coordinate(y, self.size[1]);
+
+        let entry = |x: usize, y: usize| self.data[x + y * self.size[0] as usize];
+        let (v00, v10, v01, v11) = (entry(x0, y0), entry(x1, y0), entry(x0, y1), entry(x1, y1));
+        let mut value = [0.0; 4];
+        for c in 0..4 {
+            let top = v00[c] * (1.0 - fx) + v10[c] * fx;
+            let bottom = v01[c] * (1.0 - fx) + v11[c] * fx;
+            value[c] = top * (1.0 - fy) + bottom * fy;
+        }
+        value
+    }
 }
 // pub struct GpuLookupTable<R: gfx::Resources> {
 //     pub(crate) texture_view: handle::ShaderResourceView<R, [f32; 4]>,
@@ -90,3 +152,24 @@ impl LookupTable {
 //         })
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guard_clamps_and_reports() {
+        assert_eq!(guard(0.25, 0.0, 1.0, 0.5, "test value"), 0.25);
+
+        // Other tests may report invalid values concurrently, so only a lower bound is checked.
+        let before = invalid_values();
+        assert_eq!(guard(f64::NAN, 0.0, 1.0, 0.5, "test value"), 0.5);
+        assert_eq!(guard(2.0, 0.0, 1.0, 0.5, "test value"), 1.0);
+        assert_eq!(guard(-1.0, 0.0, 1.0, 0.5, "test value"), 0.0);
+        assert!(invalid_values() - before >= 3);
+
+        let before = invalid_values();
+        assert_eq!(guard_entry([1.0, f32::NAN, -2.0, f32::INFINITY]), [1.0, 0.0, 0.0, f32::MAX]);
+        assert!(invalid_values() - before >= 3);
+    }
+}
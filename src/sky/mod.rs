@@ -1,7 +1,12 @@
 use crate::asset::AssetLoadContext;
+use crate::cache::TextureFormat;
+use crate::coordinates::{ATMOSPHERE_RADIUS, PLANET_RADIUS};
+use crate::mapfile::MapFile;
 use crate::sky::lut::{LookupTable, LookupTableDefinition};
 use crate::sky::precompute::{InscatteringTable, TransmittanceTable};
 use anyhow::Error;
+use cgmath::{ElementWise, InnerSpace, Vector3, Zero};
+use std::f64::consts::PI;
 use wgpu::util::DeviceExt;
 
 mod lut;
@@ -21,8 +26,171 @@ impl Atmosphere {
     }
 }
 
+/// Transmittance table read back from the map file, for computing aerial perspective on the CPU
+/// the same way `gen-aerial-perspective.comp` does on the GPU. The table is interpolated
+/// bilinearly, so results vary smoothly with the ray instead of stepping between entries.
+pub(crate) struct CpuAtmosphere {
+    transmittance: LookupTable,
+}
+impl CpuAtmosphere {
+    pub fn new(mapfile: &MapFile) -> Result<Self, Error> {
+        let (desc, bytes) = mapfile.read_raw_texture("transmittance", TextureFormat::RGBA32F)?;
+        let mut data = vec![[0.0; 4]; (desc.width * desc.height) as usize];
+        bytemuck::cast_slice_mut(&mut data).copy_from_slice(&bytes[..data.len() * 16]);
+        let transmittance = LookupTable { size: [desc.width as u16, desc.height as u16, 1], data };
+        Ok(Self { transmittance })
+    }
+
+    /// Light scattered towards `x0` along the segment from `x0` to `x1`, in the same units as
+    /// `sun_illuminance`, followed by the fraction of light from `x1` that reaches `x0`.
+    pub fn aerial_perspective(
+        &self,
+        x0: Vector3<f64>,
+        x1: Vector3<f64>,
+        sun_direction: Vector3<f64>,
+        sun_illuminance: Vector3<f64>,
+    ) -> [f32; 4] {
+        let r = (x1 - x0).normalize();
+        match ray_sphere(x0, r, ATMOSPHERE_RADIUS) {
+            Some((enter, exit)) if exit >= 0.0 && enter < (x1 - x0).magnitude() => {
+                let x0 = x0 + r * enter.max(0.0);
+                let inscattering = self
+                    .inscattering(x0, x1, sun_direction.normalize())
+                    .mul_element_wise(sun_illuminance);
+                let transmittance = self.transmittance_between(x1, x0);
+                [
+                    inscattering.x as f32,
+                    inscattering.y as f32,
+                    inscattering.z as f32,
+                    transmittance as f32,
+                ]
+            }
+            _ => [0.0, 0.0, 0.0, 1.0],
+        }
+    }
+
+    /// Port of `precomputed_transmittance` from `atmosphere.glsl`.
+    fn transmittance(&self, r: f64, mu: f64) -> Vector3<f64> {
+        let h = (ATMOSPHERE_RADIUS * ATMOSPHERE_RADIUS - PLANET_RADIUS * PLANET_RADIUS).sqrt();
+        let rho = (r * r - PLANET_RADIUS * PLANET_RADIUS).max(0.0).sqrt();
+        let u_r = (rho / h).max(0.0).min(1.0);
+
+        let height = self.transmittance.size[1] as f64;
+        let hp = (height * 0.5 - 1.0) / (height - 1.0);
+        let mu_horizon = -rho / r;
+        let u_mu = if mu > mu_horizon {
+            ((mu - mu_horizon) / (1.0 - mu_horizon)).powf(0.2) * hp + (1.0 - hp)
+        } else {
+            ((mu_horizon - mu) / (1.0 + mu_horizon)).powf(0.2) * hp
+        };
+
+        let t = self.transmittance.get2_bilinear(u_r, u_mu.max(0.0).min(1.0));
+        Vector3::new(t[0] as f64, t[1] as f64, t[2] as f64)
+    }
+
+    /// Port of `precomputed_transmittance2` from `atmosphere.glsl`, which is the blue channel of
+    /// the transmittance between `x` and `y`.
+    fn transmittance_between(&self, x: Vector3<f64>, y: Vector3<f64>) -> f64 {
+        let v = (x - y).normalize();
+        let (r1, r2) = (x.magnitude(), y.magnitude());
+        let (mu1, mu2) = (x.normalize().dot(v), y.normalize().dot(v));
+
+        let height = self.transmittance.size[1] as f64;
+        let hp = (height * 0.5 - 1.0) / (height - 1.0);
+        let mu1_horizon = -(1.0 - (PLANET_RADIUS / r1).powi(2)).max(0.0).sqrt();
+        let mu2_horizon = -(1.0 - (PLANET_RADIUS / r2).powi(2)).max(0.0).sqrt();
+
+        let h = (ATMOSPHERE_RADIUS * ATMOSPHERE_RADIUS - PLANET_RADIUS * PLANET_RADIUS).sqrt();
+        let u_r1 = (r1 * r1 - PLANET_RADIUS * PLANET_RADIUS).max(0.0).sqrt() / h;
+        let u_r2 = (r2 * r2 - PLANET_RADIUS * PLANET_RADIUS).max(0.0).sqrt() / h;
+
+        let (u_mu1, u_mu2) = if mu1 > mu1_horizon {
+            (
+                (1.0 - hp) + hp * ((mu1 - mu1_horizon) / (1.0 - mu1_horizon)).powf(0.2),
+                (1.0 - hp) + hp * ((mu2 - mu2_horizon).max(0.0) / (1.0 - mu2_horizon)).powf(0.2),
+            )
+        } else {
+            (
+                hp * ((mu1_horizon - mu1) / (1.0 + mu1_horizon)).powf(0.2),
+                hp * ((mu2_horizon - mu2).max(0.0) / (1.0 + mu2_horizon)).powf(0.2),
+            )
+        };
+
+        let clamp = |v: f64| v.max(0.0).min(1.0);
+        let t1 = self.transmittance.get2_bilinear(clamp(u_r1), clamp(u_mu1))[2] as f64;
+        let t2 = self.transmittance.get2_bilinear(clamp(u_r2), clamp(u_mu2))[2] as f64;
+        if t1 > 0.0 {
+            (t2 / t1).min(1.0)
+        } else {
+            0.0
+        }
+    }
+
+    /// Port of `atmosphere` from `atmosphere.glsl`, without the final scaling by the sun's
+    /// illuminance.
+    fn inscattering(&self, x0: Vector3<f64>, x1: Vector3<f64>, sun: Vector3<f64>) -> Vector3<f64> {
+        const STEPS: u32 = 32;
+        let k_rayleigh = Vector3::new(5.8e-6, 13.5e-6, 33.1e-6);
+        let k_mie = 2.0e-6;
+        let (scale_height_rayleigh, scale_height_mie) = (8000.0, 1200.0);
+
+        let r = (x1 - x0).normalize();
+        let step_size = (x1 - x0).magnitude() / STEPS as f64;
+
+        let mut total_rayleigh = Vector3::zero();
+        let mut total_mie = Vector3::zero();
+        let (mut depth_rayleigh, mut depth_mie) = (0.0, 0.0);
+        for i in 0..STEPS {
+            let p = x0 + r * (step_size * (i as f64 + 0.5));
+            let height = p.magnitude() - PLANET_RADIUS;
+            let step_rayleigh = (-height / scale_height_rayleigh).exp() * step_size;
+            let step_mie = (-height / scale_height_mie).exp() * step_size;
+            depth_rayleigh += step_rayleigh;
+            depth_mie += step_mie;
+
+            // Only points that the sun isn't hidden from by the planet scatter its light.
+            if !matches!(ray_sphere(p, sun, PLANET_RADIUS), Some((_, exit)) if exit >= 0.0) {
+                let t = self.transmittance(p.magnitude(), sun.dot(p.normalize()));
+                let attenuation = (-k_rayleigh * depth_rayleigh).map(f64::exp).mul_element_wise(t)
+                    * (-k_mie * depth_mie).exp();
+                total_rayleigh += attenuation * step_rayleigh;
+                total_mie += attenuation * step_mie;
+            }
+        }
+
+        let mu = r.dot(sun);
+        k_rayleigh.mul_element_wise(total_rayleigh) * rayleigh_phase(mu)
+            + total_mie * (k_mie * mie_phase(mu))
+    }
+}
+
+fn rayleigh_phase(mu: f64) -> f64 {
+    3.0 / (16.0 * PI) * (1.0 + mu * mu)
+}
+
+fn mie_phase(mu: f64) -> f64 {
+    let g: f64 = 0.76;
+    3.0 / (8.0 * PI) * ((1.0 - g * g) * (1.0 + mu * mu))
+        / ((2.0 + g * g) * (1.0 + g * g - 2.0 * g * mu).powf(1.5))
+}
+
+/// Distances along the ray from `origin` in the unit `direction` at which it enters and leaves
+/// the sphere of `radius` around the center of the planet, if it intersects it at all.
+fn ray_sphere(origin: Vector3<f64>, direction: Vector3<f64>, radius: f64) -> Option<(f64, f64)> {
+    let b = 2.0 * direction.dot(origin);
+    let c = origin.magnitude2() - radius * radius;
+    let d = b * b - 4.0 * c;
+    if d < 0.0 {
+        return None;
+    }
+    Some(((-b - d.sqrt()) / 2.0, (-b + d.sqrt()) / 2.0))
+}
+
+/// Number of stars in the starfield, each of which is stored as four floats.
+pub(crate) const NUM_STARS: usize = 9096;
+
 pub(crate) fn create_starfield(device: &wgpu::Device) -> wgpu::Buffer {
-    let mut stars = vec![0.0f32; 4 * 9096];
+    let mut stars = vec![0.0f32; 4 * NUM_STARS];
     bytemuck::cast_slice_mut(&mut stars).copy_from_slice(include_bytes!("../../assets/stars.bin"));
 
     for star in stars.chunks_mut(4) {
@@ -1,7 +1,7 @@
 #![allow(non_snake_case)]
 #![allow(non_upper_case_globals)]
 
-use crate::sky::lut::{LookupTable, LookupTableDefinition};
+use crate::sky::lut::{guard, report_invalid, LookupTable, LookupTableDefinition};
 use cgmath::{ElementWise, InnerSpace, Vector2, Vector3, Vector4, VectorSpace, Zero};
 
 // Simulation is done at λ = (680, 550, 440) nm = (red, green, blue).
@@ -58,9 +58,10 @@ where
         (-b + f64::sqrt(b * b - 4.0 * c_atmosphere)) / 2.0
     };
 
-    assert!(!r.is_nan());
-    assert!(!theta.is_nan());
-    assert!(!length.is_nan());
+    if r.is_nan() || theta.is_nan() || length.is_nan() {
+        report_invalid("integration length", length);
+        return Zero::zero();
+    }
 
     if length <= 0.0 {
         return Zero::zero();
@@ -103,14 +104,14 @@ impl TransmittanceTable {
             -f64::powf(uu, 5.0) * (1.0 + mu_horizon) + mu_horizon
         };
 
-        assert!(r >= Rg && r <= Rt && !r.is_nan());
-        assert!(mu >= -1.0 && mu <= 1.0 && !mu.is_nan(), "{} {}", mu, u_mu);
+        let r = guard(r, Rg, Rt, Rg, "radius");
+        let mu = guard(mu, -1.0, 1.0, mu_horizon, "view angle cosine");
 
         (r, mu)
     }
     fn reverse_parameters(size: [u16; 3], r: f64, mu: f64) -> (f64, f64) {
-        assert!(r >= Rg && r <= Rt);
-        assert!(mu >= -1.0 && mu <= 1.0);
+        let r = guard(r, Rg, Rt, Rg, "radius");
+        let mu = guard(mu, -1.0, 1.0, 0.0, "view angle cosine");
 
         let H = f64::sqrt(Rt * Rt - Rg * Rg);
         let rho = f64::sqrt(r * r - Rg * Rg);
@@ -126,8 +127,8 @@ impl TransmittanceTable {
             uu * hp
         };
 
-        assert!(u_r >= 0.0 && u_r <= 1.0 && !u_r.is_nan());
-        assert!(u_mu >= 0.0 && u_mu <= 1.0 && !u_mu.is_nan());
+        let u_r = guard(u_r, 0.0, 1.0, 0.0, "radius coordinate");
+        let u_mu = guard(u_mu, 0.0, 1.0, 1.0 - hp, "view angle coordinate");
 
         (u_r, u_mu)
     }
@@ -146,31 +147,16 @@ impl LookupTableDefinition for TransmittanceTable {
             f64::from(y) / f64::from(self.size()[1] - 1),
         );
 
-        assert!(v >= -1.0 && v <= 1.0, "AA {}", v);
-
         let intersects_ground = y < self.size()[1] / 2;
         let t = integral(r, f64::acos(v), self.steps, intersects_ground, |y| {
             let height = y.magnitude() - Rg;
             let Beta_e_R = rayleigh::Beta_e * f64::exp(-height / rayleigh::H);
             let Beta_e_M = mie::Beta_e * f64::exp(-height / mie::H);
-            assert!(!Beta_e_R.x.is_nan(), "{} {} {:?}", Beta_e_R.x, height, y);
-            assert!(!Beta_e_M.is_nan());
             Beta_e_R + Vector3::new(Beta_e_M, Beta_e_M, Beta_e_M)
         });
 
-        assert!(!t.x.is_nan());
-        assert!(!t.y.is_nan());
-        assert!(!t.z.is_nan());
-
-        assert!(!f64::exp(-t.x).is_nan());
-        assert!(!f64::exp(-t.y).is_nan());
-        assert!(!f64::exp(-t.z).is_nan());
-
-        assert!(!(f64::exp(-t.x) as f32).is_nan());
-        assert!(!(f64::exp(-t.y) as f32).is_nan());
-        assert!(!(f64::exp(-t.z) as f32).is_nan());
-
-        [f64::exp(-t.x) as f32, f64::exp(-t.y) as f32, f64::exp(-t.z) as f32, 0.0]
+        let transmittance = |t: f64| guard(f64::exp(-t), 0.0, 1.0, 0.0, "transmittance") as f32;
+        [transmittance(t.x), transmittance(t.y), transmittance(t.z), 0.0]
     }
 }
 
@@ -259,6 +245,7 @@ impl<'a> LookupTableDefinition for InscatteringTable<'a> {
         let vv = Vector2::new(f64::sqrt(1.0 - mu * mu), mu);
         // let ss = Vector2::new(f64::sqrt(1.0 - mu_s * mu_s), mu_s);
 
+        // Matches the default `SunConfig::intensity`. Other intensities scale the result linearly.
         let L_sun = 100000.0;
         let s = integral(r, f64::acos(mu), self.steps, intersects_ground, |y| {
             // // Check if the sun is below the horizon
@@ -306,9 +293,7 @@ impl<'a> LookupTableDefinition for InscatteringTable<'a> {
             //     (Tb * Tb1 / Tb0) as f64,
             // );
             // };
-            assert!(!T.x.is_nan() && !T.y.is_nan() && !T.z.is_nan());
-            assert!(T.x >= 0. && T.y >= 0. && T.z >= 0.);
-            assert!(T.x <= 1. && T.y <= 1. && T.z <= 1., "{} {} {}", mu, yy, yy0);
+            let T = T.map(|t| guard(t, 0.0, 1.0, 0.0, "transmittance"));
 
             let R = T.mul_element_wise(rayleigh::Beta_s) * f64::exp(-h / rayleigh::H) * L_sun;
             let M = T.x * mie::Beta_s * f64::exp(-h / mie::H) * L_sun * rayleigh::Beta_s.x;
@@ -3,10 +3,12 @@ use crate::generate::heightmap::HeightmapCache;
 use crate::mapfile::MapFile;
 use anyhow::Error;
 use futures::{FutureExt, StreamExt};
+use std::collections::HashSet;
 use std::sync::Arc;
 use std::thread;
 use tokio::runtime::Runtime;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Semaphore;
 use types::VNode;
 
 #[derive(Copy, Clone, Debug)]
@@ -15,33 +17,67 @@ struct TileRequest {
     layer: LayerType,
 }
 
+#[derive(Debug)]
+enum StreamerMessage {
+    Request(TileRequest),
+    /// The tiles of `layer` for these nodes changed on disk, so any decoded copies are stale.
+    Invalidate(LayerType, HashSet<VNode>),
+}
+
 #[derive(Debug)]
 pub(crate) enum TileResult {
     Heightmaps(VNode, Arc<Vec<i16>>),
     Generic(VNode, LayerType, Vec<u8>),
+    /// The tile could not be read or decoded.
+    Failed(VNode, LayerType, TileFailure),
 }
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum TileFailure {
+    /// The tile couldn't be fetched from disk or the tile server. The copy on disk, if any, was
+    /// left in place.
+    Fetch,
+    /// The tile was fetched but couldn't be decoded. It has been removed from disk so that it
+    /// will be downloaded again the next time it is requested.
+    Corrupt,
+}
+
+/// Attached to the error for a tile that was fetched but failed to decode, identifying which
+/// tile's data is corrupt. For heightmaps this may be an ancestor of the requested node.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct CorruptTile(pub VNode);
+impl std::fmt::Display for CorruptTile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "tile {} is corrupt", self.0)
+    }
+}
+impl std::error::Error for CorruptTile {}
 impl TileResult {
     pub fn layer(&self) -> LayerType {
         match self {
             TileResult::Heightmaps(..) => LayerType::Heightmaps,
-            TileResult::Generic(_, ty, _) => *ty,
+            TileResult::Generic(_, ty, _) | TileResult::Failed(_, ty, _) => *ty,
         }
     }
     pub fn node(&self) -> VNode {
         match self {
-            TileResult::Heightmaps(node, ..) | TileResult::Generic(node, ..) => *node,
+            TileResult::Heightmaps(node, ..)
+            | TileResult::Generic(node, ..)
+            | TileResult::Failed(node, ..) => *node,
         }
     }
 }
 
 pub(crate) struct TileStreamerEndpoint {
-    sender: UnboundedSender<TileRequest>,
+    sender: UnboundedSender<StreamerMessage>,
     receiver: crossbeam::channel::Receiver<TileResult>,
     join_handle: Option<thread::JoinHandle<Result<(), Error>>>,
     num_inflight: usize,
 }
 impl TileStreamerEndpoint {
-    pub(crate) fn new(mapfile: Arc<MapFile>) -> Result<Self, Error> {
+    /// Start streaming tiles from `mapfile`, decoding at most `max_concurrent_decodes` tiles at
+    /// once.
+    pub(crate) fn new(mapfile: Arc<MapFile>, max_concurrent_decodes: usize) -> Result<Self, Error> {
         let (sender, requests) = unbounded_channel();
         let (results, receiver) = crossbeam::channel::unbounded();
 
@@ -56,6 +92,7 @@ impl TileStreamerEndpoint {
                         mapfile.layers()[LayerType::Heightmaps].texture_border_size as usize,
                         128,
                     ),
+                    decode_limit: Arc::new(Semaphore::new(max_concurrent_decodes)),
                     mapfile,
                 }
                 .run(),
@@ -66,13 +103,23 @@ impl TileStreamerEndpoint {
     }
 
     pub(crate) fn request_tile(&mut self, node: VNode, layer: LayerType) {
-        if let Err(_) = self.sender.send(TileRequest { node, layer }) {
+        self.send(StreamerMessage::Request(TileRequest { node, layer }));
+        self.num_inflight += 1;
+    }
+
+    /// Discard any decoded tiles of `layer` that the streamer has cached for `nodes` or their
+    /// descendants, so that they are read from disk again the next time they are requested.
+    pub(crate) fn invalidate_tiles(&mut self, layer: LayerType, nodes: HashSet<VNode>) {
+        self.send(StreamerMessage::Invalidate(layer, nodes));
+    }
+
+    fn send(&mut self, message: StreamerMessage) {
+        if let Err(_) = self.sender.send(message) {
             // The worker thread has panicked (we still have the sender open, so that cannot be why
             // it exited). Join it to see what the panic message was.
             self.join_handle.take().unwrap().join().unwrap().expect("TileStreamer panicked");
             unreachable!("TileStreamer exited without panicking");
         }
-        self.num_inflight += 1;
     }
 
     pub(crate) fn try_complete(&mut self) -> Option<TileResult> {
@@ -90,25 +137,39 @@ impl TileStreamerEndpoint {
 }
 
 struct TileStreamer {
-    requests: UnboundedReceiver<TileRequest>,
+    requests: UnboundedReceiver<StreamerMessage>,
     results: crossbeam::channel::Sender<TileResult>,
     mapfile: Arc<MapFile>,
     heightmap_tiles: HeightmapCache,
+    /// Bounds how many tiles are decoded at once, separately from how many are being read from
+    /// disk or downloaded.
+    decode_limit: Arc<Semaphore>,
 }
 
 impl TileStreamer {
     async fn run(self) -> Result<(), Error> {
-        let TileStreamer { mut requests, results, mapfile, mut heightmap_tiles } = self;
+        let TileStreamer { mut requests, results, mapfile, mut heightmap_tiles, decode_limit } =
+            self;
         let mapfile = &*mapfile;
 
         let mut pending = futures::stream::futures_unordered::FuturesUnordered::new();
         loop {
             futures::select! {
-                request = requests.recv().fuse() => if let Some(request) = request {
-
-                    pending.push(match request.layer {
+                message = requests.recv().fuse() => if let Some(message) = message {
+                    let request = match message {
+                        StreamerMessage::Request(request) => request,
+                        StreamerMessage::Invalidate(layer, nodes) => {
+                            if layer == LayerType::Heightmaps {
+                                heightmap_tiles.invalidate(&nodes);
+                            }
+                            continue;
+                        }
+                    };
+                    log::trace!("Streaming {} tile {}", request.layer.name(), request.node);
+                    let decode_limit = Arc::clone(&decode_limit);
+                    let fut = match request.layer {
                         LayerType::Heightmaps => {
-                            let fut = heightmap_tiles.get_tile(mapfile, request.node);
+                            let fut = heightmap_tiles.get_tile(mapfile, request.node, decode_limit);
                             async move {
                                 Ok(TileResult::Heightmaps(request.node, fut.await?))
                             }.boxed()
@@ -116,8 +177,10 @@ impl TileStreamer {
                         _ => async move {
                             let data = match mapfile.read_tile(request.layer, request.node).await? {
                                 Some(raw_data) => {
+                                    let _permit = decode_limit.acquire_owned().await?;
                                     tokio::task::spawn_blocking(move || {
-                                        let img = image::load_from_memory(&raw_data)?;
+                                        let img = image::load_from_memory(&raw_data)
+                                            .map_err(|e| Error::new(e).context(CorruptTile(request.node)))?;
                                         Ok::<Vec<u8>, Error>(match request.layer {
                                             LayerType::BaseAlbedo => img.to_rgba8().to_vec(),
                                             LayerType::TreeCover => img.to_luma8().to_vec(),
@@ -129,10 +192,34 @@ impl TileStreamer {
                             };
                             Ok::<TileResult, Error>(TileResult::Generic(request.node, request.layer, data))
                         }.boxed()
+                    };
+
+                    // A single bad tile shouldn't take down the streamer, so report the failure
+                    // and let the cache fall back to the parent tile instead. Only tiles whose
+                    // contents are corrupt are removed from disk; a failed fetch says nothing
+                    // about the copy already there.
+                    pending.push(async move {
+                        match fut.await {
+                            Ok(result) => result,
+                            Err(e) => match e.downcast_ref::<CorruptTile>() {
+                                Some(&CorruptTile(node)) => {
+                                    log::warn!("Failed to decode {} tile {}: {:#}", request.layer.name(), node, e);
+                                    if let Err(e) = mapfile.invalidate_tile(request.layer, node) {
+                                        log::warn!("Failed to remove {} tile {}: {}", request.layer.name(), node, e);
+                                    }
+                                    TileResult::Failed(request.node, request.layer, TileFailure::Corrupt)
+                                }
+                                None => {
+                                    log::warn!("Failed to fetch {} tile {}: {}", request.layer.name(), request.node, e);
+                                    TileResult::Failed(request.node, request.layer, TileFailure::Fetch)
+                                }
+                            },
+                        }
                     });
                 },
                 tile_result = pending.select_next_some() => {
-                    results.send(tile_result?)?;
+                    log::trace!("Finished streaming {} tile {}", tile_result.layer().name(), tile_result.node());
+                    results.send(tile_result)?;
                 },
                 complete => break,
             }
@@ -0,0 +1,202 @@
+use crate::cache::{LayerParams, LayerType, TileLayout};
+use crate::generate::{merged_tile_filename, MergedTiles, RasterCodec, TiffCodec};
+use crate::tile_source::TileSource;
+use anyhow::Error;
+use lru::LruCache;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use types::{VNode, NODE_OFFSETS};
+
+/// Streams heightmap tiles from a dataset that was merged into tiles on disk, such as the
+/// `copernicus-hgt` tiles written by `Terrain::generate_and_new`, instead of from terra's tile
+/// server. Merged tiles are resampled onto the heightmap layer's grid as they are read.
+pub(crate) struct DatasetTileSource {
+    /// Directory the dataset's tiles were merged into.
+    directory: PathBuf,
+    /// Name of the dataset, which prefixes every tile filename.
+    name: String,
+    params: LayerParams,
+    /// Number of samples along each side of a merged tile, including borders.
+    merged_resolution: usize,
+    merged_layout: TileLayout,
+    codec: TiffCodec<tiff::encoder::colortype::GrayI16>,
+    /// Recently resampled heightmaps, which are needed again to encode their children.
+    heightmaps: Mutex<LruCache<VNode, Option<Arc<Vec<i16>>>>>,
+}
+impl DatasetTileSource {
+    /// Stream the tiles of the dataset `name` from `directory`, which were merged in the format
+    /// described by `merged`. Fails unless `can_stream` accepts that format.
+    pub(crate) fn new(
+        directory: PathBuf,
+        name: String,
+        params: LayerParams,
+        merged: &MergedTiles,
+    ) -> Result<Self, Error> {
+        if !Self::can_stream(merged) {
+            anyhow::bail!(
+                "Dataset '{}' was merged into {} tiles of {} samples, but only TIFF tiles of i16 \
+                 heights can be streamed",
+                name,
+                merged.extension,
+                merged.sample_type
+            );
+        }
+
+        Ok(Self {
+            directory,
+            name,
+            params,
+            merged_resolution: merged.tile_resolution,
+            merged_layout: merged.layout(),
+            codec: TiffCodec::default(),
+            heightmaps: Mutex::new(LruCache::new(64)),
+        })
+    }
+
+    /// Whether tiles merged in the format described by `merged` can be streamed as heightmaps.
+    pub(crate) fn can_stream(merged: &MergedTiles) -> bool {
+        let codec: TiffCodec<tiff::encoder::colortype::GrayI16> = TiffCodec::default();
+        merged.extension == codec.extension()
+            && merged.sample_type == std::any::type_name::<i16>()
+            && merged.tile_resolution > merged.border_size * 2 + 1
+    }
+
+    /// Heights of `node` on the heightmap layer's grid, or `None` if the dataset has no tile
+    /// for it.
+    fn heights(&self, node: VNode) -> Result<Option<Arc<Vec<i16>>>, Error> {
+        if let Some(heights) = self.heightmaps.lock().unwrap().get(&node) {
+            return Ok(heights.clone());
+        }
+
+        let filename = merged_tile_filename(&self.name, node, self.codec.extension());
+        let heights = match std::fs::read(self.directory.join(filename)) {
+            // Tiles that are entirely zero are stored as empty files.
+            Ok(bytes) if bytes.is_empty() => Some(Arc::new(vec![0; self.len()])),
+            Ok(bytes) => Some(Arc::new(self.resample(&self.codec.decode(&bytes)?))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        self.heightmaps.lock().unwrap().put(node, heights.clone());
+        Ok(heights)
+    }
+
+    fn len(&self) -> usize {
+        (self.params.texture_resolution * self.params.texture_resolution) as usize
+    }
+
+    /// Bilinearly resample a merged tile onto the samples of the heightmap layer, matching up
+    /// positions within the node whichever registration either uses. The outermost border
+    /// samples lie beyond the merged tile and are clamped to its edge.
+    fn resample(&self, merged: &[i16]) -> Vec<i16> {
+        let resolution = self.params.texture_resolution as usize;
+        let (layout, merged_resolution) = (self.params.layout(), self.merged_resolution);
+        let max = (merged_resolution - 1) as f64;
+        let coordinate = |i: usize| {
+            // Sample `i` lies at the center of texel `i`, in both tiles.
+            let t = layout.node_position(i as f64 + 0.5);
+            let v = self.merged_layout.texel_coordinate(t) - 0.5;
+            let v = v.max(0.0).min(max);
+            let i0 = (v.floor() as usize).min(merged_resolution - 2);
+            (i0, v - i0 as f64)
+        };
+
+        let mut heights = Vec::with_capacity(self.len());
+        for y in 0..resolution {
+            let (y0, fy) = coordinate(y);
+            for x in 0..resolution {
+                let (x0, fx) = coordinate(x);
+                let h = |x: usize, y: usize| merged[y * merged_resolution + x] as f64;
+                let top = h(x0, y0) * (1.0 - fx) + h(x0 + 1, y0) * fx;
+                let bottom = h(x0, y0 + 1) * (1.0 - fx) + h(x0 + 1, y0 + 1) * fx;
+                heights.push((top * (1.0 - fy) + bottom * fy).round() as i16);
+            }
+        }
+        heights
+    }
+
+    fn heightmap_tile(&self, node: VNode) -> Result<Option<Vec<u8>>, Error> {
+        let heights = match self.heights(node)? {
+            Some(heights) => heights,
+            None => return Ok(None),
+        };
+        let parent = match node.parent() {
+            Some((parent, index)) => match self.heights(parent)? {
+                Some(h) => Some((index, h)),
+                None => anyhow::bail!("{} has no tile for the parent of {:?}", self.name, node),
+            },
+            None => None,
+        };
+
+        let border = self.params.texture_border_size as usize;
+        Ok(Some(tilefmt::compress_heightmap_tile(
+            self.params.texture_resolution as usize,
+            0,
+            &heights,
+            parent.as_ref().map(|(index, h)| (NODE_OFFSETS[*index as usize], border, &h[..])),
+            5,
+        )))
+    }
+}
+#[async_trait::async_trait]
+impl TileSource for DatasetTileSource {
+    async fn fetch(&self, layer: LayerType, node: VNode) -> Result<Option<Vec<u8>>, Error> {
+        assert_eq!(layer, LayerType::Heightmaps);
+        tokio::task::block_in_place(|| self.heightmap_tile(node))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::TextureFormat;
+
+    #[test]
+    fn registrations_agree_on_tile_edges() {
+        let params = LayerParams {
+            layer_type: LayerType::Heightmaps,
+            texture_resolution: 521,
+            texture_border_size: 4,
+            texture_format: &[TextureFormat::R32],
+            grid_registration: true,
+            min_level: 0,
+            max_level: 0,
+        };
+
+        // Merge the same west to east ramp with each registration, then stream both.
+        let resampled = |grid_registration| {
+            let merged = MergedTiles {
+                tile_resolution: 516,
+                border_size: 2,
+                grid_registration,
+                extension: TiffCodec::<tiff::encoder::colortype::GrayI16>::default()
+                    .extension()
+                    .to_owned(),
+                sample_type: "i16".to_owned(),
+            };
+            let layout = merged.layout();
+            let row: Vec<i16> = (0..516)
+                .map(|i| (layout.node_position(i as f64 + 0.5) * 10000.0).round() as i16)
+                .collect();
+            let tile = row.repeat(516);
+
+            let source =
+                DatasetTileSource::new(PathBuf::new(), "ramp".to_owned(), params.clone(), &merged)
+                    .unwrap();
+            source.resample(&tile)
+        };
+        let (cell, grid) = (resampled(false), resampled(true));
+
+        // Every sample from the near edge of the node to the far one, which both layers share,
+        // lands at the same height.
+        let layout = params.layout();
+        for i in 4..=516 {
+            let expected = layout.node_position(i as f64 + 0.5) * 10000.0;
+            for heights in [&cell, &grid] {
+                assert!((heights[100 * 521 + i] as f64 - expected).abs() <= 1.0, "{}", i);
+            }
+        }
+        assert_eq!((cell[100 * 521 + 4], grid[100 * 521 + 4]), (0, 0));
+        assert_eq!((cell[100 * 521 + 516], grid[100 * 521 + 516]), (10000, 10000));
+    }
+}
@@ -170,7 +170,7 @@ pub(crate) fn make_treecover_raster_cache(
         let values = match decoder.read_image() {
             Ok(tiff::decoder::DecodingResult::U8(data)) => data,
             e => {
-                println!("Bad treecover file lat={} long={} ({:?})", lat, long, e);
+                log::warn!("Bad treecover file lat={} long={} ({:?})", lat, long, e);
                 anyhow::bail!("bad treecover file");
             }
         };
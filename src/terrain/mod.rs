@@ -2,5 +2,7 @@ pub mod dem;
 // pub mod material;
 pub mod quadtree;
 
+pub(crate) mod dataset;
 pub(crate) mod heightmap;
+pub(crate) mod procedural;
 pub(crate) mod raster;
@@ -0,0 +1,135 @@
+use crate::cache::{LayerParams, LayerType};
+use crate::tile_source::TileSource;
+use anyhow::Error;
+use cgmath::{InnerSpace, Vector3};
+use lru::LruCache;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+use types::{VNode, NODE_OFFSETS};
+use vec_map::VecMap;
+
+/// Pseudo-random value in [-1, 1) for an integer lattice point.
+fn lattice_value(x: i64, y: i64, z: i64) -> f64 {
+    let mut h = (x as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+        ^ (y as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F)
+        ^ (z as u64).wrapping_mul(0x1656_67B1_9E37_79F9);
+    h ^= h >> 29;
+    h = h.wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    h ^= h >> 32;
+    (h >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+}
+
+/// Smoothly interpolated value noise.
+fn value_noise(p: Vector3<f64>) -> f64 {
+    let (x0, y0, z0) = (p.x.floor(), p.y.floor(), p.z.floor());
+    let smooth = |t: f64| t * t * (3.0 - 2.0 * t);
+    let (fx, fy, fz) = (smooth(p.x - x0), smooth(p.y - y0), smooth(p.z - z0));
+    let (x0, y0, z0) = (x0 as i64, y0 as i64, z0 as i64);
+
+    let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+    let edge = |y, z| lerp(lattice_value(x0, y, z), lattice_value(x0 + 1, y, z), fx);
+    let face = |z| lerp(edge(y0, z), edge(y0 + 1, z), fy);
+    lerp(face(z0), face(z0 + 1), fz)
+}
+
+/// Elevation in meters of the procedural planet in the direction of the unit vector `p`.
+fn elevation(p: Vector3<f64>) -> f64 {
+    let mut sum = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = 2.0;
+    for _ in 0..12 {
+        sum += amplitude * value_noise(p * frequency);
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    sum * 4000.0 - 200.0
+}
+
+/// Base color for terrain at `height` meters above sea level.
+fn base_albedo(height: f64) -> [u8; 4] {
+    let mix = |a: [f64; 3], b: [f64; 3], t: f64| {
+        let t = t.max(0.0).min(1.0);
+        [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t, a[2] + (b[2] - a[2]) * t]
+    };
+    let color = if height <= 0.0 {
+        [20.0, 40.0, 70.0]
+    } else if height < 1500.0 {
+        mix([60.0, 90.0, 40.0], [110.0, 95.0, 70.0], height / 1500.0)
+    } else {
+        mix([110.0, 95.0, 70.0], [230.0, 230.0, 235.0], (height - 1500.0) / 1500.0)
+    };
+    [color[0] as u8, color[1] as u8, color[2] as u8, 255]
+}
+
+/// Synthesizes heightmap and base albedo tiles from noise, so that terrain can be rendered
+/// without any real world data. Other layers are left empty.
+pub(crate) struct ProceduralTileSource {
+    layers: VecMap<LayerParams>,
+    /// Recently generated heightmaps, which are needed again to encode their children.
+    heightmaps: Mutex<LruCache<VNode, Arc<Vec<i16>>>>,
+}
+impl ProceduralTileSource {
+    pub(crate) fn new(layers: VecMap<LayerParams>) -> Self {
+        Self { layers, heightmaps: Mutex::new(LruCache::new(64)) }
+    }
+
+    fn heights(&self, node: VNode) -> Arc<Vec<i16>> {
+        if let Some(heights) = self.heightmaps.lock().unwrap().get(&node) {
+            return Arc::clone(heights);
+        }
+
+        let params = &self.layers[LayerType::Heightmaps];
+        let (resolution, border) = (params.texture_resolution, params.texture_border_size);
+        let mut heights = Vec::with_capacity((resolution * resolution) as usize);
+        for y in 0..resolution {
+            for x in 0..resolution {
+                let p = node.grid_position_cspace(x as i32, y as i32, border, resolution);
+                let h = elevation(p.normalize()).round();
+                heights.push(h.max(i16::MIN as f64).min(i16::MAX as f64) as i16);
+            }
+        }
+
+        let heights = Arc::new(heights);
+        self.heightmaps.lock().unwrap().put(node, Arc::clone(&heights));
+        heights
+    }
+
+    fn heightmap_tile(&self, node: VNode) -> Vec<u8> {
+        let params = &self.layers[LayerType::Heightmaps];
+        let (resolution, border) =
+            (params.texture_resolution as usize, params.texture_border_size as usize);
+        let heights = self.heights(node);
+        let parent = node.parent().map(|(parent, index)| (index, self.heights(parent)));
+        tilefmt::compress_heightmap_tile(
+            resolution,
+            0,
+            &heights,
+            parent.as_ref().map(|(index, h)| (NODE_OFFSETS[*index as usize], border, &h[..])),
+            5,
+        )
+    }
+
+    fn base_albedo_tile(&self, node: VNode) -> Result<Vec<u8>, Error> {
+        let params = &self.layers[LayerType::BaseAlbedo];
+        let (resolution, border) = (params.texture_resolution, params.texture_border_size);
+        let mut image = image::RgbaImage::new(resolution, resolution);
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            let p = node.cell_position_cspace(x as i32, y as i32, border, resolution);
+            *pixel = image::Rgba(base_albedo(elevation(p.normalize())));
+        }
+
+        let mut bytes = Vec::new();
+        image.write_to(&mut Cursor::new(&mut bytes), image::ImageOutputFormat::Png)?;
+        Ok(bytes)
+    }
+}
+#[async_trait::async_trait]
+impl TileSource for ProceduralTileSource {
+    async fn fetch(&self, layer: LayerType, node: VNode) -> Result<Option<Vec<u8>>, Error> {
+        tokio::task::block_in_place(|| match layer {
+            LayerType::Heightmaps => Ok(Some(self.heightmap_tile(node))),
+            LayerType::BaseAlbedo => Ok(Some(self.base_albedo_tile(node)?)),
+            _ => Ok(None),
+        })
+    }
+}
@@ -8,26 +8,126 @@ use types::{Priority, VNode, MAX_QUADTREE_LEVEL};
 pub(crate) struct QuadTree {
     node_priorities: FnvHashMap<VNode, Priority>,
     last_camera_position: Option<mint::Point3<f64>>,
+    /// Finest level that nodes will be selected at.
+    max_level: u8,
+    /// Factor that node priorities are multiplied by, so values above one select finer nodes.
+    lod_scale: f32,
+    /// How strongly the view direction skews which nodes grass and trees are generated for.
+    vegetation_anisotropy: f32,
+    /// Unit vector the camera is looking along, or `None` for orthographic views.
+    view_direction: Option<Vector3<f64>>,
+    /// Which root faces nodes are selected from.
+    faces: [bool; 6],
+    /// Nodes that are always selected regardless of camera distance, keyed by pin id.
+    pinned: FnvHashMap<u64, VNode>,
+    next_pin: u64,
 }
 
 impl QuadTree {
-    pub(crate) fn new() -> Self {
-        Self { node_priorities: FnvHashMap::default(), last_camera_position: None }
+    pub(crate) fn new(faces: [bool; 6]) -> Self {
+        Self {
+            node_priorities: FnvHashMap::default(),
+            last_camera_position: None,
+            max_level: MAX_QUADTREE_LEVEL,
+            lod_scale: 1.0,
+            vegetation_anisotropy: 0.0,
+            view_direction: None,
+            faces,
+            pinned: FnvHashMap::default(),
+            next_pin: 0,
+        }
+    }
+
+    /// Force `node` and all its ancestors to be selected until `unpin` is called with the
+    /// returned id.
+    pub(crate) fn pin(&mut self, node: VNode) -> u64 {
+        let id = self.next_pin;
+        self.next_pin += 1;
+        self.pinned.insert(id, node);
+        self.last_camera_position = None;
+        id
+    }
+
+    /// Remove a pin. Returns whether it existed.
+    pub(crate) fn unpin(&mut self, id: u64) -> bool {
+        self.last_camera_position = None;
+        self.pinned.remove(&id).is_some()
+    }
+
+    pub(crate) fn face_enabled(&self, face: u8) -> bool {
+        self.faces[face as usize]
+    }
+
+    pub(crate) fn set_max_level(&mut self, max_level: u8) {
+        let max_level = max_level.min(MAX_QUADTREE_LEVEL);
+        if max_level != self.max_level {
+            self.max_level = max_level;
+            self.last_camera_position = None;
+        }
+    }
+
+    pub(crate) fn set_lod_scale(&mut self, lod_scale: f32) {
+        // Ignore tiny changes, such as rounding error as the camera rotates, so that priorities
+        // are only recomputed when the field of view actually changes.
+        if (lod_scale / self.lod_scale - 1.0).abs() > 0.01 {
+            self.lod_scale = lod_scale;
+            self.last_camera_position = None;
+        }
+    }
+
+    pub(crate) fn set_vegetation_anisotropy(&mut self, anisotropy: f32) {
+        self.vegetation_anisotropy = anisotropy;
     }
 
-    pub(crate) fn create_index_buffer(resolution: u32) -> Vec<u32> {
+    pub(crate) fn set_view_direction(&mut self, view_direction: Option<Vector3<f64>>) {
+        // Like the LOD scale, ignore turns of less than about a degree so that vegetation at the
+        // edge of the cutoff doesn't flicker as the camera wobbles.
+        let turned = match (view_direction, self.view_direction) {
+            (Some(v), Some(last)) => v.dot(last) < 0.9998,
+            (v, last) => v.is_some() != last.is_some(),
+        };
+        if turned {
+            self.view_direction = view_direction;
+        }
+    }
+
+    /// Factor to scale the priority of grass and trees on `node` by.
+    ///
+    /// Looking towards the horizon, nodes ahead fill most of the view while those underfoot
+    /// and behind are barely visible, whereas looking down the nodes below the camera fill the
+    /// view and the ground further out is out of sight. Weighting by how closely the direction
+    /// to the node lines up with the view keeps foliage density closer to uniform on screen
+    /// across camera pitches.
+    fn vegetation_scale(&self, node: VNode, camera: Vector3<f64>) -> f32 {
+        let view_direction = match self.view_direction {
+            Some(v) if self.vegetation_anisotropy > 0.0 => v,
+            _ => return 1.0,
+        };
+        let alignment = match (node.center_wspace() - camera).normalize().dot(view_direction) {
+            a if a.is_finite() => a as f32,
+            _ => 1.0,
+        };
+        (self.vegetation_anisotropy * (2.0 * alignment - 1.0)).exp2()
+    }
+
+    /// Build the index buffer for a `resolution` x `resolution` grid of quads, split into
+    /// `blocks_per_side` x `blocks_per_side` blocks that are stored contiguously in row-major
+    /// order. Each block takes up `(resolution / blocks_per_side)^2 * 6` indices, so block `i`
+    /// can be drawn on its own starting at `i` times that.
+    pub(crate) fn create_index_buffer(resolution: u32, blocks_per_side: u32) -> Vec<u32> {
+        assert_eq!(resolution % blocks_per_side, 0);
         let mut data = Vec::new();
-        let half_resolution = resolution / 2;
+        let block_resolution = resolution / blocks_per_side;
         let width = resolution + 1;
-        for k in 0..2 {
-            for h in 0..2 {
-                for y in 0..half_resolution {
-                    for x in 0..half_resolution {
+        for k in 0..blocks_per_side {
+            for h in 0..blocks_per_side {
+                for y in 0..block_resolution {
+                    for x in 0..block_resolution {
                         for offset in [0, 1, width, 1, width + 1, width].iter() {
                             data.push(
                                 offset
-                                    + ((h * half_resolution + x)
-                                        + (k * half_resolution + y) * width),
+                                    + ((h * block_resolution + x)
+                                        + (k * block_resolution + y) * width),
                             );
                         }
                     }
@@ -46,13 +146,99 @@ impl QuadTree {
 
         self.node_priorities.clear();
         VNode::breadth_first(|node| {
-            let priority = node.priority(camera, cache.get_height_range(node));
+            // Nodes on disabled faces keep the default priority of none, so they are never
+            // streamed or rendered.
+            if !self.faces[node.face() as usize] {
+                return false;
+            }
+            let mut priority = node.priority(camera, cache.get_height_range(node));
+            if node.level() > 0 {
+                priority = priority.scaled(self.lod_scale);
+            }
             self.node_priorities.insert(node, priority);
-            priority >= Priority::cutoff() && node.level() < MAX_QUADTREE_LEVEL
+            priority >= Priority::cutoff() && node.level() < self.max_level
         });
+
+        // Pinned nodes get the same priority as roots, as do their ancestors so that there is
+        // an unbroken chain of selected nodes down to them.
+        for &pinned in self.pinned.values() {
+            if !self.faces[pinned.face() as usize] {
+                continue;
+            }
+            let mut node = Some(pinned);
+            while let Some(n) = node {
+                if n.level() <= self.max_level {
+                    let priority = self.node_priorities.entry(n).or_insert(Priority::none());
+                    *priority = (*priority).max(Priority::from_f32(2.0));
+                }
+                node = n.parent().map(|(p, _)| p);
+            }
+        }
     }
 
     pub fn node_priority(&self, node: VNode) -> Priority {
         self.node_priorities.get(&node).cloned().unwrap_or(Priority::none())
     }
+
+    /// Priority for generating and drawing grass and trees on `node`. This is its node priority
+    /// skewed towards the view direction, so it only affects vegetation and never which terrain
+    /// nodes are selected.
+    pub fn vegetation_priority(&self, node: VNode) -> Priority {
+        let priority = self.node_priority(node);
+        match self.last_camera_position {
+            Some(camera) => priority
+                .scaled(self.vegetation_scale(node, Vector3::new(camera.x, camera.y, camera.z))),
+            None => priority,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A camera standing two meters above the middle of a node, along with that node and another
+    /// node about five kilometers away.
+    fn setup() -> (Vector3<f64>, VNode, VNode) {
+        let underfoot = VNode::from_parts(14, 0, 8192, 8192).unwrap();
+        let distant = VNode::from_parts(14, 0, 8200, 8192).unwrap();
+        let camera = underfoot.center_wspace() + underfoot.center_wspace().normalize() * 2.0;
+        (camera, underfoot, distant)
+    }
+
+    #[test]
+    fn vegetation_scale_follows_view() {
+        let (camera, underfoot, distant) = setup();
+        let up = camera.normalize();
+        let to_distant = distant.center_wspace() - camera;
+        let horizon = (to_distant - up * to_distant.dot(up)).normalize();
+
+        let mut quadtree = QuadTree::new([true; 6]);
+        quadtree.set_vegetation_anisotropy(2.0);
+
+        quadtree.set_view_direction(Some(-up));
+        assert!(
+            quadtree.vegetation_scale(underfoot, camera)
+                > quadtree.vegetation_scale(distant, camera)
+        );
+
+        quadtree.set_view_direction(Some(horizon));
+        assert!(
+            quadtree.vegetation_scale(distant, camera)
+                > quadtree.vegetation_scale(underfoot, camera)
+        );
+    }
+
+    #[test]
+    fn zero_vegetation_anisotropy_is_noop() {
+        let (camera, underfoot, distant) = setup();
+
+        let mut quadtree = QuadTree::new([true; 6]);
+        quadtree.set_vegetation_anisotropy(0.0);
+        for direction in [-camera.normalize(), (distant.center_wspace() - camera).normalize()] {
+            quadtree.set_view_direction(Some(direction));
+            assert_eq!(quadtree.vegetation_scale(underfoot, camera), 1.0);
+            assert_eq!(quadtree.vegetation_scale(distant, camera), 1.0);
+        }
+    }
 }
@@ -0,0 +1,195 @@
+use crate::cache::LayerType;
+use crate::mapfile::MapFile;
+use anyhow::Error;
+use atomicwrites::{AtomicFile, OverwriteBehavior};
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use types::VNode;
+use vec_map::VecMap;
+
+/// A backend that streamed tiles are fetched from.
+///
+/// Tiles returned by a source are cached on disk, so `fetch` is only called the first time a
+/// given tile is needed. The returned bytes must use the same encoding as the tiles served by
+/// terra's own tile server.
+#[async_trait::async_trait]
+pub trait TileSource: Send + Sync {
+    /// Fetch the encoded contents of a tile, or `None` if the source doesn't have it. Missing
+    /// tiles are treated as empty.
+    async fn fetch(&self, layer: LayerType, node: VNode) -> Result<Option<Vec<u8>>, Error>;
+}
+
+/// A destination for generated tiles.
+///
+/// By default generated tiles are written atomically into terra's on-disk tile cache. A custom
+/// sink can send them elsewhere, such as to object storage. Tiles given to a custom sink are not
+/// cached locally, so to render them they must be made available through a `TileSource`.
+pub trait TileSink: Send + Sync {
+    /// Store the encoded contents of a tile, using the same encoding as `TileSource::fetch`.
+    fn write(&self, layer: LayerType, node: VNode, bytes: &[u8]) -> Result<(), Error>;
+
+    /// Store a file generated from a source dataset, either a reprojected sector or a merged tile
+    /// along with its fingerprint. `path` is where the file belongs within the dataset directory.
+    ///
+    /// By default the file is written there atomically. Merging reads reprojected sectors back
+    /// from the dataset directory, and only skips sectors and tiles that are already there, so a
+    /// sink that stores them elsewhere will regenerate them on every run.
+    fn write_dataset_file(&self, path: &Path, bytes: &[u8]) -> Result<(), Error> {
+        write_file_atomically(path, bytes)
+    }
+}
+
+/// Atomically replace the contents of `path` with `bytes`, creating its parent directories.
+pub(crate) fn write_file_atomically(path: &Path, bytes: &[u8]) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    AtomicFile::new(path, OverwriteBehavior::AllowOverwrite).write(|f| f.write_all(bytes))?;
+    Ok(())
+}
+
+/// Default limit on the size of a tile or tile list downloaded from terra's tile server.
+pub(crate) const DEFAULT_MAX_DOWNLOAD_SIZE: u64 = 64 << 20;
+
+/// Location of terra's own tile server, used when no other download sources are configured.
+pub(crate) const DEFAULT_TILE_SERVER_URL: &str = "https://terra.fintelia.io/file/terra-tiles/";
+
+/// Read the body of `response`, failing if it is larger than `max_size` bytes. This guards
+/// against misconfigured servers returning far more data than expected.
+pub(crate) async fn read_body_limited(
+    response: hyper::Response<hyper::Body>,
+    max_size: u64,
+    url: &str,
+) -> Result<Vec<u8>, Error> {
+    use hyper::body::HttpBody;
+
+    let too_large =
+        || anyhow::anyhow!("Download of '{}' aborted: response exceeds {} bytes", url, max_size);
+    if response.body().size_hint().lower() > max_size {
+        return Err(too_large());
+    }
+
+    let mut body = response.into_body();
+    let mut contents = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if (contents.len() + chunk.len()) as u64 > max_size {
+            return Err(too_large());
+        }
+        contents.extend_from_slice(&chunk);
+    }
+    Ok(contents)
+}
+
+type HttpsClient = hyper::Client<hyper_tls::HttpsConnector<hyper::client::HttpConnector>>;
+
+/// Download `path` relative to `base_url`.
+async fn download(
+    client: &HttpsClient,
+    base_url: &str,
+    path: &str,
+    max_size: u64,
+) -> Result<Vec<u8>, Error> {
+    let url = format!("{}/{}", base_url.trim_end_matches('/'), path);
+    let resp = client.get(url.parse()?).await?;
+    if !resp.status().is_success() {
+        anyhow::bail!("Download failed with {:?} for URL '{}'", resp.status(), url);
+    }
+    read_body_limited(resp, max_size, &url).await
+}
+
+/// Download `path` relative to each of `base_urls` in turn, returning the contents from the first
+/// that succeeds. If every source fails, the error from the last one is returned.
+pub(crate) async fn download_with_fallback(
+    base_urls: &[String],
+    path: &str,
+    max_size: u64,
+) -> Result<Vec<u8>, Error> {
+    let client = hyper::Client::builder().build::<_, hyper::Body>(hyper_tls::HttpsConnector::new());
+    let mut last_error = None;
+    for base_url in base_urls {
+        match download(&client, base_url, path, max_size).await {
+            Ok(contents) => {
+                log::debug!("Downloaded '{}' from '{}'", path, base_url);
+                return Ok(contents);
+            }
+            Err(e) => {
+                log::warn!("Download of '{}' from '{}' failed: {}", path, base_url, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No download sources for '{}'", path)))
+}
+
+/// Download `path` relative to every one of `base_urls`, returning the contents from each source
+/// that succeeds. Fails only if no source succeeds, with the error from the last one.
+pub(crate) async fn download_from_all(
+    base_urls: &[String],
+    path: &str,
+    max_size: u64,
+) -> Result<Vec<Vec<u8>>, Error> {
+    let client = hyper::Client::builder().build::<_, hyper::Body>(hyper_tls::HttpsConnector::new());
+    let mut downloads = Vec::new();
+    let mut last_error = None;
+    for base_url in base_urls {
+        match download(&client, base_url, path, max_size).await {
+            Ok(contents) => {
+                log::debug!("Downloaded '{}' from '{}'", path, base_url);
+                downloads.push(contents);
+            }
+            Err(e) => {
+                log::warn!("Download of '{}' from '{}' failed: {}", path, base_url, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    if downloads.is_empty() {
+        return Err(
+            last_error.unwrap_or_else(|| anyhow::anyhow!("No download sources for '{}'", path))
+        );
+    }
+    Ok(downloads)
+}
+
+/// Downloads tiles over HTTP from terra's tile server, or from the mirrors configured in its place.
+pub(crate) struct TerraTileServer {
+    /// Tiles listed as available by the server, indexed by layer.
+    remote_tiles: Arc<Mutex<VecMap<HashSet<VNode>>>>,
+    /// Base URLs to try, in order, for each tile.
+    base_urls: Arc<Mutex<Vec<String>>>,
+    /// Largest response that will be accepted, in bytes.
+    max_download_size: Arc<AtomicU64>,
+}
+impl TerraTileServer {
+    pub(crate) fn new(
+        remote_tiles: Arc<Mutex<VecMap<HashSet<VNode>>>>,
+        base_urls: Arc<Mutex<Vec<String>>>,
+        max_download_size: Arc<AtomicU64>,
+    ) -> Self {
+        Self { remote_tiles, base_urls, max_download_size }
+    }
+}
+#[async_trait::async_trait]
+impl TileSource for TerraTileServer {
+    async fn fetch(&self, layer: LayerType, node: VNode) -> Result<Option<Vec<u8>>, Error> {
+        let available = self
+            .remote_tiles
+            .lock()
+            .unwrap()
+            .get(layer.index())
+            .map(|tiles| tiles.contains(&node))
+            .unwrap_or(false);
+        if !available {
+            return Ok(None);
+        }
+
+        let base_urls = self.base_urls.lock().unwrap().clone();
+        let max_size = self.max_download_size.load(Ordering::Relaxed);
+        let path = MapFile::tile_name(layer, node);
+        Ok(Some(download_with_fallback(&base_urls, &path, max_size).await?))
+    }
+}
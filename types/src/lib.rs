@@ -10,7 +10,7 @@ use std::f64::consts::PI;
 mod node;
 mod math;
 
-pub use node::{VNode, NODE_OFFSETS};
+pub use node::{ParseVNodeError, VNode, NODE_OFFSETS};
 pub use math::{BoundingBox, InfiniteFrustum};
 
 pub const EARTH_RADIUS: f64 = 6371000.0;
@@ -31,6 +31,13 @@ impl Priority {
         assert!(value.is_finite());
         Priority(value)
     }
+    /// Multiply the priority by `factor`, leaving `none()` unchanged.
+    pub fn scaled(self, factor: f32) -> Self {
+        if self.0 < 0.0 {
+            return self;
+        }
+        Priority::from_f32(self.0 * factor)
+    }
 }
 impl Eq for Priority {}
 impl Ord for Priority {
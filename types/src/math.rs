@@ -34,29 +34,56 @@ impl BoundingBox {
 
 #[derive(Clone, Debug)]
 pub struct InfiniteFrustum {
-    pub planes: [Vector4<f64>; 5],
+    /// Left, right, bottom, top, near and far planes, in that order. Points inside the frustum
+    /// are on the positive side of every plane. Planes that don't bound the frustum, such as the
+    /// far plane of an infinite projection, are replaced by `InfiniteFrustum::UNBOUNDED`.
+    pub planes: [Vector4<f64>; 6],
 }
 impl InfiniteFrustum {
+    /// Index of the near plane in `planes`.
+    pub const NEAR_PLANE: usize = 4;
+    /// Index of the far plane in `planes`.
+    pub const FAR_PLANE: usize = 5;
+    /// A plane that every point is on the positive side of.
+    pub const UNBOUNDED: Vector4<f64> = Vector4::new(0.0, 0.0, 0.0, 1.0);
+
     fn normalize_plane(plane: Vector4<f64>) -> Vector4<f64> {
         let magnitude = (plane.x * plane.x + plane.y * plane.y + plane.z * plane.z).sqrt();
+        // The far plane of an infinite projection has no normal, only a constant term.
+        if magnitude <= plane.w.abs() * 1e-9 {
+            return Self::UNBOUNDED;
+        }
         plane / magnitude
     }
 
-    pub fn from_matrix(m: Matrix4<f64>) -> Self {
+    /// Extract the side, near and far planes of a view-projection matrix whose clip space depth
+    /// ranges from 0 to 1, with the near plane at depth 1 if `reversed_z` and at depth 0
+    /// otherwise.
+    ///
+    /// Works for both perspective and orthographic projections. Infinite projections have no far
+    /// plane, so it is left unbounded.
+    pub fn from_matrix(m: Matrix4<f64>, reversed_z: bool) -> Self {
         let m = m.transpose();
+        let (near, far) = if reversed_z { (m.w - m.z, m.z) } else { (m.z, m.w - m.z) };
         Self {
             planes: [
                 Self::normalize_plane(m.w + m.x),
                 Self::normalize_plane(m.w - m.x),
                 Self::normalize_plane(m.w + m.y),
                 Self::normalize_plane(m.w - m.y),
-                Self::normalize_plane(m.w + m.z),
+                Self::normalize_plane(near),
+                Self::normalize_plane(far),
             ],
         }
     }
 
+    /// Stop the plane at `index` from bounding the frustum.
+    pub fn remove_plane(&mut self, index: usize) {
+        self.planes[index] = Self::UNBOUNDED;
+    }
+
     pub fn intersects_sphere(&self, center: Vector3<f64>, radius_squared: f64) -> bool {
-        for p in &self.planes[0..5] {
+        for p in &self.planes {
             let distance = p.x * center.x + p.y * center.y + p.z * center.z + p.w;
             if distance < 0.0 && distance * distance > radius_squared {
                 return false;
@@ -65,3 +92,70 @@ impl InfiniteFrustum {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Orthographic projection of a 200m wide square looking down the negative z axis, with
+    /// depth from 1 at `near` to 0 at `far`.
+    fn reversed_ortho(near: f64, far: f64) -> Matrix4<f64> {
+        Matrix4::from_cols(
+            Vector4::new(0.01, 0.0, 0.0, 0.0),
+            Vector4::new(0.0, 0.01, 0.0, 0.0),
+            Vector4::new(0.0, 0.0, 1.0 / (far - near), 0.0),
+            Vector4::new(0.0, 0.0, far / (far - near), 1.0),
+        )
+    }
+
+    #[test]
+    fn orthographic_frustum_culling() {
+        for (m, reversed_z) in [
+            (reversed_ortho(1.0, 1000.0), true),
+            // The same projection with depth from 0 at the near plane to 1 at the far plane.
+            (
+                Matrix4::from_translation(Vector3::new(0.0, 0.0, 1.0))
+                    * Matrix4::from_nonuniform_scale(1.0, 1.0, -1.0)
+                    * reversed_ortho(1.0, 1000.0),
+                false,
+            ),
+        ] {
+            let f = InfiniteFrustum::from_matrix(m, reversed_z);
+            assert!(f.intersects_sphere(Vector3::new(0.0, 0.0, -500.0), 1.0));
+            assert!(f.intersects_sphere(Vector3::new(99.0, -99.0, -500.0), 1.0));
+            assert!(f.intersects_sphere(Vector3::new(101.5, 0.0, -500.0), 4.0));
+            assert!(!f.intersects_sphere(Vector3::new(102.0, 0.0, -500.0), 1.0));
+            assert!(!f.intersects_sphere(Vector3::new(0.0, -150.0, -500.0), 1.0));
+            // Behind the camera, and between the camera and the near plane.
+            assert!(!f.intersects_sphere(Vector3::new(0.0, 0.0, 50.0), 1.0));
+            assert!(!f.intersects_sphere(Vector3::new(0.0, 0.0, 1.5), 0.01));
+            // Beyond the far plane.
+            assert!(f.intersects_sphere(Vector3::new(0.0, 0.0, -999.5), 1.0));
+            assert!(!f.intersects_sphere(Vector3::new(0.0, 0.0, -1002.0), 1.0));
+
+            let mut f = f;
+            f.remove_plane(InfiniteFrustum::NEAR_PLANE);
+            f.remove_plane(InfiniteFrustum::FAR_PLANE);
+            assert!(f.intersects_sphere(Vector3::new(0.0, 0.0, 50.0), 1.0));
+            assert!(f.intersects_sphere(Vector3::new(0.0, 0.0, -1002.0), 1.0));
+            assert!(!f.intersects_sphere(Vector3::new(0.0, -150.0, -500.0), 1.0));
+        }
+    }
+
+    #[test]
+    fn perspective_frustum_culling() {
+        // Infinite reversed-Z projection with a 90 degree field of view and near plane at 1m.
+        let m = Matrix4::from_cols(
+            Vector4::new(1.0, 0.0, 0.0, 0.0),
+            Vector4::new(0.0, 1.0, 0.0, 0.0),
+            Vector4::new(0.0, 0.0, 0.0, -1.0),
+            Vector4::new(0.0, 0.0, 1.0, 0.0),
+        );
+        let f = InfiniteFrustum::from_matrix(m, true);
+        assert_eq!(f.planes[InfiniteFrustum::FAR_PLANE], InfiniteFrustum::UNBOUNDED);
+        assert!(f.intersects_sphere(Vector3::new(0.0, 0.0, -1e6), 1.0));
+        assert!(f.intersects_sphere(Vector3::new(90.0, 0.0, -100.0), 1.0));
+        assert!(!f.intersects_sphere(Vector3::new(110.0, 0.0, -100.0), 1.0));
+        assert!(!f.intersects_sphere(Vector3::new(0.0, 0.0, 10.0), 1.0));
+    }
+}
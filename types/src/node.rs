@@ -74,6 +74,32 @@ impl VNode {
         debug_assert!(y <= 0x3ffffff && y < (1 << level));
         Self((level as u64) << 56 | (face as u64) << 53 | (y as u64) << 26 | (x as u64))
     }
+    /// Construct a node from its level, face and position within the face, returning `None` if
+    /// any of them are out of range.
+    pub fn from_parts(level: u8, face: u8, x: u32, y: u32) -> Option<Self> {
+        if face < 6 && level <= MAX_QUADTREE_LEVEL && x < (1 << level) && y < (1 << level) {
+            Some(Self::new(level, face, x, y))
+        } else {
+            None
+        }
+    }
+    /// A compact identifier for this node that is stable across sessions and terra versions, so
+    /// it can be used to key external data. The level, face, y and x are packed into bits 56..64,
+    /// 53..56, 26..52 and 0..26 respectively.
+    pub fn id(&self) -> u64 {
+        self.0
+    }
+    /// Inverse of `id`. Returns `None` if `id` doesn't correspond to a valid node.
+    pub fn from_id(id: u64) -> Option<Self> {
+        let node = Self(id);
+        let expected = Self::from_parts(node.level(), node.face(), node.x(), node.y())?;
+        if expected == node {
+            Some(node)
+        } else {
+            None
+        }
+    }
+    /// The six level zero nodes, one for each face of the cube that the planet is projected onto.
     pub fn roots() -> [Self; 6] {
         [
             Self::new(0, 0, 0, 0),
@@ -85,15 +111,20 @@ impl VNode {
         ]
     }
 
+    /// Horizontal index of this node within its face, in the range `0..(1 << level)`.
     pub fn x(&self) -> u32 {
         self.0 as u32 & 0x3ffffff
     }
+    /// Vertical index of this node within its face, in the range `0..(1 << level)`.
     pub fn y(&self) -> u32 {
         (self.0 >> 26) as u32 & 0x3ffffff
     }
+    /// Depth of this node in the quadtree. Root nodes are level zero, and each level halves the
+    /// side length of its parent.
     pub fn level(&self) -> u8 {
         (self.0 >> 56) as u8
     }
+    /// Which of the six cube faces this node lies on.
     pub fn face(&self) -> u8 {
         (self.0 >> 53) as u8 & 0x7
     }
@@ -274,6 +305,28 @@ impl VNode {
         d2
     }
 
+    /// Returns the approximate minimum and maximum corners of an axis aligned box in world space
+    /// containing this node, for terrain with heights in `height_range`. The box is found by
+    /// sampling points across the node, so it may not contain the full curvature of very large
+    /// nodes.
+    pub fn bounds(&self, height_range: (f32, f32)) -> (Vector3<f64>, Vector3<f64>) {
+        const SAMPLES: i32 = 5;
+
+        let mut min = Vector3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY);
+        let mut max = Vector3::new(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY);
+        for y in 0..SAMPLES {
+            for x in 0..SAMPLES {
+                let direction = self.grid_position_cspace(x, y, 0, SAMPLES as u32).normalize();
+                for &height in &[height_range.0, height_range.1] {
+                    let p = direction * (EARTH_RADIUS + height as f64);
+                    min = Vector3::new(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z));
+                    max = Vector3::new(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z));
+                }
+            }
+        }
+        (min, max)
+    }
+
     pub fn in_frustum(&self, f: &InfiniteFrustum, height_range: (f32, f32)) -> bool {
         let corners = [
             self.grid_position_cspace(0, 0, 0, 2).normalize(),
@@ -309,6 +362,8 @@ impl VNode {
         Priority::from_f32(priority)
     }
 
+    /// Returns the parent of this node along with which of the parent's children this node is,
+    /// or `None` for root nodes.
     pub fn parent(&self) -> Option<(VNode, u8)> {
         if self.level() == 0 {
             return None;
@@ -317,6 +372,8 @@ impl VNode {
         Some((VNode::new(self.level() - 1, self.face(), self.x() / 2, self.y() / 2), child_index))
     }
 
+    /// Returns the four children of this node, in the same order as `NODE_OFFSETS`. Must not be
+    /// called on nodes at `MAX_QUADTREE_LEVEL`.
     pub fn children(&self) -> [VNode; 4] {
         assert!(self.level() < 31);
         [
@@ -327,6 +384,21 @@ impl VNode {
         ]
     }
 
+    /// Returns the nodes at the same level that share each edge of this node, ordered by the edges
+    /// at x = 0, x = 1, y = 0 and y = 1 of the node. Neighbors across the edge of a cube face lie
+    /// on the adjacent face.
+    pub fn neighbors(&self) -> [VNode; 4] {
+        let center = self.grid_position_cspace(1, 1, 0, 3);
+        [(0, 1), (2, 1), (1, 0), (1, 2)].map(|(x, y)| {
+            // Step past the middle of the edge and project back onto the cube, which moves points
+            // that left this face onto the adjacent one.
+            let edge = self.grid_position_cspace(x, y, 0, 3);
+            let p = edge + (edge - center) * 0.5;
+            let p = p / p.x.abs().max(p.y.abs()).max(p.z.abs());
+            VNode::from_cspace(p, self.level()).0
+        })
+    }
+
     pub fn find_ancestor<Visit>(&self, mut visit: Visit) -> Option<(VNode, usize, Vector2<u32>)>
     where
         Visit: FnMut(VNode) -> bool,
@@ -345,6 +417,8 @@ impl VNode {
         Some((node, generations, offset))
     }
 
+    /// Visit nodes in breadth first order starting from the roots. The children of a node are
+    /// only visited if `visit` returns true for it.
     pub fn breadth_first<Visit>(mut visit: Visit)
     where
         Visit: FnMut(VNode) -> bool,
@@ -365,6 +439,8 @@ impl VNode {
     }
 }
 
+/// Formats the node as `N{level}-{face}-{x}x{y}`, where `face` is one of `0E`, `180E`, `90E`,
+/// `90W`, `N` or `S`. The format is stable and can be parsed back with `str::parse`.
 impl std::fmt::Display for VNode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         let face = match self.face() {
@@ -381,6 +457,42 @@ impl std::fmt::Display for VNode {
     }
 }
 
+/// Error returned when parsing a `VNode` from a string that isn't in the format produced by its
+/// `Display` implementation, or that names a node which doesn't exist.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseVNodeError(String);
+impl std::fmt::Display for ParseVNodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "invalid node '{}', expected N{{level}}-{{face}}-{{x}}x{{y}}", self.0)
+    }
+}
+impl std::error::Error for ParseVNodeError {}
+
+impl std::str::FromStr for VNode {
+    type Err = ParseVNodeError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = || {
+            let mut parts = s.strip_prefix('N')?.split('-');
+            let level = parts.next()?.parse().ok()?;
+            let face = match parts.next()? {
+                "0E" => 0,
+                "180E" => 1,
+                "90E" => 2,
+                "90W" => 3,
+                "N" => 4,
+                "S" => 5,
+                _ => return None,
+            };
+            let (x, y) = parts.next()?.split_once('x')?;
+            if parts.next().is_some() {
+                return None;
+            }
+            VNode::from_parts(level, face, x.parse().ok()?, y.parse().ok()?)
+        };
+        parse().ok_or_else(|| ParseVNodeError(s.to_owned()))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -392,4 +504,74 @@ mod tests {
         let p = node.priority(camera);
         assert!(p > Priority::cutoff());
     }
+
+    #[test]
+    fn string_round_trip() {
+        assert_eq!(VNode::new(3, 5, 2, 7).to_string(), "N3-S-2x7");
+
+        let mut nodes = Vec::new();
+        VNode::breadth_first(|node| {
+            nodes.push(node);
+            node.level() < 2
+        });
+        let deepest = (1 << MAX_QUADTREE_LEVEL) - 1;
+        nodes.push(VNode::new(MAX_QUADTREE_LEVEL, 3, deepest, 0));
+        for node in nodes {
+            assert_eq!(node.to_string().parse::<VNode>(), Ok(node));
+        }
+    }
+
+    #[test]
+    fn parse_rejects_malformed_nodes() {
+        for s in [
+            "",
+            "N",
+            "3-S-2x7",
+            "N3-S-2x",
+            "N3-S-2",
+            "N3-S--2x7",
+            "N3-W-2x7",
+            "N3-S-2x7-1",
+            "N3-S-2x7 ",
+            "n3-S-2x7",
+            "N3-S-8x7",
+            "N-3-S-2x7",
+            "N255-S-0x0",
+        ] {
+            assert_eq!(s.parse::<VNode>(), Err(ParseVNodeError(s.to_owned())), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn neighbors_share_edges() {
+        let node = VNode::new(3, 0, 2, 5);
+        assert_eq!(
+            node.neighbors(),
+            [
+                VNode::new(3, 0, 1, 5),
+                VNode::new(3, 0, 3, 5),
+                VNode::new(3, 0, 2, 4),
+                VNode::new(3, 0, 2, 6)
+            ]
+        );
+
+        // Every face borders the four faces other than itself and the one opposite it.
+        for root in VNode::roots() {
+            let mut faces: Vec<u8> = root.neighbors().iter().map(|n| n.face()).collect();
+            faces.sort();
+            faces.dedup();
+            assert_eq!(faces.len(), 4);
+            assert!(!faces.contains(&root.face()));
+            assert!(!faces.contains(&(root.face() ^ 1)));
+        }
+
+        // Being neighbors is mutual, including across faces.
+        VNode::breadth_first(|node| {
+            for neighbor in node.neighbors() {
+                assert_eq!(neighbor.level(), node.level());
+                assert!(neighbor.neighbors().contains(&node), "{} {}", node, neighbor);
+            }
+            node.level() < 3
+        });
+    }
 }